@@ -0,0 +1,163 @@
+//! Compares the polling and the notified collection of client requests by the client pools
+//! (see `BatchCollection`).
+//!
+//! Clients push requests at a fixed rate, from a light trickle to a heavy load, while the consumer
+//! picks up the batches as fast as it can. For each mode we report how much CPU the process used
+//! per second, and how long the requests took from being pushed to being picked up.
+//!
+//! Run with `cargo bench --bench client_pool_collection`. The CPU time is read from `/proc`, so it
+//! is only reported on Linux.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use atlas_common::node_id::{NodeId, NodeType};
+use atlas_communication::client_pooling::PeerIncomingRqHandling;
+use atlas_communication::config::{AdmissionConfig, BatchCollection, ClientPoolConfig};
+use atlas_communication::protocol_node::NodeIncomingRqHandler;
+
+const CLIENTS: u32 = 64;
+const FIRST_CLI: u32 = 1000;
+const RUN_TIME: Duration = Duration::from_secs(3);
+
+/// How long each client waits between its requests, from a trickle to a heavy load
+const PUSH_INTERVALS: [Duration; 3] = [
+    Duration::from_millis(10),
+    Duration::from_millis(1),
+    Duration::from_micros(50),
+];
+
+struct RunResult {
+    requests: usize,
+    batches: usize,
+    cpu_per_second: Option<f64>,
+    mean_latency: Duration,
+    p99_latency: Duration,
+}
+
+fn pool_config(collection: BatchCollection) -> ClientPoolConfig {
+    ClientPoolConfig {
+        batch_size: 256,
+        clients_per_pool: 32,
+        batch_timeout_micros: 1000,
+        batch_sleep_micros: 200,
+        admission_config: AdmissionConfig {
+            client_quota: usize::MAX,
+            overloaded_client_quota: usize::MAX,
+            high_watermark: usize::MAX,
+            low_watermark: usize::MAX,
+        },
+        adaptive_batching: None,
+        collection,
+    }
+}
+
+/// The CPU time used by this process so far (its user and system time, which `/proc/self/stat`
+/// reports in clock ticks of 1/100 of a second)
+fn process_cpu_time() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+
+    // The fields after the name of the process start at the state, the 3rd field
+    let fields = stat.rsplit_once(')')?.1.split_whitespace().collect::<Vec<_>>();
+
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+
+    Some(Duration::from_millis((utime + stime) * 10))
+}
+
+fn run(collection: BatchCollection, push_interval: Duration) -> RunResult {
+    let handling = Arc::new(PeerIncomingRqHandling::<Instant>::new(NodeId(0), NodeType::Replica, pool_config(collection)));
+
+    let clients = (0..CLIENTS)
+        .map(|client| handling.init_peer_conn(NodeId(FIRST_CLI + client), NodeType::Client))
+        .collect::<Vec<_>>();
+
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let producer = {
+        let stop = stop.clone();
+
+        std::thread::spawn(move || {
+            let mut next_push = Instant::now();
+
+            while !stop.load(Ordering::Relaxed) {
+                for client in &clients {
+                    client.push_request(Instant::now()).unwrap();
+                }
+
+                next_push += push_interval;
+
+                if let Some(wait) = next_push.checked_duration_since(Instant::now()) {
+                    std::thread::sleep(wait);
+                }
+            }
+        })
+    };
+
+    let cpu_start = process_cpu_time();
+    let start = Instant::now();
+
+    let mut latencies = Vec::new();
+    let mut batches = 0;
+
+    while start.elapsed() < RUN_TIME {
+        let batch = handling.receive_from_clients(Some(Duration::from_millis(100))).unwrap();
+
+        if batch.is_empty() {
+            continue;
+        }
+
+        let picked_up = Instant::now();
+
+        batches += 1;
+        latencies.extend(batch.into_iter().map(|pushed| picked_up.duration_since(pushed)));
+    }
+
+    let elapsed = start.elapsed();
+
+    let cpu_per_second = cpu_start.zip(process_cpu_time())
+        .map(|(cpu_start, cpu_end)| (cpu_end - cpu_start).as_secs_f64() / elapsed.as_secs_f64());
+
+    stop.store(true, Ordering::Relaxed);
+    producer.join().unwrap();
+
+    handling.shutdown();
+
+    latencies.sort();
+
+    let requests = latencies.len();
+
+    RunResult {
+        requests,
+        batches,
+        cpu_per_second,
+        mean_latency: latencies.iter().sum::<Duration>().checked_div(requests as u32).unwrap_or_default(),
+        p99_latency: latencies.get(requests * 99 / 100).copied().unwrap_or_default(),
+    }
+}
+
+fn main() {
+    println!("{:<10} {:>14} {:>10} {:>10} {:>12} {:>14} {:>14}",
+             "mode", "interval", "requests", "batches", "cpu/s", "mean latency", "p99 latency");
+
+    for push_interval in PUSH_INTERVALS {
+        for collection in [BatchCollection::Polling, BatchCollection::Notified] {
+            let result = run(collection, push_interval);
+
+            let cpu = result.cpu_per_second
+                .map(|cpu| format!("{:.2}", cpu))
+                .unwrap_or_else(|| "n/a".to_string());
+
+            println!("{:<10} {:>14} {:>10} {:>10} {:>12} {:>14} {:>14}",
+                     format!("{:?}", collection),
+                     format!("{:?}", push_interval),
+                     result.requests,
+                     result.batches,
+                     cpu,
+                     format!("{:?}", result.mean_latency),
+                     format!("{:?}", result.p99_latency));
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Adaptive sizing of the batches collected by the client pools.
+//!
+//! Each pool has its own [BatchSizer], since each one sees the arrival rate of its own clients,
+//! while the latency of the consumer (how long the batches wait to be picked up, see
+//! [crate::protocol_node::NodeIncomingRqHandler::receive_from_clients]) is shared by all of them.
+//!
+//! The target batch size grows while the consumer is slower than the target latency, so it is handed
+//! fewer, larger batches, and shrinks while the consumer keeps up easily, so requests don't wait for
+//! batches to fill. The batch timeout is how long a batch of the target size takes to arrive.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use atlas_metrics::metrics::{metric_duration, metric_store_count};
+
+use crate::config::AdaptiveBatchingConfig;
+use crate::metric::{CLIENT_POOL_BATCH_SIZE_ID, CLIENT_POOL_BATCH_TIMEOUT_ID};
+
+/// How much the previous observations weigh in the moving averages
+const SMOOTHING: f64 = 0.8;
+
+/// How much the target batch size grows by when the consumer falls behind
+const GROWTH_FACTOR: f64 = 1.25;
+
+/// How much the target batch size shrinks by when the consumer keeps up easily
+const SHRINK_FACTOR: f64 = 0.9;
+
+fn moving_average(previous: Option<f64>, observed: f64) -> f64 {
+    match previous {
+        Some(previous) => previous * SMOOTHING + observed * (1.0 - SMOOTHING),
+        None => observed,
+    }
+}
+
+/// How long the batches of all of the pools wait to be picked up by the consumer
+pub(crate) struct ConsumerLatency {
+    // The moving average of the latency, in micros
+    micros: Mutex<Option<f64>>,
+}
+
+impl ConsumerLatency {
+    pub fn new() -> Self {
+        Self {
+            micros: Mutex::new(None),
+        }
+    }
+
+    /// A batch was picked up after waiting for the given time
+    pub fn observe(&self, latency: Duration) {
+        let mut micros = self.micros.lock().unwrap();
+
+        *micros = Some(moving_average(*micros, latency.as_micros() as f64));
+    }
+
+    pub fn current(&self) -> Option<Duration> {
+        self.micros.lock().unwrap().map(|micros| Duration::from_micros(micros as u64))
+    }
+}
+
+/// Chooses the target batch size and timeout of a pool
+pub(crate) struct BatchSizer {
+    config: AdaptiveBatchingConfig,
+    batch_size: usize,
+    batch_timeout_micros: u64,
+    // The moving average of the requests arriving in the pool, per second
+    arrival_rate: Option<f64>,
+    last_collection: Instant,
+}
+
+impl BatchSizer {
+    /// Start with the given batch size and timeout, within the bounds of the config
+    pub fn new(mut config: AdaptiveBatchingConfig, batch_size: usize, batch_timeout_micros: u64) -> Self {
+        // Make sure the bounds can be clamped to, whatever we were given
+        config.min_batch_size = config.min_batch_size.max(1);
+        config.max_batch_size = config.max_batch_size.max(config.min_batch_size);
+        config.max_batch_timeout_micros = config.max_batch_timeout_micros.max(config.min_batch_timeout_micros);
+
+        Self {
+            batch_size: batch_size.clamp(config.min_batch_size, config.max_batch_size),
+            batch_timeout_micros: batch_timeout_micros.clamp(config.min_batch_timeout_micros, config.max_batch_timeout_micros),
+            config,
+            arrival_rate: None,
+            last_collection: Instant::now(),
+        }
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    pub fn batch_timeout_micros(&self) -> u64 {
+        self.batch_timeout_micros
+    }
+
+    /// We have collected the given amount of requests, adjust the target of the next batches
+    pub fn collected(&mut self, requests: usize, now: Instant, consumer_latency: Option<Duration>) {
+        let elapsed = now.saturating_duration_since(self.last_collection).max(Duration::from_micros(1));
+
+        self.last_collection = now;
+
+        let arrival_rate = moving_average(self.arrival_rate, requests as f64 / elapsed.as_secs_f64());
+
+        self.arrival_rate = Some(arrival_rate);
+
+        let target_latency = Duration::from_micros(self.config.target_consumer_latency_micros);
+
+        let batch_size = match consumer_latency {
+            Some(latency) if latency > target_latency => (self.batch_size as f64 * GROWTH_FACTOR).ceil() as usize,
+            Some(latency) if latency < target_latency / 2 => (self.batch_size as f64 * SHRINK_FACTOR).floor() as usize,
+            _ => self.batch_size,
+        };
+
+        self.batch_size = batch_size.clamp(self.config.min_batch_size, self.config.max_batch_size);
+
+        let batch_timeout_micros = if arrival_rate > 0.0 {
+            (self.batch_size as f64 / arrival_rate * 1_000_000.0) as u64
+        } else {
+            self.config.max_batch_timeout_micros
+        };
+
+        self.batch_timeout_micros = batch_timeout_micros.clamp(self.config.min_batch_timeout_micros, self.config.max_batch_timeout_micros);
+
+        metric_store_count(CLIENT_POOL_BATCH_SIZE_ID, self.batch_size);
+        metric_duration(CLIENT_POOL_BATCH_TIMEOUT_ID, Duration::from_micros(self.batch_timeout_micros));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::client_pooling::adaptive::{BatchSizer, ConsumerLatency};
+    use crate::config::AdaptiveBatchingConfig;
+
+    fn config() -> AdaptiveBatchingConfig {
+        AdaptiveBatchingConfig {
+            min_batch_size: 10,
+            max_batch_size: 1000,
+            min_batch_timeout_micros: 100,
+            max_batch_timeout_micros: 10_000,
+            target_consumer_latency_micros: 1000,
+        }
+    }
+
+    #[test]
+    fn test_batch_size_follows_consumer_latency() {
+        let mut sizer = BatchSizer::new(config(), 100, 1000);
+
+        let mut now = Instant::now();
+
+        // A slow consumer gets larger batches, up to the bound
+        for _ in 0..100 {
+            now += Duration::from_millis(1);
+
+            sizer.collected(100, now, Some(Duration::from_millis(5)));
+        }
+
+        assert_eq!(sizer.batch_size(), 1000);
+
+        // A consumer that keeps up gets smaller ones, down to the bound
+        for _ in 0..100 {
+            now += Duration::from_millis(1);
+
+            sizer.collected(100, now, Some(Duration::from_micros(100)));
+        }
+
+        assert_eq!(sizer.batch_size(), 10);
+
+        // Within the target, the batch size is left alone
+        sizer.collected(100, now + Duration::from_millis(1), Some(Duration::from_micros(800)));
+
+        assert_eq!(sizer.batch_size(), 10);
+    }
+
+    #[test]
+    fn test_batch_timeout_follows_arrival_rate() {
+        let mut sizer = BatchSizer::new(config(), 100, 1000);
+
+        let mut now = Instant::now();
+
+        // 100 requests per millisecond fill a batch of 100 in a millisecond
+        for _ in 0..50 {
+            now += Duration::from_millis(1);
+
+            sizer.collected(100, now, None);
+        }
+
+        assert_eq!(sizer.batch_size(), 100);
+        assert!((900..=1100).contains(&sizer.batch_timeout_micros()), "{}", sizer.batch_timeout_micros());
+
+        // Nothing arriving waits as long as the bound allows
+        for _ in 0..100 {
+            now += Duration::from_millis(1);
+
+            sizer.collected(0, now, None);
+        }
+
+        assert_eq!(sizer.batch_timeout_micros(), 10_000);
+
+        let latency = ConsumerLatency::new();
+
+        assert!(latency.current().is_none());
+
+        latency.observe(Duration::from_millis(2));
+
+        assert_eq!(latency.current(), Some(Duration::from_millis(2)));
+    }
+}
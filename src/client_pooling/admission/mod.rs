@@ -0,0 +1,171 @@
+//! Admission control of the requests of the clients, before they are queued in the client pools.
+//!
+//! Each client may only have so many requests waiting to be collected (its quota), so a client
+//! flooding us only fills its own queue instead of the pools. The pools as a whole also keep track of
+//! how many requests are waiting in them: once that goes over the high watermark they are overloaded,
+//! and every client gets the smaller overloaded quota until they have drained down to the low watermark.
+//!
+//! The requests that are refused are shed, and the client is told we are busy (see
+//! [crate::mio_tcp::connections::busy]).
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::{info, warn};
+
+use atlas_common::node_id::NodeId;
+use atlas_metrics::metrics::metric_increment;
+
+use crate::config::AdmissionConfig;
+use crate::metric::CLIENT_POOL_RQS_SHED_ID;
+
+/// Whether a request of a client is let into the pools
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    /// The client has used up its quota, so the request is shed
+    Refused,
+}
+
+/// The admission control shared by all of the client pools of a node
+pub struct AdmissionControl {
+    own_id: NodeId,
+    config: AdmissionConfig,
+    // How many requests are waiting to be collected, in all of the pools
+    queued: AtomicUsize,
+    // Have the pools gone over the high watermark, without having drained back to the low one yet
+    overloaded: AtomicBool,
+}
+
+impl AdmissionControl {
+    pub fn new(own_id: NodeId, config: AdmissionConfig) -> Self {
+        Self {
+            own_id,
+            config,
+            queued: AtomicUsize::new(0),
+            overloaded: AtomicBool::new(false),
+        }
+    }
+
+    /// Should a request of a client that already has the given amount of requests waiting be let in
+    pub fn admit(&self, client_queued: usize) -> Admission {
+        let quota = if self.is_overloaded() {
+            self.config.overloaded_client_quota
+        } else {
+            self.config.client_quota
+        };
+
+        if client_queued < quota {
+            Admission::Admitted
+        } else {
+            metric_increment(CLIENT_POOL_RQS_SHED_ID, Some(1));
+
+            Admission::Refused
+        }
+    }
+
+    /// The given amount of requests have been queued in the pools
+    pub fn pushed(&self, requests: usize) {
+        let queued = self.queued.fetch_add(requests, Ordering::Relaxed) + requests;
+
+        if queued >= self.config.high_watermark && !self.overloaded.swap(true, Ordering::Relaxed) {
+            warn!("{:?} // The client pools are overloaded with {} requests, shedding the requests of clients over {}",
+                self.own_id, queued, self.config.overloaded_client_quota);
+        }
+    }
+
+    /// The given amount of requests have been taken out of the pools
+    pub fn collected(&self, requests: usize) {
+        let queued = self.queued.fetch_sub(requests, Ordering::Relaxed) - requests;
+
+        if queued <= self.config.low_watermark && self.overloaded.swap(false, Ordering::Relaxed) {
+            info!("{:?} // The client pools are no longer overloaded, with {} requests", self.own_id, queued);
+        }
+    }
+
+    /// How many requests are waiting to be collected, in all of the pools
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn is_overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use atlas_common::node_id::NodeId;
+
+    use crate::client_pooling::admission::{Admission, AdmissionControl};
+    use crate::client_pooling::ConnectedPeer;
+    use crate::config::AdmissionConfig;
+
+    fn admission_control() -> AdmissionControl {
+        AdmissionControl::new(NodeId::from(0u32), AdmissionConfig {
+            client_quota: 10,
+            overloaded_client_quota: 2,
+            high_watermark: 100,
+            low_watermark: 50,
+        })
+    }
+
+    #[test]
+    fn test_clients_are_held_to_their_quota() {
+        let admission = admission_control();
+
+        assert_eq!(admission.admit(0), Admission::Admitted);
+        assert_eq!(admission.admit(9), Admission::Admitted);
+        assert_eq!(admission.admit(10), Admission::Refused);
+    }
+
+    #[test]
+    fn test_overload_shrinks_quota_until_drained() {
+        let admission = admission_control();
+
+        admission.pushed(99);
+
+        assert!(!admission.is_overloaded());
+        assert_eq!(admission.admit(5), Admission::Admitted);
+
+        admission.pushed(1);
+
+        assert!(admission.is_overloaded());
+        assert_eq!(admission.admit(1), Admission::Admitted);
+        assert_eq!(admission.admit(5), Admission::Refused);
+
+        // Draining under the high watermark is not enough to recover
+        admission.collected(40);
+
+        assert!(admission.is_overloaded());
+
+        admission.collected(10);
+
+        assert!(!admission.is_overloaded());
+        assert_eq!(admission.queued(), 50);
+        assert_eq!(admission.admit(5), Admission::Admitted);
+    }
+
+    #[test]
+    fn test_bursts_are_held_to_the_quota_before_being_queued() {
+        let client = ConnectedPeer::pooled(NodeId::from(1000u32), 16, Arc::new(admission_control()));
+
+        // A burst of requests is admitted before any of them has made it to the queue
+        let mut reservations = (0..15).filter_map(|_| client.admit()).collect::<Vec<_>>();
+
+        assert_eq!(reservations.len(), 10);
+
+        // Queueing a request keeps its place in the quota
+        reservations.pop().unwrap().push_request(0u32).unwrap();
+
+        assert!(client.admit().is_none());
+
+        // While requests that are dropped on their way (failing verification, for example) free theirs
+        reservations.truncate(5);
+
+        let admitted = (0..10).filter_map(|_| client.admit()).collect::<Vec<_>>();
+
+        assert_eq!(admitted.len(), 4);
+    }
+}
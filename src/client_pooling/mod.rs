@@ -1,6 +1,11 @@
+pub mod admission;
+pub(crate) mod adaptive;
+pub mod ready;
+
 use std::collections::BTreeMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use dashmap::DashMap;
 
@@ -13,7 +18,10 @@ use atlas_common::node_id::NodeType;
 use atlas_metrics::metrics::metric_duration;
 
 use crate::{NodeId};
-use crate::config::ClientPoolConfig;
+use crate::client_pooling::adaptive::{BatchSizer, ConsumerLatency};
+use crate::client_pooling::admission::{Admission, AdmissionControl};
+use crate::client_pooling::ready::ReadyClients;
+use crate::config::{AdaptiveBatchingConfig, AdmissionConfig, BatchCollection, ClientPoolConfig};
 use crate::metric::{CLIENT_POOL_BATCH_PASSING_TIME_ID, CLIENT_POOL_COLLECT_TIME_ID, REPLICA_RQ_PASSING_TIME_ID};
 use crate::protocol_node::NodeIncomingRqHandler;
 
@@ -54,6 +62,9 @@ const NODE_CHAN_BOUND: usize = 1024;
 const DEFAULT_CLIENT_QUEUE: usize = 16384;
 const DEFAULT_REPLICA_QUEUE: usize = 131072;
 
+///How long a notified pool waits for its clients before checking whether it should stop
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
 ///We make this class Sync and send since the clients are going to be handled by a single class
 ///And the replicas are going to be handled by another class.
 /// There is no possibility of 2 threads accessing the client_rx or replica_rx concurrently
@@ -69,7 +80,8 @@ impl<T> PeerIncomingRqHandling<T> where T: Send {
         let client_channel;
 
         let ClientPoolConfig {
-            batch_size, clients_per_pool, batch_timeout_micros, batch_sleep_micros
+            batch_size, clients_per_pool, batch_timeout_micros, batch_sleep_micros, admission_config, adaptive_batching,
+            collection
         } = config;
 
         match node_type {
@@ -83,7 +95,10 @@ impl<T> PeerIncomingRqHandling<T> where T: Send {
                                                                 id,
                                                                 clients_per_pool,
                                                                 batch_timeout_micros,
-                                                                batch_sleep_micros));
+                                                                batch_sleep_micros,
+                                                                admission_config,
+                                                                adaptive_batching,
+                                                                collection));
                 client_channel = Some((client_tx, client_rx));
             }
             NodeType::Client => {
@@ -186,6 +201,24 @@ impl<T> PeerIncomingRqHandling<T> where T: Send {
     pub fn replica_count(&self) -> usize {
         return self.replica_handling.connected_client_count.load(Ordering::Relaxed);
     }
+
+    /// A batch of client requests created at the given instant has been picked up
+    fn batch_picked_up(&self, time_created: Instant) {
+        let latency = time_created.elapsed();
+
+        metric_duration(CLIENT_POOL_BATCH_PASSING_TIME_ID, latency);
+
+        if let Some(client_handling) = &self.client_handling {
+            client_handling.consumer_latency.observe(latency);
+        }
+    }
+
+    /// Stop collecting the requests of the clients, waiting for the collector threads to finish
+    pub fn shutdown(&self) {
+        if let Some(client_handling) = &self.client_handling {
+            client_handling.shutdown();
+        }
+    }
 }
 
 impl<T: Send> NodeIncomingRqHandler<T> for PeerIncomingRqHandling<T> {
@@ -207,14 +240,14 @@ impl<T: Send> NodeIncomingRqHandler<T> for PeerIncomingRqHandling<T> {
             None => {
                 let (vec, time_created) = rx.recv()?;
 
-                metric_duration(CLIENT_POOL_BATCH_PASSING_TIME_ID, time_created.elapsed());
+                self.batch_picked_up(time_created);
 
                 Ok(vec)
             }
             Some(timeout) => {
                 match rx.recv_timeout(timeout) {
                     Ok((vec, time_created)) => {
-                        metric_duration(CLIENT_POOL_BATCH_PASSING_TIME_ID, time_created.elapsed());
+                        self.batch_picked_up(time_created);
 
                         Ok(vec)
                     }
@@ -241,7 +274,7 @@ impl<T: Send> NodeIncomingRqHandler<T> for PeerIncomingRqHandling<T> {
 
         match rx.try_recv() {
             Ok((msgs, time_created)) => {
-                metric_duration(CLIENT_POOL_BATCH_PASSING_TIME_ID, time_created.elapsed());
+                self.batch_picked_up(time_created);
 
                 Ok(Some(msgs))
             }
@@ -278,6 +311,15 @@ pub enum ConnectedPeer<T> where T: Send {
         client_id: NodeId,
         queue: Mutex<Option<Vec<T>>>,
         disconnected: AtomicBool,
+        admission: Arc<AdmissionControl>,
+        //The requests that were let in but are still on their way to the queue (being deserialized and verified)
+        in_flight: AtomicUsize,
+        //Are we in the ready clients of our pool, waiting to be collected
+        ready: AtomicBool,
+        //The ready clients of the pool we belong to, if it is notified of our requests
+        pool: Mutex<Weak<ReadyClients<T>>>,
+        //Ourselves, so we can add ourselves to the ready clients of the pool
+        me: Weak<ConnectedPeer<T>>,
     },
     UnpooledConnection {
         client_id: NodeId,
@@ -418,6 +460,18 @@ pub struct ConnectedPeersGroup<T: Send + 'static> {
     clients_per_pool: usize,
     //Counter used to keep track of the created pools
     pool_id_counter: AtomicUsize,
+    //The threads collecting the requests of each pool
+    collectors: Mutex<Vec<JoinHandle<()>>>,
+    //Have we been shut down, in which case the pools should stop collecting
+    shut_down: AtomicBool,
+    //Which requests of the clients are let into the pools
+    admission: Arc<AdmissionControl>,
+    //How the pools adapt their batches to the load, if they do
+    adaptive_batching: Option<AdaptiveBatchingConfig>,
+    //How long the batches wait to be picked up, which the adaptive pools follow
+    consumer_latency: ConsumerLatency,
+    //How the pools find the requests of their clients
+    collection: BatchCollection,
 }
 
 pub struct ConnectedPeersPool<T: Send + 'static> {
@@ -433,13 +487,18 @@ pub struct ConnectedPeersPool<T: Send + 'static> {
     client_limit: usize,
     batch_timeout_micros: u64,
     batch_sleep_micros: u64,
+    //Chooses the batch size and timeout of this pool, when they adapt to the load
+    batch_sizer: Option<Mutex<BatchSizer>>,
+    //The clients with requests waiting to be collected, when this pool is notified of them
+    ready_clients: Option<Arc<ReadyClients<T>>>,
 }
 
 impl<T> ConnectedPeersGroup<T> where T: Send + 'static {
     pub fn new(per_client_bound: usize, batch_size: usize,
                batch_transmission: ChannelSyncTx<ClientRqBatchOutput<T>>,
                own_id: NodeId, clients_per_pool: usize, batch_timeout_micros: u64,
-               batch_sleep_micros: u64) -> Arc<Self> {
+               batch_sleep_micros: u64, admission_config: AdmissionConfig,
+               adaptive_batching: Option<AdaptiveBatchingConfig>, collection: BatchCollection) -> Arc<Self> {
         Arc::new(Self {
             own_id,
             client_pools: Mutex::new(BTreeMap::new()),
@@ -452,6 +511,12 @@ impl<T> ConnectedPeersGroup<T> where T: Send + 'static {
             batch_transmission,
             clients_per_pool,
             pool_id_counter: AtomicUsize::new(0),
+            collectors: Mutex::new(Vec::new()),
+            shut_down: AtomicBool::new(false),
+            admission: Arc::new(AdmissionControl::new(own_id, admission_config)),
+            adaptive_batching,
+            consumer_latency: ConsumerLatency::new(),
+            collection,
         })
     }
 
@@ -478,11 +543,7 @@ impl<T> ConnectedPeersGroup<T> where T: Send + 'static {
     }
 
     pub fn init_client(self: &Arc<Self>, peer_id: NodeId) -> Arc<ConnectedPeer<T>> {
-        let connected_client = Arc::new(ConnectedPeer::PoolConnection {
-            client_id: peer_id,
-            disconnected: AtomicBool::new(false),
-            queue: Mutex::new(Some(Vec::with_capacity(self.per_client_cache))),
-        });
+        let connected_client = ConnectedPeer::pooled(peer_id, self.per_client_cache, self.admission.clone());
 
         self.connected_clients.fetch_add(1, Ordering::SeqCst);
 
@@ -527,7 +588,9 @@ impl<T> ConnectedPeersGroup<T> where T: Send + 'static {
             Arc::clone(self),
             self.clients_per_pool,
             self.batch_timeout_micros,
-            self.batch_sleep_micros);
+            self.batch_sleep_micros,
+            self.adaptive_batching.clone(),
+            self.collection);
 
         match pool.attempt_to_add(clone_queue) {
             Ok(_) => {}
@@ -544,7 +607,13 @@ impl<T> ConnectedPeersGroup<T> where T: Send + 'static {
 
             let id = guard.len();
 
-            pool_clone.start(id as u32);
+            let collector = pool_clone.start(id as u32);
+
+            let mut collectors = self.collectors.lock().unwrap();
+
+            // Pools that were emptied have already stopped
+            collectors.retain(|collector| !collector.is_finished());
+            collectors.push(collector);
         }
 
 
@@ -583,6 +652,37 @@ impl<T> ConnectedPeersGroup<T> where T: Send + 'static {
 
         self.connected_clients.fetch_sub(clients.len(), Ordering::Relaxed);
     }
+
+    fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::Relaxed)
+    }
+
+    /// The admission control of the requests of our clients
+    pub fn admission(&self) -> &Arc<AdmissionControl> {
+        &self.admission
+    }
+
+    /// Stop all the pools, waiting for their collector threads to finish
+    pub fn shutdown(&self) {
+        if self.shut_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // Dropping the pools also breaks the reference cycle between them and us
+        let pools = std::mem::take(&mut *self.client_pools.lock().unwrap());
+
+        for pool in pools.values() {
+            pool.shutdown();
+        }
+
+        let collectors = std::mem::take(&mut *self.collectors.lock().unwrap());
+
+        for collector in collectors {
+            if collector.join().is_err() {
+                error!("{:?} // A client pool collector thread has panicked", self.own_id);
+            }
+        }
+    }
 }
 
 impl<T> ConnectedPeersPool<T> where T: Send {
@@ -590,7 +690,11 @@ impl<T> ConnectedPeersPool<T> where T: Send {
     //The owner also has to be active
     pub fn new(pool_id: usize, batch_size: usize, batch_transmission: ChannelSyncTx<ClientRqBatchOutput<T>>,
                owner: Arc<ConnectedPeersGroup<T>>, client_per_pool: usize,
-               batch_timeout_micros: u64, batch_sleep_micros: u64) -> Arc<Self> {
+               batch_timeout_micros: u64, batch_sleep_micros: u64,
+               adaptive_batching: Option<AdaptiveBatchingConfig>, collection: BatchCollection) -> Arc<Self> {
+        let batch_sizer = adaptive_batching
+            .map(|config| Mutex::new(BatchSizer::new(config, batch_size, batch_timeout_micros)));
+
         let result = Self {
             pool_id,
             connected_clients: Mutex::new(Vec::new()),
@@ -601,6 +705,11 @@ impl<T> ConnectedPeersPool<T> where T: Send {
             client_limit: client_per_pool,
             finish_execution: AtomicBool::new(false),
             owner,
+            batch_sizer,
+            ready_clients: match collection {
+                BatchCollection::Polling => None,
+                BatchCollection::Notified => Some(Arc::new(ReadyClients::new())),
+            },
         };
 
         let pool = Arc::new(result);
@@ -608,7 +717,7 @@ impl<T> ConnectedPeersPool<T> where T: Send {
         pool
     }
 
-    pub fn start(self: Arc<Self>, pool_id: u32) {
+    pub fn start(self: Arc<Self>, pool_id: u32) -> JoinHandle<()> {
 
         //Spawn the thread that will collect client requests
         //and then send the batches to the channel.
@@ -616,11 +725,18 @@ impl<T> ConnectedPeersPool<T> where T: Send {
             .name(format!("Peer pool collector thread #{}", pool_id))
             .spawn(move || {
                 loop {
-                    if self.finish_execution.load(Ordering::Relaxed) {
+                    if self.finish_execution.load(Ordering::Relaxed) || self.owner.is_shut_down() {
                         break;
                     }
 
-                    let vec = match self.collect_requests(self.batch_size, &self.owner) {
+                    let (batch_size, batch_timeout_micros) = self.batch_target();
+
+                    let collected = match &self.ready_clients {
+                        Some(ready_clients) => self.collect_ready_requests(ready_clients, batch_size, batch_timeout_micros, &self.owner),
+                        None => self.collect_requests(batch_size, batch_timeout_micros, &self.owner),
+                    };
+
+                    let vec = match collected {
                         Ok(vec) => { vec }
                         Err(err) => {
                             match err {
@@ -637,10 +753,21 @@ impl<T> ConnectedPeersPool<T> where T: Send {
                         }
                     };
 
+                    if let Some(batch_sizer) = &self.batch_sizer {
+                        batch_sizer.lock().unwrap().collected(vec.len(), Instant::now(), self.owner.consumer_latency.current());
+                    }
+
                     if !vec.is_empty() {
+                        // Adaptive pools that filled their batch have more requests waiting, so they don't sleep
+                        let keep_collecting = self.batch_sizer.is_some() && vec.len() >= batch_size;
+
                         self.batch_transmission.send_return((vec, Instant::now()))
                             .expect("Failed to send proposed batch");
 
+                        if keep_collecting {
+                            continue;
+                        }
+
                         // Sleep for a determined amount of time to allow clients to send requests
                         let three_quarters_sleep = (self.batch_sleep_micros / 4) * 3;
                         let five_quarters_sleep = (self.batch_sleep_micros / 4) * 5;
@@ -652,13 +779,29 @@ impl<T> ConnectedPeersPool<T> where T: Send {
 
                     // backoff.spin();
                 }
-            }).unwrap();
+            }).unwrap()
+    }
+
+    /// The batch size and timeout the next batch should be collected with
+    fn batch_target(&self) -> (usize, u64) {
+        match &self.batch_sizer {
+            Some(batch_sizer) => {
+                let batch_sizer = batch_sizer.lock().unwrap();
+
+                (batch_sizer.batch_size(), batch_sizer.batch_timeout_micros())
+            }
+            None => (self.batch_size, self.batch_timeout_micros)
+        }
     }
 
     pub fn attempt_to_add(&self, client: Arc<ConnectedPeer<T>>) -> std::result::Result<(), Arc<ConnectedPeer<T>>> {
         let mut guard = self.connected_clients.lock().unwrap();
 
         if guard.len() < self.client_limit {
+            if let Some(ready_clients) = &self.ready_clients {
+                client.attach_to_pool(ready_clients);
+            }
+
             guard.push(client);
 
             return Ok(());
@@ -682,7 +825,7 @@ impl<T> ConnectedPeersPool<T> where T: Send {
         };
     }
 
-    pub fn collect_requests(&self, batch_target_size: usize, owner: &Arc<ConnectedPeersGroup<T>>) -> std::result::Result<Vec<T>, ClientPoolError> {
+    pub fn collect_requests(&self, batch_target_size: usize, batch_timeout_micros: u64, owner: &Arc<ConnectedPeersGroup<T>>) -> std::result::Result<Vec<T>, ClientPoolError> {
         let start = Instant::now();
 
         let vec_size = std::cmp::max(batch_target_size, self.owner.per_client_cache);
@@ -752,7 +895,7 @@ impl<T> ConnectedPeersPool<T> where T: Send {
                 } else {
                     let current_time = Instant::now();
 
-                    if current_time.duration_since(start_time).as_micros() >= self.batch_timeout_micros as u128 {
+                    if current_time.duration_since(start_time).as_micros() >= batch_timeout_micros as u128 {
                         //Check if a given amount of time limit has passed, to prevent us getting
                         //Stuck while checking for requests
                         break;
@@ -763,6 +906,93 @@ impl<T> ConnectedPeersPool<T> where T: Send {
             }
         }
 
+        self.remove_disconnected(dced, owner)?;
+
+        metric_duration(CLIENT_POOL_COLLECT_TIME_ID, start.elapsed());
+
+        Ok(batch)
+    }
+
+    /// Collect the requests of the clients that are ready, waiting for them to be.
+    /// Gives up on an empty batch when no client has become ready for a while, so the collector
+    /// can check whether it should stop
+    pub fn collect_ready_requests(&self, ready_clients: &ReadyClients<T>, batch_target_size: usize, batch_timeout_micros: u64,
+                                  owner: &Arc<ConnectedPeersGroup<T>>) -> std::result::Result<Vec<T>, ClientPoolError> {
+        if self.connected_clients.lock().unwrap().is_empty() {
+            return Err!(ClientPoolError::ClosePool);
+        }
+
+        let start = Instant::now();
+
+        let mut batch = Vec::with_capacity(std::cmp::max(batch_target_size, self.owner.per_client_cache));
+
+        let mut replacement_vec = Vec::with_capacity(self.owner.per_client_cache);
+
+        let mut ready = Vec::new();
+
+        let mut dced = Vec::new();
+
+        //The batch times out counting from when its first request was collected
+        let mut deadline = None;
+
+        loop {
+            let wait = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => IDLE_WAIT,
+            };
+
+            ready_clients.wait_ready(&mut ready, wait);
+
+            if ready.is_empty() {
+                break;
+            }
+
+            for client in ready.drain(..) {
+                //Any request pushed from now on will mark the client as ready again
+                client.clear_ready();
+
+                if client.is_dc() {
+                    dced.push(client.client_id().clone());
+
+                    continue;
+                }
+
+                match client.dump_requests(replacement_vec) {
+                    Ok(mut rqs_dumped) => {
+                        batch.append(&mut rqs_dumped);
+
+                        replacement_vec = rqs_dumped;
+                    }
+                    Err(vec) => {
+                        dced.push(client.client_id().clone());
+
+                        replacement_vec = vec;
+                    }
+                }
+            }
+
+            if batch.len() >= batch_target_size {
+                break;
+            }
+
+            if !batch.is_empty() {
+                let deadline = *deadline.get_or_insert_with(|| Instant::now() + Duration::from_micros(batch_timeout_micros));
+
+                if Instant::now() >= deadline {
+                    break;
+                }
+            }
+        }
+
+        self.remove_disconnected(dced, owner)?;
+
+        metric_duration(CLIENT_POOL_COLLECT_TIME_ID, start.elapsed());
+
+        Ok(batch)
+    }
+
+    /// Remove the given disconnected clients from this pool, closing it if it has become empty
+    fn remove_disconnected(&self, dced: Vec<NodeId>, owner: &Arc<ConnectedPeersGroup<T>>) -> std::result::Result<(), ClientPoolError> {
         //This might cause some lag since it has to access the intmap, but
         //Should be fine as it will only happen on client dcs
         if !dced.is_empty() {
@@ -796,19 +1026,35 @@ impl<T> ConnectedPeersPool<T> where T: Send {
             }
         }
 
-        metric_duration(CLIENT_POOL_COLLECT_TIME_ID, start.elapsed());
-
-        Ok(batch)
+        Ok(())
     }
 
     pub fn shutdown(&self) {
         info!("{:?} // Pool {} is shutting down", self.owner.own_id, self.pool_id);
 
         self.finish_execution.store(true, Ordering::Relaxed);
+
+        if let Some(ready_clients) = &self.ready_clients {
+            ready_clients.close();
+        }
     }
 }
 
 impl<T> ConnectedPeer<T> where T: Send {
+    ///A new pooled client, whose requests are waiting to be collected by a pool
+    pub fn pooled(client_id: NodeId, queue_capacity: usize, admission: Arc<AdmissionControl>) -> Arc<Self> {
+        Arc::new_cyclic(|me| Self::PoolConnection {
+            client_id,
+            queue: Mutex::new(Some(Vec::with_capacity(queue_capacity))),
+            disconnected: AtomicBool::new(false),
+            admission,
+            in_flight: AtomicUsize::new(0),
+            ready: AtomicBool::new(false),
+            pool: Mutex::new(Weak::new()),
+            me: me.clone(),
+        })
+    }
+
     pub fn client_id(&self) -> &NodeId {
         match self {
             Self::PoolConnection { client_id, .. } => {
@@ -838,13 +1084,94 @@ impl<T> ConnectedPeer<T> where T: Send {
             }
             Self::UnpooledConnection { .. } => {}
         };
+
+        //Notified pools only visit ready clients, so they would never notice otherwise
+        self.mark_ready();
+    }
+
+    ///Let the pool know of our requests from now on, through its ready clients
+    pub fn attach_to_pool(&self, ready_clients: &Arc<ReadyClients<T>>) {
+        if let Self::PoolConnection { pool, ready, .. } = self {
+            *pool.lock().unwrap() = Arc::downgrade(ready_clients);
+
+            //We may have been marked before being attached
+            if ready.load(Ordering::Acquire) {
+                if let Some(me) = self.me() {
+                    ready_clients.mark_ready(me);
+                }
+            }
+        }
+    }
+
+    ///Our pool is about to collect our requests, so any requests pushed from now on
+    ///have to mark us as ready again
+    pub fn clear_ready(&self) {
+        if let Self::PoolConnection { ready, .. } = self {
+            ready.store(false, Ordering::Release);
+        }
+    }
+
+    fn me(&self) -> Option<Arc<Self>> {
+        match self {
+            Self::PoolConnection { me, .. } => me.upgrade(),
+            Self::UnpooledConnection { .. } => None,
+        }
+    }
+
+    ///Add ourselves to the ready clients of our pool, unless we already are
+    fn mark_ready(&self) {
+        if let Self::PoolConnection { ready, pool, .. } = self {
+            if ready.swap(true, Ordering::AcqRel) {
+                return;
+            }
+
+            let ready_clients = pool.lock().unwrap().upgrade();
+
+            if let (Some(ready_clients), Some(me)) = (ready_clients, self.me()) {
+                ready_clients.mark_ready(me);
+            }
+        }
+    }
+
+    ///Should the next request of this peer be let in, according to its quota.
+    ///The requests that were let in but not yet queued count towards the quota, so an admitted request
+    ///holds its place until it is queued or dropped (see [Reservation]).
+    ///Only the requests of pooled clients are subject to admission control
+    pub fn admit(self: &Arc<Self>) -> Option<Reservation<T>> {
+        match &**self {
+            Self::PoolConnection { queue, admission, in_flight, .. } => {
+                //Pushing a request takes the lock, so the queued and in flight requests are not counted twice
+                let guard = queue.lock().unwrap();
+
+                let queued = guard.as_ref().map_or(0, Vec::len);
+
+                match admission.admit(queued + in_flight.load(Ordering::Relaxed)) {
+                    Admission::Admitted => {
+                        in_flight.fetch_add(1, Ordering::Relaxed);
+
+                        Some(Reservation { connection: self.clone() })
+                    }
+                    Admission::Refused => None,
+                }
+            }
+            Self::UnpooledConnection { .. } => {
+                Some(Reservation { connection: self.clone() })
+            }
+        }
+    }
+
+    ///The admitted request has been queued or dropped, so it is no longer on its way
+    fn release(&self) {
+        if let Self::PoolConnection { in_flight, .. } = self {
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
     }
 
     ///Dump n requests into the provided vector
     ///Returns the amount of requests that were dumped into the array
     pub fn dump_requests(&self, replacement_vec: Vec<T>) -> std::result::Result<Vec<T>, Vec<T>> {
         return match self {
-            Self::PoolConnection { queue, .. } => {
+            Self::PoolConnection { queue, admission, .. } => {
                 let mut guard = queue.lock().unwrap();
 
                 match &mut *guard {
@@ -852,6 +1179,8 @@ impl<T> ConnectedPeer<T> where T: Send {
                         Err(replacement_vec)
                     }
                     Some(rqs) => {
+                        admission.collected(rqs.len());
+
                         Ok(std::mem::replace(rqs, replacement_vec))
                     }
                 }
@@ -866,7 +1195,7 @@ impl<T> ConnectedPeer<T> where T: Send {
         trace!("Pushing request to client {:?}", self.client_id());
 
         match self {
-            Self::PoolConnection { queue, client_id, .. } => {
+            Self::PoolConnection { queue, client_id, admission, .. } => {
                 let mut sender_guard = queue.lock().unwrap();
 
                 match &mut *sender_guard {
@@ -881,6 +1210,12 @@ impl<T> ConnectedPeer<T> where T: Send {
                         //So, no more than one thread will be trying to acquire this lock at the same time
                         sender.push(msg);
 
+                        admission.pushed(1);
+
+                        drop(sender_guard);
+
+                        self.mark_ready();
+
                         Ok(())
                     }
                 }
@@ -901,6 +1236,41 @@ impl<T> ConnectedPeer<T> where T: Send {
     }
 }
 
+///The place of an admitted request in the quota of its client, held until the request is queued.
+///Dropping it (whether the request was queued or not) frees the place
+pub struct Reservation<T> where T: Send {
+    connection: Arc<ConnectedPeer<T>>,
+}
+
+impl<T> Reservation<T> where T: Send {
+    ///The client the request was admitted for
+    pub fn connection(&self) -> &Arc<ConnectedPeer<T>> {
+        &self.connection
+    }
+
+    ///Queue the admitted request
+    pub fn push_request(self, msg: T) -> Result<()> {
+        self.connection.push_request(msg)
+    }
+}
+
+impl<T> Drop for Reservation<T> where T: Send {
+    fn drop(&mut self) {
+        self.connection.release();
+    }
+}
+
+impl<T> Drop for ConnectedPeer<T> where T: Send {
+    fn drop(&mut self) {
+        // The requests that were never collected no longer count towards the pools
+        if let Self::PoolConnection { queue, admission, .. } = self {
+            if let Ok(Some(rqs)) = queue.get_mut() {
+                admission.collected(rqs.len());
+            }
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ClientPoolError {
     #[error("This error is meant to be used to close the pool")]
@@ -0,0 +1,128 @@
+//! The clients of a pool that have requests waiting to be collected.
+//!
+//! When a client pushes a request while it isn't marked as ready, it marks itself and adds itself
+//! to the ready clients of its pool, waking the collector of the pool. The collector only visits
+//! the clients that are ready, clearing their mark before it takes their requests, and sleeps
+//! while none are, instead of polling every one of its clients.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::client_pooling::ConnectedPeer;
+
+/// The clients of a pool with requests waiting to be collected
+pub struct ReadyClients<T> where T: Send {
+    state: Mutex<ReadyState<T>>,
+    wakeup: Condvar,
+}
+
+struct ReadyState<T> where T: Send {
+    // The clients that are ready, in the order they became ready
+    clients: Vec<Arc<ConnectedPeer<T>>>,
+    // Has the pool been shut down, in which case its collector shouldn't wait anymore
+    closed: bool,
+}
+
+impl<T> ReadyClients<T> where T: Send {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(ReadyState {
+                clients: Vec::new(),
+                closed: false,
+            }),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// The given client has requests waiting to be collected
+    pub fn mark_ready(&self, client: Arc<ConnectedPeer<T>>) {
+        self.state.lock().unwrap().clients.push(client);
+
+        self.wakeup.notify_one();
+    }
+
+    /// Move the clients that are ready into `ready`, waiting up to `timeout` for any to be.
+    /// Once the pool has been shut down, this no longer waits
+    pub fn wait_ready(&self, ready: &mut Vec<Arc<ConnectedPeer<T>>>, timeout: Duration) {
+        let guard = self.state.lock().unwrap();
+
+        let (mut guard, _) = self.wakeup.wait_timeout_while(guard, timeout, |state| {
+            state.clients.is_empty() && !state.closed
+        }).unwrap();
+
+        ready.append(&mut guard.clients);
+    }
+
+    /// Stop the collector of the pool from waiting for clients
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+
+        self.wakeup.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use atlas_common::node_id::NodeId;
+
+    use crate::client_pooling::ready::ReadyClients;
+    use crate::client_pooling::ConnectedPeer;
+    use crate::config::AdmissionConfig;
+    use crate::client_pooling::admission::AdmissionControl;
+
+    #[test]
+    fn test_clients_are_only_ready_once_until_collected() {
+        let ready_clients = Arc::new(ReadyClients::new());
+
+        let admission = Arc::new(AdmissionControl::new(NodeId::from(0u32), AdmissionConfig {
+            client_quota: 100,
+            overloaded_client_quota: 100,
+            high_watermark: 1000,
+            low_watermark: 500,
+        }));
+
+        let client = ConnectedPeer::pooled(NodeId::from(1000u32), 16, admission);
+
+        client.attach_to_pool(&ready_clients);
+
+        let mut ready = Vec::new();
+
+        // Nothing was pushed, so the collector gives up after the timeout
+        ready_clients.wait_ready(&mut ready, Duration::from_millis(10));
+
+        assert!(ready.is_empty());
+
+        client.push_request(1u32).unwrap();
+        client.push_request(2u32).unwrap();
+
+        ready_clients.wait_ready(&mut ready, Duration::from_secs(5));
+
+        assert_eq!(ready.len(), 1);
+
+        ready[0].clear_ready();
+
+        assert_eq!(ready[0].dump_requests(Vec::new()).ok(), Some(vec![1, 2]));
+
+        ready.clear();
+
+        // Pushing after being collected marks the client again
+        client.push_request(3u32).unwrap();
+
+        ready_clients.wait_ready(&mut ready, Duration::from_secs(5));
+
+        assert_eq!(ready.len(), 1);
+
+        // A closed pool no longer waits for its clients
+        ready.clear();
+        ready_clients.close();
+
+        let start = Instant::now();
+
+        ready_clients.wait_ready(&mut ready, Duration::from_secs(5));
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
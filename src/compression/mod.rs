@@ -0,0 +1,132 @@
+//! Optional compression of message payloads.
+//!
+//! Payloads above a configurable size threshold are compressed with lz4 (when the `compression`
+//! feature is enabled) after they have been digested, so the digest (and therefore the signature)
+//! of a message is always computed over its uncompressed bytes. Compressed payloads are marked
+//! in the header flags (see [Header::is_compressed]) and carry their uncompressed size in a
+//! little endian `u32` prefix.
+
+use bytes::{Bytes, BytesMut};
+use thiserror::Error;
+
+use atlas_common::Err;
+use atlas_common::error::*;
+use atlas_common::node_id::NodeId;
+
+use crate::message::Header;
+
+/// The size of the prefix with the uncompressed size of the payload
+const SIZE_PREFIX_LENGTH: usize = std::mem::size_of::<u32>();
+
+/// lz4 can't compress data by more than this ratio, so any payload that claims to
+/// decompress to more than this is malformed (and we shouldn't allocate for it)
+#[cfg(feature = "compression")]
+const MAX_COMPRESSION_RATIO: usize = 255;
+
+/// Is payload compression available in this build
+pub fn is_available() -> bool {
+    cfg!(feature = "compression")
+}
+
+/// Compress the given payload if it is larger than the threshold and compressing it actually saves space.
+/// Returns the payload to send and whether it was compressed.
+#[cfg(feature = "compression")]
+pub(crate) fn compress_above(payload: Bytes, threshold: usize) -> (Bytes, bool) {
+    if payload.len() <= threshold || payload.len() > u32::MAX as usize {
+        return (payload, false);
+    }
+
+    let compressed = lz4_flex::compress(&payload[..]);
+
+    if compressed.len() + SIZE_PREFIX_LENGTH >= payload.len() {
+        // Not worth it
+        return (payload, false);
+    }
+
+    let mut buf = Vec::with_capacity(SIZE_PREFIX_LENGTH + compressed.len());
+
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes()[..]);
+    buf.extend_from_slice(&compressed[..]);
+
+    (Bytes::from(buf), true)
+}
+
+#[cfg(not(feature = "compression"))]
+pub(crate) fn compress_above(payload: Bytes, _threshold: usize) -> (Bytes, bool) {
+    (payload, false)
+}
+
+/// Decompress the payload of a message whose header is marked as compressed
+pub(crate) fn decompress(header: &Header, payload: &[u8]) -> Result<BytesMut> {
+    if payload.len() < SIZE_PREFIX_LENGTH {
+        return Err!(CompressionError::MalformedPayload(header.from()));
+    }
+
+    let mut size = [0; SIZE_PREFIX_LENGTH];
+    size.copy_from_slice(&payload[..SIZE_PREFIX_LENGTH]);
+
+    decompress_sized(header, u32::from_le_bytes(size) as usize, &payload[SIZE_PREFIX_LENGTH..])
+}
+
+#[cfg(feature = "compression")]
+fn decompress_sized(header: &Header, size: usize, compressed: &[u8]) -> Result<BytesMut> {
+    if size > compressed.len().saturating_mul(MAX_COMPRESSION_RATIO) {
+        return Err!(CompressionError::MalformedPayload(header.from()));
+    }
+
+    match lz4_flex::decompress(compressed, size) {
+        Ok(decompressed) if decompressed.len() == size => Ok(BytesMut::from(&decompressed[..])),
+        _ => Err!(CompressionError::MalformedPayload(header.from())),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress_sized(header: &Header, _size: usize, _compressed: &[u8]) -> Result<BytesMut> {
+    Err!(CompressionError::Unavailable(header.from()))
+}
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("Received a compressed message from {0:?}, but compression is not available in this build")]
+    Unavailable(NodeId),
+    #[error("Received a malformed compressed message from {0:?}")]
+    MalformedPayload(NodeId),
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use bytes::Bytes;
+
+    use atlas_common::node_id::NodeId;
+
+    use crate::compression;
+    use crate::message::WireMessage;
+
+    #[test]
+    fn test_compression_roundtrip() {
+        let payload = Bytes::from(vec![7u8; 64 * 1024]);
+
+        let (small, compressed) = compression::compress_above(Bytes::from_static(b"small"), 1024);
+
+        assert!(!compressed);
+        assert_eq!(&small[..], b"small");
+
+        let (compressed_payload, compressed) = compression::compress_above(payload.clone(), 1024);
+
+        assert!(compressed);
+        assert!(compressed_payload.len() < payload.len());
+
+        let (header, _) = WireMessage::new(NodeId::from(0u32), NodeId::from(1u32),
+                                           compressed_payload.clone(), 0, None, None).into_inner();
+
+        let decompressed = compression::decompress(&header, &compressed_payload[..]).unwrap();
+
+        assert_eq!(&decompressed[..], &payload[..]);
+
+        // Payloads that claim an unreasonable size are rejected before allocating
+        let mut bomb = compressed_payload.to_vec();
+        bomb[..4].copy_from_slice(&u32::MAX.to_le_bytes()[..]);
+
+        assert!(compression::decompress(&header, &bomb[..]).is_err());
+    }
+}
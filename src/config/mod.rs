@@ -17,6 +17,24 @@ pub struct NodeConfig {
     pub tcp_config: TcpConfig,
     ///The configurations of the client pool config
     pub client_pool_config: ClientPoolConfig,
+    /// The configuration of which received messages should be verified
+    pub verification_config: MessageVerificationConfig,
+    /// The configuration of the protection against replayed messages
+    pub replay_protection_config: ReplayProtectionConfig,
+    /// The configuration of the fragmentation of large messages
+    pub fragmentation_config: FragmentationConfig,
+    /// The configuration of the heartbeats used to detect dead connections
+    pub heartbeat_config: HeartbeatConfig,
+    /// The configuration of the failure detector that grades how suspicious each peer is
+    pub failure_detector_config: FailureDetectorConfig,
+    /// The configuration of how we reconnect to peers after losing connections to them
+    pub reconnection_config: ReconnectionConfig,
+    /// The configuration of what happens to messages sent to peers that can't keep up with them
+    pub backpressure_config: BackpressureConfig,
+    /// The configuration of the reliable delivery of protocol messages across connection resets
+    pub reliable_delivery_config: ReliableDeliveryConfig,
+    /// The configuration of the priority lanes of the send queues
+    pub priority_lanes_config: PriorityLanesConfig,
 }
 
 pub struct TcpConfig {
@@ -25,7 +43,201 @@ pub struct TcpConfig {
     /// How many concurrent connections should be established between replica nodes of the system
     pub replica_concurrent_connections: usize,
     /// How many client concurrent connections should be established between replica <-> client connections
-    pub client_concurrent_connections: usize
+    pub client_concurrent_connections: usize,
+    /// Authenticate the signed messages we send to other replicas with a MAC, keyed with the session key
+    /// of the connection they are sent over, instead of signing each one of them.
+    /// Receivers accept both schemes, so this can be toggled independently on each node
+    pub replica_session_macs: bool,
+    /// The highest wire protocol version we accept to use on our connections.
+    /// Each connection uses the highest version supported by both ends, so this allows
+    /// rolling out a new version gradually. `None` means the newest version we support
+    pub max_wire_version: Option<u32>,
+    /// Compress the payloads of messages larger than this amount of bytes.
+    /// Only available with the `compression` feature. `None` disables compression
+    pub compression_threshold: Option<usize>,
+    /// The largest payload we accept in a single frame from any connection.
+    /// Connections that announce larger payloads are closed before we allocate anything for them.
+    /// (Larger messages can still be received in fragments, see [FragmentationConfig])
+    pub max_payload_length: usize,
+    /// How long we keep writing the messages queued on our connections when the node is shut down
+    /// (or when we disconnect from a node), before closing the connections anyway
+    pub shutdown_timeout_millis: u64,
+    /// The bandwidth limits of our connections.
+    /// They can be changed at runtime (see [crate::NodeConnections::set_rate_limits])
+    pub rate_limit_config: RateLimitConfig,
+}
+
+/// A token bucket limit on the bandwidth used by a connection (or group of connections).
+/// Frames are never split to fit the limit: a frame larger than what is left in the bucket
+/// leaves it in debt, and nothing else goes through until the debt is paid
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BandwidthLimit {
+    /// The sustained rate we are allowed to use, in bytes per second. Must be greater than 0
+    pub bytes_per_second: u64,
+    /// How many bytes can go through in a burst, after the bandwidth has not been used for a while
+    pub burst_bytes: u64,
+}
+
+/// The bandwidth limits of each of our peers of a given type, shared by all of our connections to them.
+/// `None` means unlimited
+#[derive(Clone, Debug)]
+pub struct PeerRateLimits {
+    /// The limit on what we write to the peer
+    pub send_limit: Option<BandwidthLimit>,
+    /// The limit on what we read from the peer. Once it is reached, we stop reading from
+    /// the connections to the peer, so it is slowed down by TCP flow control
+    pub receive_limit: Option<BandwidthLimit>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    /// The limit on what we write to all of our peers combined. `None` means unlimited
+    pub global_send_limit: Option<BandwidthLimit>,
+    /// The limits of each replica
+    pub replica_limits: PeerRateLimits,
+    /// The limits of each client (and of peers whose type we don't know yet)
+    pub client_limits: PeerRateLimits,
+}
+
+/// Which kinds of received messages should have their digest and signature verified
+/// before being delivered to the upper layers.
+/// Messages that fail verification are dropped.
+#[derive(Clone, Debug)]
+pub struct MessageVerificationConfig {
+    /// Verify the reconfiguration messages we receive
+    pub verify_reconfiguration_messages: bool,
+    /// Verify the protocol messages we receive.
+    /// Only enable this when all protocol messages are sent signed (`send_signed`/`broadcast_signed`),
+    /// as unsigned messages will be rejected
+    pub verify_protocol_messages: bool,
+}
+
+/// How received messages are protected against replays.
+/// We remember the nonces of the last messages each peer has sent us and reject
+/// any message whose nonce we still remember.
+#[derive(Clone, Debug)]
+pub struct ReplayProtectionConfig {
+    /// How many nonces we remember for each peer, which bounds the memory used
+    /// (roughly 16 bytes per nonce). 0 disables replay protection
+    pub nonces_per_peer: usize,
+    /// How many peers we remember nonces for at once. Messages of any further peers are dropped
+    /// until we lose every connection to one of the peers we are tracking
+    pub max_peers: usize,
+}
+
+/// How large messages are split into frames on the wire.
+/// Messages larger than the maximum frame size are sent in fragments, which are reassembled by
+/// the receiver, so that a large transfer does not hold back the smaller messages queued behind it.
+#[derive(Clone, Debug)]
+pub struct FragmentationConfig {
+    /// The largest payload we send in a single frame. 0 disables fragmentation
+    pub max_frame_size: usize,
+    /// How many bytes we are willing to hold in partially reassembled messages from each peer.
+    /// Peers that go over this limit are disconnected
+    pub max_reassembly_bytes_per_peer: usize,
+}
+
+/// Heartbeats sent over idle connections, so we detect connections that stopped working
+/// (for example half open TCP connections) instead of waiting on them forever.
+#[derive(Clone, Debug)]
+pub struct HeartbeatConfig {
+    /// How long a connection can go without receiving anything before we ping the peer over it.
+    /// 0 disables heartbeats
+    pub interval_millis: u64,
+    /// How long we wait for an answer to a ping before closing the connection
+    pub timeout_millis: u64,
+}
+
+/// The phi-accrual failure detector, which estimates how likely it is that a peer has failed
+/// from how long it has been silent, compared to the usual intervals between its messages.
+#[derive(Clone, Debug)]
+pub struct FailureDetectorConfig {
+    /// The suspicion level (phi) above which a peer is considered suspected
+    pub phi_threshold: f64,
+    /// How many of the latest intervals between messages from a peer are used to estimate the next ones
+    pub max_sample_size: usize,
+    /// Lower bound of the standard deviation of the intervals, so that very regular traffic doesn't
+    /// make a peer suspected as soon as it is a bit late
+    pub min_std_deviation_millis: u64,
+    /// How much longer than usual a peer may stay silent before becoming suspicious.
+    /// Should account for the heartbeat interval, as idle peers are only heard from when pinged
+    pub acceptable_pause_millis: u64,
+    /// The interval between messages we assume for a peer before we have any samples
+    pub first_interval_estimate_millis: u64,
+}
+
+/// How we (re)connect to peers whose connections failed.
+/// Failed attempts are retried with an exponential backoff, randomized with some jitter so that
+/// nodes which lost their connections at the same time don't all retry in lockstep.
+#[derive(Clone, Debug)]
+pub struct ReconnectionConfig {
+    /// The delay before the first attempt at reconnecting to a peer after a connection to it fails
+    pub initial_backoff_millis: u64,
+    /// The longest we wait between two attempts
+    pub max_backoff_millis: u64,
+    /// How much the delay grows after each failed attempt
+    pub backoff_multiplier: f64,
+    /// Which fraction of the delay is randomized, between 0 (no jitter) and 1
+    /// (anywhere between no delay and twice the delay)
+    pub jitter: f64,
+    /// How long a connection attempt may take, from the TCP connection to the end of the
+    /// authentication handshake, before it is considered failed
+    pub connect_timeout_millis: u64,
+    /// How many attempts in a row may fail before we give up on a peer.
+    /// `None` keeps retrying until the connection is cancelled with `disconnect_from_node`
+    pub max_attempts: Option<u32>,
+}
+
+/// What we do with a message sent to a peer whose send queue is full
+/// (because the peer, or our connections to it, can't keep up with what we send it).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackpressurePolicy {
+    /// Wait for room in the queue, failing the send if there is still none after the given time
+    Block { timeout_millis: u64 },
+    /// Make room for the message by dropping the oldest message in the least urgent lane of the queue
+    DropOldest,
+    /// Drop the message, reporting it as dropped
+    DropNewest,
+    /// Fail the send
+    Fail,
+}
+
+/// How full send queues are handled, depending on the type of the peer they belong to.
+/// The outcome is reported to the caller of `send`/`broadcast`, so blocking only ever
+/// blocks the caller, never the threads serializing and writing the messages.
+#[derive(Clone, Debug)]
+pub struct BackpressureConfig {
+    /// The policy applied to the send queues of replicas
+    pub replica_policy: BackpressurePolicy,
+    /// The policy applied to the send queues of clients (and of nodes whose type we don't know yet)
+    pub client_policy: BackpressurePolicy,
+}
+
+/// The messages waiting to be written to a peer are split in lanes, one for each priority
+/// (see `MessagePriority`), so that large transfers don't hold up what is queued behind them.
+/// The lanes are drained in weighted round robin: in each round, each lane gets to write up to its
+/// weight in messages, from the most to the least urgent lane.
+#[derive(Clone, Debug)]
+pub struct PriorityLanesConfig {
+    /// The weight of the lane of control messages (reconfiguration messages, by default)
+    pub control_weight: u32,
+    /// The weight of the lane of regular protocol messages
+    pub normal_weight: u32,
+    /// The weight of the lane of bulk transfers
+    pub bulk_weight: u32,
+}
+
+/// The reliable delivery of protocol messages, so they survive the connections they were sent over breaking.
+/// Protocol messages carry a sequence number the peer acknowledges, and the ones it has not acknowledged
+/// when a connection breaks are sent again (the peer drops those it had already received).
+#[derive(Clone, Debug)]
+pub struct ReliableDeliveryConfig {
+    /// Sequence the protocol messages we send. We always acknowledge (and drop duplicates of)
+    /// the sequenced messages we receive, so this can be toggled independently on each node
+    pub enabled: bool,
+    /// How many messages sent to a peer may be waiting for its acknowledgement. Once reached,
+    /// nothing else is written to the peer until it acknowledges some of them
+    pub max_unacked: usize,
 }
 
 pub struct PKConfig {
@@ -57,4 +269,55 @@ pub struct ClientPoolConfig {
     ///How long should a client pool sleep for before attempting to collect requests again
     /// (It actually will sleep between 3/4 and 5/4 of this value, to make sure they don't all sleep / wake up at the same time)
     pub batch_sleep_micros: u64,
+    ///Which client requests are let into the pools, and which ones are shed
+    pub admission_config: AdmissionConfig,
+    ///Adapt the batch size and timeout of each pool to the load, instead of always using the ones above.
+    /// `None` keeps them fixed
+    pub adaptive_batching: Option<AdaptiveBatchingConfig>,
+    ///How each pool finds the requests of its clients
+    pub collection: BatchCollection,
+}
+
+/// How the client pools find the requests of their clients to collect
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BatchCollection {
+    /// Go around all of the clients of the pool, yielding between rounds, until the batch is full or times out
+    Polling,
+    /// Only visit the clients that have pushed requests, sleeping while there are none.
+    /// The batch timeout starts once the first request of the batch has been collected
+    Notified,
+}
+
+/// The adaptive sizing of the batches of client requests. Each pool grows its target batch size while
+/// the consumer takes longer than the target latency to pick up the batches, and shrinks it while the
+/// consumer keeps up easily. The batch timeout follows how long it takes for a batch to arrive at the
+/// observed arrival rate. Pools that fill their batches no longer sleep between them
+#[derive(Clone, Debug)]
+pub struct AdaptiveBatchingConfig {
+    ///The smallest target batch size
+    pub min_batch_size: usize,
+    ///The largest target batch size
+    pub max_batch_size: usize,
+    ///The shortest batch timeout
+    pub min_batch_timeout_micros: u64,
+    ///The longest batch timeout
+    pub max_batch_timeout_micros: u64,
+    ///How long the batches should wait for the consumer to pick them up
+    pub target_consumer_latency_micros: u64,
+}
+
+/// The admission control of client requests, so a single client flooding us can't starve the others.
+/// Each client may only have so many requests waiting to be collected by the pools, and once the
+/// pools as a whole are overloaded, that quota shrinks until they have drained back under the low watermark.
+/// The requests that are refused are answered with a busy frame, so the client can retry them later
+#[derive(Clone, Debug)]
+pub struct AdmissionConfig {
+    ///How many requests each client may have waiting to be collected
+    pub client_quota: usize,
+    ///How many requests each client may have waiting to be collected while the pools are overloaded
+    pub overloaded_client_quota: usize,
+    ///How many requests may be waiting in all of the pools before they are considered overloaded
+    pub high_watermark: usize,
+    ///How few requests must be waiting in all of the pools for them to no longer be considered overloaded
+    pub low_watermark: usize,
 }
\ No newline at end of file
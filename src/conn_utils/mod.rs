@@ -1,9 +1,84 @@
+use std::sync::Mutex;
+use atlas_common::channel;
+use atlas_common::channel::{ChannelSyncRx, ChannelSyncTx};
 use atlas_common::node_id::{NodeId, NodeType};
 use crate::config::TcpConfig;
 use crate::reconfiguration_node::NetworkInformationProvider;
 
 pub type Callback = Option<Box<dyn FnOnce(bool) -> () + Send>>;
 
+/// Something that happened to our connections, see [crate::NetworkNode::subscribe_connection_events]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    /// A connection to the node has been established, with the given connection id
+    Connected(NodeId, NodeType, u32),
+    /// A connection to the node has been closed
+    Disconnected(NodeId, DisconnectReason),
+    /// The node has authenticated itself, but we don't know it yet, so it is waiting
+    /// for the reconfiguration protocol to authorize it
+    PendingAuthorization(NodeId),
+    /// The last connection to the node has been closed
+    AllConnectionsLost(NodeId),
+    /// The node was too busy to take our request with the given nonce, which it has dropped
+    Busy(NodeId, u64),
+}
+
+/// Why a connection was closed
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The connection was closed by the other end, or broke
+    ConnectionBroken,
+    /// We failed to handle the connection (for example, because the peer sent something invalid)
+    Error,
+    /// The peer stopped answering our heartbeats
+    HeartbeatTimeout,
+    /// We asked to disconnect from the node
+    Requested,
+    /// The peer closed the connection on purpose (it said goodbye, for the given reason),
+    /// so we don't reconnect to it
+    PeerLeft(LeaveReason),
+}
+
+/// Why a peer told us it was closing its connections to us
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeaveReason {
+    /// The peer is shutting down
+    ShuttingDown,
+    /// The peer disconnected from us (see [crate::NodeConnections::disconnect_from_node])
+    Disconnected,
+    /// A reason we don't know about, sent by a peer running a newer version
+    Unknown(u32),
+}
+
+/// The subscribers of a kind of event.
+/// Subscribers that drop their receiver are removed on the next notification
+pub(crate) struct Subscribers<T> {
+    name: &'static str,
+    subscribers: Mutex<Vec<ChannelSyncTx<T>>>,
+}
+
+impl<T> Subscribers<T> where T: Clone {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> ChannelSyncRx<T> {
+        let (tx, rx) = channel::new_unbounded_sync(Some(self.name));
+
+        self.subscribers.lock().unwrap().push(tx);
+
+        rx
+    }
+
+    pub fn notify(&self, event: T) {
+        self.subscribers.lock().unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
 /// The amount of parallel TCP connections we should try to maintain for
 /// each connection
 #[derive(Clone)]
@@ -2,17 +2,23 @@ use std::sync::Arc;
 use std::time::Instant;
 use bytes::{Bytes, BytesMut};
 use log::{error, info, warn};
+use thiserror::Error;
 use atlas_common::channel::{new_oneshot_channel, OneShotRx};
 use atlas_common::crypto::hash::Digest;
+use atlas_common::crypto::signature::PublicKey;
 use atlas_common::error::*;
-use atlas_common::{channel, quiet_unwrap, threadpool};
-use atlas_metrics::metrics::metric_duration;
-use crate::client_pooling::ConnectedPeer;
-use crate::message::{Header, NetworkMessage, NetworkMessageKind, StoredMessage};
-use crate::metric::{COMM_DESERIALIZE_VERIFY_TIME_ID, COMM_SERIALIZE_SIGN_TIME_ID, THREADPOOL_PASS_TIME_ID};
-use crate::reconfiguration_node::ReconfigurationMessageHandler;
-use crate::serialize;
-use crate::serialize::Serializable;
+use atlas_common::{channel, Err, quiet_unwrap, threadpool};
+use atlas_common::node_id::NodeId;
+use atlas_metrics::metrics::{metric_duration, metric_increment};
+use crate::client_pooling::Reservation;
+use crate::config::MessageVerificationConfig;
+use crate::message::{AuthScheme, Header, MessageClass, NetworkMessage, NetworkMessageKind, StoredMessage, WireMessage};
+use crate::message_signing::{DefaultProtocolSignatureVerifier, DefaultReconfigSignatureVerifier, MessageKind, NetworkMessageSignatureVerifier, SessionKey};
+use crate::metric::{COMM_DESERIALIZE_VERIFY_TIME_ID, COMM_MSGS_FAILED_VERIFICATION_ID, COMM_MSGS_REPLAYED_ID, COMM_SERIALIZE_SIGN_TIME_ID, THREADPOOL_PASS_TIME_ID};
+use crate::reconfiguration_node::{NetworkInformationProvider, ReconfigurationMessageHandler};
+use crate::replay_protection::ReplayWindow;
+use crate::{compression, serialize};
+use crate::serialize::{digest_message, Serializable};
 
 //TODO: Statistics
 
@@ -73,17 +79,30 @@ pub(crate) fn serialize_digest_no_threadpool<RM, PM>(message: &NetworkMessageKin
 }
 
 /// Deserialize a given message without using the threadpool.
-pub(crate) fn deserialize_message_no_threadpool<RM, PM>(header: Header, payload: BytesMut) -> Result<(NetworkMessageKind<RM, PM>, BytesMut)>
+/// Compressed payloads are decompressed first, in which case the returned bytes are the uncompressed payload.
+pub(crate) fn deserialize_message_no_threadpool<RM, PM>(header: Header, mut payload: BytesMut) -> Result<(NetworkMessageKind<RM, PM>, BytesMut)>
     where RM: Serializable + 'static, PM: Serializable + 'static {
     let start = Instant::now();
 
-    //TODO: Verify signatures
+    if header.is_compressed() {
+        payload = compression::decompress(&header, &payload[..header.payload_length()])?;
+    } else {
+        payload.truncate(header.payload_length());
+    }
 
     // deserialize payload
-    let message = serialize::deserialize_message::<&[u8], RM, PM>(&payload[..header.payload_length()])?;
+    let message = serialize::deserialize_message::<&[u8], RM, PM>(&payload[..])?;
 
     metric_duration(COMM_DESERIALIZE_VERIFY_TIME_ID, start.elapsed());
 
+    // The message may have been routed according to the class announced in the header,
+    // so it must match the message we actually received
+    if let Some(class) = header.message_class() {
+        if class != message.class() {
+            return Err!(VerificationError::ClassMismatch(header.from(), class, message.class()));
+        }
+    }
+
     Ok((message, payload))
 }
 
@@ -107,14 +126,121 @@ pub(crate) fn deserialize_message<RM, PM>(header: Header, payload: BytesMut)
     rx
 }
 
-pub(crate) fn deserialize_and_push_reconf_message<RM, PM>(header: Header, payload: BytesMut, reconf_handle: Arc<ReconfigurationMessageHandler<StoredMessage<RM::Message>>>)
-    where RM: Serializable + 'static, PM: Serializable + 'static {
+/// Verify the digest and the signature of a message we have received, if the configuration
+/// requires messages of its kind to be verified.
+///
+/// Reconfiguration messages of nodes whose key we don't know yet are verified with `presented_key`,
+/// the key they authenticated with when connecting to us, if any
+pub(crate) fn verify_received_message<NI, RM, PM>(info_provider: &Arc<NI>, verification: &MessageVerificationConfig,
+                                                  session_key: Option<&SessionKey>,
+                                                  presented_key: Option<&PublicKey>,
+                                                  header: &Header, message: &NetworkMessageKind<RM, PM>, payload: &Bytes) -> Result<()>
+    where NI: NetworkInformationProvider + 'static, RM: Serializable + 'static, PM: Serializable + 'static {
+    let kind = match message {
+        NetworkMessageKind::ReconfigurationMessage(_) => MessageKind::Reconfig,
+        NetworkMessageKind::System(_) => MessageKind::Protocol,
+        NetworkMessageKind::Ping(_) => return Ok(()),
+    };
+
+    if !kind.should_verify(verification) {
+        return Ok(());
+    }
+
+    let digest = digest_message(payload.clone())?;
+
+    if digest.as_ref() != header.digest().as_ref() {
+        return Err!(VerificationError::DigestMismatch(header.from()));
+    }
+
+    match (message, header.auth_scheme(), session_key) {
+        (NetworkMessageKind::ReconfigurationMessage(reconf), AuthScheme::SessionMac, Some(key)) => {
+            DefaultReconfigSignatureVerifier::<RM, PM, NI>::verify_mac_with_buf(info_provider, key, header, &**reconf, payload)
+        }
+        (NetworkMessageKind::System(sys), AuthScheme::SessionMac, Some(key)) => {
+            DefaultProtocolSignatureVerifier::<RM, PM, NI>::verify_mac_with_buf(info_provider, key, header, &**sys, payload)
+        }
+        (NetworkMessageKind::ReconfigurationMessage(reconf), _, _) => {
+            match (info_provider.get_public_key(&header.from()), presented_key) {
+                (None, Some(presented_key)) => {
+                    DefaultReconfigSignatureVerifier::<RM, PM, NI>::verify_presented_key_with_buf(info_provider, presented_key, header, &**reconf, payload)
+                }
+                _ => DefaultReconfigSignatureVerifier::<RM, PM, NI>::verify_signature_with_buf(info_provider, header, &**reconf, payload)
+            }
+        }
+        (NetworkMessageKind::System(sys), _, _) => {
+            DefaultProtocolSignatureVerifier::<RM, PM, NI>::verify_signature_with_buf(info_provider, header, &**sys, payload)
+        }
+        (NetworkMessageKind::Ping(_), _, _) => unreachable!()
+    }
+}
+
+/// Deserialize a message and verify it according to the verification configuration.
+/// Messages that fail verification are counted and dropped (an error is returned).
+fn deserialize_and_verify<NI, RM, PM>(info_provider: &Arc<NI>, verification: &MessageVerificationConfig,
+                                      session_key: Option<&SessionKey>,
+                                      presented_key: Option<&PublicKey>,
+                                      header: &Header, payload: BytesMut) -> Result<NetworkMessageKind<RM, PM>>
+    where NI: NetworkInformationProvider + 'static, RM: Serializable + 'static, PM: Serializable + 'static {
+    if !WireMessage::is_supported_version(header.protocol_version()) {
+        // The peer should only use the version negotiated with us, so we can't know how to interpret this
+        metric_increment(COMM_MSGS_FAILED_VERIFICATION_ID, Some(1));
+
+        warn!("Dropping message from {:?} as it uses an unsupported wire version {}", header.from(), header.protocol_version());
+
+        return Err!(VerificationError::UnsupportedVersion(header.from(), header.protocol_version()));
+    }
+
+    let (message, payload) = deserialize_message_no_threadpool::<RM, PM>(header.clone(), payload)?;
+
+    // The digest covers the uncompressed payload
+    let payload = payload.freeze();
+
+    if let Err(err) = verify_received_message(info_provider, verification, session_key, presented_key, header, &message, &payload) {
+        metric_increment(COMM_MSGS_FAILED_VERIFICATION_ID, Some(1));
+
+        warn!("Dropping message {:?} from {:?} as it failed verification: {:?}", message, header.from(), err);
+
+        return Err(err);
+    }
+
+    Ok(message)
+}
+
+/// Check that we have not yet delivered this message, received from the given peer.
+/// Replayed messages are counted and dropped (an error is returned).
+fn check_replay(replay_window: &ReplayWindow, peer: NodeId, header: &Header) -> Result<()> {
+    if let Err(err) = replay_window.check_and_register(peer, header) {
+        metric_increment(COMM_MSGS_REPLAYED_ID, Some(1));
+
+        warn!("Dropping message from {:?} as it has been replayed: {:?}", header.from(), err);
+
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// Deserialize, verify and deliver a reconfiguration message to the reconfiguration protocol.
+///
+/// `presented_key` is the key a node we don't know yet (e.g. a node joining through reconfiguration)
+/// authenticated with when connecting to us, which its messages are verified with until
+/// the reconfiguration protocol vouches for it
+pub(crate) fn deserialize_and_push_reconf_message<NI, RM, PM>(peer: NodeId, header: Header, payload: BytesMut,
+                                                              info_provider: Arc<NI>,
+                                                              verification: MessageVerificationConfig,
+                                                              replay_window: Arc<ReplayWindow>,
+                                                              session_key: Option<SessionKey>,
+                                                              presented_key: Option<PublicKey>,
+                                                              reconf_handle: Arc<ReconfigurationMessageHandler<StoredMessage<RM::Message>>>)
+    where NI: NetworkInformationProvider + 'static, RM: Serializable + 'static, PM: Serializable + 'static {
     let start = Instant::now();
 
     threadpool::execute(move || {
         metric_duration(THREADPOOL_PASS_TIME_ID, start.elapsed());
 
-        let (message, _) = quiet_unwrap!( deserialize_message_no_threadpool::<RM, PM>(header.clone(), payload));
+        let message = quiet_unwrap!(deserialize_and_verify::<NI, RM, PM>(&info_provider, &verification, session_key.as_ref(), presented_key.as_ref(), &header, payload));
+
+        quiet_unwrap!(check_replay(&replay_window, peer, &header));
 
         match message {
             NetworkMessageKind::ReconfigurationMessage(reconf) => {
@@ -125,27 +251,237 @@ pub(crate) fn deserialize_and_push_reconf_message<RM, PM>(header: Header, payloa
     });
 }
 
-pub(crate) fn deserialize_and_push_message<RM, PM>(header: Header, payload: BytesMut,
-                                                   connection: Arc<ConnectedPeer<StoredMessage<PM::Message>>>,
-                                                   reconf_handle: Arc<ReconfigurationMessageHandler<StoredMessage<RM::Message>>>)
-    where RM: Serializable + 'static, PM: Serializable + 'static {
+/// Deserialize, verify and queue a request that was admitted into the client pools. Its reservation
+/// is released whether it makes it to the pools or not
+pub(crate) fn deserialize_and_push_message<NI, RM, PM>(peer: NodeId, header: Header, payload: BytesMut,
+                                                       info_provider: Arc<NI>,
+                                                       verification: MessageVerificationConfig,
+                                                       replay_window: Arc<ReplayWindow>,
+                                                       session_key: Option<SessionKey>,
+                                                       reservation: Reservation<StoredMessage<PM::Message>>,
+                                                       reconf_handle: Arc<ReconfigurationMessageHandler<StoredMessage<RM::Message>>>)
+    where NI: NetworkInformationProvider + 'static, RM: Serializable + 'static, PM: Serializable + 'static {
     let start = Instant::now();
 
     threadpool::execute(move || {
         metric_duration(THREADPOOL_PASS_TIME_ID, start.elapsed());
 
-        let (message, _) = quiet_unwrap!(deserialize_message_no_threadpool::<RM, PM>(header.clone(), payload));
+        let message = quiet_unwrap!(deserialize_and_verify::<NI, RM, PM>(&info_provider, &verification, session_key.as_ref(), None, &header, payload));
+
+        quiet_unwrap!(check_replay(&replay_window, peer, &header));
 
         match message {
             NetworkMessageKind::ReconfigurationMessage(reconf) => {
                 quiet_unwrap!(reconf_handle.push_request(StoredMessage::new(header, reconf.into())));
             }
             NetworkMessageKind::Ping(_) => {
-                warn!("MIO does not currently use this (and the only one that uses this function is MIO so....)")
+                // Pings are answered by the epoll workers, based on the class announced in the header
+                warn!("Received a ping from {:?} that did not announce its class, ignoring it", header.from())
             }
             NetworkMessageKind::System(sys_msg) => {
-                quiet_unwrap!(connection.push_request(StoredMessage::new(header, sys_msg.into())));
+                quiet_unwrap!(reservation.push_request(StoredMessage::new(header, sys_msg.into())));
             }
         }
     });
-}
\ No newline at end of file
+}
+#[derive(Error, Debug)]
+pub enum VerificationError {
+    #[error("The digest of the payload sent by {0:?} does not match the digest in the header")]
+    DigestMismatch(NodeId),
+    #[error("The header sent by {0:?} announced a {1:?} message, but it carried a {2:?} message")]
+    ClassMismatch(NodeId, MessageClass, MessageClass),
+    #[error("The message sent by {0:?} uses the wire protocol version {1}, which we don't support")]
+    UnsupportedVersion(NodeId, u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+
+    use atlas_common::crypto::signature::{KeyPair, PublicKey};
+    use atlas_common::error::*;
+    use atlas_common::node_id::{NodeId, NodeType};
+    use atlas_common::peer_addr::PeerAddr;
+
+    use crate::config::{MessageVerificationConfig, ReplayProtectionConfig};
+    use crate::cpu_workers;
+    use crate::message::{Header, NetworkMessageKind, WireMessage};
+    use crate::message_signing::NetworkMessageSignatureVerifier;
+    use crate::reconfiguration_node::NetworkInformationProvider;
+    use crate::replay_protection::ReplayWindow;
+    use crate::serialize::Serializable;
+
+    struct TestMessage;
+
+    impl Serializable for TestMessage {
+        type Message = Vec<u8>;
+
+        fn verify_message_internal<NI, SV>(_info_provider: &Arc<NI>, _header: &Header, _msg: &Self::Message) -> Result<()>
+            where NI: NetworkInformationProvider + 'static,
+                  SV: NetworkMessageSignatureVerifier<Self, NI> {
+            Ok(())
+        }
+    }
+
+    /// Only knows the public key of a single peer, if any
+    struct SinglePeerInfo {
+        key_pair: Arc<KeyPair>,
+        peer: NodeId,
+        peer_key: Option<PublicKey>,
+    }
+
+    impl NetworkInformationProvider for SinglePeerInfo {
+        fn get_own_id(&self) -> NodeId {
+            NodeId::from(0u32)
+        }
+
+        fn get_own_addr(&self) -> PeerAddr {
+            PeerAddr::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000), String::from("srv0"))
+        }
+
+        fn get_key_pair(&self) -> &Arc<KeyPair> {
+            &self.key_pair
+        }
+
+        fn get_own_node_type(&self) -> NodeType {
+            NodeType::Replica
+        }
+
+        fn get_node_type(&self, _node: &NodeId) -> Option<NodeType> {
+            Some(NodeType::Replica)
+        }
+
+        fn get_public_key(&self, node: &NodeId) -> Option<PublicKey> {
+            if *node == self.peer { self.peer_key.clone() } else { None }
+        }
+
+        fn get_addr_for_node(&self, _node: &NodeId) -> Option<PeerAddr> {
+            None
+        }
+    }
+
+    fn verify(info: &Arc<SinglePeerInfo>, header: &Header, payload: &[u8]) -> Result<NetworkMessageKind<TestMessage, TestMessage>> {
+        verify_presented(info, None, header, payload)
+    }
+
+    fn verify_presented(info: &Arc<SinglePeerInfo>, presented_key: Option<&PublicKey>, header: &Header, payload: &[u8]) -> Result<NetworkMessageKind<TestMessage, TestMessage>> {
+        let verification = MessageVerificationConfig {
+            verify_reconfiguration_messages: true,
+            verify_protocol_messages: true,
+        };
+
+        cpu_workers::deserialize_and_verify::<SinglePeerInfo, TestMessage, TestMessage>(info, &verification, None, presented_key, header, BytesMut::from(payload))
+    }
+
+    #[test]
+    fn test_unauthenticated_reconfiguration_messages_are_dropped() {
+        let (me, peer) = (NodeId::from(0u32), NodeId::from(1u32));
+
+        let peer_key = KeyPair::from_bytes(&[1; 32][..]).unwrap();
+
+        let info = Arc::new(SinglePeerInfo {
+            key_pair: Arc::new(KeyPair::from_bytes(&[0; 32][..]).unwrap()),
+            peer,
+            peer_key: Some(peer_key.public_key().into()),
+        });
+
+        let message = NetworkMessageKind::<TestMessage, TestMessage>::from_reconfig(vec![1, 2, 3]);
+
+        let (payload, digest) = cpu_workers::serialize_digest_no_threadpool(&message).unwrap();
+
+        let (header, _) = WireMessage::new(peer, me, payload.clone(), 1, Some(digest.clone()), Some(&peer_key)).into_inner();
+
+        verify(&info, &header, &payload[..]).expect("A correctly signed message should be accepted");
+
+        // A payload that was changed after being signed
+        let mut tampered = payload.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+
+        assert!(verify(&info, &header, &tampered[..]).is_err());
+
+        // A message that was never signed
+        let (unsigned, _) = WireMessage::new(peer, me, payload.clone(), 2, Some(digest), None).into_inner();
+
+        assert!(verify(&info, &unsigned, &payload[..]).is_err());
+
+        // A correctly signed message from a node whose key we don't know can't be authenticated either
+        let unknown = Arc::new(SinglePeerInfo {
+            key_pair: info.key_pair.clone(),
+            peer: NodeId::from(2u32),
+            peer_key: None,
+        });
+
+        assert!(verify(&unknown, &header, &payload[..]).is_err());
+    }
+
+    #[test]
+    fn test_joining_node_messages_are_verified_with_the_presented_key() {
+        let (me, joining) = (NodeId::from(0u32), NodeId::from(1u32));
+
+        let joining_key = KeyPair::from_bytes(&[1; 32][..]).unwrap();
+        let presented: PublicKey = joining_key.public_key().into();
+
+        // We don't know the key of the joining node yet
+        let info = Arc::new(SinglePeerInfo {
+            key_pair: Arc::new(KeyPair::from_bytes(&[0; 32][..]).unwrap()),
+            peer: joining,
+            peer_key: None,
+        });
+
+        let message = NetworkMessageKind::<TestMessage, TestMessage>::from_reconfig(vec![1, 2, 3]);
+
+        let (payload, digest) = cpu_workers::serialize_digest_no_threadpool(&message).unwrap();
+
+        let (header, _) = WireMessage::new(joining, me, payload.clone(), 1, Some(digest.clone()), Some(&joining_key)).into_inner();
+
+        verify_presented(&info, Some(&presented), &header, &payload[..])
+            .expect("A join request signed with the key presented at the handshake should be accepted");
+
+        // Signed with a key other than the one the node authenticated with
+        let other_key = KeyPair::from_bytes(&[2; 32][..]).unwrap();
+
+        let (forged, _) = WireMessage::new(joining, me, payload.clone(), 2, Some(digest.clone()), Some(&other_key)).into_inner();
+
+        assert!(verify_presented(&info, Some(&presented), &forged, &payload[..]).is_err());
+
+        // Only reconfiguration messages are accepted from nodes that have yet to be vouched for
+        let system = NetworkMessageKind::<TestMessage, TestMessage>::from_system(vec![1, 2, 3]);
+
+        let (payload, digest) = cpu_workers::serialize_digest_no_threadpool(&system).unwrap();
+
+        let (header, _) = WireMessage::new(joining, me, payload.clone(), 3, Some(digest), Some(&joining_key)).into_inner();
+
+        assert!(verify_presented(&info, Some(&presented), &header, &payload[..]).is_err());
+    }
+
+    #[test]
+    fn test_captured_frames_are_not_delivered_twice() {
+        let (me, peer) = (NodeId::from(0u32), NodeId::from(1u32));
+
+        let peer_key = KeyPair::from_bytes(&[1; 32][..]).unwrap();
+
+        let info = Arc::new(SinglePeerInfo {
+            key_pair: Arc::new(KeyPair::from_bytes(&[0; 32][..]).unwrap()),
+            peer,
+            peer_key: Some(peer_key.public_key().into()),
+        });
+
+        let replay_window = ReplayWindow::new(&ReplayProtectionConfig { nonces_per_peer: 16, max_peers: 16 });
+
+        let message = NetworkMessageKind::<TestMessage, TestMessage>::from_reconfig(vec![1, 2, 3]);
+
+        let (payload, digest) = cpu_workers::serialize_digest_no_threadpool(&message).unwrap();
+
+        let (header, _) = WireMessage::new(peer, me, payload.clone(), 1, Some(digest), Some(&peer_key)).into_inner();
+
+        verify(&info, &header, &payload[..]).unwrap();
+        cpu_workers::check_replay(&replay_window, peer, &header).expect("The first copy should be delivered");
+
+        // The captured frame is still correctly signed, so only the replay protection can stop it
+        verify(&info, &header, &payload[..]).unwrap();
+        assert!(cpu_workers::check_replay(&replay_window, peer, &header).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! A phi-accrual failure detector (Hayashibara et al.), giving a graded level of suspicion for
+//! each peer instead of a plain connected/disconnected.
+//!
+//! We keep a window of the intervals between the moments we receive something from each peer
+//! (any message, including heartbeats) and estimate how likely it is that the peer is still alive
+//! given how long it has been silent. The suspicion level (phi) is `-log10` of that likelihood,
+//! so a phi of 1 means a 10% chance we are wrong to suspect the peer, 2 means 1%, and so on.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use log::info;
+
+use atlas_common::channel::ChannelSyncRx;
+use atlas_common::node_id::NodeId;
+
+use crate::config::FailureDetectorConfig;
+use crate::conn_utils::Subscribers;
+
+/// How often we check whether peers should become suspected (or trusted again)
+const EVALUATION_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A change in the suspicion of a peer
+#[derive(Clone, Debug, PartialEq)]
+pub enum SuspicionEvent {
+    /// The suspicion level of the peer went over the threshold
+    Suspected(NodeId, f64),
+    /// We have heard from a suspected peer again
+    Trusted(NodeId),
+}
+
+/// The intervals between the messages we received from a peer
+struct ArrivalWindow {
+    last_arrival: Option<Instant>,
+    // In milliseconds
+    intervals: VecDeque<f64>,
+    sum: f64,
+    squared_sum: f64,
+}
+
+/// The failure detection state of a single peer, fed by every connection to it
+pub(crate) struct PeerSuspicion {
+    node: NodeId,
+    window: Mutex<ArrivalWindow>,
+    suspected: AtomicBool,
+}
+
+/// The failure detector of all the peers we know of
+pub(crate) struct FailureDetector {
+    config: FailureDetectorConfig,
+    peers: DashMap<NodeId, Arc<PeerSuspicion>>,
+    subscribers: Subscribers<SuspicionEvent>,
+    // Has the detector been stopped (see [FailureDetector::stop])
+    stopped: AtomicBool,
+    // The thread that watches for suspicion changes
+    evaluation_thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ArrivalWindow {
+    fn new() -> Self {
+        Self {
+            last_arrival: None,
+            intervals: VecDeque::new(),
+            sum: 0.0,
+            squared_sum: 0.0,
+        }
+    }
+
+    fn push_interval(&mut self, interval: f64, max_sample_size: usize) {
+        if self.intervals.len() >= max_sample_size.max(1) {
+            if let Some(oldest) = self.intervals.pop_front() {
+                self.sum -= oldest;
+                self.squared_sum -= oldest * oldest;
+            }
+        }
+
+        self.intervals.push_back(interval);
+        self.sum += interval;
+        self.squared_sum += interval * interval;
+    }
+
+    fn arrival(&mut self, now: Instant, config: &FailureDetectorConfig) {
+        match self.last_arrival {
+            Some(last) => {
+                let interval = now.saturating_duration_since(last).as_secs_f64() * 1000.0;
+
+                self.push_interval(interval, config.max_sample_size);
+            }
+            None => {
+                // Until we have real samples, assume intervals around the estimate
+                // (with a standard deviation of a quarter of it)
+                let estimate = config.first_interval_estimate_millis as f64;
+
+                self.push_interval(estimate - estimate / 4.0, config.max_sample_size);
+                self.push_interval(estimate + estimate / 4.0, config.max_sample_size);
+            }
+        }
+
+        self.last_arrival = Some(match self.last_arrival {
+            Some(last) => last.max(now),
+            None => now,
+        });
+    }
+
+    fn phi(&self, now: Instant, config: &FailureDetectorConfig) -> f64 {
+        let last_arrival = match self.last_arrival {
+            Some(last) => last,
+            // We have never heard from this peer, so we have nothing to suspect
+            None => return 0.0,
+        };
+
+        let samples = self.intervals.len() as f64;
+
+        let mean = self.sum / samples;
+        let variance = (self.squared_sum / samples - mean * mean).max(0.0);
+
+        let std_deviation = variance.sqrt().max(config.min_std_deviation_millis as f64);
+
+        let elapsed = now.saturating_duration_since(last_arrival).as_secs_f64() * 1000.0;
+
+        phi(elapsed, mean + config.acceptable_pause_millis as f64, std_deviation)
+    }
+}
+
+/// The phi of having been silent for `elapsed` ms, when the intervals follow a normal distribution
+/// with the given mean and standard deviation (using the logistic approximation of its CDF)
+fn phi(elapsed: f64, mean: f64, std_deviation: f64) -> f64 {
+    let y = (elapsed - mean) / std_deviation;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+impl PeerSuspicion {
+    fn new(node: NodeId) -> Self {
+        Self {
+            node,
+            window: Mutex::new(ArrivalWindow::new()),
+            suspected: AtomicBool::new(false),
+        }
+    }
+
+    /// We have received something from this peer
+    pub fn heartbeat(&self, now: Instant, config: &FailureDetectorConfig) {
+        self.window.lock().unwrap().arrival(now, config);
+    }
+
+    pub fn phi(&self, now: Instant, config: &FailureDetectorConfig) -> f64 {
+        self.window.lock().unwrap().phi(now, config)
+    }
+}
+
+impl FailureDetector {
+    fn new(config: FailureDetectorConfig) -> Self {
+        Self {
+            config,
+            peers: Default::default(),
+            subscribers: Subscribers::new("Suspicion events"),
+            stopped: AtomicBool::new(false),
+            evaluation_thread: Mutex::new(None),
+        }
+    }
+
+    /// Create the failure detector, along with the thread that watches for suspicion changes
+    /// (which stops once the detector is stopped or dropped)
+    pub fn init(config: FailureDetectorConfig) -> Arc<Self> {
+        let detector = Arc::new(Self::new(config));
+
+        let weak = Arc::downgrade(&detector);
+
+        let thread = std::thread::Builder::new()
+            .name(String::from("Failure Detector"))
+            .spawn(move || Self::evaluation_loop(weak))
+            .expect("Failed to allocate failure detector thread");
+
+        *detector.evaluation_thread.lock().unwrap() = Some(thread);
+
+        detector
+    }
+
+    /// Stop watching for suspicion changes, waiting for the evaluation thread to finish
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+
+        let thread = self.evaluation_thread.lock().unwrap().take();
+
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+
+    pub fn config(&self) -> &FailureDetectorConfig {
+        &self.config
+    }
+
+    /// The failure detection state of the given peer, which the connections to it should feed
+    pub fn peer(&self, node: NodeId) -> Arc<PeerSuspicion> {
+        self.peers.entry(node)
+            .or_insert_with(|| Arc::new(PeerSuspicion::new(node)))
+            .value()
+            .clone()
+    }
+
+    pub fn suspicion_level(&self, node: &NodeId) -> f64 {
+        self.peers.get(node)
+            .map(|peer| peer.phi(Instant::now(), &self.config))
+            .unwrap_or(0.0)
+    }
+
+    pub fn subscribe(&self) -> ChannelSyncRx<SuspicionEvent> {
+        self.subscribers.subscribe()
+    }
+
+    fn evaluation_loop(detector: Weak<Self>) {
+        loop {
+            std::thread::sleep(EVALUATION_INTERVAL);
+
+            match detector.upgrade() {
+                Some(detector) if !detector.stopped.load(Ordering::Relaxed) => detector.evaluate(Instant::now()),
+                _ => break,
+            }
+        }
+    }
+
+    /// Check which peers crossed the suspicion threshold, in either direction, and notify the subscribers
+    fn evaluate(&self, now: Instant) {
+        for peer in self.peers.iter() {
+            let phi = peer.phi(now, &self.config);
+
+            if phi >= self.config.phi_threshold {
+                if !peer.suspected.swap(true, Ordering::AcqRel) {
+                    info!("Suspecting node {:?}, with phi {:.2}", peer.node, phi);
+
+                    self.subscribers.notify(SuspicionEvent::Suspected(peer.node, phi));
+                }
+            } else if peer.suspected.swap(false, Ordering::AcqRel) {
+                info!("Trusting node {:?} again, with phi {:.2}", peer.node, phi);
+
+                self.subscribers.notify(SuspicionEvent::Trusted(peer.node));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use atlas_common::node_id::NodeId;
+
+    use crate::config::FailureDetectorConfig;
+    use crate::failure_detector::{FailureDetector, SuspicionEvent};
+
+    fn config() -> FailureDetectorConfig {
+        FailureDetectorConfig {
+            phi_threshold: 8.0,
+            max_sample_size: 100,
+            min_std_deviation_millis: 10,
+            acceptable_pause_millis: 0,
+            first_interval_estimate_millis: 100,
+        }
+    }
+
+    #[test]
+    fn test_phi_grows_with_silence() {
+        let config = config();
+        let detector = FailureDetector::new(config.clone());
+
+        let node = NodeId::from(1u32);
+        let peer = detector.peer(node);
+
+        let start = Instant::now();
+
+        for i in 0..50 {
+            peer.heartbeat(start + Duration::from_millis(i * 100), &config);
+        }
+
+        let last = start + Duration::from_millis(49 * 100);
+
+        let on_time = peer.phi(last + Duration::from_millis(100), &config);
+        let late = peer.phi(last + Duration::from_millis(200), &config);
+        let very_late = peer.phi(last + Duration::from_millis(1000), &config);
+
+        assert!(on_time < 1.0);
+        assert!(late > on_time);
+        assert!(very_late > late);
+        assert!(very_late > config.phi_threshold);
+
+        // Nodes we have never heard from are not suspected
+        assert_eq!(detector.suspicion_level(&NodeId::from(2u32)), 0.0);
+    }
+
+    #[test]
+    fn test_suspicion_transitions_are_notified() {
+        let config = config();
+        // No evaluation thread, so we control when the peers are evaluated
+        let detector = FailureDetector::new(config.clone());
+
+        let events = detector.subscribe();
+
+        let node = NodeId::from(1u32);
+        let peer = detector.peer(node);
+
+        let start = Instant::now();
+
+        for i in 0..10 {
+            peer.heartbeat(start + Duration::from_millis(i * 100), &config);
+        }
+
+        let last = start + Duration::from_millis(9 * 100);
+
+        detector.evaluate(last + Duration::from_millis(50));
+        detector.evaluate(last + Duration::from_secs(5));
+
+        // Only the transition is notified, not every evaluation
+        detector.evaluate(last + Duration::from_secs(6));
+
+        peer.heartbeat(last + Duration::from_secs(6), &config);
+        detector.evaluate(last + Duration::from_secs(6) + Duration::from_millis(50));
+
+        let received = (0..2).map(|_| events.recv().unwrap()).collect::<Vec<_>>();
+
+        assert!(matches!(received[0], SuspicionEvent::Suspected(suspected, _) if suspected == node));
+        assert_eq!(received[1], SuspicionEvent::Trusted(node));
+        assert!(events.try_recv().is_err());
+    }
+}
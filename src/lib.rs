@@ -7,9 +7,12 @@ use atlas_common::error::*;
 #[cfg(feature = "serialize_serde")]
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use atlas_common::channel::OneShotRx;
+use atlas_common::channel::{ChannelSyncRx, OneShotRx};
 use atlas_common::crypto::signature::{KeyPair, PublicKey};
-use atlas_common::node_id::NodeId;
+use atlas_common::node_id::{NodeId, NodeType};
+use crate::config::{BandwidthLimit, PeerRateLimits};
+use crate::conn_utils::ConnectionEvent;
+use crate::failure_detector::SuspicionEvent;
 use crate::reconfiguration_node::{NetworkInformationProvider, ReconfigurationNode};
 use crate::protocol_node::ProtocolNetworkNode;
 
@@ -23,6 +26,9 @@ pub mod metric;
 pub mod reconfiguration_node;
 pub mod protocol_node;
 pub mod conn_utils;
+pub mod replay_protection;
+pub mod compression;
+pub mod failure_detector;
 
 /// Actual node implementations
 //pub mod tcpip;
@@ -49,8 +55,24 @@ pub trait NodeConnections {
     /// Returns a vec with the results of each of the attempted connections
     fn connect_to_node(self: &Arc<Self>, node: NodeId) -> Vec<OneShotRx<Result<()>>>;
 
-    /// Disconnect this node from another node
+    /// Disconnect this node from another node.
+    /// Peers that understand it are told we are leaving them on purpose, so they don't try to reconnect
     async fn disconnect_from_node(&self, node: &NodeId) -> Result<()>;
+
+    /// How strongly we suspect a given node has failed (see [failure_detector]).
+    /// 0 means no suspicion at all (which is also the case for nodes we have never heard from)
+    fn suspicion_level(&self, node: &NodeId) -> f64;
+
+    /// Subscribe to the nodes becoming suspected or trusted again.
+    /// A node becomes suspected when its suspicion level goes over the configured threshold
+    fn subscribe_suspicions(&self) -> ChannelSyncRx<SuspicionEvent>;
+
+    /// Change the bandwidth limits of the nodes of a given type (see [config::RateLimitConfig]),
+    /// including the ones we are already connected to
+    fn set_rate_limits(&self, node_type: NodeType, limits: PeerRateLimits);
+
+    /// Change the limit on the bandwidth we use writing to all nodes combined. `None` removes the limit
+    fn set_global_send_limit(&self, limit: Option<BandwidthLimit>);
 }
 
 pub trait NetworkNode {
@@ -66,6 +88,15 @@ pub trait NetworkNode {
     fn node_connections(&self) -> &Arc<Self::ConnectionManager>;
 
     fn network_info_provider(&self) -> &Arc<Self::NetworkInfoProvider>;
+
+    /// Subscribe to the connections to other nodes being established and lost,
+    /// so there is no need to poll [NodeConnections::connected_nodes]
+    fn subscribe_connection_events(&self) -> ChannelSyncRx<ConnectionEvent>;
+
+    /// Shut down this node: our peers are told we are leaving (once what was queued for them has
+    /// been written, up to a deadline), all connections are closed and the threads of the node are
+    /// stopped. Shutting down a node that was already shut down does nothing
+    fn shutdown(&self);
 }
 
 /// A full network node implementation
@@ -86,5 +117,11 @@ pub trait FullNetworkNode<NI, RM, PM>: ProtocolNetworkNode<PM> + Reconfiguration
 #[derive(Error, Debug)]
 pub enum NetworkSendError {
     #[error("Peer not found {0:?}")]
-    PeerNotFound(NodeId)
+    PeerNotFound(NodeId),
+    #[error("The send queue of {0:?} is full")]
+    SendQueueFull(NodeId),
+    #[error("The send queue of {0:?} is full, so the message was dropped")]
+    MessageDropped(NodeId),
+    #[error("The message to {0:?} was not delivered, as it was dropped or the connection broke before it was written")]
+    NotDelivered(NodeId),
 }
\ No newline at end of file
@@ -21,6 +21,7 @@ use atlas_common::Err;
 use atlas_common::node_id::NodeId;
 use atlas_common::ordering::{Orderable, SeqNo};
 
+use crate::message_signing::SessionKey;
 use crate::serialize::{Buf, Serializable};
 
 // convenience type
@@ -209,6 +210,15 @@ impl<RM, PM> NetworkMessageKind<RM, PM> where RM: Serializable, PM: Serializable
             }
         }
     }
+
+    /// The class of this message, to be announced in the header flags
+    pub fn class(&self) -> MessageClass {
+        match self {
+            NetworkMessageKind::ReconfigurationMessage(_) => MessageClass::Reconfiguration,
+            NetworkMessageKind::Ping(_) => MessageClass::Ping,
+            NetworkMessageKind::System(_) => MessageClass::Protocol,
+        }
+    }
 }
 
 impl<RM, PM> From<System<PM::Message>> for NetworkMessageKind<RM, PM> where RM: Serializable, PM: Serializable {
@@ -296,8 +306,9 @@ impl<M: Clone> From<M> for Reconfig<M> {
 #[derive(Copy, Clone, Eq, PartialEq)]
 #[repr(C, packed)]
 pub struct Header {
-    // manually align memory for cross platform compat
-    pub(crate) _align: u32,
+    // flags describing the message. Only sent from `WireMessage::FLAGS_VERSION` onwards,
+    // older versions used this word to manually align memory for cross platform compat
+    pub(crate) flags: u32,
     // the protocol version
     pub(crate) version: u32,
     // origin of the message
@@ -341,6 +352,87 @@ impl<'de> serde::Deserialize<'de> for Header {
     }
 }
 
+/// How the header of a message is authenticated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthScheme {
+    /// The signature slot carries a signature by the sender's `KeyPair`
+    /// (or is empty, for unsigned messages)
+    Signature,
+    /// The signature slot carries a MAC with the session key of the connection
+    /// the message is sent over
+    SessionMac,
+}
+
+/// The class of message carried in the payload of a message (which [NetworkMessageKind] it is),
+/// so receivers can route it before deserializing it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageClass {
+    Reconfiguration,
+    Protocol,
+    Ping,
+}
+
+/// The priority class of a message, from most to least urgent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessagePriority {
+    /// Messages that keep the network itself running (reconfiguration, pings)
+    Control,
+    /// Regular protocol messages
+    #[default]
+    Normal,
+    /// Large transfers that should not delay anything else (e.g. state transfer)
+    Bulk,
+}
+
+impl MessageClass {
+    const RECONFIGURATION: u32 = 1;
+    const PROTOCOL: u32 = 2;
+    const PING: u32 = 3;
+
+    fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            Self::RECONFIGURATION => Some(MessageClass::Reconfiguration),
+            Self::PROTOCOL => Some(MessageClass::Protocol),
+            Self::PING => Some(MessageClass::Ping),
+            _ => None
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self {
+            MessageClass::Reconfiguration => Self::RECONFIGURATION,
+            MessageClass::Protocol => Self::PROTOCOL,
+            MessageClass::Ping => Self::PING,
+        }
+    }
+
+    /// The priority messages of this class are sent with, unless otherwise specified
+    pub fn default_priority(&self) -> MessagePriority {
+        match self {
+            MessageClass::Reconfiguration | MessageClass::Ping => MessagePriority::Control,
+            MessageClass::Protocol => MessagePriority::Normal,
+        }
+    }
+}
+
+impl MessagePriority {
+    fn from_bits(bits: u32) -> Self {
+        match bits {
+            1 => MessagePriority::Control,
+            2 => MessagePriority::Bulk,
+            _ => MessagePriority::Normal,
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self {
+            MessagePriority::Normal => 0,
+            MessagePriority::Control => 1,
+            MessagePriority::Bulk => 2,
+        }
+    }
+}
+
 /// A message to be sent over the wire. The payload should be a serialized
 /// `SystemMessage`, for correctness.
 #[derive(Debug)]
@@ -390,9 +482,22 @@ impl Header {
     /// The size of the memory representation of the `Header` in bytes.
     pub const LENGTH: usize = std::mem::size_of::<Self>();
 
+    // The layout of the flags word
+    const COMPRESSED_FLAG: u32 = 1;
+    const CLASS_SHIFT: u32 = 1;
+    const CLASS_MASK: u32 = 0b11;
+    const PRIORITY_SHIFT: u32 = 3;
+    const PRIORITY_MASK: u32 = 0b11;
+    const FRAGMENT_FLAG: u32 = 1 << 5;
+    const GOODBYE_FLAG: u32 = 1 << 6;
+    const SEQUENCED_FLAG: u32 = 1 << 7;
+    const ACK_FLAG: u32 = 1 << 8;
+    const BUSY_FLAG: u32 = 1 << 9;
+
     unsafe fn serialize_into_unchecked(self, buf: &mut [u8]) {
         #[cfg(target_endian = "big")]
         {
+            self.flags = self.flags.to_le();
             self.version = self.version.to_le();
             self.nonce = self.nonce.to_le();
             self.from = self.from.to_le();
@@ -419,6 +524,7 @@ impl Header {
         (&mut hdr[..]).copy_from_slice(&buf[..Self::LENGTH]);
         #[cfg(target_endian = "big")]
         {
+            hdr.flags = hdr.flags.to_be();
             hdr.version = hdr.version.to_be();
             hdr.nonce = hdr.nonce.to_be();
             hdr.from = hdr.from.to_be();
@@ -436,12 +542,160 @@ impl Header {
         Ok(unsafe { Self::deserialize_from_unchecked(buf) })
     }
 
-    /// Reports the current version of the wire protocol,
-    /// i.e. `WireMessage::CURRENT_VERSION`.
+    /// Serialize a `Header` with the layout of the given wire protocol version,
+    /// which should be the version negotiated with the peer we are sending it to.
+    /// Fields that don't exist in that version are not sent.
+    pub fn serialize_versioned(mut self, version: u32, buf: &mut [u8]) -> Result<()> {
+        self.version = version | (self.version & WireMessage::SESSION_MAC_FLAG);
+
+        if version < WireMessage::FLAGS_VERSION {
+            self.flags = 0;
+        }
+
+        self.serialize_into(buf)
+    }
+
+    /// Deserialize a `Header` according to the layout of the wire protocol version it carries.
+    /// Headers with versions newer than ours are decoded with the newest layout we know,
+    /// it is up to the receiver to reject them (see [WireMessage::is_supported_version]).
+    pub fn deserialize_versioned(buf: &[u8]) -> Result<Self> {
+        let mut header = Self::deserialize_from(buf)?;
+
+        if header.protocol_version() < WireMessage::FLAGS_VERSION {
+            // This word was only used for alignment, so we can't trust its contents
+            header.flags = 0;
+        }
+
+        Ok(header)
+    }
+
+    /// Reports the version of the wire protocol this header was encoded with.
+    /// This includes the bit marking the authentication scheme in use, see [Header::protocol_version]
     pub fn version(&self) -> u32 {
         self.version
     }
 
+    /// The version of the wire protocol, without the authentication scheme marker
+    pub fn protocol_version(&self) -> u32 {
+        self.version & !WireMessage::SESSION_MAC_FLAG
+    }
+
+    /// How this header (and associated payload) is authenticated
+    pub fn auth_scheme(&self) -> AuthScheme {
+        if self.version & WireMessage::SESSION_MAC_FLAG != 0 {
+            AuthScheme::SessionMac
+        } else {
+            AuthScheme::Signature
+        }
+    }
+
+    /// The flags of this header (always 0 for versions prior to `WireMessage::FLAGS_VERSION`)
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Is the payload of this message compressed
+    pub fn is_compressed(&self) -> bool {
+        self.flags & Self::COMPRESSED_FLAG != 0
+    }
+
+    /// Is this frame a fragment of a larger message (see [crate::mio_tcp::connections::fragmentation])
+    pub fn is_fragment(&self) -> bool {
+        self.flags & Self::FRAGMENT_FLAG != 0
+    }
+
+    /// Is this frame a goodbye, with which the peer announces it is closing the connection
+    /// (see [crate::mio_tcp::connections::goodbye])
+    pub fn is_goodbye(&self) -> bool {
+        self.flags & Self::GOODBYE_FLAG != 0
+    }
+
+    /// Does the payload of this message carry a sequence number for its reliable delivery
+    /// (see [crate::mio_tcp::connections::reliable])
+    pub fn is_sequenced(&self) -> bool {
+        self.flags & Self::SEQUENCED_FLAG != 0
+    }
+
+    /// Is this frame an acknowledgement of the sequenced messages we have sent
+    /// (see [crate::mio_tcp::connections::reliable])
+    pub fn is_ack(&self) -> bool {
+        self.flags & Self::ACK_FLAG != 0
+    }
+
+    /// Is this frame telling us the peer was too busy to take one of our requests
+    /// (see [crate::mio_tcp::connections::busy])
+    pub fn is_busy(&self) -> bool {
+        self.flags & Self::BUSY_FLAG != 0
+    }
+
+    /// The class of the message in the payload, if the sender announced it.
+    /// This is only a hint for routing, the deserialized message is what counts
+    /// (see [crate::cpu_workers::deserialize_message_no_threadpool])
+    pub fn message_class(&self) -> Option<MessageClass> {
+        MessageClass::from_bits((self.flags >> Self::CLASS_SHIFT) & Self::CLASS_MASK)
+    }
+
+    /// The priority class of this message
+    pub fn priority(&self) -> MessagePriority {
+        MessagePriority::from_bits((self.flags >> Self::PRIORITY_SHIFT) & Self::PRIORITY_MASK)
+    }
+
+    pub(crate) fn set_compressed(&mut self, compressed: bool) {
+        if compressed {
+            self.flags |= Self::COMPRESSED_FLAG;
+        } else {
+            self.flags &= !Self::COMPRESSED_FLAG;
+        }
+    }
+
+    pub(crate) fn set_fragment(&mut self, fragment: bool) {
+        if fragment {
+            self.flags |= Self::FRAGMENT_FLAG;
+        } else {
+            self.flags &= !Self::FRAGMENT_FLAG;
+        }
+    }
+
+    pub(crate) fn set_goodbye(&mut self, goodbye: bool) {
+        if goodbye {
+            self.flags |= Self::GOODBYE_FLAG;
+        } else {
+            self.flags &= !Self::GOODBYE_FLAG;
+        }
+    }
+
+    pub(crate) fn set_sequenced(&mut self, sequenced: bool) {
+        if sequenced {
+            self.flags |= Self::SEQUENCED_FLAG;
+        } else {
+            self.flags &= !Self::SEQUENCED_FLAG;
+        }
+    }
+
+    pub(crate) fn set_ack(&mut self, ack: bool) {
+        if ack {
+            self.flags |= Self::ACK_FLAG;
+        } else {
+            self.flags &= !Self::ACK_FLAG;
+        }
+    }
+
+    pub(crate) fn set_busy(&mut self, busy: bool) {
+        if busy {
+            self.flags |= Self::BUSY_FLAG;
+        } else {
+            self.flags &= !Self::BUSY_FLAG;
+        }
+    }
+
+    pub(crate) fn set_message_class(&mut self, class: MessageClass) {
+        self.flags = (self.flags & !(Self::CLASS_MASK << Self::CLASS_SHIFT)) | (class.bits() << Self::CLASS_SHIFT);
+    }
+
+    pub(crate) fn set_priority(&mut self, priority: MessagePriority) {
+        self.flags = (self.flags & !(Self::PRIORITY_MASK << Self::PRIORITY_SHIFT)) | (priority.bits() << Self::PRIORITY_SHIFT);
+    }
+
     /// The originating `NodeId`.
     pub fn from(&self) -> NodeId {
         self.from.into()
@@ -523,12 +777,37 @@ impl<T: AsRef<[u8]>> OwnedWireMessage<T> {
 
 impl WireMessage {
     /// The current version of the wire protocol.
-    pub const CURRENT_VERSION: u32 = 0;
+    pub const CURRENT_VERSION: u32 = 6;
+
+    /// The version every node supports. The connection handshake is always performed with it,
+    /// and signatures are always computed with it, so they remain valid across versions
+    pub const BASE_VERSION: u32 = 0;
+
+    /// The version that introduced the header flags
+    pub const FLAGS_VERSION: u32 = 1;
+
+    /// The version that introduced the fragmentation of large messages
+    pub const FRAGMENTATION_VERSION: u32 = 2;
+
+    /// The version from which nodes answer the heartbeat pings sent over their connections
+    pub const HEARTBEAT_VERSION: u32 = 3;
+
+    /// The version from which nodes say goodbye before closing their connections
+    pub const GOODBYE_VERSION: u32 = 4;
+
+    /// The version from which nodes acknowledge the sequenced messages they receive, for their reliable delivery
+    pub const RELIABLE_VERSION: u32 = 5;
+
+    /// The version from which nodes tell their clients when they are too busy to take their requests
+    pub const BUSY_VERSION: u32 = 6;
+
+    /// The bit of the header version that marks messages authenticated with a session MAC
+    pub const SESSION_MAC_FLAG: u32 = 1 << 31;
 
     /// Wraps a `Header` and a byte array payload into a `WireMessage`.
     pub fn from_parts(header: Header, payload: Buf) -> Result<Self> {
         let wm = Self { header, payload };
-        if !wm.is_valid(None, true) {
+        if !wm.is_valid(None, None, true) {
             return Err!(MessageErrors::InvalidWireMessage);
         }
         Ok(wm)
@@ -536,7 +815,7 @@ impl WireMessage {
 
     pub fn from_header(header: Header) -> Result<Self> {
         let wm = Self { header, payload: Buf::new() };
-        if !wm.is_valid(None, false) {
+        if !wm.is_valid(None, None, false) {
             return Err!(MessageErrors::InvalidWireMessage);
         }
         Ok(wm)
@@ -574,7 +853,7 @@ impl WireMessage {
         let (from, to) = (from.into(), to.into());
 
         let header = Header {
-            _align: 0,
+            flags: 0,
             version: Self::CURRENT_VERSION,
             length: payload.len() as u64,
             signature,
@@ -587,6 +866,65 @@ impl WireMessage {
         Self { header, payload }
     }
 
+    /// Constructs a new message that is to be authenticated with the session key
+    /// of the connection it is sent over.
+    /// The MAC is only filled in when the connection picks up the message, see [WireMessage::authenticate_with_session]
+    pub(crate) fn new_session_authenticated(
+        from: NodeId,
+        to: NodeId,
+        payload: Buf,
+        nonce: u64,
+        digest: Digest,
+    ) -> Self {
+        let mut message = Self::new(from, to, payload, nonce, Some(digest), None);
+
+        message.header.version |= Self::SESSION_MAC_FLAG;
+
+        message
+    }
+
+    /// Fill in the MAC of a message created with [WireMessage::new_session_authenticated]
+    pub(crate) fn authenticate_with_session(&mut self, key: &SessionKey) {
+        let header = &mut self.header;
+
+        header.signature = crate::message_signing::mac_parts(
+            key,
+            header.from,
+            header.to,
+            header.nonce,
+            &header.digest[..],
+        );
+    }
+
+    /// Can we understand messages of the given wire protocol version
+    pub fn is_supported_version(version: u32) -> bool {
+        version <= Self::CURRENT_VERSION
+    }
+
+    /// Announce the class of the message carried by this `WireMessage` in its header,
+    /// along with the default priority of that class.
+    /// The flags are not covered by the digest, so this does not invalidate the signature
+    pub fn with_class(mut self, class: MessageClass) -> Self {
+        self.header.set_message_class(class);
+        self.header.set_priority(class.default_priority());
+
+        self
+    }
+
+    /// Send this `WireMessage` with the given priority, instead of the default priority of its class
+    pub fn with_priority(mut self, priority: MessagePriority) -> Self {
+        self.header.set_priority(priority);
+
+        self
+    }
+
+    /// Mark whether the payload of this `WireMessage` is compressed (see [crate::compression])
+    pub(crate) fn with_compressed_payload(mut self, compressed: bool) -> Self {
+        self.header.set_compressed(compressed);
+
+        self
+    }
+
     /// Retrieve the inner `Header` and payload byte buffer stored
     /// inside the `WireMessage`.
     pub fn into_inner(self) -> (Header, Buf) {
@@ -604,16 +942,33 @@ impl WireMessage {
     }
 
     /// Checks for the correctness of the `WireMessage`. This implies
-    /// checking its signature, if a `PublicKey` is provided.
-    pub fn is_valid(&self, public_key: Option<&PublicKey>, check_payload_len: bool) -> bool {
+    /// checking its signature, if a `PublicKey` is provided, or its MAC,
+    /// if a `SessionKey` is provided, depending on the scheme the message uses.
+    pub fn is_valid(&self, public_key: Option<&PublicKey>, session_key: Option<&SessionKey>, check_payload_len: bool) -> bool {
         let preliminary_check_failed =
-            self.header.version != WireMessage::CURRENT_VERSION
+            !WireMessage::is_supported_version(self.header.protocol_version())
                 || (check_payload_len && self.header.length != self.payload.len() as u64);
 
         if preliminary_check_failed {
             return false;
         }
 
+        if let AuthScheme::SessionMac = self.header.auth_scheme() {
+            // A MAC can't be verified with a public key, so we can't accept it in its place
+            return public_key.is_none() && session_key
+                .map(|key| {
+                    crate::message_signing::verify_mac_parts(
+                        key,
+                        &self.header.signature,
+                        self.header.from,
+                        self.header.to,
+                        self.header.nonce,
+                        &self.header.digest[..],
+                    ).is_ok()
+                })
+                .unwrap_or(true);
+        }
+
         public_key
             .map(|pk| {
                 crate::message_signing::verify_parts(
@@ -684,6 +1039,7 @@ impl WireMessage {
 impl Debug for Header {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let version = self.version;
+        let flags = self.flags;
         let length = self.length;
         let signature = self.signature.chunks(4).next().unwrap();
         let digest = self.digest.chunks(4).next().unwrap();
@@ -691,8 +1047,8 @@ impl Debug for Header {
         let from = self.from;
         let to = self.to;
 
-        write!(f, "Header {{ version: {}, length: {}, signature: {:x?}, digest: {:x?}, nonce: {}, from: {}, to: {} }}",
-               version, length, signature, digest, nonce, from, to
+        write!(f, "Header {{ version: {}, flags: {:#x}, length: {}, signature: {:x?}, digest: {:x?}, nonce: {}, from: {}, to: {} }}",
+               version, flags, length, signature, digest, nonce, from, to
         )
     }
 }
@@ -709,14 +1065,17 @@ pub enum MessageErrors {
 
 #[cfg(test)]
 mod tests {
-    use atlas_common::crypto::hash::Digest;
-    use atlas_common::crypto::signature::Signature;
-    use crate::message::{WireMessage, Header};
+    use bytes::Bytes;
+    use atlas_common::crypto::hash::{Context, Digest};
+    use atlas_common::crypto::signature::{KeyPair, PublicKey, Signature};
+    use atlas_common::node_id::NodeId;
+    use crate::message::{AuthScheme, WireMessage, Header, MessageClass, MessagePriority};
+    use crate::message_signing::SessionKey;
 
     #[test]
     fn test_header_serialize() {
         let old_header = Header {
-            _align: 0,
+            flags: 0,
             version: WireMessage::CURRENT_VERSION,
             signature: [0; Signature::LENGTH],
             digest: [0; Digest::LENGTH],
@@ -732,4 +1091,102 @@ mod tests {
             .expect("Deserialize failed");
         assert_eq!(old_header, new_header);
     }
+
+    #[test]
+    fn test_header_versioned_codec() {
+        let (mut header, _) = WireMessage::new(NodeId::from(0u32), NodeId::from(3u32),
+                                               Bytes::new(), 0, None, None).into_inner();
+
+        header.flags = 0b101;
+
+        let mut buf = [0; Header::LENGTH];
+
+        // Peers that only speak the base version don't know about flags
+        header.serialize_versioned(WireMessage::BASE_VERSION, &mut buf[..])
+            .expect("Serialize failed");
+
+        let old_header = Header::deserialize_versioned(&buf[..]).expect("Deserialize failed");
+
+        assert_eq!(old_header.protocol_version(), WireMessage::BASE_VERSION);
+        assert_eq!(old_header.flags(), 0);
+
+        header.serialize_versioned(WireMessage::FLAGS_VERSION, &mut buf[..])
+            .expect("Serialize failed");
+
+        let new_header = Header::deserialize_versioned(&buf[..]).expect("Deserialize failed");
+
+        assert_eq!(new_header.protocol_version(), WireMessage::FLAGS_VERSION);
+        assert_eq!(new_header.flags(), 0b101);
+
+        // Headers of older versions may carry garbage in the alignment word
+        let mut legacy = header;
+        legacy.version = WireMessage::BASE_VERSION;
+        legacy.serialize_into(&mut buf[..]).expect("Serialize failed");
+
+        assert_eq!(Header::deserialize_versioned(&buf[..]).unwrap().flags(), 0);
+    }
+
+    #[test]
+    fn test_header_flags() {
+        let key_pair = KeyPair::from_bytes(&[0; 32][..]).unwrap();
+        let public_key: PublicKey = key_pair.public_key().into();
+
+        let payload = Bytes::from_static(b"payload");
+        let digest = Context::new().finish();
+
+        let message = WireMessage::new(NodeId::from(0u32), NodeId::from(1u32),
+                                       payload.clone(), 10, Some(digest), Some(&key_pair));
+
+        // Peers that don't announce anything
+        assert_eq!(message.header().message_class(), None);
+        assert_eq!(message.header().priority(), MessagePriority::Normal);
+        assert!(!message.header().is_compressed());
+
+        let message = message.with_class(MessageClass::Reconfiguration);
+
+        assert_eq!(message.header().message_class(), Some(MessageClass::Reconfiguration));
+        assert_eq!(message.header().priority(), MessagePriority::Control);
+        assert!(!message.header().is_compressed());
+
+        // The flags are not part of the signed content
+        assert!(message.is_valid(Some(&public_key), None, true));
+
+        let mut header = *message.header();
+
+        header.set_message_class(MessageClass::Protocol);
+        header.set_priority(MessagePriority::Bulk);
+
+        assert_eq!(header.message_class(), Some(MessageClass::Protocol));
+        assert_eq!(header.priority(), MessagePriority::Bulk);
+    }
+
+    #[test]
+    fn test_session_mac_scheme() {
+        let key_pair = KeyPair::from_bytes(&[0; 32][..]).unwrap();
+        let public_key: PublicKey = key_pair.public_key().into();
+
+        let session_key = SessionKey::from_bytes([1; SessionKey::LENGTH]);
+        let other_session_key = SessionKey::from_bytes([2; SessionKey::LENGTH]);
+
+        let payload = Bytes::from_static(b"payload");
+        let digest = Context::new().finish();
+
+        let mut message = WireMessage::new_session_authenticated(NodeId::from(0u32), NodeId::from(1u32),
+                                                                 payload.clone(), 10, digest);
+
+        assert_eq!(message.header().auth_scheme(), AuthScheme::SessionMac);
+        assert_eq!(message.header().protocol_version(), WireMessage::CURRENT_VERSION);
+
+        message.authenticate_with_session(&session_key);
+
+        assert!(message.is_valid(None, Some(&session_key), true));
+        assert!(!message.is_valid(None, Some(&other_session_key), true));
+        assert!(!message.is_valid(Some(&public_key), None, true));
+
+        let signed = WireMessage::new(NodeId::from(0u32), NodeId::from(1u32),
+                                      payload, 10, Some(digest), Some(&key_pair));
+
+        assert_eq!(signed.header().auth_scheme(), AuthScheme::Signature);
+        assert!(signed.is_valid(Some(&public_key), None, true));
+    }
 }
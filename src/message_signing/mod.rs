@@ -1,13 +1,18 @@
 use std::marker::PhantomData;
 use std::sync::Arc;
 use anyhow::anyhow;
+use hmac::{Hmac, Mac};
 use intmap::IntMap;
+use sha2::Sha512;
+use thiserror::Error;
 use atlas_common::crypto::hash::{Context, Digest};
 use atlas_common::crypto::signature::{KeyPair, PublicKey, Signature};
 use atlas_common::error::*;
-use crate::config::PKConfig;
+use atlas_common::Err;
+use atlas_common::node_id::NodeId;
+use crate::config::{MessageVerificationConfig, PKConfig};
 use crate::cpu_workers;
-use crate::message::{Header, NetworkMessageKind, WireMessage};
+use crate::message::{AuthScheme, Header, NetworkMessageKind, WireMessage};
 use crate::reconfiguration_node::NetworkInformationProvider;
 use crate::serialize::{Buf, digest_message, Serializable};
 
@@ -19,13 +24,54 @@ pub trait NetworkMessageSignatureVerifier<M, NI>
     /// Verify the signature of the internal message structure
     /// Returns Result<bool> where true means the signature is valid, false means it is not
     fn verify_signature_with_buf(info_provider: &Arc<NI>, header: &Header, msg: &M::Message, buf: &Buf) -> Result<()>;
+
+    /// Verify a message that was authenticated with the session key of the connection it was
+    /// received on (see [SessionKey]), instead of being signed
+    fn verify_mac_with_buf(info_provider: &Arc<NI>, session_key: &SessionKey, header: &Header, msg: &M::Message, buf: &Buf) -> Result<()> {
+        let digest = digest_message(buf.clone())?;
+
+        verify_mac_parts(session_key, &header.signature, header.from().0 as u32, header.to().0 as u32, header.nonce(), digest.as_ref())?;
+
+        M::verify_message_internal::<NI, Self>(info_provider, header, msg)
+    }
+
+    /// Verify a message signed by a node whose key we don't know yet, with the key it
+    /// authenticated with when connecting to us (e.g. a node joining through reconfiguration)
+    fn verify_presented_key_with_buf(info_provider: &Arc<NI>, public_key: &PublicKey, header: &Header, msg: &M::Message, buf: &Buf) -> Result<()> {
+        ensure_signed(header)?;
+
+        let digest = digest_message(buf.clone())?;
+
+        verify_parts(public_key, header.signature(), header.from().0 as u32, header.to().0 as u32, header.nonce(), digest.as_ref())?;
+
+        M::verify_message_internal::<NI, Self>(info_provider, header, msg)
+    }
 }
 
+/// Messages authenticated with a session key can't be verified with the public key of the sender
+fn ensure_signed(header: &Header) -> Result<()> {
+    match header.auth_scheme() {
+        AuthScheme::Signature => Ok(()),
+        AuthScheme::SessionMac => Err!(SessionMacError::MissingSessionKey(header.from())),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MessageKind {
     Reconfig,
     Protocol,
 }
 
+impl MessageKind {
+    /// Should messages of this kind be verified, according to the given configuration
+    pub fn should_verify(&self, config: &MessageVerificationConfig) -> bool {
+        match self {
+            MessageKind::Reconfig => config.verify_reconfiguration_messages,
+            MessageKind::Protocol => config.verify_protocol_messages,
+        }
+    }
+}
+
 pub struct DefaultReconfigSignatureVerifier<RM: Serializable, PM: Serializable, NI: NetworkInformationProvider>(Arc<NI>, PhantomData<(RM, PM)>);
 
 impl<PM, RM, NI> NetworkMessageSignatureVerifier<RM, NI> for DefaultReconfigSignatureVerifier<RM, PM, NI>
@@ -33,6 +79,8 @@ impl<PM, RM, NI> NetworkMessageSignatureVerifier<RM, NI> for DefaultReconfigSign
     fn verify_signature(info_provider: &Arc<NI>, header: &Header, msg: RM::Message) -> Result<RM::Message> {
         let key = info_provider.get_public_key(&header.from()).ok_or(anyhow!( "Could not find public key for peer"))?;
 
+        ensure_signed(header)?;
+
         let sig = header.signature();
 
         let network = NetworkMessageKind::<RM, PM>::from_reconfig(msg);
@@ -49,6 +97,8 @@ impl<PM, RM, NI> NetworkMessageSignatureVerifier<RM, NI> for DefaultReconfigSign
     fn verify_signature_with_buf(info_provider: &Arc<NI>, header: &Header, msg: &RM::Message, buf: &Buf) -> Result<()> {
         let key = info_provider.get_public_key(&header.from()).ok_or(anyhow!( "Could not find public key for peer"))?;
 
+        ensure_signed(header)?;
+
         let sig = header.signature();
 
         let digest = digest_message(buf.clone())?;
@@ -66,6 +116,8 @@ impl<RM, PM, NI> NetworkMessageSignatureVerifier<PM, NI> for DefaultProtocolSign
     fn verify_signature(info_provider: &Arc<NI>, header: &Header, msg: PM::Message) -> Result<PM::Message> {
         let key = info_provider.get_public_key(&header.from()).ok_or(anyhow!("Could not find public key for peer"))?;
 
+        ensure_signed(header)?;
+
         let sig = header.signature();
 
         let network = NetworkMessageKind::<RM, PM>::from_system(msg);
@@ -82,6 +134,8 @@ impl<RM, PM, NI> NetworkMessageSignatureVerifier<PM, NI> for DefaultProtocolSign
     fn verify_signature_with_buf(info_provider: &Arc<NI>, header: &Header, msg: &PM::Message, buf: &Buf) -> Result<()> {
         let key = info_provider.get_public_key(&header.from()).ok_or(anyhow!("Could not find public key for peer"))?;
 
+        ensure_signed(header)?;
+
         let sig = header.signature();
 
         let digest = digest_message(buf.clone())?;
@@ -132,7 +186,7 @@ impl NodePKCrypto {
 fn digest_parts(from: u32, to: u32, nonce: u64, payload: &[u8]) -> Digest {
     let mut ctx = Context::new();
 
-    let buf = WireMessage::CURRENT_VERSION.to_le_bytes();
+    let buf = WireMessage::BASE_VERSION.to_le_bytes();
     ctx.update(&buf[..]);
 
     let buf = from.to_le_bytes();
@@ -151,6 +205,88 @@ fn digest_parts(from: u32, to: u32, nonce: u64, payload: &[u8]) -> Digest {
     ctx.finish()
 }
 
+type SessionMac = Hmac<Sha512>;
+
+// The length of the tags produced by [SessionMac]
+const SESSION_MAC_LENGTH: usize = 64;
+
+/// A symmetric key shared by the two ends of a single connection.
+///
+/// It is exported from the TLS session of the connection, which both peers have bound
+/// to their identities by signing the authentication handshake with their `KeyPair`.
+/// Messages sent over that connection can then be authenticated with a MAC
+/// (much cheaper than a signature), which is placed in the signature slot of the header.
+#[derive(Clone)]
+pub struct SessionKey([u8; SessionKey::LENGTH]);
+
+impl SessionKey {
+    pub const LENGTH: usize = 32;
+
+    pub(crate) fn from_bytes(bytes: [u8; SessionKey::LENGTH]) -> Self {
+        Self(bytes)
+    }
+
+    fn mac(&self) -> SessionMac {
+        // HMAC accepts keys of any length
+        SessionMac::new_from_slice(&self.0[..]).unwrap()
+    }
+}
+
+fn mac_parts_into(mac: &mut SessionMac, from: u32, to: u32, nonce: u64, payload: &[u8]) {
+    // The same inputs as [digest_parts]
+    mac.update(&WireMessage::BASE_VERSION.to_le_bytes()[..]);
+    mac.update(&from.to_le_bytes()[..]);
+    mac.update(&to.to_le_bytes()[..]);
+    mac.update(&nonce.to_le_bytes()[..]);
+    mac.update(&(payload.len() as u64).to_le_bytes()[..]);
+    mac.update(payload);
+}
+
+/// Authenticate a given message with a session key, with the same parameters as [sign_parts].
+/// The MAC fills (as much as it can of) a signature slot.
+pub(crate) fn mac_parts(
+    key: &SessionKey,
+    from: u32,
+    to: u32,
+    nonce: u64,
+    payload: &[u8],
+) -> [u8; Signature::LENGTH] {
+    let mut mac = key.mac();
+
+    mac_parts_into(&mut mac, from, to, nonce, payload);
+
+    let tag = mac.finalize().into_bytes();
+
+    let mut slot = [0; Signature::LENGTH];
+    let len = slot.len().min(SESSION_MAC_LENGTH);
+
+    slot[..len].copy_from_slice(&tag[..len]);
+
+    slot
+}
+
+/// Verify the MAC of a given message, with the same parameters as [verify_parts]
+pub(crate) fn verify_mac_parts(
+    key: &SessionKey,
+    slot: &[u8; Signature::LENGTH],
+    from: u32,
+    to: u32,
+    nonce: u64,
+    payload_digest: &[u8],
+) -> Result<()> {
+    let mut mac = key.mac();
+
+    mac_parts_into(&mut mac, from, to, nonce, payload_digest);
+
+    let len = slot.len().min(SESSION_MAC_LENGTH);
+
+    if mac.verify_truncated_left(&slot[..len]).is_err() {
+        return Err!(SessionMacError::InvalidMac(NodeId::from(from)));
+    }
+
+    Ok(())
+}
+
 ///Sign a given message, with the following passed parameters
 /// From is the node that sent the message
 /// to is the destination node
@@ -189,4 +325,14 @@ pub(crate) fn verify_parts(
 ) -> Result<()> {
     let digest = digest_parts(from, to, nonce, payload_digest);
     pk.verify(digest.as_ref(), sig)
-}
\ No newline at end of file
+}
+
+#[derive(Error, Debug)]
+pub enum SessionMacError {
+    #[error("Message from {0:?} is authenticated with a session key, but we have no session key to verify it")]
+    MissingSessionKey(NodeId),
+    #[error("Invalid session MAC in message from {0:?}")]
+    InvalidMac(NodeId),
+    #[error("Message to {0:?} should be authenticated with a session key, but the connection has none")]
+    NoSessionKey(NodeId),
+}
@@ -27,6 +27,42 @@ pub const COMM_RQ_TIME_SPENT_IN_MOD_ID: usize = 410;
 pub const COMM_RQ_SEND_CLI_PASSING_TIME: &str = "COMM_RQ_SEND_CLI_PASSING_TIME";
 pub const COMM_RQ_SEND_CLI_PASSING_TIME_ID: usize = 411;
 
+pub const COMM_MSGS_FAILED_VERIFICATION: &str = "COMM_MSGS_FAILED_VERIFICATION";
+pub const COMM_MSGS_FAILED_VERIFICATION_ID: usize = 412;
+
+pub const COMM_MSGS_REPLAYED: &str = "COMM_MSGS_REPLAYED";
+pub const COMM_MSGS_REPLAYED_ID: usize = 413;
+
+pub const COMM_OVERSIZED_PAYLOADS: &str = "COMM_OVERSIZED_PAYLOADS";
+pub const COMM_OVERSIZED_PAYLOADS_ID: usize = 414;
+
+pub const COMM_HEARTBEAT_TIMEOUTS: &str = "COMM_HEARTBEAT_TIMEOUTS";
+pub const COMM_HEARTBEAT_TIMEOUTS_ID: usize = 415;
+
+pub const COMM_SEND_QUEUE_FULL: &str = "COMM_SEND_QUEUE_FULL";
+pub const COMM_SEND_QUEUE_FULL_ID: usize = 416;
+
+pub const COMM_RETRANSMISSIONS: &str = "COMM_RETRANSMISSIONS";
+pub const COMM_RETRANSMISSIONS_ID: usize = 417;
+
+pub const COMM_DUPLICATES_DROPPED: &str = "COMM_DUPLICATES_DROPPED";
+pub const COMM_DUPLICATES_DROPPED_ID: usize = 418;
+
+pub const COMM_SEND_THROTTLED: &str = "COMM_SEND_THROTTLED";
+pub const COMM_SEND_THROTTLED_ID: usize = 419;
+
+pub const COMM_RECEIVE_THROTTLED: &str = "COMM_RECEIVE_THROTTLED";
+pub const COMM_RECEIVE_THROTTLED_ID: usize = 420;
+
+pub const CLIENT_POOL_RQS_SHED: &str = "CLIENT_POOL_RQS_SHED";
+pub const CLIENT_POOL_RQS_SHED_ID: usize = 421;
+
+pub const CLIENT_POOL_BATCH_SIZE: &str = "CLIENT_POOL_BATCH_SIZE";
+pub const CLIENT_POOL_BATCH_SIZE_ID: usize = 422;
+
+pub const CLIENT_POOL_BATCH_TIMEOUT: &str = "CLIENT_POOL_BATCH_TIMEOUT";
+pub const CLIENT_POOL_BATCH_TIMEOUT_ID: usize = 423;
+
 pub const CLIENT_POOL_COLLECT_TIME: &str = "CLIENT_POOL_COLLECT_TIME";
 pub const CLIENT_POOL_COLLECT_TIME_ID: usize = 404;
 
@@ -53,5 +89,17 @@ pub fn metrics() -> Vec<MetricRegistry> {
         (COMM_RQ_SEND_PASSING_TIME_ID, COMM_RQ_SEND_PASSING_TIME.to_string(), MetricKind::Duration, MetricLevel::Trace, 8).into(),
         (COMM_RQ_TIME_SPENT_IN_MOD_ID, COMM_RQ_TIME_SPENT_IN_MOD.to_string(), MetricKind::Duration, MetricLevel::Trace, 8).into(),
         (COMM_RQ_SEND_CLI_PASSING_TIME_ID, COMM_RQ_SEND_CLI_PASSING_TIME.to_string(), MetricKind::Duration, MetricLevel::Debug, 8).into(),
+        (COMM_MSGS_FAILED_VERIFICATION_ID, COMM_MSGS_FAILED_VERIFICATION.to_string(), MetricKind::Counter).into(),
+        (COMM_MSGS_REPLAYED_ID, COMM_MSGS_REPLAYED.to_string(), MetricKind::Counter).into(),
+        (COMM_OVERSIZED_PAYLOADS_ID, COMM_OVERSIZED_PAYLOADS.to_string(), MetricKind::Counter).into(),
+        (COMM_HEARTBEAT_TIMEOUTS_ID, COMM_HEARTBEAT_TIMEOUTS.to_string(), MetricKind::Counter).into(),
+        (COMM_SEND_QUEUE_FULL_ID, COMM_SEND_QUEUE_FULL.to_string(), MetricKind::Counter).into(),
+        (COMM_RETRANSMISSIONS_ID, COMM_RETRANSMISSIONS.to_string(), MetricKind::Counter).into(),
+        (COMM_DUPLICATES_DROPPED_ID, COMM_DUPLICATES_DROPPED.to_string(), MetricKind::Counter).into(),
+        (COMM_SEND_THROTTLED_ID, COMM_SEND_THROTTLED.to_string(), MetricKind::Counter).into(),
+        (COMM_RECEIVE_THROTTLED_ID, COMM_RECEIVE_THROTTLED.to_string(), MetricKind::Counter).into(),
+        (CLIENT_POOL_RQS_SHED_ID, CLIENT_POOL_RQS_SHED.to_string(), MetricKind::Counter).into(),
+        (CLIENT_POOL_BATCH_SIZE_ID, CLIENT_POOL_BATCH_SIZE.to_string(), MetricKind::Count).into(),
+        (CLIENT_POOL_BATCH_TIMEOUT_ID, CLIENT_POOL_BATCH_TIMEOUT.to_string(), MetricKind::Duration).into(),
     ]
 }
\ No newline at end of file
@@ -0,0 +1,156 @@
+//! Telling our clients we are too busy to take their requests.
+//!
+//! When the admission control of the client pools refuses a request (see
+//! [crate::client_pooling::admission]), the request is dropped and the client is sent a busy frame:
+//! a header marked with the busy flag, whose payload is the nonce of the refused request
+//! (a little endian `u64`). The client can then back off and send the request again later,
+//! instead of waiting for a reply that is never coming.
+//!
+//! Busy frames are only sent to peers that speak at least [WireMessage::BUSY_VERSION],
+//! as older peers would not understand the flag.
+
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use atlas_common::Err;
+use atlas_common::error::*;
+use atlas_common::node_id::NodeId;
+
+use crate::message::WireMessage;
+
+const NONCE_LENGTH: usize = std::mem::size_of::<u64>();
+
+/// How many busy replies we keep waiting to be written on a connection.
+/// A client flooding us past this is only told about some of its refused requests
+const MAX_PENDING_REPLIES: usize = 64;
+
+/// The busy replies waiting to be written on a connection
+pub(crate) struct BusyReplies {
+    // The nonces of the refused requests we still have to reply to
+    pending: VecDeque<u64>,
+}
+
+impl BusyReplies {
+    pub fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// We have refused the request with the given nonce
+    pub fn refused(&mut self, nonce: u64) {
+        if self.pending.len() < MAX_PENDING_REPLIES {
+            self.pending.push_back(nonce);
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Take the next busy reply to write to the peer.
+    /// Peers using older wire versions are left without them
+    pub fn take_reply(&mut self, my_id: NodeId, peer_id: NodeId, wire_version: u32) -> Option<WireMessage> {
+        if !is_supported(wire_version) {
+            self.pending.clear();
+
+            return None;
+        }
+
+        self.pending.pop_front().map(|nonce| busy_message(my_id, peer_id, nonce))
+    }
+}
+
+/// Do peers using the given wire version understand busy frames
+pub(crate) fn is_supported(wire_version: u32) -> bool {
+    wire_version >= WireMessage::BUSY_VERSION
+}
+
+/// Build a busy reply to the request with the given nonce. Like pings, busy frames are neither
+/// signed nor digested, as they only concern the connection they are sent over
+pub(crate) fn busy_message(my_id: NodeId, peer_id: NodeId, nonce: u64) -> WireMessage {
+    let payload = Bytes::copy_from_slice(&nonce.to_le_bytes()[..]);
+
+    let mut message = WireMessage::new(my_id, peer_id, payload, fastrand::u64(..), None, None);
+
+    message.header.set_busy(true);
+
+    message
+}
+
+/// Read the nonce of the refused request out of the payload of a busy frame
+pub(crate) fn read_busy(payload: &[u8]) -> Result<u64> {
+    let nonce: [u8; NONCE_LENGTH] = match payload.try_into() {
+        Ok(nonce) => nonce,
+        Err(_) => return Err!(BusyError::MalformedNonce(payload.len())),
+    };
+
+    Ok(u64::from_le_bytes(nonce))
+}
+
+#[derive(Error, Debug)]
+pub enum BusyError {
+    #[error("The nonce of a busy frame must be {} bytes long, received {0}", NONCE_LENGTH)]
+    MalformedNonce(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use atlas_common::node_id::NodeId;
+
+    use crate::message::{Header, WireMessage};
+    use crate::mio_tcp::connections::busy;
+    use crate::mio_tcp::connections::busy::BusyReplies;
+
+    #[test]
+    fn test_busy_reply_carries_nonce() {
+        let mut replies = BusyReplies::new();
+
+        replies.refused(42);
+
+        assert!(replies.has_pending());
+
+        let message = replies.take_reply(NodeId::from(0u32), NodeId::from(1u32), WireMessage::BUSY_VERSION)
+            .expect("Peers using the busy version should be told we are busy");
+
+        assert!(message.header().is_busy());
+
+        let mut buf = [0; Header::LENGTH];
+
+        message.header().serialize_versioned(WireMessage::BUSY_VERSION, &mut buf[..]).unwrap();
+
+        assert!(Header::deserialize_versioned(&buf[..]).unwrap().is_busy());
+
+        let (_, payload) = message.into_inner();
+
+        assert_eq!(busy::read_busy(&payload[..]).unwrap(), 42);
+        assert!(busy::read_busy(&[0; 4][..]).is_err());
+
+        assert!(!replies.has_pending());
+    }
+
+    #[test]
+    fn test_busy_replies_are_bounded_and_versioned() {
+        let mut replies = BusyReplies::new();
+
+        for nonce in 0..1000 {
+            replies.refused(nonce);
+        }
+
+        let mut taken = 0;
+
+        while replies.take_reply(NodeId::from(0u32), NodeId::from(1u32), WireMessage::CURRENT_VERSION).is_some() {
+            taken += 1;
+        }
+
+        assert_eq!(taken, super::MAX_PENDING_REPLIES);
+
+        // Peers that don't understand busy frames are left without them
+        replies.refused(1);
+
+        assert!(replies.take_reply(NodeId::from(0u32), NodeId::from(1u32), WireMessage::RELIABLE_VERSION).is_none());
+        assert!(!replies.has_pending());
+    }
+}
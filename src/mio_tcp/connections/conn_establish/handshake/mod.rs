@@ -0,0 +1,336 @@
+//! The authentication handshake performed on every new connection, before
+//! it is handed over to the epoll workers.
+//!
+//! The handshake is composed of three frames:
+//!
+//! 1) Hello (connecting node -> accepting node): identifies the node that is connecting,
+//!    carrying a fresh nonce, the highest wire protocol version it supports and, as its
+//!    payload, the public key of the connecting node. The accepting node only relies on that
+//!    key for nodes it does not know yet (e.g. nodes joining through reconfiguration), whose
+//!    connections are kept pending until the reconfiguration protocol vouches for the key.
+//! 2) Challenge (accepting node -> connecting node): carries a fresh challenge nonce and
+//!    the wire protocol version selected for the connection (the highest version supported
+//!    by both nodes). It is signed by the accepting node over the nonce of the hello and the
+//!    selected version, so the connecting node knows it is talking to who it meant to and
+//!    that the version was not downgraded along the way.
+//! 3) Response (connecting node -> accepting node): signed by the connecting node over the
+//!    challenge, proving it holds the private key of the id it claimed in the hello.
+//!
+//! The challenge and response are bare headers (no payload).
+//!
+//! The challenge and response digests are domain separated, so a signed challenge
+//! can never be replayed as a response (or vice versa). They also cover the channel
+//! binding of the TLS session, so they can't be relayed to another connection, which
+//! in turn authenticates the session key exported from that TLS session.
+//!
+//! The versions are carried in the version field of the frames. Since the frames carry no
+//! flags, every build can read them, whatever version they are encoded with.
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use atlas_common::crypto::hash::{Context, Digest};
+use atlas_common::crypto::signature::{KeyPair, PublicKey};
+use atlas_common::Err;
+use atlas_common::error::*;
+use atlas_common::node_id::NodeId;
+
+use crate::message::{Header, WireMessage};
+
+const CHALLENGE_CONTEXT: &[u8] = b"atlas-comm-mio/handshake/challenge";
+const RESPONSE_CONTEXT: &[u8] = b"atlas-comm-mio/handshake/response";
+
+fn challenge_digest(hello_nonce: u64, wire_version: u32, binding: &[u8]) -> Digest {
+    let mut ctx = Context::new();
+
+    ctx.update(CHALLENGE_CONTEXT);
+    ctx.update(&hello_nonce.to_le_bytes()[..]);
+    ctx.update(&wire_version.to_le_bytes()[..]);
+    ctx.update(binding);
+    ctx.finish()
+}
+
+fn response_digest(challenge: u64, binding: &[u8]) -> Digest {
+    let mut ctx = Context::new();
+
+    ctx.update(RESPONSE_CONTEXT);
+    ctx.update(&challenge.to_le_bytes()[..]);
+    ctx.update(binding);
+    ctx.finish()
+}
+
+fn with_version(mut message: WireMessage, wire_version: u32) -> WireMessage {
+    message.header.version = wire_version;
+
+    message
+}
+
+/// The first frame sent by the connecting node, identifying itself, presenting
+/// its public key and advertising the highest wire protocol version it supports
+pub(crate) fn hello(key_pair: &KeyPair, my_id: NodeId, peer_id: NodeId, nonce: u64, max_version: u32) -> WireMessage {
+    let public_key = Bytes::copy_from_slice(key_pair.public_key_bytes());
+
+    with_version(WireMessage::new(my_id, peer_id, public_key, nonce, None, None), max_version)
+}
+
+/// The public key presented in a hello sent to us.
+///
+/// The key is only authenticated once the sender answers our challenge with it
+/// (see [verify_response]), and only identifies the sender once the reconfiguration
+/// protocol vouches for it
+pub(crate) fn presented_key(hello: &Header, payload: &[u8]) -> Result<PublicKey> {
+    match PublicKey::from_bytes(payload) {
+        Ok(public_key) => Ok(public_key),
+        Err(_) => Err!(HandshakeError::InvalidPublicKey(hello.from())),
+    }
+}
+
+/// Are both keys the same
+pub(crate) fn same_key(key: &PublicKey, other: &PublicKey) -> bool {
+    key.pk_bytes() == other.pk_bytes()
+}
+
+/// The wire protocol version to use with a peer that sent us the given hello
+pub(crate) fn select_version(hello: &Header, max_version: u32) -> u32 {
+    hello.protocol_version().min(max_version)
+}
+
+/// The challenge sent by the accepting node, in response to a hello with the given nonce
+pub(crate) fn challenge(key_pair: &KeyPair, my_id: NodeId, peer_id: NodeId,
+                        hello_nonce: u64, challenge: u64, wire_version: u32, binding: &[u8]) -> WireMessage {
+    with_version(WireMessage::new(my_id, peer_id, Bytes::new(), challenge,
+                                  Some(challenge_digest(hello_nonce, wire_version, binding)), Some(key_pair)),
+                 wire_version)
+}
+
+/// The response of the connecting node to the given challenge
+pub(crate) fn response(key_pair: &KeyPair, my_id: NodeId, peer_id: NodeId, challenge: u64,
+                       wire_version: u32, binding: &[u8]) -> WireMessage {
+    with_version(WireMessage::new(my_id, peer_id, Bytes::new(), challenge,
+                                  Some(response_digest(challenge, binding)), Some(key_pair)),
+                 wire_version)
+}
+
+/// Check that a handshake frame goes from the node we expect, to us and carries no payload
+fn verify_frame(header: &Header, my_id: NodeId, peer_id: NodeId) -> Result<()> {
+    if header.from() != peer_id || header.to() != my_id {
+        return Err!(HandshakeError::UnexpectedPeer {
+            expected: peer_id,
+            from: header.from(),
+            to: header.to(),
+        });
+    }
+
+    if header.payload_length() != 0 {
+        return Err!(HandshakeError::UnexpectedPayload(header.from()));
+    }
+
+    Ok(())
+}
+
+fn verify_signed_frame(public_key: &PublicKey, header: &Header, expected_digest: Digest) -> Result<()> {
+    if header.digest().as_ref() != expected_digest.as_ref() {
+        return Err!(HandshakeError::InvalidSignature(header.from()));
+    }
+
+    let verified = crate::message_signing::verify_parts(public_key, header.signature(),
+                                                        header.from().into(), header.to().into(),
+                                                        header.nonce(), header.digest().as_ref());
+
+    if verified.is_err() {
+        return Err!(HandshakeError::InvalidSignature(header.from()));
+    }
+
+    Ok(())
+}
+
+/// Verify the challenge received from the node we are connecting to,
+/// returning the wire protocol version it selected for the connection.
+///
+/// Nodes whose public key we don't know can't be authenticated, so they are rejected
+pub(crate) fn verify_challenge(public_key: Option<&PublicKey>, header: &Header,
+                               my_id: NodeId, peer_id: NodeId, hello_nonce: u64,
+                               max_version: u32, binding: &[u8]) -> Result<u32> {
+    verify_frame(header, my_id, peer_id)?;
+
+    let wire_version = header.protocol_version();
+
+    if wire_version > max_version {
+        return Err!(HandshakeError::UnsupportedVersion(peer_id, wire_version));
+    }
+
+    let public_key = match public_key {
+        Some(public_key) => public_key,
+        None => return Err!(HandshakeError::UnknownNode(peer_id)),
+    };
+
+    verify_signed_frame(public_key, header, challenge_digest(hello_nonce, wire_version, binding))?;
+
+    Ok(wire_version)
+}
+
+/// Verify the response to the challenge we sent to a node that claims to be `claimed_id`,
+/// proving it holds the private key of `public_key`.
+///
+/// That is the key we know for `claimed_id` or, for nodes we don't know yet, the key
+/// they presented in their hello
+pub(crate) fn verify_response(public_key: &PublicKey, header: &Header,
+                              my_id: NodeId, claimed_id: NodeId, challenge: u64, binding: &[u8]) -> Result<()> {
+    verify_frame(header, my_id, claimed_id)?;
+
+    if header.nonce() != challenge {
+        return Err!(HandshakeError::ChallengeMismatch(claimed_id));
+    }
+
+    verify_signed_frame(public_key, header, response_digest(challenge, binding))?;
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("Expected a handshake frame from {expected:?}, received one from {from:?} to {to:?}")]
+    UnexpectedPeer { expected: NodeId, from: NodeId, to: NodeId },
+    #[error("Handshake frames must not carry a payload (sent by {0:?})")]
+    UnexpectedPayload(NodeId),
+    #[error("The response sent by {0:?} does not answer our challenge")]
+    ChallengeMismatch(NodeId),
+    #[error("Invalid handshake signature for {0:?}")]
+    InvalidSignature(NodeId),
+    #[error("{0:?} selected the wire protocol version {1}, which we don't support")]
+    UnsupportedVersion(NodeId, u32),
+    #[error("We don't know the public key of {0:?}, so it can't be authenticated")]
+    UnknownNode(NodeId),
+    #[error("{0:?} presented an invalid public key")]
+    InvalidPublicKey(NodeId),
+}
+
+#[cfg(test)]
+mod tests {
+    use atlas_common::crypto::signature::{KeyPair, PublicKey};
+    use atlas_common::node_id::NodeId;
+
+    use crate::message::WireMessage;
+    use crate::mio_tcp::connections::conn_establish::handshake;
+
+    const BINDING: [u8; 32] = [7; 32];
+
+    #[test]
+    fn test_response_authenticates_claimed_id() {
+        let key = KeyPair::from_bytes(&[0; 32][..]).unwrap();
+        let spoofer_key = KeyPair::from_bytes(&[1; 32][..]).unwrap();
+        let public_key: PublicKey = key.public_key().into();
+
+        let (server, client) = (NodeId::from(0u32), NodeId::from(1u32));
+
+        let version = WireMessage::CURRENT_VERSION;
+
+        let (challenge, _) = handshake::challenge(&key, server, client, 10, 20, version, &BINDING).into_inner();
+
+        handshake::verify_challenge(Some(&public_key), &challenge, client, server, 10, version, &BINDING)
+            .expect("Valid challenge should be accepted");
+
+        assert!(handshake::verify_challenge(Some(&public_key), &challenge, client, server, 11, version, &BINDING).is_err());
+
+        let (response, _) = handshake::response(&key, client, server, 20, version, &BINDING).into_inner();
+
+        handshake::verify_response(&public_key, &response, server, client, 20, &BINDING)
+            .expect("Valid response should be accepted");
+
+        // A response signed with a key that does not belong to the claimed id
+        let (spoofed, _) = handshake::response(&spoofer_key, client, server, 20, version, &BINDING).into_inner();
+
+        assert!(handshake::verify_response(&public_key, &spoofed, server, client, 20, &BINDING).is_err());
+
+        // A response that answers another challenge (e.g. replayed from another connection)
+        let (replayed, _) = handshake::response(&key, client, server, 21, version, &BINDING).into_inner();
+
+        assert!(handshake::verify_response(&public_key, &replayed, server, client, 20, &BINDING).is_err());
+
+        // A response relayed from another TLS session
+        assert!(handshake::verify_response(&public_key, &response, server, client, 20, &[8; 32]).is_err());
+
+        // A signed challenge can't be reflected back as a response
+        let (reflected, _) = handshake::challenge(&key, client, server, 20, 20, version, &BINDING).into_inner();
+
+        assert!(handshake::verify_response(&public_key, &reflected, server, client, 20, &BINDING).is_err());
+
+        // We don't connect to nodes whose key we don't know
+        assert!(handshake::verify_challenge(None, &challenge, client, server, 10, version, &BINDING).is_err());
+    }
+
+    #[test]
+    fn test_presented_key_is_authenticated_by_the_response() {
+        let key = KeyPair::from_bytes(&[0; 32][..]).unwrap();
+        let spoofer_key = KeyPair::from_bytes(&[1; 32][..]).unwrap();
+
+        let (server, joining) = (NodeId::from(0u32), NodeId::from(1u32));
+
+        let version = WireMessage::CURRENT_VERSION;
+
+        let (hello, payload) = handshake::hello(&key, joining, server, 10, version).into_inner();
+
+        let presented = handshake::presented_key(&hello, &payload[..])
+            .expect("The hello should present the key of the joining node");
+
+        assert!(handshake::same_key(&presented, &key.public_key().into()));
+        assert!(!handshake::same_key(&presented, &spoofer_key.public_key().into()));
+
+        // Hellos must carry a valid key
+        assert!(handshake::presented_key(&hello, &[]).is_err());
+
+        let (response, _) = handshake::response(&key, joining, server, 20, version, &BINDING).into_inner();
+
+        handshake::verify_response(&presented, &response, server, joining, 20, &BINDING)
+            .expect("The joining node holds the key it presented");
+
+        // A node presenting a key it does not hold can't answer the challenge
+        let (spoofed, _) = handshake::response(&spoofer_key, joining, server, 20, version, &BINDING).into_inner();
+
+        assert!(handshake::verify_response(&presented, &spoofed, server, joining, 20, &BINDING).is_err());
+
+        // Nor can the response be relayed from another TLS session
+        assert!(handshake::verify_response(&presented, &response, server, joining, 20, &[8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        let key = KeyPair::from_bytes(&[0; 32][..]).unwrap();
+        let public_key: PublicKey = key.public_key().into();
+
+        let (server, client) = (NodeId::from(0u32), NodeId::from(1u32));
+
+        // An older client talking to a newer server
+        let (hello, _) = handshake::hello(&key, client, server, 10, WireMessage::BASE_VERSION).into_inner();
+
+        let selected = handshake::select_version(&hello, WireMessage::CURRENT_VERSION);
+
+        assert_eq!(selected, WireMessage::BASE_VERSION);
+
+        let (challenge, _) = handshake::challenge(&key, server, client, 10, 20, selected, &BINDING).into_inner();
+
+        let negotiated = handshake::verify_challenge(Some(&public_key), &challenge, client, server, 10,
+                                                     WireMessage::BASE_VERSION, &BINDING)
+            .expect("Valid challenge should be accepted");
+
+        assert_eq!(negotiated, WireMessage::BASE_VERSION);
+
+        // A newer client talking to an older server
+        let (hello, _) = handshake::hello(&key, client, server, 10, WireMessage::CURRENT_VERSION).into_inner();
+
+        assert_eq!(handshake::select_version(&hello, WireMessage::BASE_VERSION), WireMessage::BASE_VERSION);
+
+        // A server can't select a version the client does not support
+        let (too_new, _) = handshake::challenge(&key, server, client, 10, 20, WireMessage::CURRENT_VERSION, &BINDING).into_inner();
+
+        assert!(handshake::verify_challenge(Some(&public_key), &too_new, client, server, 10,
+                                            WireMessage::BASE_VERSION, &BINDING).is_err());
+
+        // Nor can the selected version be tampered with, as it is covered by the signature
+        let (mut downgraded, _) = handshake::challenge(&key, server, client, 10, 20, WireMessage::CURRENT_VERSION, &BINDING).into_inner();
+        downgraded.version = WireMessage::BASE_VERSION;
+
+        assert!(handshake::verify_challenge(Some(&public_key), &downgraded, client, server, 10,
+                                            WireMessage::CURRENT_VERSION, &BINDING).is_err());
+    }
+}
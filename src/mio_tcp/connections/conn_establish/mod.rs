@@ -1,36 +1,43 @@
-use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
 use std::fs::read;
 use std::io;
 use std::io::Write;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use bytes::{Bytes, BytesMut};
+use bytes::BytesMut;
 use log::{debug, error, info, trace, warn};
 use mio::{Events, Interest, Poll, Token, Waker};
 use mio::event::Event;
+use rustls::{ClientConfig, ServerConfig};
 use slab::Slab;
 use thiserror::Error;
 
-use atlas_common::{channel, Err, prng, quiet_unwrap, socket};
-use atlas_common::channel::{ChannelSyncRx, ChannelSyncTx, OneShotRx};
+use atlas_common::{Err, prng, quiet_unwrap};
+use atlas_common::channel::ChannelSyncRx;
+use atlas_common::crypto::signature::PublicKey;
 use atlas_common::error::*;
 use atlas_common::node_id::{NodeId, NodeType};
 use atlas_common::peer_addr::PeerAddr;
-use atlas_common::socket::{MioListener, MioSocket, SecureSocket, SecureSocketSync, SyncListener};
+use atlas_common::socket::{MioListener, MioSocket, SyncListener};
 
-use crate::conn_utils::ConnCounts;
+use crate::config::TlsConfig;
+use crate::conn_utils::ConnectionEvent;
 use crate::{cpu_workers, NetworkSendError};
 use crate::message::{Header, StoredMessage, WireMessage};
-use crate::mio_tcp::connections::{conn_util, Connections, NetworkSerializedMessage};
+use crate::mio_tcp::connections::{conn_util, Connections};
 use crate::mio_tcp::connections::conn_establish::pending_conn::{NetworkUpdate, PendingConnHandle, ServerRegisteredPendingConns};
 use crate::mio_tcp::connections::conn_util::{ConnectionReadWork, ConnectionWriteWork, ReadingBuffer, WritingBuffer};
 use crate::mio_tcp::connections::epoll_group::epoll_workers::{interrupted, would_block};
+use crate::mio_tcp::connections::send_queue::SendQueue;
+use crate::mio_tcp::connections::tls;
+use crate::mio_tcp::connections::tls::SecureMioSocket;
 use crate::reconfiguration_node::{NetworkInformationProvider, NetworkUpdateMessage, ReconfigurationMessageHandler};
 use crate::serialize::Serializable;
 
 pub mod pending_conn;
+pub mod handshake;
 
 const DEFAULT_ALLOWED_CONCURRENT_JOINS: usize = 128;
 // Since the tokens will always start at 0, we limit the amount of concurrent joins we can have
@@ -41,8 +48,14 @@ const SERVER_TOKEN: Token = Token(DEFAULT_ALLOWED_CONCURRENT_JOINS + 1);
 pub struct ConnectionHandler {
     my_id: NodeId,
 
-    concurrent_conn: ConnCounts,
-    currently_connecting: Mutex<BTreeMap<NodeId, usize>>,
+    // The TLS configuration used when connecting to other nodes
+    tls_client_config: Arc<ClientConfig>,
+    // The TLS configuration used when accepting connections from other nodes
+    tls_server_config: Arc<ServerConfig>,
+    // The highest wire protocol version we accept to use with other nodes
+    max_wire_version: u32,
+    // The largest payload we accept in a frame from other nodes
+    max_payload_length: usize,
 }
 
 /// A pending connection object, waiting for new information and to be accepted
@@ -51,15 +64,42 @@ enum PendingConnection {
     PendingConn {
         peer_id: Option<NodeId>,
         node_type: Option<NodeType>,
-        socket: MioSocket,
+        socket: SecureMioSocket,
         read_buf: ReadingBuffer,
         write_buf: Option<WritingBuffer>,
-        channel: Option<(ChannelSyncTx<NetworkSerializedMessage>, ChannelSyncRx<NetworkSerializedMessage>)>,
+        // The progress of the authentication of the peer
+        auth_state: HandshakeState,
+        // The public key the peer authenticates with (for nodes we don't know yet,
+        // the key they presented in their hello)
+        peer_key: Option<PublicKey>,
+        // The wire protocol version selected for this connection (only final after the hello)
+        wire_version: u32,
+        // Are we currently registered for writability notifications on this socket?
+        write_interest: bool,
+        // When the connection has to be established by (only for the connections we initiate)
+        connect_deadline: Option<Instant>,
+        channel: Option<Arc<SendQueue>>,
     },
     Waker,
     ServerToken,
 }
 
+/// The state of the authentication handshake of a pending connection
+/// (see [handshake] for the frames that are exchanged)
+#[derive(Debug)]
+enum HandshakeState {
+    // We are connecting to the peer and will identify ourselves with the given nonce once connected
+    Connecting { hello_nonce: u64 },
+    // We have identified ourselves and are waiting for the peer to challenge us
+    AwaitingChallenge { hello_nonce: u64 },
+    // Waiting for the peer to identify itself
+    AwaitingHello,
+    // We have challenged the peer and are waiting for its signed response
+    AwaitingResponse { claimed_id: NodeId, challenge: u64 },
+    // The peer has proven it holds the key of the id it claimed
+    Authenticated,
+}
+
 pub struct ServerWorker<NI, RM, PM>
     where NI: NetworkInformationProvider + 'static,
           RM: Serializable + 'static,
@@ -75,6 +115,8 @@ pub struct ServerWorker<NI, RM, PM>
     network_message_rx: ChannelSyncRx<NetworkUpdate>,
     waker: Arc<Waker>,
     poll: Poll,
+    // Used to generate the challenges for the authentication handshake
+    rng: prng::State,
 
     waker_token: Token,
     server_token: Token,
@@ -139,6 +181,7 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
             network_message_rx,
             waker: Arc::new(waker),
             poll,
+            rng: prng::State::new(),
             waker_token,
             server_token: listener_token,
         })
@@ -149,8 +192,18 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
         let mut events = Events::with_capacity(DEFAULT_ALLOWED_CONCURRENT_JOINS);
 
         loop {
+            if self.peer_conns.is_shutting_down() {
+                info!("{:?} // Server worker is shutting down, closing the listener", self.my_id);
+
+                return Ok(());
+            }
+
             self.read_network_update_messages()?;
 
+            self.connect_to_due_peers()?;
+
+            self.expire_outbound_connections()?;
+
             self.poll.poll(&mut events, Some(Duration::from_millis(25)))?;
 
             for event in events.iter() {
@@ -186,11 +239,16 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
                         continue;
                     }
 
-                    let mut read_buffer = BytesMut::with_capacity(Header::LENGTH);
+                    let socket = match SecureMioSocket::new_tls_server(self.conn_handler.tls_server_config().clone(), MioSocket::from(socket)) {
+                        Ok(socket) => socket,
+                        Err(err) => {
+                            error!("{:?} // Failed to initialize TLS session for connection from {}: {:?}", self.my_id, addr, err);
 
-                    read_buffer.resize(Header::LENGTH, 0);
+                            continue;
+                        }
+                    };
 
-                    let currently_accept = self.currently_accepting.insert(PendingConnection::from_socket(MioSocket::from(socket)));
+                    let currently_accept = self.currently_accepting.insert(PendingConnection::from_socket(socket, self.conn_handler.max_payload_length()));
 
                     let token = Token(currently_accept);
 
@@ -203,7 +261,14 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
                         _ => unreachable!()
                     }
 
-                    let result = self.handle_connection_readable(token)?;
+                    let mut result = self.handle_connection_readable(token)?;
+
+                    if let ConnectionResult::Working = &result {
+                        if self.has_pending_output(token) {
+                            // The TLS handshake (or our challenge) has records we were not able to write right away
+                            result = self.try_write_until_block(token)?;
+                        }
+                    }
 
                     debug!("{:?} // Connection from {} is {:?} (Token {:?})", self.my_id, addr, result, token);
 
@@ -223,6 +288,102 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
         Ok(())
     }
 
+    /// Start connecting to the peers whose (re)connection is due
+    fn connect_to_due_peers(&mut self) -> io::Result<()> {
+        let due = self.peer_conns.reconnection.take_due(Instant::now(), |node| self.peer_conns.missing_connections(node));
+
+        for (peer_id, missing) in due {
+            let (addr, node_type) = match (self.network_info.get_addr_for_node(&peer_id), self.network_info.get_node_type(&peer_id)) {
+                (Some(addr), Some(node_type)) => (addr, node_type),
+                _ => {
+                    error!("{:?} // No address found for node {:?}, not connecting to it", self.my_id, peer_id);
+
+                    self.peer_conns.reconnection.give_up(peer_id);
+
+                    continue;
+                }
+            };
+
+            debug!("{:?} // Establishing {} connections to node {:?} at {:?}", self.my_id, missing, peer_id, addr);
+
+            for _ in 0..missing {
+                if let Err(err) = self.start_outbound_connection(peer_id, node_type, &addr) {
+                    warn!("{:?} // Failed to start connecting to node {:?}: {:?}", self.my_id, peer_id, err);
+
+                    self.peer_conns.reconnection.attempt_failed(&peer_id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start a non blocking connection to the given node. The connection then goes through
+    /// the TLS and authentication handshakes in this worker's poll, like the ones we accept
+    fn start_outbound_connection(&mut self, peer_id: NodeId, node_type: NodeType, addr: &PeerAddr) -> Result<()> {
+        if self.currently_accepting.len() >= DEFAULT_ALLOWED_CONCURRENT_JOINS {
+            return Err!(ConnectionEstablishError::TooManyPendingConnections(peer_id));
+        }
+
+        //Get the correct IP for us to address the node
+        //If I'm a client I will always use the client facing addr
+        //While if I'm a replica I'll connect to the replica addr (clients only have this addr)
+        let (socket_addr, host_name) = addr.clone().into_inner();
+
+        let stream = mio::net::TcpStream::connect(socket_addr)?;
+
+        let session = tls::new_client_session(self.conn_handler.tls_client_config().clone(), &host_name)?;
+
+        let socket = SecureMioSocket::new_tls_client(session, MioSocket::from(stream));
+
+        let deadline = Instant::now() + Duration::from_millis(self.peer_conns.reconnection.config().connect_timeout_millis);
+
+        let connection = PendingConnection::outbound(socket, peer_id, node_type, self.rng.next_state(),
+                                                     deadline, self.conn_handler.max_payload_length());
+
+        let token = Token(self.currently_accepting.insert(connection));
+
+        let registered = match &mut self.currently_accepting[token.into()] {
+            PendingConnection::PendingConn { socket, .. } => {
+                // We learn that the connection has been established once the socket becomes writable
+                self.poll.registry().register(socket, token, Interest::READABLE.add(Interest::WRITABLE))
+            }
+            _ => unreachable!()
+        };
+
+        if let Err(err) = registered {
+            self.currently_accepting.remove(token.into());
+
+            return Err!(err);
+        }
+
+        trace!("{:?} // Connecting to node {:?} at {:?} (Token {:?})", self.my_id, peer_id, socket_addr, token);
+
+        Ok(())
+    }
+
+    /// Discard the connections we initiated that took too long to be established
+    fn expire_outbound_connections(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+
+        let expired = self.currently_accepting.iter()
+            .filter_map(|(slot, conn)| match conn {
+                PendingConnection::PendingConn { peer_id, connect_deadline: Some(deadline), .. } if *deadline <= now => {
+                    warn!("{:?} // Timed out connecting to node {:?}", self.my_id, peer_id);
+
+                    Some(Token(slot))
+                }
+                _ => None
+            })
+            .collect::<Vec<_>>();
+
+        for token in expired {
+            self.handle_connection_result(token, ConnectionResult::ConnectionBroken)?;
+        }
+
+        Ok(())
+    }
+
     /// Read network update messages from the reconfiguration module
     fn read_network_update_messages(&mut self) -> io::Result<()> {
         match self.network_message_rx.try_recv() {
@@ -234,23 +395,23 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
                         debug!("Received network update message for node {:?} with type {:?}. Moving connections to the final connection pool, {:?}", node_id, node_type,
                     self.currently_accepting.iter().map(|(token, conn)| (Token(token), conn)).collect::<Vec<_>>());
 
-                        while let Some((position, _)) = self.currently_accepting.iter().find(|(token, pend)| {
-                            return match pend {
-                                PendingConnection::PendingConn { peer_id, .. } => {
-                                    if let Some(node) = peer_id {
-                                        if *node == node_id {
-                                            true
-                                        } else {
-                                            false
-                                        }
-                                    } else {
-                                        false
-                                    }
+                        // The connections of the node that are waiting for authorization, and whether
+                        // the key they authenticated with is the one the reconfiguration protocol vouched for
+                        while let Some((position, vouched)) = self.currently_accepting.iter().find_map(|(token, pend)| {
+                            match pend {
+                                PendingConnection::PendingConn { peer_id: Some(node), auth_state: HandshakeState::Authenticated, peer_key, .. } if *node == node_id => {
+                                    Some((token, peer_key.as_ref().map_or(false, |key| handshake::same_key(key, &pk))))
                                 }
-                                _ => false,
-                            };
+                                _ => None,
+                            }
                         }) {
-                            let connection_result = ConnectionResult::Connected(node_id, node_type, Vec::new());
+                            let connection_result = if vouched {
+                                ConnectionResult::Connected(node_id, node_type, Vec::new())
+                            } else {
+                                warn!("{:?} // Node {:?} authenticated with a key other than the one it was permitted with, closing connection", self.my_id, node_id);
+
+                                ConnectionResult::ConnectionBroken
+                            };
 
                             self.handle_connection_result(Token(position), connection_result)?;
                         }
@@ -274,8 +435,11 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
         self.currently_accepting.iter().for_each(|(slot, conn)| {
             let token = Token(slot);
 
-            if let PendingConnection::PendingConn { .. } = conn {
-                to_verify.push(token);
+            match conn {
+                // Nothing can be written until the connection is established
+                PendingConnection::PendingConn { auth_state: HandshakeState::Connecting { .. }, .. } => {}
+                PendingConnection::PendingConn { .. } => to_verify.push(token),
+                _ => {}
             }
         });
 
@@ -302,24 +466,50 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
 
                 // We have identified the peer and should now handle the connection
                 for (header, message) in pending_messages {
+                    if header.from() != node_id {
+                        error!("{:?} // Dropping a frame from {:?} claiming to be from {:?}", self.my_id, node_id, header.from());
+
+                        continue;
+                    }
+
                     if header.payload_length() > 0 {
-                        cpu_workers::deserialize_and_push_reconf_message::<RM, PM>(header, message, self.reconf_handling.clone());
+                        cpu_workers::deserialize_and_push_reconf_message::<NI, RM, PM>(node_id, header, message,
+                                                                                       self.network_info.clone(),
+                                                                                       self.peer_conns.verification_config().clone(),
+                                                                                       self.peer_conns.replay_window().clone(),
+                                                                                       None,
+                                                                                       None,
+                                                                                       self.reconf_handling.clone());
                     }
                 }
 
                 if let Some(mut connection) = self.currently_accepting.try_remove(token.into()) {
                     match connection {
-                        PendingConnection::PendingConn { mut socket, channel, write_buf, read_buf, .. } => {
+                        PendingConnection::PendingConn { mut socket, channel, write_buf, read_buf, wire_version, connect_deadline, .. } => {
                             // Deregister from this poller as we are no longer
                             // the ones that should handle this connection
                             self.poll.registry().deregister(&mut socket)?;
 
-                            self.peer_conns.handle_connection_established_with_socket(node_id.clone(),
-                                                                                      socket,
-                                                                                      node_type,
-                                                                                      read_buf,
-                                                                                      write_buf,
-                                                                                      channel.unwrap_or_else(conn_util::initialize_send_channel));
+                            let outbound = connect_deadline.is_some();
+
+                            if outbound && !self.peer_conns.reconnection.is_connecting(&node_id) {
+                                // We were told to disconnect from this node while connecting to it
+                                debug!("{:?} // Dropping connection to {:?} as it was cancelled", self.my_id, node_id);
+
+                                return Ok(());
+                            }
+
+                            self.peer_conns.handle_connection_established(node_id.clone(),
+                                                                          socket,
+                                                                          node_type,
+                                                                          wire_version,
+                                                                          read_buf,
+                                                                          write_buf,
+                                                                          channel.unwrap_or_else(|| conn_util::initialize_send_channel(self.peer_conns.priority_lanes_config())));
+
+                            if outbound {
+                                self.peer_conns.reconnection.attempt_succeeded(&node_id);
+                            }
                         }
                         _ => unreachable!()
                     }
@@ -333,8 +523,12 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
                 // Discard of the connection since it has been broken
                 if let Some(mut connection) = self.currently_accepting.try_remove(token.into()) {
                     match connection {
-                        PendingConnection::PendingConn { mut socket, .. } => {
+                        PendingConnection::PendingConn { mut socket, peer_id, connect_deadline, .. } => {
                             self.poll.registry().deregister(&mut socket)?;
+
+                            if let (Some(peer_id), Some(_)) = (peer_id, connect_deadline) {
+                                self.peer_conns.reconnection.attempt_failed(&peer_id);
+                            }
                         }
                         _ => unreachable!()
                     }
@@ -350,6 +544,15 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
 
     /// Handle connection events, received from epoll
     fn handle_connection_ev(&mut self, token: Token, ev: &Event) -> io::Result<ConnectionResult> {
+        if let ConnectionResult::ConnectionBroken = self.handle_connecting_ev(token, ev) {
+            return Ok(ConnectionResult::ConnectionBroken);
+        }
+
+        if self.is_connecting(token) {
+            // Still waiting for the connection to be established
+            return Ok(ConnectionResult::Working);
+        }
+
         if ev.is_readable() {
             let connection_result = self.handle_connection_readable(token)?;
 
@@ -364,7 +567,8 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
             }
         }
 
-        if ev.is_writable() {
+        // Reading may have generated TLS records or a handshake challenge that still have to be written
+        if ev.is_writable() || self.has_pending_output(token) {
             let connection_result = self.try_write_until_block(token)?;
 
             match &connection_result {
@@ -381,79 +585,175 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
         Ok(ConnectionResult::Working)
     }
 
+    /// Is the given connection one we initiated that is not yet established
+    fn is_connecting(&self, token: Token) -> bool {
+        matches!(self.currently_accepting.get(token.into()),
+            Some(PendingConnection::PendingConn { auth_state: HandshakeState::Connecting { .. }, .. }))
+    }
+
+    /// Check whether a connection we initiated has been established, in which case we
+    /// identify ourselves to the peer
+    fn handle_connecting_ev(&mut self, token: Token, ev: &Event) -> ConnectionResult {
+        let max_wire_version = self.conn_handler.max_wire_version();
+
+        match &mut self.currently_accepting[token.into()] {
+            PendingConnection::PendingConn { peer_id, socket, write_buf, auth_state, .. } => {
+                let hello_nonce = match *auth_state {
+                    HandshakeState::Connecting { hello_nonce } => hello_nonce,
+                    _ => return ConnectionResult::Working,
+                };
+
+                if ev.is_error() || ev.is_read_closed() || ev.is_write_closed() {
+                    debug!("{:?} // Failed to connect to node {:?} (Token {:?})", self.my_id, peer_id, token);
+
+                    return ConnectionResult::ConnectionBroken;
+                }
+
+                if !ev.is_writable() {
+                    return ConnectionResult::Working;
+                }
+
+                match socket.peer_addr() {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == io::ErrorKind::NotConnected || interrupted(&err) => {
+                        return ConnectionResult::Working;
+                    }
+                    Err(err) => {
+                        debug!("{:?} // Failed to connect to node {:?} (Token {:?}): {:?}", self.my_id, peer_id, token, err);
+
+                        return ConnectionResult::ConnectionBroken;
+                    }
+                }
+
+                let peer_id = peer_id.expect("Connections we initiate always know their peer");
+
+                // The hello is only sent once the TLS handshake completes, as TLS buffers it until then
+                let hello = handshake::hello(&**self.network_info.get_key_pair(), self.my_id, peer_id, hello_nonce, max_wire_version);
+
+                match WritingBuffer::init_from_message(hello, max_wire_version) {
+                    Ok(buf) => *write_buf = Some(buf),
+                    Err(err) => {
+                        error!("{:?} // Failed to serialize handshake hello for token {:?}: {:?}", self.my_id, token, err);
+
+                        return ConnectionResult::ConnectionBroken;
+                    }
+                }
+
+                *auth_state = HandshakeState::AwaitingChallenge { hello_nonce };
+
+                trace!("{:?} // Connected to node {:?} (Token {:?})", self.my_id, peer_id, token);
+
+                ConnectionResult::Working
+            }
+            _ => unreachable!()
+        }
+    }
+
     fn try_write_until_block(&mut self, token: Token) -> io::Result<ConnectionResult> {
         let connection = &mut self.currently_accepting[token.into()];
 
         match connection {
-            PendingConnection::PendingConn { socket, write_buf, channel, .. } => {
-                let was_waiting_for_write = write_buf.is_some();
+            PendingConnection::PendingConn { socket, write_buf, write_interest, wire_version, channel, .. } => {
+                let was_waiting_for_write = *write_interest;
                 let mut wrote = false;
 
-                if let Some((_, rx)) = channel {
-                    loop {
-                        let writing = if let Some(writing_info) = write_buf {
-                            wrote = true;
+                loop {
+                    let writing = if let Some(writing_info) = write_buf {
+                        wrote = true;
+
+                        //We are writing something
+                        writing_info
+                    } else {
+                        // We are not currently writing anything
 
-                            //We are writing something
-                            writing_info
-                        } else {
-                            // We are not currently writing anything
+                        // Until the peer has been identified, we only have our own handshake frames to send
+                        let next = match channel {
+                            Some(queue) => queue.try_pop().ok().flatten(),
+                            None => None,
+                        };
 
-                            match rx.try_recv() {
-                                Ok(to_write) => {
-                                    trace!("Writing message {:?}", to_write);
-                                    wrote = true;
+                        match next {
+                            Some((to_write, notifier)) => {
+                                trace!("Writing message {:?}", to_write);
+                                wrote = true;
 
-                                    // We have something to write
-                                    *write_buf = Some(WritingBuffer::init_from_message(to_write).unwrap());
+                                // The wire version was negotiated with the peer, so it might not be one we can write
+                                let buffer = match WritingBuffer::init_from_message(to_write, *wire_version) {
+                                    Ok(buffer) => buffer,
+                                    Err(err) => {
+                                        error!("{:?} // Failed to prepare message for token {:?} with wire version {}, closing connection: {:?}",
+                                            self.my_id, token, *wire_version, err);
 
-                                    write_buf.as_mut().unwrap()
-                                }
-                                Err(_) => {
-                                    // Nothing to write
-                                    trace!("Nothing left to write, wrote? {}",  wrote);
-
-                                    // If we have written something in this loop but we have not written until
-                                    // Would block then we should flush the connection
-                                    if wrote {
-                                        match socket.flush() {
-                                            Ok(_) => {}
-                                            Err(ref err) if would_block(err) => break,
-                                            Err(ref err) if interrupted(err) => continue,
-                                            Err(err) => { return Err(err); }
-                                        };
+                                        return Ok(ConnectionResult::ConnectionBroken);
                                     }
+                                };
 
-                                    break;
-                                }
+                                // We have something to write
+                                *write_buf = Some(buffer.track(notifier));
+
+                                write_buf.as_mut().unwrap()
                             }
-                        };
+                            None => {
+                                // Nothing to write
+                                trace!("Nothing left to write, wrote? {}",  wrote);
+
+                                // If we have written something in this loop but we have not written until
+                                // Would block then we should flush the connection
+                                if wrote {
+                                    match socket.flush() {
+                                        Ok(_) => {}
+                                        Err(ref err) if would_block(err) => break,
+                                        Err(ref err) if interrupted(err) => continue,
+                                        Err(err) => { return Err(err); }
+                                    };
+                                }
 
-                        match conn_util::try_write_until_block(socket, writing).expect("Failed to write to socket") {
-                            ConnectionWriteWork::ConnectionBroken => {
-                                return Ok(ConnectionResult::ConnectionBroken);
+                                break;
                             }
-                            ConnectionWriteWork::Working => { break; }
-                            ConnectionWriteWork::Done => {
-                                *write_buf = None;
+                        }
+                    };
+
+                    match conn_util::try_write_until_block(socket, writing) {
+                        Ok(ConnectionWriteWork::ConnectionBroken) | Err(_) => {
+                            return Ok(ConnectionResult::ConnectionBroken);
+                        }
+                        Ok(ConnectionWriteWork::Working) => { break; }
+                        Ok(ConnectionWriteWork::Done) => {
+                            if let Some(mut written) = write_buf.take() {
+                                written.take_notifier().delivered();
                             }
                         }
                     }
+                }
 
-                    if write_buf.is_none() && was_waiting_for_write {
-                        // We have nothing more to write, so we no longer need to be notified of writability
-                        self.poll.registry().reregister(socket, token, Interest::READABLE)?;
-                    } else if write_buf.is_some() && !was_waiting_for_write {
-                        // We still have something to write but we reached a would block state,
-                        // so we need to be notified of writability.
-                        self.poll.registry().reregister(socket, token, Interest::READABLE.add(Interest::WRITABLE))?;
-                    } else {
-                        // We have nothing to write and we were not waiting for writability, so we
-                        // Don't need to re register
-                        // Or we have something to write and we were already waiting for writability,
-                        // So we also don't have to re register
+                if !wrote && socket.has_pending_output() {
+                    // We have no messages to write, but the TLS session still has records to send
+                    match socket.flush() {
+                        Ok(_) => {}
+                        Err(ref err) if would_block(err) || interrupted(err) => {}
+                        Err(_) => { return Ok(ConnectionResult::ConnectionBroken); }
                     }
                 }
+
+                let needs_write = write_buf.is_some() || socket.has_pending_output();
+
+                if !needs_write && was_waiting_for_write {
+                    // We have nothing more to write, so we no longer need to be notified of writability
+                    self.poll.registry().reregister(socket, token, Interest::READABLE)?;
+
+                    *write_interest = false;
+                } else if needs_write && !was_waiting_for_write {
+                    // We still have something to write but we reached a would block state,
+                    // so we need to be notified of writability.
+                    self.poll.registry().reregister(socket, token, Interest::READABLE.add(Interest::WRITABLE))?;
+
+                    *write_interest = true;
+                } else {
+                    // We have nothing to write and we were not waiting for writability, so we
+                    // Don't need to re register
+                    // Or we have something to write and we were already waiting for writability,
+                    // So we also don't have to re register
+                }
             }
             _ => unreachable!()
         }
@@ -461,13 +761,28 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
         Ok(ConnectionResult::Working)
     }
 
+    /// Does the connection with the given token still have handshake frames or
+    /// TLS records waiting to be written
+    fn has_pending_output(&self, token: Token) -> bool {
+        match self.currently_accepting.get(token.into()) {
+            Some(PendingConnection::PendingConn { socket, write_buf, .. }) => write_buf.is_some() || socket.has_pending_output(),
+            _ => false
+        }
+    }
+
     fn handle_connection_readable(&mut self, token: Token) -> io::Result<ConnectionResult> {
         let connection = &mut self.currently_accepting[token.into()];
         trace!("{:?} // Handling read event for connection {:?}", self.my_id, token);
 
         let result = match connection {
-            PendingConnection::PendingConn { peer_id, socket, read_buf, .. } => {
-                if let Ok(read) = conn_util::read_until_block(socket, read_buf) {
+            PendingConnection::PendingConn { peer_id, node_type, socket, read_buf, write_buf, auth_state, peer_key, wire_version, connect_deadline, .. } => {
+                let read = conn_util::read_until_block(socket, read_buf);
+
+                if let Err(err) = &read {
+                    debug!("{:?} // Failed to read from pending connection with token {:?}: {:?}", self.my_id, token, err);
+                }
+
+                if let Ok(read) = read {
                     match read {
                         ConnectionReadWork::ConnectionBroken => {
                             ConnectionResult::ConnectionBroken
@@ -476,225 +791,250 @@ impl<NI, RM, PM> ServerWorker<NI, RM, PM>
                             ConnectionResult::Working
                         }
                         ConnectionReadWork::WorkingAndReceived(received) | ConnectionReadWork::ReceivedAndDone(received) => {
-                            if let Some((header, message)) = received.first() {
-                                if peer_id.is_none() {
-                                    *peer_id = Some(header.from());
-
-                                    // Check the general connections first as we add to this before removing from the pending connections
-                                    match self.peer_conns.get_connection(&header.from()) {
-                                        None => {
-                                            match self.registered_conns.get_pending_conn(&header.from()) {
-                                                None => {
-                                                    let node_type = self.peer_conns.network_info.get_node_type(&header.from());
-
-                                                    debug!("Received connection ID for token {:?}, from {:?}, node type is: {:?} (None means unknown)", token, header.from(), node_type);
-
-                                                    if let Some(node_type) = node_type {
-                                                        return Ok(ConnectionResult::Connected(header.from(), node_type, received));
-                                                    } else {
-                                                        let to_send = conn_util::initialize_send_channel();
-
-                                                        self.registered_conns.insert_pending_connection(PendingConnHandle::new(peer_id.unwrap(), to_send, self.waker.clone()))
-                                                    }
-                                                }
-                                                Some(conn) => {
-                                                    connection.fill_channel(conn.channel().clone());
-                                                }
+                            let mut received = received.into_iter();
+
+                            // The node we have just authenticated with this batch of frames, if any,
+                            // and whether we knew its key (instead of relying on the key it presented)
+                            let mut authenticated = None;
+
+                            while !matches!(auth_state, HandshakeState::Authenticated) {
+                                let (header, payload) = if let Some(frame) = received.next() {
+                                    frame
+                                } else {
+                                    break;
+                                };
+
+                                match *auth_state {
+                                    HandshakeState::AwaitingChallenge { hello_nonce } => {
+                                        let peer = peer_id.expect("Connections we initiate always know their peer");
+
+                                        let public_key = self.network_info.get_public_key(&peer);
+
+                                        let binding = socket.channel_binding().unwrap_or_default();
+
+                                        match handshake::verify_challenge(public_key.as_ref(), &header, self.my_id, peer, hello_nonce,
+                                                                          self.conn_handler.max_wire_version(), &binding) {
+                                            Ok(version) => *wire_version = version,
+                                            Err(err) => {
+                                                warn!("{:?} // Failed to authenticate node {:?} on token {:?}: {:?}", self.my_id, peer, token, err);
+
+                                                return Ok(ConnectionResult::ConnectionBroken);
                                             }
                                         }
-                                        Some(conn) => {
-                                            // This node is already known to us, we don't have to wait for reconfiguration messages
-                                            let channel = conn.to_send.clone();
 
-                                            connection.fill_channel(channel);
+                                        *peer_key = public_key;
+
+                                        // Prove our identity to the peer
+                                        let response_msg = handshake::response(&**self.network_info.get_key_pair(), self.my_id, peer,
+                                                                               header.nonce(), *wire_version, &binding);
+
+                                        match WritingBuffer::init_from_message(response_msg, *wire_version) {
+                                            Ok(buf) => *write_buf = Some(buf),
+                                            Err(err) => {
+                                                error!("{:?} // Failed to serialize handshake response for token {:?}: {:?}", self.my_id, token, err);
 
-                                            return Ok(ConnectionResult::Connected(header.from(), conn.node_type, received));
+                                                return Ok(ConnectionResult::ConnectionBroken);
+                                            }
                                         }
-                                    }
-                                }
-                            }
 
-                            for (header, message) in received {
-                                // The first header, sent just after the connection is established, is just so we can identify the peer
-                                if header.payload_length() > 0 {
-                                    cpu_workers::deserialize_and_push_reconf_message::<RM, PM>(header, message, self.reconf_handling.clone());
-                                }
-                            }
+                                        debug!("{:?} // Authenticated connection to {:?} on token {:?}, using wire version {}", self.my_id, peer, token, *wire_version);
 
-                            ConnectionResult::Working
-                        }
-                    }
-                } else {
-                    ConnectionResult::Working
-                }
-            }
-            _ => unreachable!()
-        };
+                                        *auth_state = HandshakeState::Authenticated;
 
+                                        authenticated = Some((peer, true));
+                                    }
+                                    HandshakeState::AwaitingHello => {
+                                        if header.to() != self.my_id {
+                                            warn!("{:?} // Received invalid hello from {:?} on token {:?}, discarding connection", self.my_id, header.from(), token);
 
-        Ok(result)
-    }
-}
+                                            return Ok(ConnectionResult::ConnectionBroken);
+                                        }
 
+                                        match handshake::presented_key(&header, &payload[..]) {
+                                            Ok(presented) => *peer_key = Some(presented),
+                                            Err(err) => {
+                                                warn!("{:?} // Received invalid hello from {:?} on token {:?}, discarding connection: {:?}", self.my_id, header.from(), token, err);
 
-impl ConnectionHandler {
-    pub(super) fn initialize(my_id: NodeId, conn_count: ConnCounts) -> Self {
-        Self {
-            my_id,
-            concurrent_conn: conn_count,
-            currently_connecting: Mutex::new(Default::default()),
-        }
-    }
+                                                return Ok(ConnectionResult::ConnectionBroken);
+                                            }
+                                        }
 
-    /// Register that we are currently attempting to connect to a node.
-    /// Returns true if we can attempt to connect to this node, false otherwise
-    /// We may not be able to connect to a given node if the amount of connections
-    /// being established already overtakes the limit of concurrent connections
-    fn register_connecting_to_node<NI>(&self, peer_id: NodeId, network_info: &NI) -> bool where NI: NetworkInformationProvider {
-        let mut connecting_guard = self.currently_connecting.lock().unwrap();
+                                        *wire_version = handshake::select_version(&header, self.conn_handler.max_wire_version());
 
-        let value = connecting_guard.entry(peer_id).or_insert(0);
+                                        // Challenge the peer to prove it is who it claims to be
+                                        let challenge = self.rng.next_state();
 
-        *value += 1;
+                                        let challenge_msg = handshake::challenge(&**self.network_info.get_key_pair(),
+                                                                                 self.my_id, header.from(),
+                                                                                 header.nonce(), challenge, *wire_version,
+                                                                                 &socket.channel_binding().unwrap_or_default());
 
-        if *value > self.concurrent_conn.get_connections_to_node(self.my_id(), peer_id, network_info) * 2 {
-            *value -= 1;
+                                        match WritingBuffer::init_from_message(challenge_msg, *wire_version) {
+                                            Ok(buf) => *write_buf = Some(buf),
+                                            Err(err) => {
+                                                error!("{:?} // Failed to serialize handshake challenge for token {:?}: {:?}", self.my_id, token, err);
 
-            false
-        } else {
-            true
-        }
-    }
+                                                return Ok(ConnectionResult::ConnectionBroken);
+                                            }
+                                        }
 
-    /// Register that we are done connecting to a given node (The connection was either successful or failed)
-    fn done_connecting_to_node(&self, peer_id: &NodeId) {
-        let mut connection_guard = self.currently_connecting.lock().unwrap();
+                                        *auth_state = HandshakeState::AwaitingResponse { claimed_id: header.from(), challenge };
+                                    }
+                                    HandshakeState::AwaitingResponse { claimed_id, challenge } => {
+                                        // Nodes we don't know yet (e.g. joining through reconfiguration) can only
+                                        // prove they hold the key they presented, which is vouched for later on
+                                        let known_key = self.network_info.get_public_key(&claimed_id);
 
-        connection_guard.entry(peer_id.clone()).and_modify(|value| { *value -= 1 });
+                                        let known = known_key.is_some();
 
-        if let Some(connection_count) = connection_guard.get(peer_id) {
-            if *connection_count <= 0 {
-                connection_guard.remove(peer_id);
-            }
-        }
-    }
+                                        if let Some(known_key) = known_key {
+                                            *peer_key = Some(known_key);
+                                        }
 
-    pub fn connect_to_node<NI, RM, PM>(self: &Arc<Self>, connections: Arc<Connections<NI, RM, PM>>,
-                                       peer_id: NodeId, peer_node_type: NodeType, addr: PeerAddr) -> OneShotRx<Result<()>>
-        where
-            NI: NetworkInformationProvider + 'static,
-            RM: Serializable + 'static,
-            PM: Serializable + 'static {
-        let (tx, rx) = channel::new_oneshot_channel();
+                                        let public_key = peer_key.as_ref().expect("The hello presents the key of the peer");
 
-        debug!(" {:?} // Connecting to node {:?} at {:?}", self.my_id(), peer_id, addr);
+                                        let binding = socket.channel_binding().unwrap_or_default();
 
-        let conn_handler = Arc::clone(self);
+                                        if let Err(err) = handshake::verify_response(public_key, &header, self.my_id, claimed_id, challenge, &binding) {
+                                            warn!("{:?} // Failed to authenticate connection claiming to be {:?} on token {:?}: {:?}", self.my_id, claimed_id, token, err);
 
-        if !self.register_connecting_to_node(peer_id, &*connections.network_info) {
-            warn!("{:?} // Tried to connect to node that I'm already connecting to {:?}",
-                conn_handler.my_id(), peer_id);
+                                            return Ok(ConnectionResult::ConnectionBroken);
+                                        }
 
-            let _ = tx.send(Err!(ConnectionEstablishError::AlreadyConnectingToNode(peer_id)));
+                                        debug!("{:?} // Authenticated connection from {:?} on token {:?}, using wire version {} (known key: {})", self.my_id, claimed_id, token, *wire_version, known);
 
-            return rx;
-        }
+                                        *auth_state = HandshakeState::Authenticated;
 
-        std::thread::Builder::new()
-            .name(format!("Connecting to Node {:?}", peer_id))
-            .spawn(move || {
-
-                //Get the correct IP for us to address the node
-                //If I'm a client I will always use the client facing addr
-                //While if I'm a replica I'll connect to the replica addr (clients only have this addr)
-                let addr = (addr.clone().into_inner());
-
-                const SECS: u64 = 1;
-                const RETRY: usize = 3 * 60;
-
-                let mut rng = prng::State::new();
-
-                let nonce = rng.next_state();
-
-                let my_id = conn_handler.my_id();
-
-                // NOTE:
-                // ========
-                //
-                // 1) not an issue if `tx` is closed, this is not a
-                // permanently running task, so channel send failures
-                // are tolerated
-                //
-                // 2) try to connect up to `RETRY` times, then announce
-                // failure
-                for _try in 0..RETRY {
-                    debug!("Attempting to connect to node {:?} with addr {:?} for the {} time", peer_id, addr, _try);
-
-                    match socket::connect_sync(addr.0) {
-                        Ok(mut sock) => {
-
-                            // create header
-                            let (header, _) =
-                                WireMessage::new(my_id, peer_id,
-                                                 Bytes::new(), nonce,
-                                                 None, None).into_inner();
-
-                            // serialize header
-                            let mut buf = [0; Header::LENGTH];
-                            header.serialize_into(&mut buf[..]).unwrap();
-
-                            // send header
-                            if let Err(err) = sock.write_all(&buf[..]) {
-                                // errors writing -> faulty connection;
-                                // drop this socket
-                                error!("{:?} // Failed to connect to the node {:?} {:?} ", conn_handler.my_id(), peer_id, err);
-                                break;
+                                        authenticated = Some((claimed_id, known));
+                                    }
+                                    HandshakeState::Connecting { .. } | HandshakeState::Authenticated => unreachable!()
+                                }
                             }
 
-                            if let Err(err) = sock.flush() {
-                                // errors flushing -> faulty connection;
-                                // drop this socket
-                                error!("{:?} // Failed to connect to the node {:?} {:?} ", conn_handler.my_id(), peer_id, err);
-                                break;
+                            let received = received.collect::<Vec<_>>();
+
+                            if let (Some((node, _)), Some(node_type), Some(_)) = (authenticated, *node_type, *connect_deadline) {
+                                // We initiated this connection, so the node is known to us. Our response
+                                // is written by the epoll worker that takes over the connection
+                                return Ok(ConnectionResult::Connected(node, node_type, received));
                             }
 
-                            // TLS handshake; drop connection if it fails
-                            let sock = SecureSocketSync::new_plain(sock);
+                            if let Some((node, known)) = authenticated {
+                                *peer_id = Some(node);
 
-                            info!("{:?} // Established connection to node {:?}", my_id, peer_id);
+                                // Check the general connections first as we add to this before removing from the pending connections
+                                match self.peer_conns.get_connection(&node) {
+                                    None => {
+                                        match self.registered_conns.get_pending_conn(&node) {
+                                            None => {
+                                                // Nodes that authenticated with the key they presented have to wait for the
+                                                // reconfiguration protocol to vouch for it, even if we know their type
+                                                let node_type = if known {
+                                                    self.peer_conns.network_info.get_node_type(&node)
+                                                } else {
+                                                    None
+                                                };
 
-                            connections.handle_connection_established(peer_id, SecureSocket::Sync(sock),
-                                                                      peer_node_type,
-                                                                      ReadingBuffer::init_with_size(Header::LENGTH),
-                                                                      None,
-                                                                      conn_util::initialize_send_channel());
+                                                debug!("Received connection ID for token {:?}, from {:?}, node type is: {:?} (None means unknown)", token, node, node_type);
 
-                            conn_handler.done_connecting_to_node(&peer_id);
+                                                if let Some(node_type) = node_type {
+                                                    return Ok(ConnectionResult::Connected(node, node_type, received));
+                                                } else {
+                                                    let to_send = conn_util::initialize_send_channel(self.peer_conns.priority_lanes_config());
 
-                            let _ = tx.send(Ok(()));
+                                                    let key = peer_key.clone().expect("Authenticated peers have a key");
 
-                            return;
-                        }
-                        Err(err) => {
-                            warn!("{:?} // Error on connecting to {:?} addr {:?}: {:?}",
-                                conn_handler.my_id(), peer_id, addr, err);
+                                                    self.registered_conns.insert_pending_connection(PendingConnHandle::new(node, key, to_send, self.waker.clone()));
+
+                                                    self.peer_conns.connection_events().notify(ConnectionEvent::PendingAuthorization(node));
+                                                }
+                                            }
+                                            Some(conn) => {
+                                                connection.fill_channel(conn.channel().clone());
+                                            }
+                                        }
+                                    }
+                                    Some(_) if !known => {
+                                        // A key nobody vouched for can't take over the connection of a node we know
+                                        warn!("{:?} // Node {:?} authenticated with a key we don't know on token {:?}, closing connection", self.my_id, node, token);
+
+                                        return Ok(ConnectionResult::ConnectionBroken);
+                                    }
+                                    Some(conn) => {
+                                        // This node is already known to us, we don't have to wait for reconfiguration messages
+                                        let channel = conn.to_send.clone();
+
+                                        connection.fill_channel(channel);
+
+                                        return Ok(ConnectionResult::Connected(node, conn.node_type, received));
+                                    }
+                                }
+                            }
+
+                            for (header, message) in received {
+                                let peer = peer_id.expect("Frames are only left over once the peer has been authenticated");
+
+                                // The handshake authenticated the peer, so it can't speak for any other node
+                                if header.from() != peer {
+                                    error!("{:?} // Received a frame from {:?} claiming to be from {:?}, closing connection", self.my_id, peer, header.from());
+
+                                    return Ok(ConnectionResult::ConnectionBroken);
+                                }
+
+                                if header.payload_length() > 0 {
+                                    cpu_workers::deserialize_and_push_reconf_message::<NI, RM, PM>(peer, header, message,
+                                                                                                   self.network_info.clone(),
+                                                                                                   self.peer_conns.verification_config().clone(),
+                                                                                                   self.peer_conns.replay_window().clone(),
+                                                                                                   None,
+                                                                                                   peer_key.clone(),
+                                                                                                   self.reconf_handling.clone());
+                                }
+                            }
+
+                            ConnectionResult::Working
                         }
                     }
-
-                    // sleep for `SECS` seconds and retry
-                    std::thread::sleep(Duration::from_secs(SECS));
+                } else {
+                    // Failing to read from a connection that hasn't yet been identified (for example,
+                    // a peer that does not speak TLS) means we should just discard it
+                    ConnectionResult::ConnectionBroken
                 }
+            }
+            _ => unreachable!()
+        };
+
+
+        Ok(result)
+    }
+}
+
+
+impl ConnectionHandler {
+    pub(super) fn initialize(my_id: NodeId, tls_config: TlsConfig, max_wire_version: u32, max_payload_length: usize) -> Self {
+        Self {
+            my_id,
+            tls_client_config: Arc::new(tls_config.sync_client_config),
+            tls_server_config: Arc::new(tls_config.sync_server_config),
+            max_wire_version,
+            max_payload_length,
+        }
+    }
 
-                conn_handler.done_connecting_to_node(&peer_id);
+    pub(super) fn max_wire_version(&self) -> u32 {
+        self.max_wire_version
+    }
 
-                // announce we have failed to connect to the peer node
-                //if we fail to connect, then just ignore
-                error!("{:?} // Failed to connect to the node {:?} ", conn_handler.my_id(), peer_id);
+    pub(super) fn max_payload_length(&self) -> usize {
+        self.max_payload_length
+    }
 
-                let _ = tx.send(Err!(ConnectionEstablishError::FailedToConnectToNode(peer_id)));
-            }).expect("Failed to allocate thread to establish connection");
+    pub(super) fn tls_client_config(&self) -> &Arc<ClientConfig> {
+        &self.tls_client_config
+    }
 
-        rx
+    pub(super) fn tls_server_config(&self) -> &Arc<ServerConfig> {
+        &self.tls_server_config
     }
 
     pub fn my_id(&self) -> NodeId {
@@ -708,7 +1048,7 @@ pub fn initialize_server<NI, RM, PM>(my_id: NodeId, listener: SyncListener,
                                      network_info: Arc<NI>,
                                      conns: Arc<Connections<NI, RM, PM>>,
                                      reconfiguration_handling: Arc<ReconfigurationMessageHandler<StoredMessage<RM::Message>>>,
-                                     network_update_channel: ChannelSyncRx<NetworkUpdate>) -> Arc<Waker>
+                                     network_update_channel: ChannelSyncRx<NetworkUpdate>) -> (Arc<Waker>, JoinHandle<()>)
     where NI: NetworkInformationProvider + 'static,
           RM: Serializable + 'static,
           PM: Serializable + 'static {
@@ -723,7 +1063,7 @@ pub fn initialize_server<NI, RM, PM>(my_id: NodeId, listener: SyncListener,
 
     let waker = server_worker.waker.clone();
 
-    std::thread::Builder::new()
+    let thread = std::thread::Builder::new()
         .name(format!("Server Worker {:?}", my_id))
         .spawn(move || {
             match server_worker.event_loop() {
@@ -734,12 +1074,12 @@ pub fn initialize_server<NI, RM, PM>(my_id: NodeId, listener: SyncListener,
             }
         }).expect("Failed to allocate thread for server worker");
 
-    waker
+    (waker, thread)
 }
 
 impl PendingConnection {
-    pub fn from_socket(socket: MioSocket) -> Self {
-        let read_buf = ReadingBuffer::init_with_size(Header::LENGTH);
+    pub fn from_socket(socket: SecureMioSocket, max_payload_length: usize) -> Self {
+        let read_buf = ReadingBuffer::init_with_size(Header::LENGTH, max_payload_length);
 
         Self::PendingConn {
             peer_id: None,
@@ -747,11 +1087,35 @@ impl PendingConnection {
             socket,
             read_buf,
             write_buf: None,
+            auth_state: HandshakeState::AwaitingHello,
+            peer_key: None,
+            wire_version: WireMessage::BASE_VERSION,
+            write_interest: false,
+            connect_deadline: None,
+            channel: None,
+        }
+    }
+
+    /// A connection we are initiating to a known node, which must be established by the deadline
+    fn outbound(socket: SecureMioSocket, peer_id: NodeId, node_type: NodeType, hello_nonce: u64,
+                deadline: Instant, max_payload_length: usize) -> Self {
+        Self::PendingConn {
+            peer_id: Some(peer_id),
+            node_type: Some(node_type),
+            socket,
+            read_buf: ReadingBuffer::init_with_size(Header::LENGTH, max_payload_length),
+            write_buf: None,
+            auth_state: HandshakeState::Connecting { hello_nonce },
+            peer_key: None,
+            wire_version: WireMessage::BASE_VERSION,
+            // We are registered for writability until the connection is established
+            write_interest: true,
+            connect_deadline: Some(deadline),
             channel: None,
         }
     }
 
-    fn fill_channel(&mut self, ch: (ChannelSyncTx<NetworkSerializedMessage>, ChannelSyncRx<NetworkSerializedMessage>)) {
+    fn fill_channel(&mut self, ch: Arc<SendQueue>) {
         match self {
             PendingConnection::PendingConn { channel, .. } => {
                 *channel = Some(ch);
@@ -779,8 +1143,10 @@ impl Debug for PendingConnection {
 
 #[derive(Error, Debug)]
 pub enum ConnectionEstablishError {
-    #[error("Failed to connect to node {0:?} as we are already connecting to that node")]
-    AlreadyConnectingToNode(NodeId),
+    #[error("Stopped connecting to node {0:?} as the connection was cancelled")]
+    ConnectionCancelled(NodeId),
     #[error("Failed to connect to node {0:?}")]
-    FailedToConnectToNode(NodeId)
+    FailedToConnectToNode(NodeId),
+    #[error("Not connecting to node {0:?} as we have reached the limit of pending connections")]
+    TooManyPendingConnections(NodeId),
 }
\ No newline at end of file
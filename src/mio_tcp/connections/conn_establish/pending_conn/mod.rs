@@ -1,16 +1,21 @@
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 use anyhow::Context;
 
 use dashmap::DashMap;
-use log::info;
+use log::{error, info, warn};
 use mio::Waker;
 
-use atlas_common::channel::{ChannelSyncRx, ChannelSyncTx};
+use atlas_common::crypto::signature::PublicKey;
 use atlas_common::error::*;
 use atlas_common::node_id::NodeId;
 
+use crate::conn_utils::Callback;
 use crate::message::{StoredMessage, WireMessage};
-use crate::mio_tcp::connections::{Connections, NetworkSerializedMessage};
+use crate::mio_tcp::connections::Connections;
+use crate::mio_tcp::connections::conn_establish::handshake;
+use crate::mio_tcp::connections::send_queue::{DeliveryNotifier, QueueSlot, SendQueue};
 use crate::reconfiguration_node::{NetworkInformationProvider, NetworkUpdateMessage, ReconfigurationMessageHandler};
 use crate::serialize::Serializable;
 
@@ -18,7 +23,9 @@ use crate::serialize::Serializable;
 #[derive(Clone)]
 pub struct PendingConnHandle {
     id: NodeId,
-    channel: (ChannelSyncTx<NetworkSerializedMessage>, ChannelSyncRx<NetworkSerializedMessage>),
+    /// The key the node authenticated with, which the reconfiguration protocol has to vouch for
+    key: PublicKey,
+    channel: Arc<SendQueue>,
     /// A correct node is not going to connect to the 2 different servers at the same time,
     /// even if he does, we can just use one server's connection to send the reconfiguration messages,
     /// So this should be just fine
@@ -37,6 +44,9 @@ pub struct NetworkUpdate {
     network_update: NetworkUpdateMessage,
 }
 
+// How often the network update handler checks whether we are shutting down
+const UPDATE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 /// handle updates sent from the reconfiguration protocol and then propagate them to the threads
 /// responsible for managing the currently pending connections, so they
 pub(crate) struct NetworkUpdateHandler<NI, RM, PM>
@@ -64,7 +74,7 @@ impl<RM, PM, NI> NetworkUpdateHandler<NI, RM, PM>
         registered_servers: RegisteredServers,
         pending_conns: Arc<ServerRegisteredPendingConns>,
         reconf_handle: Arc<ReconfigurationMessageHandler<StoredMessage<RM::Message>>>,
-        conns: Arc<Connections<NI, RM, PM>>) {
+        conns: Arc<Connections<NI, RM, PM>>) -> JoinHandle<()> {
         let handler = Self {
             server_conns: pending_conns,
             registered_servers,
@@ -76,7 +86,7 @@ impl<RM, PM, NI> NetworkUpdateHandler<NI, RM, PM>
             .name(format!("Network Update Handler Thread"))
             .spawn(move || {
                 handler.run();
-            }).expect("Failed to spawn NetworkUpdateHandler thread");
+            }).expect("Failed to spawn NetworkUpdateHandler thread")
     }
 
     pub fn registered_servers(&self) -> RegisteredServers {
@@ -84,16 +94,24 @@ impl<RM, PM, NI> NetworkUpdateHandler<NI, RM, PM>
     }
 
     fn run(self) {
-        loop {
-            let network_update = self.reconfiguration_handler.receive_network_update();
+        while !self.peer_conns.is_shutting_down() {
+            let network_update = self.reconfiguration_handler.try_receive_network_update(Some(UPDATE_POLL_INTERVAL));
 
-            if let Ok(network_update) = network_update {
+            if let Ok(Some(network_update)) = network_update {
                 match &network_update {
                     NetworkUpdateMessage::NodeConnectionPermitted(node_id, node_type, pk) => {
                         match self.server_conns.get_pending_conn(&node_id) {
                             None => {
                                 unreachable!("Received a connection permitted message for a node that is not pending connection. Node: {:?}", node_id)
                             }
+                            Some(conn) if !handshake::same_key(&conn.key, pk) => {
+                                warn!("Node {:?} was permitted with a key other than the one it authenticated with, not registering it", node_id);
+
+                                self.server_conns.remove_pending_connection(node_id);
+
+                                // The server workers close the connections that authenticated with the wrong key
+                                self.notify_servers(&conn, &network_update);
+                            }
                             Some(conn) => {
                                 info!("Received a connection permitted message for node {:?} of type {:?} from the reconfiguration protocol", node_id, node_type);
 
@@ -106,12 +124,7 @@ impl<RM, PM, NI> NetworkUpdateHandler<NI, RM, PM>
                                 // we ensure that the connection is not removed from the pending connections before the preemptive registration is complete
                                 self.server_conns.remove_pending_connection(node_id);
 
-                                self.registered_servers.registered_servers.lock().unwrap().iter().for_each(|tx|
-                                    tx.send_return(NetworkUpdate {
-                                        conn_handle: conn.clone(),
-                                        network_update: network_update.clone(),
-                                    }).unwrap()
-                                );
+                                self.notify_servers(&conn, &network_update);
                             }
                         }
                     }
@@ -119,6 +132,21 @@ impl<RM, PM, NI> NetworkUpdateHandler<NI, RM, PM>
             }
         }
     }
+
+    /// Deliver a network update to all server workers, so they can move the pending connections along
+    fn notify_servers(&self, conn: &PendingConnHandle, network_update: &NetworkUpdateMessage) {
+        self.registered_servers.registered_servers.lock().unwrap().iter().for_each(|tx| {
+            // The server worker is only gone if we are shutting down
+            let sent = tx.send_return(NetworkUpdate {
+                conn_handle: conn.clone(),
+                network_update: network_update.clone(),
+            });
+
+            if sent.is_err() {
+                error!("Failed to deliver the network update for node {:?} to a server worker", conn.id);
+            }
+        });
+    }
 }
 
 impl NetworkUpdate {
@@ -128,19 +156,20 @@ impl NetworkUpdate {
 }
 
 impl PendingConnHandle {
-    pub(crate) fn new(id: NodeId, channel: (ChannelSyncTx<NetworkSerializedMessage>, ChannelSyncRx<NetworkSerializedMessage>),
-                      waker: Arc<Waker>) -> Self {
-        Self { id, channel, waker }
+    pub(crate) fn new(id: NodeId, key: PublicKey, channel: Arc<SendQueue>, waker: Arc<Waker>) -> Self {
+        Self { id, key, channel, waker }
     }
 
-    pub fn peer_message(&self, message: WireMessage) -> Result<()> {
-        self.channel.0.send(message)
+    /// Send the pending peer a given message, in the place it reserved in the send queue.
+    /// The callback is told whether the message was written to the connection
+    pub(crate) fn peer_message(&self, slot: QueueSlot, message: WireMessage, callback: Callback) -> Result<()> {
+        slot.push(message, DeliveryNotifier::new(callback))
             .context("Failed to place peer message into the peer connection channel")?;
 
         self.waker.wake().context("Failed to wake the server thread")
     }
 
-    pub fn channel(&self) -> &(ChannelSyncTx<NetworkSerializedMessage>, ChannelSyncRx<NetworkSerializedMessage>) {
+    pub(crate) fn channel(&self) -> &Arc<SendQueue> {
         &self.channel
     }
 }
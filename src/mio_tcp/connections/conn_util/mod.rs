@@ -1,18 +1,25 @@
 use std::io;
 use std::io::{Read, Write};
+use std::sync::Arc;
 use bytes::{Buf, Bytes, BytesMut};
-use log::{debug, trace};
-use atlas_common::{channel, Err};
-use atlas_common::channel::{ChannelSyncRx, ChannelSyncTx};
-use atlas_common::socket::MioSocket;
+use log::{debug, trace, warn};
+use atlas_common::Err;
+use atlas_metrics::metrics::metric_increment;
+use crate::compression;
+use crate::config::PriorityLanesConfig;
 use crate::message::{Header, WireMessage};
-use crate::mio_tcp::connections::{NetworkSerializedMessage, SEND_QUEUE_SIZE};
+use crate::metric::COMM_OVERSIZED_PAYLOADS_ID;
+use crate::mio_tcp::connections::{MioError, SEND_QUEUE_SIZE};
+use crate::mio_tcp::connections::send_queue::{DeliveryNotifier, SendQueue};
+use crate::mio_tcp::connections::tls::SecureMioSocket;
 
 /// The reading buffer for a connection
 pub(crate) struct ReadingBuffer {
     pub(super) read_bytes: usize,
     pub(super)current_header: Option<Header>,
     pub(super) read_buffer: BytesMut,
+    // The largest payload we accept to read, before even allocating space for it
+    pub(super) max_payload_length: usize,
 }
 
 /// The writing buffer for a TCP connection
@@ -20,6 +27,8 @@ pub(crate) struct WritingBuffer {
     written_bytes: usize,
     current_header: Option<Bytes>,
     current_message: Bytes,
+    // Told once this frame has been written (only for the last frame of a tracked message)
+    notifier: DeliveryNotifier,
 }
 
 /// Result of trying to write until block in a socket
@@ -43,7 +52,7 @@ pub(super) enum ConnectionReadWork {
     ReceivedAndDone(Vec<(Header, BytesMut)>),
 }
 
-pub(super) fn try_write_until_block(socket: &mut MioSocket, writing_buffer: &mut WritingBuffer) -> atlas_common::error::Result<ConnectionWriteWork> {
+pub(super) fn try_write_until_block(socket: &mut SecureMioSocket, writing_buffer: &mut WritingBuffer) -> atlas_common::error::Result<ConnectionWriteWork> {
     loop {
         if let Some(header) = writing_buffer.current_header.as_ref() {
             match socket.write(&header[writing_buffer.written_bytes..]) {
@@ -95,7 +104,11 @@ pub(super) fn try_write_until_block(socket: &mut MioSocket, writing_buffer: &mut
     Ok(ConnectionWriteWork::Working)
 }
 
-pub(super) fn read_until_block(socket: &mut MioSocket, read_info: &mut ReadingBuffer) -> atlas_common::error::Result<ConnectionReadWork> {
+/// Read frames from the socket until it would block.
+/// Frames that announce a payload larger than the maximum payload length of the reading buffer
+/// are rejected with [MioError::PayloadTooLarge], without allocating space for them, in which
+/// case the connection must be closed (we can no longer know where the next frame starts)
+pub(super) fn read_until_block<R: Read>(socket: &mut R, read_info: &mut ReadingBuffer) -> atlas_common::error::Result<ConnectionReadWork> {
     let mut read_messages = Vec::new();
 
     loop {
@@ -109,7 +122,8 @@ pub(super) fn read_until_block(socket: &mut MioSocket, read_info: &mut ReadingBu
                     Ok(0) => {
                         // Connection closed
                         debug!("Connection closed while reading body bytes to read: {},  currently read: {}", bytes_to_read, currently_read);
-                        return Ok(ConnectionReadWork::ConnectionBroken);
+
+                        return Ok(closed_after(read_messages));
                     }
                     Ok(n) => {
                         // We still have more to read
@@ -151,7 +165,8 @@ pub(super) fn read_until_block(socket: &mut MioSocket, read_info: &mut ReadingBu
                     Ok(0) => {
                         // Connection closed
                         debug!("Connection closed while reading header bytes to read {}, current read bytes {}", bytes_to_read, currently_read_bytes);
-                        return Ok(ConnectionReadWork::ConnectionBroken);
+
+                        return Ok(closed_after(read_messages));
                     }
                     Ok(n) => {
                         // We still have to more to read
@@ -168,7 +183,17 @@ pub(super) fn read_until_block(socket: &mut MioSocket, read_info: &mut ReadingBu
             };
 
             if n >= bytes_to_read {
-                let header = Header::deserialize_from(&read_info.read_buffer[..Header::LENGTH])?;
+                let header = Header::deserialize_versioned(&read_info.read_buffer[..Header::LENGTH])?;
+
+                // The header has not been authenticated yet, so we can't trust its length
+                if header.length > read_info.max_payload_length as u64 {
+                    warn!("Received a header claiming a payload of {} bytes (from {:?}), which is over the limit of {} bytes",
+                        header.length, header.from(), read_info.max_payload_length);
+
+                    metric_increment(COMM_OVERSIZED_PAYLOADS_ID, Some(1));
+
+                    return Err!(MioError::PayloadTooLarge(header.length, read_info.max_payload_length));
+                }
 
                 *(&mut read_info.current_header) = Some(header);
 
@@ -203,6 +228,15 @@ pub(super) fn read_until_block(socket: &mut MioSocket, read_info: &mut ReadingBu
     }
 }
 
+/// The connection was closed by the peer, after it sent us the given messages
+/// (which must still be handled, as they might explain why it was closed)
+fn closed_after(read_messages: Vec<(Header, BytesMut)>) -> ConnectionReadWork {
+    if read_messages.is_empty() {
+        ConnectionReadWork::ConnectionBroken
+    } else {
+        ConnectionReadWork::ReceivedAndDone(read_messages)
+    }
+}
 
 pub(crate) fn would_block(err: &io::Error) -> bool {
     err.kind() == io::ErrorKind::WouldBlock
@@ -213,15 +247,16 @@ pub(crate) fn interrupted(err: &io::Error) -> bool {
 }
 
 impl ReadingBuffer {
-    pub fn init() -> Self {
+    pub fn init(max_payload_length: usize) -> Self {
         Self {
             read_bytes: 0,
             current_header: None,
             read_buffer: BytesMut::with_capacity(Header::LENGTH),
+            max_payload_length,
         }
     }
 
-    pub fn init_with_size(size: usize) -> Self {
+    pub fn init_with_size(size: usize, max_payload_length: usize) -> Self {
         let mut read_buf = BytesMut::with_capacity(size);
 
         read_buf.resize(size, 0);
@@ -230,19 +265,36 @@ impl ReadingBuffer {
             read_bytes: 0,
             current_header: None,
             read_buffer: read_buf,
+            max_payload_length,
         }
     }
 }
 
 impl WritingBuffer {
-    pub fn init_from_message(message: WireMessage) -> atlas_common::error::Result<Self> {
+    /// Prepare a message to be written, encoding its header with the given
+    /// wire protocol version (the one negotiated with the peer we are writing to)
+    pub fn init_from_message(message: WireMessage, wire_version: u32) -> atlas_common::error::Result<Self> {
         let (header, payload) = message.into_inner();
 
+        Self::init_from_parts(header, payload, wire_version)
+    }
+
+    /// Prepare a frame to be written, from its header and payload (see [WritingBuffer::init_from_message])
+    pub fn init_from_parts(mut header: Header, mut payload: Bytes, wire_version: u32) -> atlas_common::error::Result<Self> {
+        if header.is_compressed() && wire_version < WireMessage::FLAGS_VERSION {
+            // The peer has no way of knowing this payload is compressed.
+            // The digest covers the uncompressed payload, so the message remains valid
+            payload = compression::decompress(&header, &payload[..])?.freeze();
+
+            header.set_compressed(false);
+            header.length = payload.len() as u64;
+        }
+
         let mut header_bytes = BytesMut::with_capacity(Header::LENGTH);
 
         header_bytes.resize(Header::LENGTH, 0);
 
-        header.serialize_into(&mut header_bytes[..Header::LENGTH])?;
+        header.serialize_versioned(wire_version, &mut header_bytes[..Header::LENGTH])?;
 
         let header_bytes = header_bytes.freeze();
 
@@ -250,10 +302,166 @@ impl WritingBuffer {
             written_bytes: 0,
             current_header: Some(header_bytes),
             current_message: payload,
+            notifier: DeliveryNotifier::untracked(),
         })
     }
+
+    /// How many bytes this frame takes on the wire
+    pub fn frame_len(&self) -> usize {
+        self.current_header.as_ref().map_or(0, |header| header.len()) + self.current_message.len()
+    }
+
+    /// Tell the given notifier once this frame has been written
+    pub fn track(mut self, notifier: DeliveryNotifier) -> Self {
+        self.notifier = notifier;
+
+        self
+    }
+
+    /// Take the notifier of this frame, to be told once it has been flushed out of the socket
+    pub fn take_notifier(&mut self) -> DeliveryNotifier {
+        std::mem::replace(&mut self.notifier, DeliveryNotifier::untracked())
+    }
+}
+
+pub fn initialize_send_channel(lanes: &PriorityLanesConfig) -> Arc<SendQueue> {
+    SendQueue::new(SEND_QUEUE_SIZE, lanes)
 }
 
-pub fn initialize_send_channel() -> (ChannelSyncTx<NetworkSerializedMessage>, ChannelSyncRx<NetworkSerializedMessage>) {
-    channel::new_bounded_sync(SEND_QUEUE_SIZE, Some("Network Msg"))
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Read;
+
+    use bytes::Bytes;
+
+    use atlas_common::node_id::NodeId;
+
+    use crate::message::{Header, WireMessage};
+    use crate::mio_tcp::connections::conn_util::{ConnectionReadWork, read_until_block, ReadingBuffer};
+
+    const MAX_PAYLOAD: usize = 64 * 1024;
+
+    /// A socket that hands out the given bytes in chunks of random size, then would block
+    struct ChunkedSocket {
+        data: Vec<u8>,
+        position: usize,
+        max_chunk: usize,
+    }
+
+    impl Read for ChunkedSocket {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.position >= self.data.len() {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+
+            let chunk = fastrand::usize(1..=self.max_chunk)
+                .min(buf.len())
+                .min(self.data.len() - self.position);
+
+            buf[..chunk].copy_from_slice(&self.data[self.position..self.position + chunk]);
+
+            self.position += chunk;
+
+            Ok(chunk)
+        }
+    }
+
+    fn socket_with(data: Vec<u8>) -> ChunkedSocket {
+        ChunkedSocket {
+            data,
+            position: 0,
+            max_chunk: fastrand::usize(1..=256),
+        }
+    }
+
+    fn frame(payload_length: usize) -> Vec<u8> {
+        let payload = Bytes::from((0..payload_length).map(|_| fastrand::u8(..)).collect::<Vec<_>>());
+
+        let message = WireMessage::new(NodeId::from(fastrand::u32(..)), NodeId::from(fastrand::u32(..)),
+                                       payload.clone(), fastrand::u64(..), None, None);
+
+        let (header, payload) = message.into_inner();
+
+        let mut buf = vec![0; Header::LENGTH];
+
+        header.serialize_versioned(WireMessage::CURRENT_VERSION, &mut buf[..]).unwrap();
+
+        buf.extend_from_slice(&payload[..]);
+
+        buf
+    }
+
+    /// Read everything the socket has, returning the received frames (or the error that stopped the reading)
+    fn read_all(socket: &mut ChunkedSocket, read_info: &mut ReadingBuffer) -> atlas_common::error::Result<Vec<(Header, usize)>> {
+        let mut received = Vec::new();
+
+        while socket.position < socket.data.len() {
+            match read_until_block(socket, read_info)? {
+                ConnectionReadWork::WorkingAndReceived(frames) | ConnectionReadWork::ReceivedAndDone(frames) => {
+                    received.extend(frames.into_iter().map(|(header, payload)| (header, payload.len())));
+                }
+                ConnectionReadWork::Working => {}
+                ConnectionReadWork::ConnectionBroken => break,
+            }
+
+            assert!(read_info.read_buffer.capacity() <= MAX_PAYLOAD + Header::LENGTH);
+        }
+
+        Ok(received)
+    }
+
+    #[test]
+    fn test_random_headers_are_bounded() {
+        for _ in 0..1000 {
+            let mut data = (0..Header::LENGTH).map(|_| fastrand::u8(..)).collect::<Vec<_>>();
+
+            // Give the reader something to chew on, in case the header claims a small payload
+            data.extend((0..fastrand::usize(0..1024)).map(|_| fastrand::u8(..)));
+
+            let mut read_info = ReadingBuffer::init_with_size(Header::LENGTH, MAX_PAYLOAD);
+
+            let header = Header::deserialize_from(&data[..Header::LENGTH]).unwrap();
+
+            match read_all(&mut socket_with(data), &mut read_info) {
+                Ok(received) => {
+                    assert!(header.payload_length() <= MAX_PAYLOAD);
+                    assert!(received.iter().all(|(header, length)| *length == header.payload_length()));
+                }
+                Err(_) => assert!(header.payload_length() > MAX_PAYLOAD),
+            }
+        }
+    }
+
+    #[test]
+    fn test_oversized_payload_is_rejected() {
+        for length in [MAX_PAYLOAD as u64 + 1, u32::MAX as u64, u64::MAX] {
+            let mut data = frame(0);
+
+            let mut header = Header::deserialize_versioned(&data[..Header::LENGTH]).unwrap();
+            header.length = length;
+            header.serialize_into(&mut data[..Header::LENGTH]).unwrap();
+
+            let mut read_info = ReadingBuffer::init_with_size(Header::LENGTH, MAX_PAYLOAD);
+
+            assert!(read_all(&mut socket_with(data), &mut read_info).is_err());
+        }
+    }
+
+    #[test]
+    fn test_valid_frames_are_read() {
+        for _ in 0..100 {
+            let lengths = (0..fastrand::usize(1..16))
+                .map(|_| fastrand::usize(0..=MAX_PAYLOAD / 8))
+                .collect::<Vec<_>>();
+
+            let data = lengths.iter().flat_map(|length| frame(*length)).collect::<Vec<_>>();
+
+            let mut read_info = ReadingBuffer::init_with_size(Header::LENGTH, MAX_PAYLOAD);
+
+            let received = read_all(&mut socket_with(data), &mut read_info).unwrap();
+
+            assert_eq!(received.iter().map(|(_, length)| *length).collect::<Vec<_>>(), lengths);
+        }
+    }
 }
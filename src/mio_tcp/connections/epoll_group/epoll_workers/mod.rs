@@ -2,22 +2,32 @@ use std::io;
 use std::io::{Read, Write};
 use std::net::Shutdown;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use anyhow::Context;
 use bytes::{Buf, Bytes, BytesMut};
-use log::{error, info, trace};
-use mio::{Events, Interest, Poll, Token, Waker};
+use log::{error, info, trace, warn};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
 use mio::event::Event;
 use slab::Slab;
 use atlas_common::channel::{ChannelSyncRx};
 use atlas_common::Err;
 use atlas_common::node_id::NodeId;
-use atlas_common::socket::{MioSocket};
+use atlas_metrics::metrics::metric_increment;
+use crate::conn_utils::{ConnectionEvent, DisconnectReason, LeaveReason};
 use crate::cpu_workers;
-use crate::message::{Header, WireMessage};
+use crate::message::{AuthScheme, Header, MessageClass, WireMessage};
+use crate::message_signing::{SessionKey, SessionMacError};
 use crate::mio_tcp::connections::{conn_util, Connections, ConnHandle};
 use crate::mio_tcp::connections::conn_util::{ConnectionReadWork, ConnectionWriteWork, ReadingBuffer, WritingBuffer};
 use crate::mio_tcp::connections::epoll_group::{EpollWorkerId, EpollWorkerMessage, NewConnection};
+use crate::metric::{COMM_HEARTBEAT_TIMEOUTS_ID, COMM_RECEIVE_THROTTLED_ID, COMM_SEND_THROTTLED_ID};
+use crate::mio_tcp::connections::fragmentation::{FragmentWriter, Reassembler};
+use crate::mio_tcp::connections::{busy, goodbye, heartbeat};
+use crate::mio_tcp::connections::busy::BusyReplies;
+use crate::mio_tcp::connections::goodbye::Leaving;
+use crate::mio_tcp::connections::heartbeat::{Heartbeat, HeartbeatStatus};
+use crate::mio_tcp::connections::send_queue::DeliveryNotifier;
+use crate::mio_tcp::connections::tls::SecureMioSocket;
 use crate::reconfiguration_node::NetworkInformationProvider;
 use super::PeerConnection;
 use crate::serialize::Serializable;
@@ -29,9 +39,11 @@ const WORKER_TIMEOUT: Option<Duration> = Some(Duration::from_millis(50));
 enum ConnectionWorkResult {
     Working,
     ConnectionBroken,
+    // The peer said goodbye (for the given reason), so the connection is closing
+    PeerLeft(LeaveReason),
 }
 
-type ConnectionRegister = ChannelSyncRx<MioSocket>;
+type ConnectionRegister = ChannelSyncRx<SecureMioSocket>;
 
 /// The information for this worker thread.
 pub(super) struct EpollWorker<NI, RM, PM>
@@ -52,6 +64,9 @@ pub(super) struct EpollWorker<NI, RM, PM>
     // Waker
     waker: Arc<Waker>,
     waker_token: Token,
+    // When we have to be done closing our connections by, once we are shutting down.
+    // Connections handed to us while shutting down are closed right away
+    shutdown_deadline: Option<Instant>,
 }
 
 /// All information related to a given connection
@@ -62,12 +77,36 @@ enum SocketConnection<RM, PM>
         // The handle of this connection
         handle: ConnHandle,
         // The mio socket that this connection refers to
-        socket: MioSocket,
+        socket: SecureMioSocket,
         // Information and buffers for the read end of this connection
         read_info: ReadingBuffer,
         // Information and buffers for the write end of this connection
         // Option since we may not be writing anything at the moment
         writing_info: Option<WritingBuffer>,
+        // Are we currently registered for writability notifications on this socket?
+        write_interest: bool,
+        // Until when we have stopped reading from this connection, as the peer has used up its receive
+        // bandwidth. We are not registered for readability notifications in the meantime
+        read_throttled_until: Option<Instant>,
+        // Until when we have stopped writing to this connection, as we have used up our send bandwidth
+        write_throttled_until: Option<Instant>,
+        // The key used to authenticate messages sent with a session MAC over this connection
+        session_key: Option<SessionKey>,
+        // The wire protocol version negotiated for this connection, used to encode what we write
+        wire_version: u32,
+        // Splits the large messages we write into fragments
+        fragment_writer: FragmentWriter,
+        // Reassembles the fragmented messages we receive
+        reassembler: Reassembler,
+        // Tracks whether the peer is still answering on this connection
+        heartbeat: Heartbeat,
+        // Set once we are closing this connection on purpose, saying goodbye to the peer
+        leaving: Option<Leaving>,
+        // The replies telling the peer we refused some of its requests, as we are too busy
+        busy: BusyReplies,
+        // The tracked messages we have written, which may still be in the buffers of the TLS session.
+        // They are only delivered once those are flushed, so dropping the connection reports them as not delivered
+        unflushed: Vec<DeliveryNotifier>,
         // The connection to the peer this connection is a part of
         connection: Arc<PeerConnection<RM, PM>>,
     },
@@ -104,6 +143,7 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
             poll,
             waker,
             waker_token,
+            shutdown_deadline: None,
         })
     }
 
@@ -115,8 +155,10 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
 
         let waker_token = self.waker_token;
 
+        let mut last_heartbeat_check = Instant::now();
+
         loop {
-            if let Err(e) = self.poll.poll(&mut event_queue, WORKER_TIMEOUT) {
+            if let Err(e) = self.poll.poll(&mut event_queue, self.poll_timeout()) {
                 if e.kind() == io::ErrorKind::Interrupted {
                     // spurious wakeup
                     continue;
@@ -160,7 +202,7 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                                 error!("{:?} // Connection broken during reading. Deleting connection {:?} to node {:?}",
                                     my_id, token,peer_id);
 
-                                if let Err(err) = self.delete_connection(token, true) {
+                                if let Err(err) = self.delete_connection(token, DisconnectReason::ConnectionBroken) {
                                     error!("{:?} // Error deleting connection {:?} to node {:?}: {:?}",
                                         my_id, token, peer_id, err);
                                 }
@@ -176,7 +218,7 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                                 error!("{:?} // Error handling connection event: {:?} for token {:?} (corresponding to conn id {:?})",
                                             my_id, err, token, peer_id);
 
-                                if let Err(err) = self.delete_connection(token, true) {
+                                if let Err(err) = self.delete_connection(token, DisconnectReason::Error) {
                                     error!("{:?} // Error deleting connection {:?} to node {:?}: {:?}",
                                         my_id, token, peer_id, err);
                                 }
@@ -202,7 +244,21 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                             error!("{:?} // Connection broken during reading. Deleting connection {:?} to node {:?}",
                                     self.global_connections.id, token,peer_id);
 
-                            if let Err(err) = self.delete_connection(token, true) {
+                            if let Err(err) = self.delete_connection(token, DisconnectReason::ConnectionBroken) {
+                                error!("{:?} // Error deleting connection {:?} to node {:?}: {:?}",
+                                        my_id, token, peer_id, err);
+                            }
+                        }
+                        Ok(ConnectionWorkResult::PeerLeft(reason)) => {
+                            let peer_id = {
+                                let connection = &self.connections[token.into()];
+
+                                connection.peer_id().unwrap_or(NodeId::from(1234567u32))
+                            };
+
+                            info!("{:?} // Node {:?} said goodbye ({:?}). Closing connection {:?}", my_id, peer_id, reason, token);
+
+                            if let Err(err) = self.delete_connection(token, DisconnectReason::PeerLeft(reason)) {
                                 error!("{:?} // Error deleting connection {:?} to node {:?}: {:?}",
                                         my_id, token, peer_id, err);
                             }
@@ -218,7 +274,7 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                             error!("{:?} // Error handling connection event: {:?} for token {:?} (corresponding to conn id {:?})",
                                             self.global_connections.id, err, token, peer_id);
 
-                            if let Err(err) = self.delete_connection(token, true) {
+                            if let Err(err) = self.delete_connection(token, DisconnectReason::Error) {
                                 error!("{:?} // Error deleting connection {:?} to node {:?}: {:?}",
                                         my_id, token, peer_id, err);
                             }
@@ -227,10 +283,226 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                 }
             }
 
+            if last_heartbeat_check.elapsed() >= WORKER_TIMEOUT.unwrap() {
+                self.check_heartbeats();
+
+                last_heartbeat_check = Instant::now();
+            }
+
+            self.resume_throttled();
+
             self.register_connections()?;
+
+            let all_closed = self.drain_connections();
+
+            if self.shutdown_deadline.is_some() && all_closed {
+                info!("{:?} // Worker {} has closed all of its connections, shutting down", my_id, self.worker_id);
+
+                return Ok(());
+            }
+        }
+    }
+
+    /// Start closing the connection with the given token on purpose, saying goodbye to the peer
+    /// once everything queued on it has been written (see [Self::drain_connections])
+    fn leave_connection(&mut self, token: Token, reason: LeaveReason, deadline: Instant) {
+        if let Some(SocketConnection::PeerConn { leaving, .. }) = self.connections.get_mut(token.into()) {
+            match leaving {
+                // We keep the reason we gave first, but never wait longer than we were told to
+                Some(leaving) if leaving.deadline() <= deadline => {}
+                _ => *leaving = Some(Leaving::new(reason, deadline)),
+            }
         }
     }
 
+    /// Write what is left on the connections we are leaving, closing the ones we are done with
+    /// (or whose deadline has passed). Returns whether all of our connections are closed
+    fn drain_connections(&mut self) -> bool {
+        let now = Instant::now();
+
+        let to_drain = self.connections.iter()
+            .filter_map(|(slot, conn)| match conn {
+                SocketConnection::PeerConn { leaving: Some(leaving), .. } => Some((Token(slot), now >= leaving.deadline())),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+
+        for (token, expired) in to_drain {
+            let result = if expired {
+                Ok(ConnectionWorkResult::Working)
+            } else {
+                self.try_write_until_block(token)
+            };
+
+            let reason = match result {
+                Ok(ConnectionWorkResult::Working) if expired || self.is_drained(token) => Some(DisconnectReason::Requested),
+                Ok(ConnectionWorkResult::Working) => None,
+                Ok(ConnectionWorkResult::ConnectionBroken) => Some(DisconnectReason::ConnectionBroken),
+                Ok(ConnectionWorkResult::PeerLeft(reason)) => Some(DisconnectReason::PeerLeft(reason)),
+                Err(err) => {
+                    error!("{:?} // Error draining connection {:?}: {:?}", self.global_connections.id, token, err);
+
+                    Some(DisconnectReason::Error)
+                }
+            };
+
+            if let Some(reason) = reason {
+                if let Err(err) = self.delete_connection(token, reason) {
+                    error!("{:?} // Error deleting connection {:?}: {:?}", self.global_connections.id, token, err);
+                }
+            }
+        }
+
+        !self.connections.iter().any(|(_, conn)| matches!(conn, SocketConnection::PeerConn { .. }))
+    }
+
+    /// Have we written everything we had to on this connection, including our goodbye
+    fn is_drained(&self, token: Token) -> bool {
+        match self.connections.get(token.into()) {
+            Some(SocketConnection::PeerConn { leaving, writing_info, socket, fragment_writer, .. }) => {
+                leaving.as_ref().map_or(false, Leaving::said_goodbye) && writing_info.is_none() && !socket.has_pending_output() && !fragment_writer.has_pending_fragments()
+            }
+            _ => true
+        }
+    }
+
+    /// Ping the connections that have been idle for too long, and close the ones
+    /// whose peers have stopped answering
+    fn check_heartbeats(&mut self) {
+        let config = self.global_connections.heartbeat_config().clone();
+
+        let now = Instant::now();
+
+        let mut to_ping = Vec::new();
+        let mut timed_out = Vec::new();
+
+        for (slot, conn) in self.connections.iter_mut() {
+            if let SocketConnection::PeerConn { heartbeat, wire_version, handle, read_throttled_until, .. } = conn {
+                if !Heartbeat::is_supported(&config, *wire_version) {
+                    continue;
+                }
+
+                // While we hold back reading from the connection, we can't see the peer answering our pings
+                match heartbeat.check(&config, now, read_throttled_until.is_some()) {
+                    HeartbeatStatus::Alive => {}
+                    HeartbeatStatus::PingQueued => to_ping.push(Token(slot)),
+                    HeartbeatStatus::TimedOut(waited) => timed_out.push((Token(slot), handle.peer_id, waited)),
+                }
+            }
+        }
+
+        for (token, peer_id, waited) in timed_out {
+            warn!("{:?} // Connection {:?} to node {:?} has not answered our ping in {:?}, closing it",
+                self.global_connections.id, token, peer_id, waited);
+
+            metric_increment(COMM_HEARTBEAT_TIMEOUTS_ID, Some(1));
+
+            if let Err(err) = self.delete_connection(token, DisconnectReason::HeartbeatTimeout) {
+                error!("{:?} // Error deleting connection {:?} to node {:?}: {:?}",
+                    self.global_connections.id, token, peer_id, err);
+            }
+        }
+
+        for token in to_ping {
+            let failure = match self.try_write_until_block(token) {
+                Ok(ConnectionWorkResult::Working) => None,
+                Ok(ConnectionWorkResult::ConnectionBroken) => Some(DisconnectReason::ConnectionBroken),
+                Ok(ConnectionWorkResult::PeerLeft(reason)) => Some(DisconnectReason::PeerLeft(reason)),
+                Err(err) => {
+                    error!("{:?} // Error pinging connection {:?}: {:?}", self.global_connections.id, token, err);
+
+                    Some(DisconnectReason::Error)
+                }
+            };
+
+            if let Some(reason) = failure {
+                if let Err(err) = self.delete_connection(token, reason) {
+                    error!("{:?} // Error deleting connection {:?}: {:?}", self.global_connections.id, token, err);
+                }
+            }
+        }
+    }
+
+    /// How long we can wait for events, without missing the moment a throttled connection can be resumed
+    fn poll_timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+
+        self.connections.iter()
+            .filter_map(|(_, conn)| match conn {
+                SocketConnection::PeerConn { read_throttled_until, write_throttled_until, .. } => {
+                    (*read_throttled_until).into_iter().chain(*write_throttled_until).min()
+                }
+                _ => None
+            })
+            .map(|until| until.saturating_duration_since(now))
+            .chain(WORKER_TIMEOUT)
+            .min()
+    }
+
+    /// Resume reading from (and writing to) the connections we had stopped because of their
+    /// rate limits, once the time we were told to wait for has passed
+    fn resume_throttled(&mut self) {
+        let now = Instant::now();
+
+        let to_resume = self.connections.iter()
+            .filter_map(|(slot, conn)| match conn {
+                SocketConnection::PeerConn { read_throttled_until, write_throttled_until, .. } => {
+                    let read = read_throttled_until.map_or(false, |until| until <= now);
+                    let write = write_throttled_until.map_or(false, |until| until <= now);
+
+                    (read || write).then_some((Token(slot), read, write))
+                }
+                _ => None
+            })
+            .collect::<Vec<_>>();
+
+        for (token, read, write) in to_resume {
+            let failure = match self.resume_connection(token, read, write) {
+                Ok(ConnectionWorkResult::Working) => None,
+                Ok(ConnectionWorkResult::ConnectionBroken) => Some(DisconnectReason::ConnectionBroken),
+                Ok(ConnectionWorkResult::PeerLeft(reason)) => Some(DisconnectReason::PeerLeft(reason)),
+                Err(err) => {
+                    error!("{:?} // Error resuming connection {:?}: {:?}", self.global_connections.id, token, err);
+
+                    Some(DisconnectReason::Error)
+                }
+            };
+
+            if let Some(reason) = failure {
+                if let Err(err) = self.delete_connection(token, reason) {
+                    error!("{:?} // Error deleting connection {:?}: {:?}", self.global_connections.id, token, err);
+                }
+            }
+        }
+    }
+
+    fn resume_connection(&mut self, token: Token, read: bool, write: bool) -> atlas_common::error::Result<ConnectionWorkResult> {
+        if read {
+            if let Some(SocketConnection::PeerConn { socket, read_throttled_until, write_interest, .. }) = self.connections.get_mut(token.into()) {
+                *read_throttled_until = None;
+
+                reregister(self.poll.registry(), socket, token, interest(false, *write_interest), interest(true, *write_interest))
+                    .context("Failed to reregister socket")?;
+            }
+
+            // What the peer sent while we were not reading won't be notified again
+            match self.read_until_block(token)? {
+                ConnectionWorkResult::Working => {}
+                result => return Ok(result),
+            }
+        }
+
+        if write || self.has_pending_output(token) {
+            if let Some(SocketConnection::PeerConn { write_throttled_until, .. }) = self.connections.get_mut(token.into()) {
+                *write_throttled_until = None;
+            }
+
+            return self.try_write_until_block(token);
+        }
+
+        Ok(ConnectionWorkResult::Working)
+    }
+
     fn handle_connection_event(&mut self, token: Token, event: &Event) -> atlas_common::error::Result<ConnectionWorkResult> {
         let connection = if self.connections.contains(token.into()) {
             &self.connections[token.into()]
@@ -243,12 +515,14 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
         match &self.connections[token.into()] {
             SocketConnection::PeerConn { .. } => {
                 if event.is_readable() {
-                    if let ConnectionWorkResult::ConnectionBroken = self.read_until_block(token)? {
-                        return Ok(ConnectionWorkResult::ConnectionBroken);
+                    match self.read_until_block(token)? {
+                        ConnectionWorkResult::Working => {}
+                        result => return Ok(result),
                     }
                 }
 
-                if event.is_writable() {
+                // Reading may have generated TLS records that still have to be written
+                if event.is_writable() || self.has_pending_output(token) {
                     if let ConnectionWorkResult::ConnectionBroken = self.try_write_until_block(token)? {
                         return Ok(ConnectionWorkResult::ConnectionBroken);
                     }
@@ -274,9 +548,21 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                 socket,
                 connection,
                 writing_info,
+                write_interest,
+                session_key,
+                wire_version,
+                fragment_writer,
+                heartbeat,
+                handle,
+                leaving,
+                busy,
+                unflushed,
+                read_throttled_until,
+                write_throttled_until,
                 ..
             } => {
-                let was_waiting_for_write = writing_info.is_some();
+                let was_waiting_for_write = *write_interest;
+                let rate_limiter = self.global_connections.rate_limiter();
                 let mut wrote = false;
 
                 loop {
@@ -287,13 +573,93 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                         writing_info
                     } else {
                         // We are not currently writing anything
+                        let my_id = self.global_connections.id;
+                        let peer_id = handle.peer_id;
+
+                        // We only say goodbye once everything we had to write on this connection is written
+                        let can_say_goodbye = !fragment_writer.has_pending_fragments();
+                        let wire_version = *wire_version;
+
+                        let reliable = connection.reliable();
+
+                        let now = Instant::now();
+
+                        // Everything we write counts towards the limits, so nothing is written until they allow it
+                        let throttle = rate_limiter.send_wait(connection.rate_limits(), now);
 
-                        if let Some(to_write) = connection.try_take_from_send()? {
-                            trace!("{:?} // Writing message {:?}", self.global_connections.id, to_write);
+                        if throttle.is_some() && write_throttled_until.is_none() {
+                            metric_increment(COMM_SEND_THROTTLED_ID, Some(1));
+                        }
+
+                        *write_throttled_until = throttle.map(|wait| now + wait);
+
+                        let next_frame = if write_throttled_until.is_some() {
+                            None
+                        } else {
+                            fragment_writer.next_frame(wire_version, || {
+                                // Pings concern this connection specifically, so they go before the queued messages
+                                if let Some(ping) = heartbeat.take_ping::<RM, PM>(my_id, peer_id)? {
+                                    return Ok(Some((ping, DeliveryNotifier::untracked())));
+                                }
+
+                                if let Some(ack) = reliable.take_ack(my_id, wire_version) {
+                                    return Ok(Some((ack, DeliveryNotifier::untracked())));
+                                }
+
+                                if let Some(reply) = busy.take_reply(my_id, peer_id, wire_version) {
+                                    return Ok(Some((reply, DeliveryNotifier::untracked())));
+                                }
+
+                                let next = if reliable.is_supported(wire_version) {
+                                    reliable.next_message(|| connection.try_take_from_send())?
+                                } else {
+                                    connection.try_take_from_send()?.map(|queued| (queued, None))
+                                };
+
+                                let ((mut to_write, notifier), sequence) = if let Some(next) = next {
+                                    next
+                                } else {
+                                    if let Some(leaving) = leaving.as_mut().filter(|_| can_say_goodbye) {
+                                        return Ok(leaving.take_goodbye(my_id, peer_id, wire_version)
+                                            .map(|goodbye| (goodbye, DeliveryNotifier::untracked())));
+                                    }
+
+                                    return Ok(None);
+                                };
+
+                                trace!("{:?} // Writing message {:?}", my_id, to_write);
+
+                                if let AuthScheme::SessionMac = to_write.header().auth_scheme() {
+                                    match session_key {
+                                        Some(key) => to_write.authenticate_with_session(key),
+                                        None => {
+                                            // We won't write a frame that claims to be authenticated when it isn't, so the message
+                                            // is dropped (its sender is told it was not delivered) and the connection closed
+                                            error!("{:?} // Cannot authenticate message to {:?}, the connection has no session key. Closing the connection",
+                                                my_id, to_write.header().to());
+
+                                            return Err!(SessionMacError::NoSessionKey(to_write.header().to()));
+                                        }
+                                    }
+                                }
+
+                                // The sequence number goes around the authenticated message, like fragments do
+                                let to_write = match sequence {
+                                    Some(sequence) => reliable.wrap(to_write, sequence),
+                                    None => to_write,
+                                };
+
+                                Ok(Some((to_write, notifier)))
+                            })?
+                        };
+
+                        if let Some(next_frame) = next_frame {
                             wrote = true;
 
+                            rate_limiter.sent(connection.rate_limits(), next_frame.frame_len());
+
                             // We have something to write
-                            *writing_info = Some(WritingBuffer::init_from_message(to_write)?);
+                            *writing_info = Some(next_frame);
 
                             writing_info.as_mut().unwrap()
                         } else {
@@ -301,8 +667,9 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                             trace!("{:?} // Nothing left to write, wrote? {}", self.global_connections.id, wrote);
 
                             // If we have written something in this loop but we have not written until
-                            // Would block then we should flush the connection
-                            if wrote {
+                            // Would block then we should flush the connection.
+                            // The same applies if the TLS session has records it still has to send
+                            if wrote || socket.has_pending_output() {
                                 match socket.flush() {
                                     Ok(_) => {}
                                     Err(ref err) if would_block(err) => break,
@@ -321,26 +688,31 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                         }
                         ConnectionWriteWork::Working => { break; }
                         ConnectionWriteWork::Done => {
-                            *writing_info = None;
+                            if let Some(mut written) = writing_info.take() {
+                                let notifier = written.take_notifier();
+
+                                if notifier.is_tracked() {
+                                    unflushed.push(notifier);
+                                }
+                            }
                         }
                     }
                 }
 
-                if writing_info.is_none() && was_waiting_for_write {
-                    // We have nothing more to write, so we no longer need to be notified of writability
-                    self.poll.registry().reregister(socket, token, Interest::READABLE)
-                        .context("Failed to reregister socket")?;
-                } else if writing_info.is_some() && !was_waiting_for_write {
-                    // We still have something to write but we reached a would block state,
-                    // so we need to be notified of writability.
-                    self.poll.registry().reregister(socket, token, Interest::READABLE.add(Interest::WRITABLE))
-                        .context("Failed to reregister socket")?;
-                } else {
-                    // We have nothing to write and we were not waiting for writability, so we
-                    // Don't need to re register
-                    // Or we have something to write and we were already waiting for writability,
-                    // So we also don't have to re register
+                if !socket.has_pending_output() {
+                    unflushed.drain(..).for_each(DeliveryNotifier::delivered);
                 }
+
+                // We only need to be notified of writability if we reached a would block state with
+                // something left to write. Connections waiting for their rate limits are resumed by us
+                let needs_write = writing_info.is_some() || socket.has_pending_output();
+
+                let reading = read_throttled_until.is_none();
+
+                reregister(self.poll.registry(), socket, token, interest(reading, was_waiting_for_write), interest(reading, needs_write))
+                    .context("Failed to reregister socket")?;
+
+                *write_interest = needs_write;
             }
             _ => unreachable!()
         }
@@ -363,18 +735,176 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                 socket,
                 read_info,
                 connection,
+                session_key,
+                reassembler,
+                heartbeat,
+                busy,
+                write_interest,
+                read_throttled_until,
                 ..
             } => {
-                match conn_util::read_until_block(socket, read_info)? {
+                let now = Instant::now();
+
+                if let Some(wait) = connection.rate_limits().receive_wait(now) {
+                    // Stop reading until the peer may send more, so it is slowed down by TCP flow control
+                    if read_throttled_until.is_none() {
+                        metric_increment(COMM_RECEIVE_THROTTLED_ID, Some(1));
+
+                        reregister(self.poll.registry(), socket, token, interest(true, *write_interest), interest(false, *write_interest))
+                            .context("Failed to reregister socket")?;
+                    }
+
+                    *read_throttled_until = Some(now + wait);
+
+                    return Ok(ConnectionWorkResult::Working);
+                }
+
+                let read = conn_util::read_until_block(socket, read_info)?;
+
+                // The peer closed the connection after sending what we have read
+                let closed = matches!(read, ConnectionReadWork::ReceivedAndDone(_));
+
+                match read {
                     ConnectionReadWork::ConnectionBroken => {
                         return Ok(ConnectionWorkResult::ConnectionBroken);
                     }
                     ConnectionReadWork::Working => { return Ok(ConnectionWorkResult::Working); }
                     ConnectionReadWork::WorkingAndReceived(received) | ConnectionReadWork::ReceivedAndDone(received) => {
+                        connection.rate_limits().received(received.iter().map(|(_, message)| Header::LENGTH + message.len()).sum());
+
+                        // Anything we receive shows the connection (and the peer) is alive
+                        heartbeat.received_traffic(now);
+                        connection.suspicion.heartbeat(now, self.global_connections.failure_detector().config());
+
                         for (header, message) in received {
-                            cpu_workers::deserialize_and_push_message::<RM, PM>(header, message,
-                                                                                connection.client.clone(),
-                                                                                connection.reconf_handling.clone());
+                            // The handshake authenticated the peer, so it can't speak for any other node
+                            if header.from() != handle.peer_id {
+                                error!("{:?} // Received a frame from {:?} claiming to be from {:?}, closing connection", self.global_connections.id, handle.peer_id, header.from());
+
+                                return Ok(ConnectionWorkResult::ConnectionBroken);
+                            }
+
+                            let (header, message) = if header.is_fragment() {
+                                match reassembler.receive_fragment(header, message) {
+                                    Ok(Some(reassembled)) => reassembled,
+                                    Ok(None) => continue,
+                                    Err(err) => {
+                                        error!("{:?} // Failed to reassemble message from {:?}, closing connection: {:?}", self.global_connections.id, handle.peer_id, err);
+
+                                        return Ok(ConnectionWorkResult::ConnectionBroken);
+                                    }
+                                }
+                            } else {
+                                (header, message)
+                            };
+
+                            if header.is_ack() {
+                                match connection.reliable().acknowledge(&message[..]) {
+                                    // We had stopped writing to the peer, waiting for this
+                                    Ok(true) => connection.wake_connections(),
+                                    Ok(false) => {}
+                                    Err(err) => {
+                                        error!("{:?} // Received malformed acknowledgement from {:?}, closing connection: {:?}", self.global_connections.id, handle.peer_id, err);
+
+                                        return Ok(ConnectionWorkResult::ConnectionBroken);
+                                    }
+                                }
+
+                                continue;
+                            }
+
+                            let (header, message) = if header.is_sequenced() {
+                                match connection.reliable().receive(header, message) {
+                                    Ok(Some(received)) => received,
+                                    // We had already received it, before some connection broke
+                                    Ok(None) => continue,
+                                    Err(err) => {
+                                        error!("{:?} // Received malformed sequenced message from {:?}, closing connection: {:?}", self.global_connections.id, handle.peer_id, err);
+
+                                        return Ok(ConnectionWorkResult::ConnectionBroken);
+                                    }
+                                }
+                            } else {
+                                (header, message)
+                            };
+
+                            // The peer is leaving, so nothing else is coming over this connection
+                            if header.is_goodbye() {
+                                return match goodbye::read_goodbye(&message[..]) {
+                                    Ok(reason) => Ok(ConnectionWorkResult::PeerLeft(reason)),
+                                    Err(err) => {
+                                        error!("{:?} // Received malformed goodbye from {:?}, closing connection: {:?}", self.global_connections.id, handle.peer_id, err);
+
+                                        Ok(ConnectionWorkResult::ConnectionBroken)
+                                    }
+                                };
+                            }
+
+                            // The peer refused one of our requests, it's up to whoever sent it to try again
+                            if header.is_busy() {
+                                match busy::read_busy(&message[..]) {
+                                    Ok(nonce) => {
+                                        self.global_connections.connection_events().notify(ConnectionEvent::Busy(handle.peer_id, nonce));
+                                    }
+                                    Err(err) => {
+                                        error!("{:?} // Received malformed busy frame from {:?}, closing connection: {:?}", self.global_connections.id, handle.peer_id, err);
+
+                                        return Ok(ConnectionWorkResult::ConnectionBroken);
+                                    }
+                                }
+
+                                continue;
+                            }
+
+                            // Route the message according to the class announced by the sender,
+                            // falling back to the full deserializer for peers that don't announce it
+                            match header.message_class() {
+                                Some(MessageClass::Ping) => {
+                                    match heartbeat::read_ping::<RM, PM>(&message[..]) {
+                                        Ok(true) => heartbeat.received_ping_request(),
+                                        Ok(false) => trace!("{:?} // Received ping response from {:?}", self.global_connections.id, handle.peer_id),
+                                        Err(err) => {
+                                            error!("{:?} // Received malformed ping from {:?}, closing connection: {:?}", self.global_connections.id, handle.peer_id, err);
+
+                                            return Ok(ConnectionWorkResult::ConnectionBroken);
+                                        }
+                                    }
+                                }
+                                Some(MessageClass::Reconfiguration) => {
+                                    cpu_workers::deserialize_and_push_reconf_message::<NI, RM, PM>(handle.peer_id, header, message,
+                                                                                                   self.global_connections.network_info.clone(),
+                                                                                                   self.global_connections.verification_config().clone(),
+                                                                                                   self.global_connections.replay_window().clone(),
+                                                                                                   session_key.clone(),
+                                                                                                   None,
+                                                                                                   connection.reconf_handling.clone());
+                                }
+                                Some(MessageClass::Protocol) | None => {
+                                    // Shed the requests of clients that are over their quota, before spending any work on them
+                                    let reservation = match connection.client.admit() {
+                                        Some(reservation) => reservation,
+                                        None => {
+                                            trace!("{:?} // Shedding request {} from {:?}", self.global_connections.id, header.nonce(), handle.peer_id);
+
+                                            busy.refused(header.nonce());
+
+                                            continue;
+                                        }
+                                    };
+
+                                    cpu_workers::deserialize_and_push_message::<NI, RM, PM>(handle.peer_id, header, message,
+                                                                                            self.global_connections.network_info.clone(),
+                                                                                            self.global_connections.verification_config().clone(),
+                                                                                            self.global_connections.replay_window().clone(),
+                                                                                            session_key.clone(),
+                                                                                            reservation,
+                                                                                            connection.reconf_handling.clone());
+                                }
+                            }
+                        }
+
+                        if closed {
+                            return Ok(ConnectionWorkResult::ConnectionBroken);
                         }
                     }
                 }
@@ -386,6 +916,16 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
         Ok(ConnectionWorkResult::Working)
     }
 
+    /// Does the connection with the given token still have TLS records, pings, acknowledgements or busy replies waiting to be written
+    fn has_pending_output(&self, token: Token) -> bool {
+        match self.connections.get(token.into()) {
+            Some(SocketConnection::PeerConn { socket, heartbeat, connection, busy, .. }) => {
+                socket.has_pending_output() || heartbeat.has_pending_output() || connection.reliable().has_pending_ack() || busy.has_pending()
+            }
+            _ => false
+        }
+    }
+
     /// Receive connections from the connection register and register them with the epoll instance
     fn register_connections(&mut self) -> io::Result<()> {
         loop {
@@ -395,13 +935,26 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
                         EpollWorkerMessage::NewConnection(conn) => {
                             self.create_connection(conn)?;
                         }
-                        EpollWorkerMessage::CloseConnection(token) => {
+                        EpollWorkerMessage::CloseConnection(token, deadline) => {
                             if let SocketConnection::Waker = &self.connections[token.into()] {
                                 // We can't close the waker, wdym?
                                 continue;
                             }
 
-                            self.delete_connection(token, false)?;
+                            self.leave_connection(token, LeaveReason::Disconnected, deadline);
+                        }
+                        EpollWorkerMessage::Shutdown(deadline) => {
+                            info!("{:?} // Worker {} is shutting down, closing its connections", self.global_connections.id, self.worker_id);
+
+                            self.shutdown_deadline = Some(deadline);
+
+                            let tokens = self.connections.iter()
+                                .map(|(slot, _)| Token(slot))
+                                .collect::<Vec<_>>();
+
+                            for token in tokens {
+                                self.leave_connection(token, LeaveReason::ShuttingDown, deadline);
+                            }
                         }
                     }
                 }
@@ -418,7 +971,7 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
     fn create_connection(&mut self, conn: NewConnection<RM, PM>) -> io::Result<()> {
         let NewConnection {
             conn_id, peer_id,
-            my_id, mut socket,
+            my_id, mut socket, wire_version,
             reading_info, writing_info, peer_conn
         } = conn;
 
@@ -437,11 +990,27 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
         self.poll.registry().register(&mut socket,
                                       token, Interest::READABLE)?;
 
+        let session_key = socket.session_key();
+
+        let fragment_writer = FragmentWriter::new(self.global_connections.fragmentation_config().max_frame_size);
+        let reassembler = Reassembler::new(peer_id, peer_conn.reassembly_budget.clone());
+
         let socket_conn = SocketConnection::PeerConn {
             handle: handle.clone(),
             socket,
             read_info: reading_info,
             writing_info: writing_info,
+            write_interest: false,
+            read_throttled_until: None,
+            write_throttled_until: None,
+            session_key,
+            wire_version,
+            fragment_writer,
+            reassembler,
+            heartbeat: Heartbeat::new(),
+            leaving: self.shutdown_deadline.map(|deadline| Leaving::new(LeaveReason::ShuttingDown, deadline)),
+            busy: BusyReplies::new(),
+            unflushed: Vec::new(),
             connection: peer_conn,
         };
 
@@ -454,20 +1023,29 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
         Ok(())
     }
 
-    fn delete_connection(&mut self, token: Token, is_failure: bool) -> io::Result<()> {
+    /// Remove a connection from this worker. Unless we were asked to close it, the connection
+    /// is handled as a failure (see [Connections::handle_connection_failed])
+    fn delete_connection(&mut self, token: Token, reason: DisconnectReason) -> io::Result<()> {
         if let Some(conn) = self.connections.try_remove(token.into()) {
             match conn {
                 SocketConnection::PeerConn {
                     mut socket,
                     connection,
-                    handle, ..
+                    handle,
+                    write_interest,
+                    read_throttled_until, ..
                 } => {
-                    self.poll.registry().deregister(&mut socket)?;
+                    // Connections we are neither reading from nor writing to are not registered
+                    if interest(read_throttled_until.is_none(), write_interest).is_some() {
+                        self.poll.registry().deregister(&mut socket)?;
+                    }
 
-                    if is_failure {
-                        self.global_connections.handle_connection_failed(handle.peer_id, handle.id);
-                    } else {
+                    if let DisconnectReason::Requested = reason {
                         connection.delete_connection(handle.id);
+
+                        self.global_connections.connection_events().notify(ConnectionEvent::Disconnected(handle.peer_id, reason));
+                    } else {
+                        self.global_connections.handle_connection_failed(handle.peer_id, handle.id, reason);
                     }
 
                     socket.shutdown(Shutdown::Both)?;
@@ -486,6 +1064,27 @@ impl<NI, RM, PM> EpollWorker<NI, RM, PM>
     }
 }
 
+/// The events we want to be notified of on a connection, `None` if there are none
+fn interest(reading: bool, writing: bool) -> Option<Interest> {
+    match (reading, writing) {
+        (true, true) => Some(Interest::READABLE.add(Interest::WRITABLE)),
+        (true, false) => Some(Interest::READABLE),
+        (false, true) => Some(Interest::WRITABLE),
+        (false, false) => None,
+    }
+}
+
+/// Change the events a connection is registered for, from the current ones to the wanted ones
+fn reregister(registry: &Registry, socket: &mut SecureMioSocket, token: Token,
+              current: Option<Interest>, wanted: Option<Interest>) -> io::Result<()> {
+    match (current, wanted) {
+        (None, Some(wanted)) => registry.register(socket, token, wanted),
+        (Some(_), None) => registry.deregister(socket),
+        (Some(current), Some(wanted)) if current != wanted => registry.reregister(socket, token, wanted),
+        _ => Ok(()),
+    }
+}
+
 pub fn would_block(err: &io::Error) -> bool {
     err.kind() == io::ErrorKind::WouldBlock
 }
@@ -497,9 +1096,9 @@ pub fn interrupted(err: &io::Error) -> bool {
 impl<RM, PM> NewConnection<RM, PM>
     where RM: Serializable + 'static,
           PM: Serializable + 'static {
-    pub fn new(conn_id: u32, peer_id: NodeId, my_id: NodeId, socket: MioSocket, reading_info: ReadingBuffer,
-               writing_info: Option<WritingBuffer>, peer_conn: Arc<PeerConnection<RM, PM>>) -> Self {
-        Self { conn_id, peer_id, my_id, socket, reading_info, writing_info, peer_conn }
+    pub fn new(conn_id: u32, peer_id: NodeId, my_id: NodeId, socket: SecureMioSocket, wire_version: u32,
+               reading_info: ReadingBuffer, writing_info: Option<WritingBuffer>, peer_conn: Arc<PeerConnection<RM, PM>>) -> Self {
+        Self { conn_id, peer_id, my_id, socket, wire_version, reading_info, writing_info, peer_conn }
     }
 }
 
@@ -1,5 +1,7 @@
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::Instant;
 use anyhow::Context;
 use log::error;
 use mio::{Token};
@@ -8,10 +10,10 @@ use atlas_common::channel;
 use atlas_common::channel::{ChannelSyncRx, ChannelSyncTx};
 use atlas_common::error::*;
 use atlas_common::node_id::NodeId;
-use atlas_common::socket::MioSocket;
 use crate::mio_tcp::connections::{Connections, PeerConnection};
 use crate::mio_tcp::connections::conn_util::{ReadingBuffer, WritingBuffer};
 use crate::mio_tcp::connections::epoll_group::epoll_workers::EpollWorker;
+use crate::mio_tcp::connections::tls::SecureMioSocket;
 use crate::reconfiguration_node::NetworkInformationProvider;
 use crate::serialize::Serializable;
 
@@ -43,22 +45,27 @@ pub(crate) fn init_worker_group_handle<NI, RM, PM>(worker_count: u32) -> (EpollW
     }, receivers)
 }
 
-pub(crate) fn initialize_worker_group<NI, RM, PM>(connections: Arc<Connections<NI, RM, PM>>, receivers: Vec<ChannelSyncRx<EpollWorkerMessage<RM, PM>>>) -> Result<()>
+/// Launch the epoll workers, returning the handles of their threads (which run until the workers are shut down)
+pub(crate) fn initialize_worker_group<NI, RM, PM>(connections: Arc<Connections<NI, RM, PM>>, receivers: Vec<ChannelSyncRx<EpollWorkerMessage<RM, PM>>>) -> Result<Vec<JoinHandle<()>>>
     where NI: NetworkInformationProvider + 'static,
           RM: Serializable + 'static,
           PM: Serializable + 'static {
+    let mut threads = Vec::with_capacity(receivers.len());
+
     for (worker_id, rx) in receivers.into_iter().enumerate() {
         let worker = EpollWorker::new(worker_id as u32, connections.clone(), rx)?;
 
-        std::thread::Builder::new().name(format!("Epoll Worker {}", worker_id))
+        let thread = std::thread::Builder::new().name(format!("Epoll Worker {}", worker_id))
             .spawn(move || {
                 if let Err(err) = worker.epoll_worker_loop() {
                     error!("Epoll worker {} failed with error: {:?}", worker_id,err);
                 }
             }).expect("Failed to launch worker thread");
+
+        threads.push(thread);
     }
 
-    Ok(())
+    Ok(threads)
 }
 
 /// A handle to the worker group that handles the epoll events
@@ -76,7 +83,9 @@ pub(crate) struct NewConnection<RM, PM>
     conn_id: u32,
     peer_id: NodeId,
     my_id: NodeId,
-    socket: MioSocket,
+    socket: SecureMioSocket,
+    // The wire protocol version negotiated for this connection
+    wire_version: u32,
     reading_info: ReadingBuffer,
     writing_info: Option<WritingBuffer>,
     peer_conn: Arc<PeerConnection<RM, PM>>,
@@ -86,7 +95,10 @@ pub(crate) enum EpollWorkerMessage<RM, PM>
     where RM: Serializable + 'static,
           PM: Serializable + 'static {
     NewConnection(NewConnection<RM, PM>),
-    CloseConnection(Token),
+    // Say goodbye to the peer and close the connection, by the given deadline
+    CloseConnection(Token, Instant),
+    // Say goodbye to the peers and close all connections, by the given deadline
+    Shutdown(Instant),
 }
 
 impl<RM, PM> EpollWorkerGroupHandle<RM, PM>
@@ -107,16 +119,27 @@ impl<RM, PM> EpollWorkerGroupHandle<RM, PM>
         Ok(())
     }
 
-    /// Order a disconnection of a given connection from a worker
-    pub fn disconnect_connection_from_worker(&self, epoll_worker: EpollWorkerId, conn_id: Token) -> atlas_common::error::Result<()> {
+    /// Order a disconnection of a given connection from a worker, which writes what is queued
+    /// on the connection (and says goodbye to the peer) before closing it, until the deadline
+    pub fn disconnect_connection_from_worker(&self, epoll_worker: EpollWorkerId, conn_id: Token, deadline: Instant) -> atlas_common::error::Result<()> {
         let worker = self.workers.get(epoll_worker as usize)
             .ok_or(WorkerError::FailedToGetWorkerForConnection(epoll_worker, conn_id))?;
 
-        worker.send(EpollWorkerMessage::CloseConnection(conn_id))
+        worker.send(EpollWorkerMessage::CloseConnection(conn_id, deadline))
             .context(format!("Failed to close connection in worker {:?}, {:?}", epoll_worker, conn_id))?;
 
         Ok(())
     }
+
+    /// Order every worker to close its connections (after draining them, until the deadline) and stop
+    pub(super) fn shutdown_workers(&self, deadline: Instant) -> atlas_common::error::Result<()> {
+        for (worker_id, worker) in self.workers.iter().enumerate() {
+            worker.send(EpollWorkerMessage::Shutdown(deadline))
+                .context(format!("Failed to shut down worker {:?}", worker_id))?;
+        }
+
+        Ok(())
+    }
 }
 
 impl<RM, PM> Clone for EpollWorkerGroupHandle<RM, PM>
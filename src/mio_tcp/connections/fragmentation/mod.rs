@@ -0,0 +1,383 @@
+//! Fragmentation of large messages into frames of bounded size, and their reassembly.
+//!
+//! Messages whose payload is larger than the maximum frame size are split into fragments.
+//! Each fragment carries a copy of the header of the message (marked with [Header::is_fragment]),
+//! and a payload prefixed with the total length of the message and the offset of the fragment
+//! (both little endian `u64`s).
+//!
+//! The writer of a connection alternates between fragments and whole messages, so the smaller
+//! messages queued behind a large transfer don't have to wait for all of it to be written.
+//! The fragments of a message are always sent in order over the same connection.
+//! A tracked message is only reported as delivered once its last fragment has been written.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::collections::btree_map::Entry;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::{Buf, Bytes, BytesMut};
+use thiserror::Error;
+
+use atlas_common::Err;
+use atlas_common::error::*;
+use atlas_common::node_id::NodeId;
+
+use crate::message::{Header, WireMessage};
+use crate::mio_tcp::connections::conn_util::WritingBuffer;
+use crate::mio_tcp::connections::send_queue::DeliveryNotifier;
+
+/// The size of the prefix of each fragment (total length and offset)
+const FRAGMENT_PREFIX_LENGTH: usize = 2 * std::mem::size_of::<u64>();
+
+/// How many large messages a connection takes from its queue to fragment at once.
+/// When this is reached, we stop taking messages from the queue until one of them is done,
+/// so that a peer sending only large messages still experiences backpressure
+const MAX_PENDING_FRAGMENTED: usize = 4;
+
+/// The amount of memory a peer may use in partially reassembled messages.
+/// Shared by all connections to that peer
+pub(crate) struct ReassemblyBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+/// Memory reserved from a [ReassemblyBudget], returned when dropped
+struct Reservation {
+    budget: Arc<ReassemblyBudget>,
+    bytes: usize,
+}
+
+/// A message that is being written in fragments
+struct FragmentedMessage {
+    header: Header,
+    payload: Bytes,
+    // How much of the payload has already been fragmented
+    offset: usize,
+    // Handed to the last fragment
+    notifier: DeliveryNotifier,
+}
+
+/// Chooses the next frame to write on a connection, fragmenting large messages
+pub(crate) struct FragmentWriter {
+    max_frame_size: usize,
+    fragmenting: VecDeque<FragmentedMessage>,
+    // Should the next frame be a fragment (if there are any), instead of a whole message
+    fragment_turn: bool,
+}
+
+/// A message that is being reassembled from its fragments
+struct PartialMessage {
+    header: Header,
+    total_length: usize,
+    buffer: BytesMut,
+    _reservation: Reservation,
+}
+
+/// Reassembles the fragmented messages received on a connection
+pub(crate) struct Reassembler {
+    peer_id: NodeId,
+    budget: Arc<ReassemblyBudget>,
+    // The messages being reassembled, by nonce
+    partial: BTreeMap<u64, PartialMessage>,
+}
+
+impl ReassemblyBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    fn reserve(self: &Arc<Self>, bytes: usize) -> Option<Reservation> {
+        let reserved = self.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            used.checked_add(bytes).filter(|total| *total <= self.limit)
+        });
+
+        reserved.ok().map(|_| Reservation {
+            budget: self.clone(),
+            bytes,
+        })
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+impl FragmentedMessage {
+    fn new(message: WireMessage, notifier: DeliveryNotifier) -> Self {
+        let (header, payload) = message.into_inner();
+
+        Self {
+            header,
+            payload,
+            offset: 0,
+            notifier,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.offset >= self.payload.len()
+    }
+
+    /// Take the next fragment of this message, of at most `max_frame_size` bytes of the payload
+    fn next_fragment(&mut self, max_frame_size: usize) -> (Header, Bytes) {
+        let end = (self.offset + max_frame_size).min(self.payload.len());
+
+        let mut payload = BytesMut::with_capacity(FRAGMENT_PREFIX_LENGTH + end - self.offset);
+
+        payload.extend_from_slice(&(self.payload.len() as u64).to_le_bytes()[..]);
+        payload.extend_from_slice(&(self.offset as u64).to_le_bytes()[..]);
+        payload.extend_from_slice(&self.payload[self.offset..end]);
+
+        let mut header = self.header;
+
+        header.set_fragment(true);
+        header.length = payload.len() as u64;
+
+        self.offset = end;
+
+        (header, payload.freeze())
+    }
+}
+
+impl FragmentWriter {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            fragmenting: Default::default(),
+            fragment_turn: false,
+        }
+    }
+
+    fn should_fragment(&self, message: &WireMessage, wire_version: u32) -> bool {
+        self.max_frame_size > 0
+            && wire_version >= WireMessage::FRAGMENTATION_VERSION
+            && message.payload().len() > self.max_frame_size
+    }
+
+    /// Are we still in the middle of writing the fragments of some message
+    pub fn has_pending_fragments(&self) -> bool {
+        !self.fragmenting.is_empty()
+    }
+
+    /// Choose the next frame to write, alternating between the fragments of the large messages
+    /// we are writing and the messages returned by `take_next` (which should take them from the
+    /// queue of the connection). Returns `None` when there is nothing left to write.
+    /// The notifier of each message is handed to the buffer of its last frame
+    pub fn next_frame<F>(&mut self, wire_version: u32, mut take_next: F) -> Result<Option<WritingBuffer>>
+        where F: FnMut() -> Result<Option<(WireMessage, DeliveryNotifier)>> {
+        let message_turn = !self.fragment_turn || self.fragmenting.is_empty();
+
+        if message_turn && self.fragmenting.len() < MAX_PENDING_FRAGMENTED {
+            if let Some((message, notifier)) = take_next()? {
+                if !self.should_fragment(&message, wire_version) {
+                    self.fragment_turn = true;
+
+                    return WritingBuffer::init_from_message(message, wire_version)
+                        .map(|buffer| Some(buffer.track(notifier)));
+                }
+
+                self.fragmenting.push_back(FragmentedMessage::new(message, notifier));
+            }
+        }
+
+        self.fragment_turn = false;
+
+        let (header, payload, done) = match self.fragmenting.front_mut() {
+            Some(message) => {
+                let (header, payload) = message.next_fragment(self.max_frame_size);
+
+                (header, payload, message.is_done())
+            }
+            None => return Ok(None),
+        };
+
+        let buffer = WritingBuffer::init_from_parts(header, payload, wire_version)?;
+
+        if done {
+            let message = self.fragmenting.pop_front().unwrap();
+
+            return Ok(Some(buffer.track(message.notifier)));
+        }
+
+        Ok(Some(buffer))
+    }
+}
+
+impl Reassembler {
+    pub fn new(peer_id: NodeId, budget: Arc<ReassemblyBudget>) -> Self {
+        Self {
+            peer_id,
+            budget,
+            partial: Default::default(),
+        }
+    }
+
+    /// Receive a fragment, returning the reassembled message once all of its fragments have been received.
+    /// Errors mean the peer is misbehaving, so the connection should be closed
+    pub fn receive_fragment(&mut self, header: Header, mut payload: BytesMut) -> Result<Option<(Header, BytesMut)>> {
+        if header.from() != self.peer_id || payload.len() <= FRAGMENT_PREFIX_LENGTH {
+            return Err!(FragmentationError::MalformedFragment(self.peer_id));
+        }
+
+        let total_length = payload.get_u64_le();
+        let offset = payload.get_u64_le();
+
+        if total_length > self.budget.limit as u64 {
+            return Err!(FragmentationError::ReassemblyLimitExceeded(self.peer_id, self.budget.limit));
+        }
+
+        let (total_length, offset) = (total_length as usize, offset as usize);
+
+        let partial = match self.partial.entry(header.nonce()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                if offset != 0 {
+                    return Err!(FragmentationError::MalformedFragment(self.peer_id));
+                }
+
+                let reservation = match self.budget.reserve(total_length) {
+                    Some(reservation) => reservation,
+                    None => return Err!(FragmentationError::ReassemblyLimitExceeded(self.peer_id, self.budget.limit)),
+                };
+
+                entry.insert(PartialMessage {
+                    header,
+                    total_length,
+                    buffer: BytesMut::with_capacity(total_length),
+                    _reservation: reservation,
+                })
+            }
+        };
+
+        // Fragments are sent in order, and all of them carry the same header
+        if offset != partial.buffer.len()
+            || total_length != partial.total_length
+            || offset + payload.len() > total_length
+            || header.digest().as_ref() != partial.header.digest().as_ref() {
+            return Err!(FragmentationError::MalformedFragment(self.peer_id));
+        }
+
+        partial.buffer.extend_from_slice(&payload[..]);
+
+        if partial.buffer.len() < partial.total_length {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&header.nonce()).unwrap();
+
+        let mut header = partial.header;
+
+        header.set_fragment(false);
+        header.length = partial.total_length as u64;
+
+        Ok(Some((header, partial.buffer)))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FragmentationError {
+    #[error("Received a malformed fragment from {0:?}")]
+    MalformedFragment(NodeId),
+    #[error("{0:?} exceeded the limit of {1} bytes in messages being reassembled")]
+    ReassemblyLimitExceeded(NodeId, usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+
+    use bytes::{Bytes, BytesMut};
+
+    use atlas_common::node_id::NodeId;
+
+    use crate::message::WireMessage;
+    use crate::mio_tcp::connections::fragmentation::{FragmentedMessage, Reassembler, ReassemblyBudget};
+    use crate::mio_tcp::connections::send_queue::DeliveryNotifier;
+
+    fn fragments(payload: Bytes, nonce: u64, max_frame_size: usize) -> Vec<(crate::message::Header, BytesMut)> {
+        let message = WireMessage::new(NodeId::from(1u32), NodeId::from(0u32), payload, nonce, None, None);
+
+        let mut fragmented = FragmentedMessage::new(message, DeliveryNotifier::untracked());
+        let mut fragments = Vec::new();
+
+        while !fragmented.is_done() {
+            let (header, payload) = fragmented.next_fragment(max_frame_size);
+
+            fragments.push((header, BytesMut::from(&payload[..])));
+        }
+
+        fragments
+    }
+
+    #[test]
+    fn test_fragments_are_reassembled() {
+        let payload = Bytes::from((0..10_000u32).map(|i| i as u8).collect::<Vec<_>>());
+
+        let budget = Arc::new(ReassemblyBudget::new(64 * 1024));
+        let mut reassembler = Reassembler::new(NodeId::from(1u32), budget.clone());
+
+        let first = fragments(payload.clone(), 1, 1024);
+        let second = fragments(payload.clone(), 2, 4096);
+
+        assert_eq!(first.len(), 10);
+        assert!(first.iter().all(|(header, _)| header.is_fragment()));
+
+        // Fragments of different messages may be interleaved
+        let mut interleaved = Vec::new();
+        let mut second = second.into_iter();
+
+        for fragment in first {
+            interleaved.push(fragment);
+            interleaved.extend(second.next());
+        }
+
+        interleaved.extend(second);
+
+        let mut reassembled = Vec::new();
+
+        for (header, fragment) in interleaved {
+            if let Some(message) = reassembler.receive_fragment(header, fragment).unwrap() {
+                reassembled.push(message);
+            }
+        }
+
+        assert_eq!(reassembled.len(), 2);
+
+        for (header, message) in reassembled {
+            assert!(!header.is_fragment());
+            assert_eq!(header.payload_length(), payload.len());
+            assert_eq!(&message[..], &payload[..]);
+        }
+
+        // All the memory has been released
+        assert_eq!(budget.used.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_reassembly_respects_budget() {
+        let payload = Bytes::from(vec![1; 8 * 1024]);
+
+        let budget = Arc::new(ReassemblyBudget::new(10 * 1024));
+        let mut reassembler = Reassembler::new(NodeId::from(1u32), budget.clone());
+
+        let mut first = fragments(payload.clone(), 1, 1024).into_iter();
+        let mut second = fragments(payload.clone(), 2, 1024).into_iter();
+
+        let (header, fragment) = first.next().unwrap();
+        assert!(reassembler.receive_fragment(header, fragment).unwrap().is_none());
+
+        // Both messages don't fit in the budget of the peer at the same time
+        let (header, fragment) = second.next().unwrap();
+        assert!(reassembler.receive_fragment(header, fragment).is_err());
+
+        // Fragments out of order are rejected
+        let (header, fragment) = first.nth(1).unwrap();
+        assert!(reassembler.receive_fragment(header, fragment).is_err());
+    }
+}
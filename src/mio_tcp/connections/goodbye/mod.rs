@@ -0,0 +1,170 @@
+//! Saying goodbye to our peers before closing our connections to them.
+//!
+//! A goodbye is a header marked with the goodbye flag, written after everything that was queued
+//! on the connection. Its payload is the reason code of the goodbye (a little endian `u32`, see
+//! [LeaveReason]). A peer that receives it knows we are leaving on purpose, so it closes its end
+//! of the connection without trying to bring it back.
+//!
+//! Goodbyes are only sent to peers that speak at least [WireMessage::GOODBYE_VERSION],
+//! as older peers would not understand the flag.
+
+use std::time::Instant;
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use atlas_common::Err;
+use atlas_common::error::*;
+use atlas_common::node_id::NodeId;
+
+use crate::conn_utils::LeaveReason;
+use crate::message::WireMessage;
+
+const REASON_CODE_LENGTH: usize = std::mem::size_of::<u32>();
+
+const SHUTTING_DOWN_CODE: u32 = 0;
+const DISCONNECTED_CODE: u32 = 1;
+
+/// A connection we are closing on purpose, once everything queued on it
+/// (and our goodbye) has been written, or the deadline has passed
+pub(crate) struct Leaving {
+    reason: LeaveReason,
+    deadline: Instant,
+    // Have we queued our goodbye (or given up on it, for peers that don't understand goodbyes)
+    said_goodbye: bool,
+}
+
+impl Leaving {
+    pub fn new(reason: LeaveReason, deadline: Instant) -> Self {
+        Self {
+            reason,
+            deadline,
+            said_goodbye: false,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn said_goodbye(&self) -> bool {
+        self.said_goodbye
+    }
+
+    /// Take the goodbye to send to the peer, if we haven't already.
+    /// Peers using older wire versions are left without one
+    pub fn take_goodbye(&mut self, my_id: NodeId, peer_id: NodeId, wire_version: u32) -> Option<WireMessage> {
+        if self.said_goodbye {
+            return None;
+        }
+
+        self.said_goodbye = true;
+
+        if is_supported(wire_version) {
+            Some(goodbye_message(my_id, peer_id, self.reason))
+        } else {
+            None
+        }
+    }
+}
+
+/// Do peers using the given wire version understand goodbyes
+pub(crate) fn is_supported(wire_version: u32) -> bool {
+    wire_version >= WireMessage::GOODBYE_VERSION
+}
+
+fn reason_code(reason: LeaveReason) -> u32 {
+    match reason {
+        LeaveReason::ShuttingDown => SHUTTING_DOWN_CODE,
+        LeaveReason::Disconnected => DISCONNECTED_CODE,
+        LeaveReason::Unknown(code) => code,
+    }
+}
+
+fn from_reason_code(code: u32) -> LeaveReason {
+    match code {
+        SHUTTING_DOWN_CODE => LeaveReason::ShuttingDown,
+        DISCONNECTED_CODE => LeaveReason::Disconnected,
+        code => LeaveReason::Unknown(code),
+    }
+}
+
+/// Build a goodbye for the given reason. Like pings, goodbyes are neither signed nor digested,
+/// as they only concern the connection they are sent over
+pub(crate) fn goodbye_message(my_id: NodeId, peer_id: NodeId, reason: LeaveReason) -> WireMessage {
+    let payload = Bytes::copy_from_slice(&reason_code(reason).to_le_bytes()[..]);
+
+    let mut message = WireMessage::new(my_id, peer_id, payload, fastrand::u64(..), None, None);
+
+    message.header.set_goodbye(true);
+
+    message
+}
+
+/// Read the reason out of the payload of a goodbye
+pub(crate) fn read_goodbye(payload: &[u8]) -> Result<LeaveReason> {
+    let code: [u8; REASON_CODE_LENGTH] = match payload.try_into() {
+        Ok(code) => code,
+        Err(_) => return Err!(GoodbyeError::MalformedReason(payload.len())),
+    };
+
+    Ok(from_reason_code(u32::from_le_bytes(code)))
+}
+
+#[derive(Error, Debug)]
+pub enum GoodbyeError {
+    #[error("The reason of a goodbye must be {} bytes long, received {0}", REASON_CODE_LENGTH)]
+    MalformedReason(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use atlas_common::node_id::NodeId;
+
+    use crate::conn_utils::LeaveReason;
+    use crate::message::{Header, WireMessage};
+    use crate::mio_tcp::connections::goodbye;
+    use crate::mio_tcp::connections::goodbye::Leaving;
+
+    #[test]
+    fn test_goodbye_is_only_understood_by_newer_peers() {
+        let message = goodbye::goodbye_message(NodeId::from(0u32), NodeId::from(1u32), LeaveReason::ShuttingDown);
+
+        assert!(message.header().is_goodbye());
+
+        let mut buf = [0; Header::LENGTH];
+
+        message.header().serialize_versioned(WireMessage::GOODBYE_VERSION, &mut buf[..]).unwrap();
+
+        assert!(Header::deserialize_versioned(&buf[..]).unwrap().is_goodbye());
+
+        assert!(goodbye::is_supported(WireMessage::CURRENT_VERSION));
+        assert!(!goodbye::is_supported(WireMessage::HEARTBEAT_VERSION));
+
+        // Peers that don't understand goodbyes are left without one
+        let mut leaving = Leaving::new(LeaveReason::Disconnected, Instant::now());
+
+        assert!(leaving.take_goodbye(NodeId::from(0u32), NodeId::from(1u32), WireMessage::HEARTBEAT_VERSION).is_none());
+        assert!(leaving.said_goodbye());
+    }
+
+    #[test]
+    fn test_goodbye_carries_its_reason() {
+        for reason in [LeaveReason::ShuttingDown, LeaveReason::Disconnected, LeaveReason::Unknown(42)] {
+            let mut leaving = Leaving::new(reason, Instant::now());
+
+            let (_, payload) = leaving.take_goodbye(NodeId::from(0u32), NodeId::from(1u32), WireMessage::CURRENT_VERSION)
+                .expect("Peers using the current version should be told goodbye")
+                .into_inner();
+
+            assert_eq!(goodbye::read_goodbye(&payload[..]).unwrap(), reason);
+
+            // We only say goodbye once
+            assert!(leaving.take_goodbye(NodeId::from(0u32), NodeId::from(1u32), WireMessage::CURRENT_VERSION).is_none());
+        }
+
+        assert!(goodbye::read_goodbye(&[0; 2][..]).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+//! Heartbeats for the connections handled by the epoll workers.
+//!
+//! A connection that hasn't received anything for the heartbeat interval pings the peer over that
+//! same connection (so the ping actually tests it). Anything received afterwards (the ping response
+//! or any other traffic) proves the connection is alive. A connection that stays silent for the
+//! heartbeat timeout after being pinged is considered dead, which is how we detect half open
+//! TCP connections.
+//!
+//! While we hold back reading from a connection (because of its rate limits), the silence of the
+//! peer proves nothing, so it can't time out. We keep pinging the peer instead, so that it keeps
+//! hearing from us even though we are not reading its answers yet.
+//!
+//! Heartbeats are only used with peers that speak at least [WireMessage::HEARTBEAT_VERSION],
+//! as older peers don't answer pings.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use atlas_common::error::*;
+use atlas_common::node_id::NodeId;
+
+use crate::config::HeartbeatConfig;
+use crate::message::{MessageClass, NetworkMessageKind, PingMessage, WireMessage};
+use crate::serialize;
+use crate::serialize::Serializable;
+
+/// The outcome of checking the heartbeat of a connection
+pub(crate) enum HeartbeatStatus {
+    /// The connection is alive, nothing to do
+    Alive,
+    /// The connection has been idle, a ping has been queued for it
+    PingQueued,
+    /// The peer has not answered our ping in time
+    TimedOut(Duration),
+}
+
+/// The heartbeat state of a single connection
+pub(crate) struct Heartbeat {
+    // When we last received anything on this connection
+    last_received: Instant,
+    // When we pinged the peer, if we are still waiting for it to answer
+    awaiting_since: Option<Instant>,
+    // Do we have to write a ping request
+    request_pending: bool,
+    // How many ping requests from the peer we still have to answer
+    responses_pending: usize,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self {
+            last_received: Instant::now(),
+            awaiting_since: None,
+            request_pending: false,
+            responses_pending: 0,
+        }
+    }
+
+    /// Are heartbeats used with a peer using the given wire version
+    pub fn is_supported(config: &HeartbeatConfig, wire_version: u32) -> bool {
+        config.interval_millis > 0 && wire_version >= WireMessage::HEARTBEAT_VERSION
+    }
+
+    /// We have received something on this connection, so it is alive
+    pub fn received_traffic(&mut self, now: Instant) {
+        self.last_received = now;
+        self.awaiting_since = None;
+    }
+
+    /// The peer has pinged us, so we owe it an answer
+    pub fn received_ping_request(&mut self) {
+        self.responses_pending += 1;
+    }
+
+    /// Check whether the connection is idle (and should be pinged) or dead.
+    /// Connections we are not reading from (`reading_paused`) are pinged, but never time out
+    pub fn check(&mut self, config: &HeartbeatConfig, now: Instant, reading_paused: bool) -> HeartbeatStatus {
+        let interval = Duration::from_millis(config.interval_millis);
+
+        if let Some(sent) = self.awaiting_since {
+            let waiting = now.saturating_duration_since(sent);
+
+            return if reading_paused {
+                if waiting >= interval {
+                    self.queue_ping(now)
+                } else {
+                    HeartbeatStatus::Alive
+                }
+            } else if waiting >= Duration::from_millis(config.timeout_millis) {
+                HeartbeatStatus::TimedOut(waiting)
+            } else {
+                HeartbeatStatus::Alive
+            };
+        }
+
+        if now.saturating_duration_since(self.last_received) >= interval {
+            self.queue_ping(now)
+        } else {
+            HeartbeatStatus::Alive
+        }
+    }
+
+    fn queue_ping(&mut self, now: Instant) -> HeartbeatStatus {
+        self.awaiting_since = Some(now);
+        self.request_pending = true;
+
+        HeartbeatStatus::PingQueued
+    }
+
+    /// Do we have pings to write
+    pub fn has_pending_output(&self) -> bool {
+        self.request_pending || self.responses_pending > 0
+    }
+
+    /// Take the next ping we have to write, if any
+    pub fn take_ping<RM, PM>(&mut self, my_id: NodeId, peer_id: NodeId) -> Result<Option<WireMessage>>
+        where RM: Serializable + 'static, PM: Serializable + 'static {
+        let is_request = if self.responses_pending > 0 {
+            self.responses_pending -= 1;
+
+            false
+        } else if self.request_pending {
+            self.request_pending = false;
+
+            true
+        } else {
+            return Ok(None);
+        };
+
+        ping_message::<RM, PM>(my_id, peer_id, is_request).map(Some)
+    }
+}
+
+/// Build a ping message. Pings are neither signed nor digested, as they only concern the connection they are sent over
+pub(crate) fn ping_message<RM, PM>(my_id: NodeId, peer_id: NodeId, is_request: bool) -> Result<WireMessage>
+    where RM: Serializable + 'static, PM: Serializable + 'static {
+    let mut buf = Vec::new();
+
+    serialize::serialize_message::<Vec<u8>, RM, PM>(&mut buf, &NetworkMessageKind::Ping(PingMessage::new(is_request)))?;
+
+    Ok(WireMessage::new(my_id, peer_id, Bytes::from(buf), fastrand::u64(..), None, None)
+        .with_class(MessageClass::Ping))
+}
+
+/// Decode a ping we have received, returning whether it is a request
+pub(crate) fn read_ping<RM, PM>(payload: &[u8]) -> Result<bool>
+    where RM: Serializable + 'static, PM: Serializable + 'static {
+    match serialize::deserialize_message::<&[u8], RM, PM>(payload)? {
+        NetworkMessageKind::Ping(ping) => Ok(ping.is_request()),
+        // The peer announced a ping but sent something else, there is nothing to answer
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::config::HeartbeatConfig;
+    use crate::mio_tcp::connections::heartbeat::{Heartbeat, HeartbeatStatus};
+
+    const CONFIG: HeartbeatConfig = HeartbeatConfig {
+        interval_millis: 100,
+        timeout_millis: 300,
+    };
+
+    #[test]
+    fn test_idle_connection_is_pinged_then_times_out() {
+        let mut heartbeat = Heartbeat::new();
+        let start = Instant::now();
+
+        assert!(matches!(heartbeat.check(&CONFIG, start, false), HeartbeatStatus::Alive));
+        assert!(!heartbeat.has_pending_output());
+
+        let pinged = start + Duration::from_millis(150);
+
+        assert!(matches!(heartbeat.check(&CONFIG, pinged, false), HeartbeatStatus::PingQueued));
+        assert!(heartbeat.has_pending_output());
+
+        // Only one ping is sent while we wait for an answer
+        assert!(matches!(heartbeat.check(&CONFIG, pinged + Duration::from_millis(100), false), HeartbeatStatus::Alive));
+
+        assert!(matches!(heartbeat.check(&CONFIG, pinged + Duration::from_millis(300), false), HeartbeatStatus::TimedOut(_)));
+    }
+
+    #[test]
+    fn test_traffic_keeps_connection_alive() {
+        let mut heartbeat = Heartbeat::new();
+        let start = Instant::now();
+
+        assert!(matches!(heartbeat.check(&CONFIG, start + Duration::from_millis(150), false), HeartbeatStatus::PingQueued));
+
+        heartbeat.received_traffic(start + Duration::from_millis(200));
+
+        assert!(matches!(heartbeat.check(&CONFIG, start + Duration::from_millis(250), false), HeartbeatStatus::Alive));
+        assert!(matches!(heartbeat.check(&CONFIG, start + Duration::from_millis(500), false), HeartbeatStatus::PingQueued));
+    }
+
+    #[test]
+    fn test_connections_we_are_not_reading_keep_pinging() {
+        let mut heartbeat = Heartbeat::new();
+
+        let start = Instant::now();
+
+        assert!(matches!(heartbeat.check(&CONFIG, start + Duration::from_millis(150), true), HeartbeatStatus::PingQueued));
+
+        // The answer can't be read, so instead of timing out we ping again every interval
+        assert!(matches!(heartbeat.check(&CONFIG, start + Duration::from_millis(200), true), HeartbeatStatus::Alive));
+        assert!(matches!(heartbeat.check(&CONFIG, start + Duration::from_millis(500), true), HeartbeatStatus::PingQueued));
+        assert!(heartbeat.has_pending_output());
+
+        // Once we read again, the peer has the usual timeout to answer the last ping
+        assert!(matches!(heartbeat.check(&CONFIG, start + Duration::from_millis(700), false), HeartbeatStatus::Alive));
+        assert!(matches!(heartbeat.check(&CONFIG, start + Duration::from_millis(800), false), HeartbeatStatus::TimedOut(_)));
+    }
+}
@@ -1,6 +1,15 @@
 pub(crate) mod conn_establish;
 pub mod epoll_group;
 pub mod conn_util;
+pub(crate) mod tls;
+pub(crate) mod fragmentation;
+pub(crate) mod heartbeat;
+pub(crate) mod reconnection;
+pub(crate) mod goodbye;
+pub(crate) mod send_queue;
+pub(crate) mod reliable;
+pub(crate) mod rate_limit;
+pub(crate) mod busy;
 
 use crate::client_pooling::{ConnectedPeer, PeerIncomingRqHandling};
 use crate::message::{StoredMessage, WireMessage};
@@ -11,11 +20,11 @@ use crate::mio_tcp::connections::epoll_group::{
 use crate::reconfiguration_node::{NetworkInformationProvider, ReconfigurationMessageHandler};
 use crate::serialize::Serializable;
 use crate::NodeConnections;
-use atlas_common::channel::{ChannelSyncRx, ChannelSyncTx, OneShotRx, TryRecvError};
+use atlas_common::channel::{ChannelSyncRx, OneShotRx};
 use atlas_common::error::*;
 use atlas_common::node_id::{NodeId, NodeType};
 use atlas_common::peer_addr::PeerAddr;
-use atlas_common::socket::{MioSocket, SecureSocket, SecureSocketSync, SyncListener};
+use atlas_common::socket::SyncListener;
 use crossbeam_skiplist::SkipMap;
 use dashmap::DashMap;
 use log::{debug, error, info, warn};
@@ -23,12 +32,23 @@ use mio::{Token, Waker};
 use std::net::Shutdown;
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use anyhow::Context;
 use thiserror::Error;
-use atlas_common::{channel, Err};
-use crate::conn_utils::{Callback, ConnCounts};
+use atlas_common::channel;
+use crate::conn_utils::{Callback, ConnCounts, ConnectionEvent, DisconnectReason, Subscribers};
 use crate::mio_tcp::connections::conn_establish::pending_conn::{NetworkUpdateHandler, PendingConnHandle, RegisteredServers, ServerRegisteredPendingConns};
+use crate::config::{BackpressureConfig, BandwidthLimit, FailureDetectorConfig, FragmentationConfig, HeartbeatConfig, MessageVerificationConfig, PeerRateLimits, PriorityLanesConfig, RateLimitConfig, ReconnectionConfig, ReliableDeliveryConfig, ReplayProtectionConfig, TlsConfig};
+use crate::failure_detector::{FailureDetector, PeerSuspicion, SuspicionEvent};
+use crate::replay_protection::ReplayWindow;
 use crate::mio_tcp::connections::conn_util::{ReadingBuffer, WritingBuffer};
+use crate::mio_tcp::connections::fragmentation::ReassemblyBudget;
+use crate::mio_tcp::connections::rate_limit::{PeerBuckets, RateLimiter};
+use crate::mio_tcp::connections::reconnection::ReconnectionManager;
+use crate::mio_tcp::connections::reliable::{ReliableDelivery, ReliablePeer};
+use crate::mio_tcp::connections::send_queue::{DeliveryNotifier, QueuedMessage, QueueSlot, SendQueue};
+use crate::mio_tcp::connections::tls::SecureMioSocket;
 
 pub type NetworkSerializedMessage = (WireMessage);
 
@@ -57,6 +77,54 @@ pub struct Connections<NI, RM, PM>
     conn_counts: ConnCounts,
     // Handle establishing new connections
     conn_handler: Arc<ConnectionHandler>,
+    // Which received messages should be verified
+    verification_config: MessageVerificationConfig,
+    // The nonces we have recently received from each peer, to reject replayed messages
+    replay_window: Arc<ReplayWindow>,
+    // How large messages are fragmented and reassembled
+    fragmentation_config: FragmentationConfig,
+    // How often idle connections are pinged, and how long we wait for the answers
+    heartbeat_config: HeartbeatConfig,
+    // How suspicious we are of each peer, fed by the traffic we receive from them
+    failure_detector: Arc<FailureDetector>,
+    // Who wants to know about connections being established and lost
+    connection_events: Subscribers<ConnectionEvent>,
+    // The peers we are (re)connecting to, which the server worker connects to
+    reconnection: Arc<ReconnectionManager>,
+    // What we have sent to (and received from) each peer, for the reliable delivery of protocol messages
+    reliable_delivery: ReliableDelivery,
+    // How the send queues of our peers are split in priority lanes
+    priority_lanes_config: PriorityLanesConfig,
+    // What happens to messages sent to peers that can't keep up with them
+    backpressure_config: BackpressureConfig,
+    // How much bandwidth we may use with our peers
+    rate_limiter: RateLimiter,
+    // Are we shutting down, in which case we no longer (re)connect to anyone
+    shutting_down: AtomicBool,
+    // How long we keep writing what is queued on the connections we close on purpose
+    leave_timeout: Duration,
+}
+
+/// The configuration of the connections to our peers, gathered from the [crate::config::NodeConfig]
+/// (and its [crate::config::TcpConfig]) of the node
+pub(crate) struct ConnectionsConfig {
+    pub tls_config: TlsConfig,
+    // The highest wire protocol version we accept to use with other nodes
+    pub max_wire_version: u32,
+    // The largest payload we accept in a frame from other nodes
+    pub max_payload_length: usize,
+    // How long we keep writing what is queued on the connections we close on purpose
+    pub leave_timeout: Duration,
+    pub verification_config: MessageVerificationConfig,
+    pub replay_config: ReplayProtectionConfig,
+    pub fragmentation_config: FragmentationConfig,
+    pub heartbeat_config: HeartbeatConfig,
+    pub failure_detector_config: FailureDetectorConfig,
+    pub reconnection_config: ReconnectionConfig,
+    pub reliable_delivery_config: ReliableDeliveryConfig,
+    pub priority_lanes_config: PriorityLanesConfig,
+    pub rate_limit_config: RateLimitConfig,
+    pub backpressure_config: BackpressureConfig,
 }
 
 /// Structure that is responsible for handling all connections to a given peer
@@ -72,11 +140,16 @@ pub struct PeerConnection<RM, PM>
     conn_id_generator: AtomicU32,
     //The map connecting each connection to a token in the MIO Workers
     connections: SkipMap<u32, Option<ConnHandle>>,
-    // Sending messages to the connections
-    to_send: (
-        ChannelSyncTx<NetworkSerializedMessage>,
-        ChannelSyncRx<NetworkSerializedMessage>,
-    ),
+    // The messages waiting to be written to the peer, by any of the connections
+    to_send: Arc<SendQueue>,
+    // The memory this peer may use in messages being reassembled, across all of its connections
+    reassembly_budget: Arc<ReassemblyBudget>,
+    // The failure detection state of this peer, fed by all of its connections
+    suspicion: Arc<PeerSuspicion>,
+    // The reliable delivery state of this peer, which outlives this connection
+    reliable: Arc<ReliablePeer>,
+    // How much bandwidth we may use with this peer, across all of its connections
+    rate_limits: Arc<PeerBuckets>,
 }
 
 #[derive(Clone)]
@@ -121,48 +194,64 @@ impl<NI, RM, PM> NodeConnections for Connections<NI, RM, PM>
             return vec![];
         }
 
-        let addr = self.get_addr_for_node(&node);
-        let node_type = self.network_info.get_node_type(&node);
+        if self.is_shutting_down() {
+            warn!("{:?} // Attempted to connect to {:?} while shutting down", self.id, node);
 
-        if addr.is_none() || node_type.is_none() {
+            return vec![];
+        }
+
+        if self.get_addr_for_node(&node).is_none() || self.network_info.get_node_type(&node).is_none() {
             error!("No address found for node {:?}", node);
 
             return vec![];
         }
 
-        let addr = addr.unwrap();
+        let (txs, result_vec): (Vec<_>, Vec<_>) = (0..self.missing_connections(&node))
+            .map(|_| channel::new_oneshot_channel())
+            .unzip();
 
-        let current_connections = self
-            .registered_connections
-            .get(&node)
-            .map(|entry| entry.value().concurrent_connection_count())
-            .unwrap_or(0);
+        if !txs.is_empty() {
+            self.reconnection.connect_now(node, txs);
+        }
 
-        let connections = self
-            .conn_counts
-            .get_connections_to_node(self.id, node, &*self.network_info);
+        result_vec
+    }
 
-        let connections = if current_connections > connections {
-            0
-        } else {
-            connections - current_connections
-        };
+    fn suspicion_level(&self, node: &NodeId) -> f64 {
+        self.failure_detector.suspicion_level(node)
+    }
+
+    fn subscribe_suspicions(&self) -> ChannelSyncRx<SuspicionEvent> {
+        self.failure_detector.subscribe()
+    }
+
+    fn set_rate_limits(&self, node_type: NodeType, limits: PeerRateLimits) {
+        self.rate_limiter.set_limits(node_type, limits);
 
-        let mut result_vec = Vec::with_capacity(connections);
+        for entry in self.registered_connections.iter() {
+            let connection = entry.value();
 
-        for _ in 0..connections {
-            result_vec.push(
-                self.conn_handler
-                    .connect_to_node(Arc::clone(self), node, node_type.unwrap(), addr.clone()),
-            )
+            connection.rate_limits().set_limits(&self.rate_limiter.limits(Some(connection.node_type())));
         }
+    }
 
-        result_vec
+    fn set_global_send_limit(&self, limit: Option<BandwidthLimit>) {
+        self.rate_limiter.set_global_send_limit(limit);
     }
 
     async fn disconnect_from_node(&self, node: &NodeId) -> Result<()> {
+        // Don't bring back the connections we are closing
+        self.reconnection.cancel(node);
+
+        // Nor what we had sent over them
+        self.reliable_delivery.forget(node);
+
+        self.replay_window.forget(node);
+
         let existing_connection = self.registered_connections.remove(node);
 
+        let deadline = Instant::now() + self.leave_timeout;
+
         if let Some((node, connection)) = existing_connection {
             for entry in connection.connections.iter() {
                 if let Some(conn) = entry.value() {
@@ -170,9 +259,11 @@ impl<NI, RM, PM> NodeConnections for Connections<NI, RM, PM>
                     let conn_token = conn.token;
 
                     self.worker_group
-                        .disconnect_connection_from_worker(worker_id, conn_token)?;
+                        .disconnect_connection_from_worker(worker_id, conn_token, deadline)?;
                 }
             }
+
+            self.connection_events.notify(ConnectionEvent::AllConnectionsLost(node));
         }
 
         Ok(())
@@ -191,10 +282,30 @@ impl<NI, RM, PM> Connections<NI, RM, PM>
         conn_counts: ConnCounts,
         reconfiguration_handling: Arc<ReconfigurationMessageHandler<StoredMessage<RM::Message>>>,
         client_pooling: Arc<PeerIncomingRqHandling<StoredMessage<PM::Message>>>,
+        config: ConnectionsConfig,
     ) -> Result<Self> {
+        let ConnectionsConfig {
+            tls_config,
+            max_wire_version,
+            max_payload_length,
+            leave_timeout,
+            verification_config,
+            replay_config,
+            fragmentation_config,
+            heartbeat_config,
+            failure_detector_config,
+            reconnection_config,
+            reliable_delivery_config,
+            priority_lanes_config,
+            rate_limit_config,
+            backpressure_config,
+        } = config;
+
         let conn_handler = Arc::new(ConnectionHandler::initialize(
             id.clone(),
-            conn_counts.clone(),
+            tls_config,
+            max_wire_version,
+            max_payload_length,
         ));
 
         let server_connections = Arc::new(ServerRegisteredPendingConns::new());
@@ -212,16 +323,30 @@ impl<NI, RM, PM> Connections<NI, RM, PM>
             reconfig_handling: reconfiguration_handling,
             conn_counts,
             conn_handler,
+            verification_config,
+            replay_window: Arc::new(ReplayWindow::new(&replay_config)),
+            fragmentation_config,
+            heartbeat_config,
+            failure_detector: FailureDetector::init(failure_detector_config),
+            connection_events: Subscribers::new("Connection events"),
+            reconnection: Arc::new(ReconnectionManager::new(reconnection_config)),
+            reliable_delivery: ReliableDelivery::new(reliable_delivery_config),
+            priority_lanes_config,
+            backpressure_config,
+            rate_limiter: RateLimiter::new(rate_limit_config),
+            shutting_down: AtomicBool::new(false),
+            leave_timeout,
         })
     }
 
 
-    pub(super) fn setup_tcp_server_worker(self: &Arc<Self>, listener: SyncListener) {
+    /// Launch the server worker, returning the handle of its thread (which runs until we shut down)
+    pub(super) fn setup_tcp_server_worker(self: &Arc<Self>, listener: SyncListener) -> JoinHandle<()> {
         let (tx, rx) = channel::new_bounded_sync(SEND_QUEUE_SIZE, Some("TCP Server Worker"));
 
         self.registered_servers.register_server(tx);
 
-        let waker = conn_establish::initialize_server(
+        let (_waker, thread) = conn_establish::initialize_server(
             self.id.clone(),
             listener,
             self.conn_handler.clone(),
@@ -231,6 +356,8 @@ impl<NI, RM, PM> Connections<NI, RM, PM>
             self.reconfig_handling.clone(),
             rx,
         );
+
+        thread
     }
 
     /// Get the connection to a given node
@@ -245,6 +372,19 @@ impl<NI, RM, PM> Connections<NI, RM, PM>
         self.server_connections.get_pending_conn(node)
     }
 
+    /// How many connections to the given node we are missing, according to the connection counts
+    fn missing_connections(&self, node: &NodeId) -> usize {
+        let current_connections = self
+            .registered_connections
+            .get(node)
+            .map(|entry| entry.value().concurrent_connection_count())
+            .unwrap_or(0);
+
+        self.conn_counts
+            .get_connections_to_node(self.id, *node, &*self.network_info)
+            .saturating_sub(current_connections)
+    }
+
     /// Get the addr for the node given
     fn get_addr_for_node(&self, node: &NodeId) -> Option<PeerAddr> {
         self.network_info.get_addr_for_node(node)
@@ -253,7 +393,7 @@ impl<NI, RM, PM> Connections<NI, RM, PM>
     /// Register a connection without having to provide any sockets, as this is meant to be done
     /// preemptively so there is no possibility for the connection details to be lost due to
     /// multi threading non atomic shenanigans
-    fn preemptive_conn_register(self: &Arc<Self>, node: NodeId, node_type: NodeType, channel: (ChannelSyncTx<NetworkSerializedMessage>, ChannelSyncRx<NetworkSerializedMessage>))
+    fn preemptive_conn_register(self: &Arc<Self>, node: NodeId, node_type: NodeType, channel: Arc<SendQueue>)
                                 -> Result<Arc<PeerConnection<RM, PM>>> {
         debug!("Preemptively registering connection to node {:?}", node);
 
@@ -262,44 +402,39 @@ impl<NI, RM, PM> Connections<NI, RM, PM>
         let conn = option.or_insert_with(|| {
             Arc::new(PeerConnection::new(node_type,
                                          self.client_pooling.init_peer_conn(node, node_type),
-                                         self.reconfig_handling.clone(), channel))
+                                         self.reconfig_handling.clone(), channel,
+                                         self.fragmentation_config.max_reassembly_bytes_per_peer,
+                                         self.failure_detector.peer(node),
+                                         self.reliable_delivery.peer(node),
+                                         self.rate_limiter.peer(Some(node_type))))
         });
 
         Ok(conn.value().clone())
     }
 
     /// Handle a given socket having established the necessary connection
-    fn handle_connection_established(self: &Arc<Self>, node: NodeId,
-                                     socket: SecureSocket,
-                                     node_type: NodeType,
-                                     reading_info: ReadingBuffer,
-                                     writing_info: Option<WritingBuffer>,
-                                     channel: (ChannelSyncTx<NetworkSerializedMessage>, ChannelSyncRx<NetworkSerializedMessage>)) {
-        let socket = match socket {
-            SecureSocket::Sync(sync) => match sync {
-                SecureSocketSync::Plain(socket) => socket,
-                SecureSocketSync::Tls(tls, socket) => socket,
-            },
-            _ => unreachable!(),
-        };
-
-        self.handle_connection_established_with_socket(node, socket.into(), node_type, reading_info, writing_info, channel);
-    }
-
-    fn handle_connection_established_with_socket(
+    fn handle_connection_established(
         self: &Arc<Self>,
         node: NodeId,
-        socket: MioSocket,
+        mut socket: SecureMioSocket,
         node_type: NodeType,
+        wire_version: u32,
         reading_info: ReadingBuffer,
         writing_info: Option<WritingBuffer>,
-        channel: (ChannelSyncTx<NetworkSerializedMessage>, ChannelSyncRx<NetworkSerializedMessage>),
+        channel: Arc<SendQueue>,
     ) {
         info!(
-            "{:?} // Handling established connection to {:?} with node type: {:?}",
-            self.id, node, node_type
+            "{:?} // Handling established connection to {:?} with node type: {:?}, wire version {}",
+            self.id, node, node_type, wire_version
         );
 
+        if self.is_shutting_down() {
+            // The epoll workers are not taking any more connections
+            let _ = socket.shutdown(Shutdown::Both);
+
+            return;
+        }
+
         let option = self.registered_connections.entry(node);
 
         let peer_conn = option.or_insert_with(|| {
@@ -308,6 +443,10 @@ impl<NI, RM, PM> Connections<NI, RM, PM>
                 self.client_pooling.init_peer_conn(node, node_type),
                 self.reconfig_handling.clone(),
                 channel,
+                self.fragmentation_config.max_reassembly_bytes_per_peer,
+                self.failure_detector.peer(node),
+                self.reliable_delivery.peer(node),
+                self.rate_limiter.peer(Some(node_type)),
             ));
 
             debug!(
@@ -354,7 +493,7 @@ impl<NI, RM, PM> Connections<NI, RM, PM>
         peer_conn.register_peer_conn_intent(conn_id);
 
         let conn_details =
-            NewConnection::new(conn_id, node, self.id, socket, reading_info, writing_info, peer_conn.value().clone());
+            NewConnection::new(conn_id, node, self.id, socket, wire_version, reading_info, writing_info, peer_conn.value().clone());
 
         // We don't register the connection here as we still need some information that will only be provided
         // to us by the worker that will handle the connection.
@@ -362,13 +501,15 @@ impl<NI, RM, PM> Connections<NI, RM, PM>
         self.worker_group
             .assign_socket_to_worker(conn_details)
             .expect("Failed to assign socket to worker?");
+
+        self.connection_events.notify(ConnectionEvent::Connected(node, node_type, conn_id));
     }
 
     /// Handle a connection having broken and being removed from the worker
-    fn handle_connection_failed(self: &Arc<Self>, node: NodeId, conn_id: u32) {
+    fn handle_connection_failed(self: &Arc<Self>, node: NodeId, conn_id: u32, reason: DisconnectReason) {
         info!(
-            "{:?} // Handling failed connection to {:?}. Conn: {:?}",
-            self.id, node, conn_id
+            "{:?} // Handling failed connection to {:?}. Conn: {:?}, reason: {:?}",
+            self.id, node, conn_id, reason
         );
 
         let connection = if let Some(conn) = self.registered_connections.get(&node) {
@@ -379,12 +520,106 @@ impl<NI, RM, PM> Connections<NI, RM, PM>
 
         connection.delete_connection(conn_id);
 
-        if connection.concurrent_connection_count() == 0 {
+        // A peer that said goodbye is gone for good, there is no point in reconnecting to it
+        let peer_left = matches!(reason, DisconnectReason::PeerLeft(_));
+
+        let all_lost = connection.concurrent_connection_count() == 0;
+
+        if peer_left {
+            self.reliable_delivery.forget(&node);
+        } else {
+            // What we have yet to write to the peer is lost with its send queue, unless delivered reliably
+            if all_lost {
+                let policy = send_queue::policy_for(&self.backpressure_config, Some(connection.node_type()));
+
+                connection.reliable().salvage(connection.send_queue(), policy);
+            }
+
+            connection.reliable().connection_lost();
+
+            // The retransmissions go over the connections we still have
+            connection.wake_connections();
+        }
+
+        self.connection_events.notify(ConnectionEvent::Disconnected(node, reason));
+
+        if all_lost {
             self.registered_connections.remove(&node);
 
-            let _ = self.connect_to_node(node);
+            self.replay_window.forget(&node);
+
+            self.connection_events.notify(ConnectionEvent::AllConnectionsLost(node));
+        }
+
+        if !peer_left && !self.is_shutting_down() && self.missing_connections(&node) > 0 {
+            self.reconnection.reconnect(node);
+        }
+    }
+
+    /// Stop (re)connecting to anyone and have the epoll workers say goodbye to our peers,
+    /// closing every connection once what was queued on it has been written (or the leave timeout passes)
+    pub(super) fn shutdown(&self) {
+        if self.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        info!("{:?} // Shutting down our connections", self.id);
+
+        self.reconnection.cancel_all();
+
+        if let Err(err) = self.worker_group.shutdown_workers(Instant::now() + self.leave_timeout) {
+            error!("{:?} // Failed to shut down the epoll workers: {:?}", self.id, err);
+        }
+    }
+
+    /// Are we shutting down (see [Connections::shutdown])
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    /// Forget the peers we were connected to, once the epoll workers have closed all of our connections
+    pub(super) fn clear_connections(&self) {
+        for node in self.connected_nodes() {
+            self.replay_window.forget(&node);
+
+            if self.registered_connections.remove(&node).is_some() {
+                self.connection_events.notify(ConnectionEvent::AllConnectionsLost(node));
+            }
         }
     }
+
+    pub fn verification_config(&self) -> &MessageVerificationConfig {
+        &self.verification_config
+    }
+
+    pub fn replay_window(&self) -> &Arc<ReplayWindow> {
+        &self.replay_window
+    }
+
+    pub fn fragmentation_config(&self) -> &FragmentationConfig {
+        &self.fragmentation_config
+    }
+
+    pub fn heartbeat_config(&self) -> &HeartbeatConfig {
+        &self.heartbeat_config
+    }
+
+    pub fn priority_lanes_config(&self) -> &PriorityLanesConfig {
+        &self.priority_lanes_config
+    }
+
+    pub(crate) fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    pub(crate) fn failure_detector(&self) -> &Arc<FailureDetector> {
+        &self.failure_detector
+    }
+
+    pub(crate) fn connection_events(&self) -> &Subscribers<ConnectionEvent> {
+        &self.connection_events
+    }
+
     pub fn pending_server_connections(&self) -> &Arc<ServerRegisteredPendingConns> {
         &self.server_connections
     }
@@ -402,7 +637,11 @@ impl<RM, PM> PeerConnection<RM, PM>
         node_type: NodeType,
         client: Arc<ConnectedPeer<StoredMessage<PM::Message>>>,
         reconf_handling: Arc<ReconfigurationMessageHandler<StoredMessage<RM::Message>>>,
-        channel: (ChannelSyncTx<NetworkSerializedMessage>, ChannelSyncRx<NetworkSerializedMessage>),
+        channel: Arc<SendQueue>,
+        max_reassembly_bytes: usize,
+        suspicion: Arc<PeerSuspicion>,
+        reliable: Arc<ReliablePeer>,
+        rate_limits: Arc<PeerBuckets>,
     ) -> Self {
         Self {
             node_type,
@@ -411,6 +650,10 @@ impl<RM, PM> PeerConnection<RM, PM>
             conn_id_generator: AtomicU32::new(0),
             connections: Default::default(),
             to_send: channel,
+            reassembly_budget: Arc::new(ReassemblyBudget::new(max_reassembly_bytes)),
+            suspicion,
+            reliable,
+            rate_limits,
         }
     }
 
@@ -438,13 +681,28 @@ impl<RM, PM> PeerConnection<RM, PM>
         self.connections.len()
     }
 
-    /// Send the peer a given message
-    pub(crate) fn peer_message(&self, msg: WireMessage, callback: Callback) -> Result<()> {
+    /// The queue of the messages waiting to be written to the peer, in which
+    /// messages have to reserve a place before being sent (see [Self::peer_message])
+    pub(crate) fn send_queue(&self) -> &Arc<SendQueue> {
+        &self.to_send
+    }
+
+    /// Send the peer a given message, in the place it reserved in the send queue.
+    /// The callback is told whether the message was written to the connection (once the
+    /// epoll worker has flushed it), or not (if the message was dropped or the connection broke first)
+    pub(crate) fn peer_message(&self, slot: QueueSlot, msg: WireMessage, callback: Callback) -> Result<()> {
         let from = msg.header().from();
         let to = msg.header().to();
 
-        self.to_send.0.send(msg).context(format!("{:?} // Failed to send peer message to {:?}", from, to))?;
+        slot.push(msg, DeliveryNotifier::new(callback)).context(format!("{:?} // Failed to send peer message to {:?}", from, to))?;
+
+        self.wake_connections();
 
+        Ok(())
+    }
+
+    /// Wake the workers handling our connections to the peer, so they write what they can
+    fn wake_connections(&self) {
         for conn_ref in self.connections.iter() {
             let conn = conn_ref.value();
 
@@ -452,24 +710,19 @@ impl<RM, PM> PeerConnection<RM, PM>
                 conn.waker.wake().expect("Failed to wake connection");
             }
         }
+    }
 
-        Ok(())
+    pub(crate) fn reliable(&self) -> &Arc<ReliablePeer> {
+        &self.reliable
     }
 
-    /// Take a message from the send queue (blocking)
-    fn take_from_to_send(&self) -> Result<NetworkSerializedMessage> {
-        self.to_send.1.recv().context("Failed to take message from send queue")
+    pub(crate) fn rate_limits(&self) -> &Arc<PeerBuckets> {
+        &self.rate_limits
     }
 
     /// Attempt to take a message from the send queue (non blocking)
-    fn try_take_from_send(&self) -> Result<Option<NetworkSerializedMessage>> {
-        match self.to_send.1.try_recv() {
-            Ok(msg) => Ok(Some(msg)),
-            Err(err) => match err {
-                TryRecvError::ChannelDc => Err!(MioError::FailedToRetrieveFromSendQueue),
-                TryRecvError::ChannelEmpty | TryRecvError::Timeout => Ok(None),
-            },
-        }
+    fn try_take_from_send(&self) -> Result<Option<QueuedMessage>> {
+        self.to_send.try_pop()
     }
 
     fn delete_connection(&self, conn_id: u32) {
@@ -530,5 +783,7 @@ impl ConnHandle {
 #[derive(Error, Debug)]
 pub enum MioError {
     #[error("Failed to retrieve message from the send queue")]
-    FailedToRetrieveFromSendQueue
+    FailedToRetrieveFromSendQueue,
+    #[error("Received a header announcing a payload of {0} bytes, over the limit of {1} bytes")]
+    PayloadTooLarge(u64, usize),
 }
\ No newline at end of file
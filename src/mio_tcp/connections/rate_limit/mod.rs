@@ -0,0 +1,280 @@
+//! Token bucket rate limiting of the bandwidth used with our peers.
+//!
+//! Each peer has a bucket for what we write to it and another for what we read from it, shared by
+//! all of our connections to it, with limits that depend on the type of the peer (see [RateLimitConfig]).
+//! What we write to any peer also goes through a global bucket.
+//!
+//! Buckets are allowed to go into debt, so frames never have to be split to fit them: a connection
+//! uses its buckets as long as they are not empty, and then stops until they have been refilled.
+//! The epoll workers stop reading by dropping their read interest on the sockets, so the
+//! peer is slowed down by TCP flow control instead of us buffering what it sends.
+//!
+//! The limits can be changed at runtime, which also applies to the peers we are already connected to.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use atlas_common::node_id::NodeType;
+
+use crate::config::{BandwidthLimit, PeerRateLimits, RateLimitConfig};
+
+/// A token bucket, holding the amount of bytes we may still use
+pub(crate) struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    // `None` means unlimited
+    limit: Option<BandwidthLimit>,
+    // How many bytes we may still use, negative when the bucket is in debt
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// The buckets of a peer, shared by all of our connections to it
+pub(crate) struct PeerBuckets {
+    send: TokenBucket,
+    receive: TokenBucket,
+}
+
+/// The rate limits of all of our peers
+pub(crate) struct RateLimiter {
+    // The limits new peers get, by type
+    config: Mutex<RateLimitConfig>,
+    // What we write to all of our peers
+    global_send: TokenBucket,
+}
+
+/// The limits of a peer of the given type
+fn limits_for(config: &RateLimitConfig, node_type: Option<NodeType>) -> &PeerRateLimits {
+    match node_type {
+        Some(NodeType::Replica) => &config.replica_limits,
+        _ => &config.client_limits,
+    }
+}
+
+/// How many bytes a full bucket with the given limit holds
+fn capacity(limit: &BandwidthLimit) -> f64 {
+    limit.burst_bytes.max(1) as f64
+}
+
+impl TokenBucket {
+    pub fn new(limit: Option<BandwidthLimit>) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                limit,
+                tokens: limit.as_ref().map_or(0.0, capacity),
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Change the limit of this bucket, keeping what has already been used from it
+    pub fn set_limit(&self, limit: Option<BandwidthLimit>) {
+        let mut state = self.state.lock().unwrap();
+
+        state.refill(Instant::now());
+
+        state.tokens = match (&state.limit, &limit) {
+            (Some(_), Some(limit)) => state.tokens.min(capacity(limit)),
+            // A bucket that was unlimited hasn't been keeping track of what was used from it
+            (None, Some(limit)) => capacity(limit),
+            (_, None) => 0.0,
+        };
+
+        state.limit = limit;
+    }
+
+    /// How long until this bucket can be used again, `None` if it can be right away
+    pub fn wait_time(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+
+        let limit = state.limit?;
+
+        state.refill(now);
+
+        if state.tokens > 0.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - state.tokens) / limit.bytes_per_second.max(1) as f64))
+        }
+    }
+
+    /// Use the given amount of bytes from this bucket, which may leave it in debt
+    pub fn consume(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.limit.is_some() {
+            state.tokens -= bytes as f64;
+        }
+    }
+}
+
+impl BucketState {
+    fn refill(&mut self, now: Instant) {
+        if let Some(limit) = &self.limit {
+            let elapsed = now.saturating_duration_since(self.refilled_at);
+
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * limit.bytes_per_second as f64).min(capacity(limit));
+        }
+
+        self.refilled_at = self.refilled_at.max(now);
+    }
+}
+
+impl PeerBuckets {
+    fn new(limits: &PeerRateLimits) -> Self {
+        Self {
+            send: TokenBucket::new(limits.send_limit),
+            receive: TokenBucket::new(limits.receive_limit),
+        }
+    }
+
+    pub fn set_limits(&self, limits: &PeerRateLimits) {
+        self.send.set_limit(limits.send_limit);
+        self.receive.set_limit(limits.receive_limit);
+    }
+
+    /// How long until we can read from the peer again, `None` if we can right away
+    pub fn receive_wait(&self, now: Instant) -> Option<Duration> {
+        self.receive.wait_time(now)
+    }
+
+    /// We have read the given amount of bytes from the peer
+    pub fn received(&self, bytes: usize) {
+        self.receive.consume(bytes)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            global_send: TokenBucket::new(config.global_send_limit),
+            config: Mutex::new(config),
+        }
+    }
+
+    /// The buckets for a new peer of the given type
+    pub fn peer(&self, node_type: Option<NodeType>) -> Arc<PeerBuckets> {
+        let config = self.config.lock().unwrap();
+
+        Arc::new(PeerBuckets::new(limits_for(&config, node_type)))
+    }
+
+    /// The limits of peers of the given type
+    pub fn limits(&self, node_type: Option<NodeType>) -> PeerRateLimits {
+        limits_for(&self.config.lock().unwrap(), node_type).clone()
+    }
+
+    /// Change the limits of new peers of the given type.
+    /// The buckets of the peers we already have must be updated separately
+    pub fn set_limits(&self, node_type: NodeType, limits: PeerRateLimits) {
+        let mut config = self.config.lock().unwrap();
+
+        match node_type {
+            NodeType::Replica => config.replica_limits = limits,
+            _ => config.client_limits = limits,
+        }
+    }
+
+    pub fn set_global_send_limit(&self, limit: Option<BandwidthLimit>) {
+        self.config.lock().unwrap().global_send_limit = limit;
+
+        self.global_send.set_limit(limit);
+    }
+
+    /// How long until we can write to the peer with the given buckets again, `None` if we can right away
+    pub fn send_wait(&self, peer: &PeerBuckets, now: Instant) -> Option<Duration> {
+        peer.send.wait_time(now).max(self.global_send.wait_time(now))
+    }
+
+    /// We have written the given amount of bytes to the peer with the given buckets
+    pub fn sent(&self, peer: &PeerBuckets, bytes: usize) {
+        peer.send.consume(bytes);
+        self.global_send.consume(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use atlas_common::node_id::NodeType;
+
+    use crate::config::{BandwidthLimit, PeerRateLimits, RateLimitConfig};
+    use crate::mio_tcp::connections::rate_limit::{RateLimiter, TokenBucket};
+
+    const LIMIT: BandwidthLimit = BandwidthLimit {
+        bytes_per_second: 1000,
+        burst_bytes: 100,
+    };
+
+    #[test]
+    fn test_bucket_goes_into_debt_and_refills() {
+        let bucket = TokenBucket::new(Some(LIMIT));
+
+        let start = Instant::now();
+
+        assert!(bucket.wait_time(start).is_none());
+
+        // A frame larger than the burst still goes through, leaving the bucket in debt
+        bucket.consume(300);
+
+        let wait = bucket.wait_time(start).expect("The bucket should be in debt");
+
+        assert!(wait >= Duration::from_millis(200) && wait <= Duration::from_millis(202), "{:?}", wait);
+
+        assert!(bucket.wait_time(start + wait).is_none());
+
+        // Refilling never goes over the burst
+        assert!(bucket.wait_time(start + Duration::from_secs(10)).is_none());
+
+        bucket.consume(100);
+
+        assert!(bucket.wait_time(start + Duration::from_secs(10)).is_some());
+
+        // Unlimited buckets never have to wait
+        let unlimited = TokenBucket::new(None);
+
+        unlimited.consume(usize::MAX);
+
+        assert!(unlimited.wait_time(start).is_none());
+    }
+
+    #[test]
+    fn test_limits_are_applied_by_node_type() {
+        let unlimited = PeerRateLimits { send_limit: None, receive_limit: None };
+
+        let limiter = RateLimiter::new(RateLimitConfig {
+            global_send_limit: None,
+            replica_limits: unlimited.clone(),
+            client_limits: PeerRateLimits { send_limit: Some(LIMIT), receive_limit: Some(LIMIT) },
+        });
+
+        let now = Instant::now();
+
+        let replica = limiter.peer(Some(NodeType::Replica));
+        let client = limiter.peer(Some(NodeType::Client));
+
+        limiter.sent(&replica, 1000);
+        limiter.sent(&client, 1000);
+        client.received(1000);
+
+        assert!(limiter.send_wait(&replica, now).is_none());
+        assert!(limiter.send_wait(&client, now).is_some());
+        assert!(client.receive_wait(now).is_some());
+
+        // Lifting the limits of a peer lets it through right away
+        client.set_limits(&unlimited);
+
+        assert!(limiter.send_wait(&client, now).is_none());
+        assert!(client.receive_wait(now).is_none());
+
+        // The global limit applies to every peer
+        limiter.set_global_send_limit(Some(LIMIT));
+        limiter.sent(&replica, 1000);
+
+        assert!(limiter.send_wait(&replica, now).is_some());
+        assert!(limiter.send_wait(&client, now).is_some());
+    }
+}
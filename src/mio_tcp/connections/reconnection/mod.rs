@@ -0,0 +1,361 @@
+//! (Re)establishing the connections to our peers.
+//!
+//! We keep a schedule of the peers we are missing connections to, which the server worker polls
+//! to start non blocking connection attempts (driven by its own poll, see [conn_establish]).
+//! Every time a round of attempts fails, the next one is delayed with an exponential backoff
+//! (randomized with some jitter and capped), until we either have the connections the
+//! [ConnCounts](crate::conn_utils::ConnCounts) call for, we give up or the reconnection is cancelled.
+//!
+//! [conn_establish]: crate::mio_tcp::connections::conn_establish
+
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+
+use atlas_common::Err;
+use atlas_common::channel::OneShotTx;
+use atlas_common::error::*;
+use atlas_common::node_id::NodeId;
+
+use crate::config::ReconnectionConfig;
+use crate::mio_tcp::connections::conn_establish::ConnectionEstablishError;
+
+/// The state of the (re)connection to a single peer
+struct PeerReconnection {
+    // How many attempts have failed in a row
+    failed_attempts: u32,
+    // When we should make the next attempt
+    next_attempt: Instant,
+    // How many of the attempts of the current round are still underway
+    in_flight: usize,
+    // Has any attempt of the current round failed
+    round_failed: bool,
+    // Were we asked to connect again while a round was underway
+    rescheduled: bool,
+    // Those waiting for connections to this peer to be established
+    waiting: Vec<OneShotTx<Result<()>>>,
+}
+
+/// The schedule of the peers we have to (re)connect to
+pub(crate) struct ReconnectionManager {
+    config: ReconnectionConfig,
+    peers: Mutex<BTreeMap<NodeId, PeerReconnection>>,
+}
+
+impl ReconnectionManager {
+    pub fn new(config: ReconnectionConfig) -> Self {
+        Self {
+            config,
+            peers: Mutex::new(Default::default()),
+        }
+    }
+
+    pub fn config(&self) -> &ReconnectionConfig {
+        &self.config
+    }
+
+    /// Connect to the given peer as soon as possible.
+    /// Each of the waiters is notified when a connection is established (or when we give up)
+    pub fn connect_now(&self, node: NodeId, waiting: Vec<OneShotTx<Result<()>>>) {
+        self.schedule(node, waiting, Duration::ZERO);
+    }
+
+    /// Reconnect to a peer after a connection to it failed, once the initial backoff has passed
+    pub fn reconnect(&self, node: NodeId) {
+        self.schedule(node, Vec::new(), backoff_delay(&self.config, 0, fastrand::f64()));
+    }
+
+    fn schedule(&self, node: NodeId, waiting: Vec<OneShotTx<Result<()>>>, delay: Duration) {
+        let next_attempt = Instant::now() + delay;
+
+        let mut peers = self.peers.lock().unwrap();
+
+        match peers.entry(node) {
+            Entry::Occupied(mut entry) => {
+                // We keep the current backoff, so that failing connections don't make us retry faster
+                let peer = entry.get_mut();
+
+                peer.waiting.extend(waiting);
+
+                if peer.in_flight > 0 {
+                    peer.rescheduled = true;
+                }
+
+                peer.next_attempt = peer.next_attempt.min(next_attempt);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(PeerReconnection {
+                    failed_attempts: 0,
+                    next_attempt,
+                    in_flight: 0,
+                    round_failed: false,
+                    rescheduled: false,
+                    waiting,
+                });
+            }
+        }
+    }
+
+    /// Stop (re)connecting to the given peer. Anyone waiting on the connections is notified
+    pub fn cancel(&self, node: &NodeId) {
+        let cancelled = self.peers.lock().unwrap().remove(node);
+
+        if let Some(peer) = cancelled {
+            debug!("Cancelled the connection to node {:?}", node);
+
+            for tx in peer.waiting {
+                let _ = tx.send(Err!(ConnectionEstablishError::ConnectionCancelled(*node)));
+            }
+        }
+    }
+
+    /// Stop (re)connecting to every peer, as we are shutting down
+    pub fn cancel_all(&self) {
+        let cancelled = std::mem::take(&mut *self.peers.lock().unwrap());
+
+        for (node, peer) in cancelled {
+            for tx in peer.waiting {
+                let _ = tx.send(Err!(ConnectionEstablishError::ConnectionCancelled(node)));
+            }
+        }
+    }
+
+    /// Are we still trying to connect to the given peer (false if it was cancelled)
+    pub fn is_connecting(&self, node: &NodeId) -> bool {
+        self.peers.lock().unwrap().contains_key(node)
+    }
+
+    /// Start a new round of attempts for the peers that are due, returning how many connections
+    /// have to be attempted to each of them (given by `missing_connections`)
+    pub fn take_due(&self, now: Instant, missing_connections: impl Fn(&NodeId) -> usize) -> Vec<(NodeId, usize)> {
+        let mut peers = self.peers.lock().unwrap();
+
+        let mut due = Vec::new();
+        let mut done = Vec::new();
+
+        for (node, peer) in peers.iter_mut() {
+            if peer.in_flight > 0 || peer.next_attempt > now {
+                continue;
+            }
+
+            match missing_connections(node) {
+                0 => done.push(*node),
+                missing => {
+                    peer.in_flight = missing;
+                    peer.round_failed = false;
+                    peer.rescheduled = false;
+
+                    due.push((*node, missing));
+                }
+            }
+        }
+
+        // Someone else (for example the peer itself) has already established the connections
+        for node in done {
+            if let Some(peer) = peers.remove(&node) {
+                for tx in peer.waiting {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+        }
+
+        due
+    }
+
+    /// A connection attempt to the peer succeeded
+    pub fn attempt_succeeded(&self, node: &NodeId) {
+        let mut peers = self.peers.lock().unwrap();
+
+        if let Some(peer) = peers.get_mut(node) {
+            peer.failed_attempts = 0;
+
+            if !peer.waiting.is_empty() {
+                let _ = peer.waiting.remove(0).send(Ok(()));
+            }
+
+            peer.in_flight = peer.in_flight.saturating_sub(1);
+        }
+
+        self.finish_round(&mut peers, *node);
+    }
+
+    /// A connection attempt to the peer failed
+    pub fn attempt_failed(&self, node: &NodeId) {
+        let mut peers = self.peers.lock().unwrap();
+
+        if let Some(peer) = peers.get_mut(node) {
+            peer.round_failed = true;
+            peer.in_flight = peer.in_flight.saturating_sub(1);
+        }
+
+        self.finish_round(&mut peers, *node);
+    }
+
+    /// Once all the attempts of a round are done, either schedule the next round
+    /// (with the backoff, if any attempt failed) or stop connecting to the peer
+    fn finish_round(&self, peers: &mut BTreeMap<NodeId, PeerReconnection>, node: NodeId) {
+        let mut entry = match peers.entry(node) {
+            // The connection was cancelled in the meantime
+            Entry::Vacant(_) => return,
+            Entry::Occupied(entry) if entry.get().in_flight > 0 => return,
+            Entry::Occupied(entry) => entry,
+        };
+
+        let peer = entry.get_mut();
+
+        if peer.round_failed {
+            peer.failed_attempts += 1;
+
+            if self.config.max_attempts.map_or(false, |max| peer.failed_attempts >= max) {
+                error!("Failed to connect to node {:?} after {} attempts, giving up", node, peer.failed_attempts);
+
+                for tx in entry.remove().waiting {
+                    let _ = tx.send(Err!(ConnectionEstablishError::FailedToConnectToNode(node)));
+                }
+
+                return;
+            }
+
+            let delay = backoff_delay(&self.config, peer.failed_attempts, fastrand::f64());
+
+            debug!("Retrying the connection to node {:?} in {:?} (attempt {})", node, delay, peer.failed_attempts + 1);
+
+            peer.next_attempt = Instant::now() + delay;
+        } else if !peer.rescheduled {
+            for tx in entry.remove().waiting {
+                let _ = tx.send(Ok(()));
+            }
+        }
+
+        // Otherwise, some connection failed while we were connecting, so we go again as scheduled
+    }
+
+    /// Stop connecting to the peer, notifying anyone waiting on the connections
+    pub fn give_up(&self, node: NodeId) {
+        let removed = self.peers.lock().unwrap().remove(&node);
+
+        if let Some(peer) = removed {
+            for tx in peer.waiting {
+                let _ = tx.send(Err!(ConnectionEstablishError::FailedToConnectToNode(node)));
+            }
+        }
+    }
+}
+
+/// How long to wait after the given amount of failed attempts, where `random` is uniform in `[0, 1)`
+fn backoff_delay(config: &ReconnectionConfig, failed_attempts: u32, random: f64) -> Duration {
+    let max = config.max_backoff_millis as f64;
+
+    let delay = (config.initial_backoff_millis as f64
+        * config.backoff_multiplier.powi(failed_attempts.min(i32::MAX as u32) as i32))
+        .min(max);
+
+    let jitter = config.jitter.clamp(0.0, 1.0);
+
+    let delay = delay * (1.0 + jitter * (2.0 * random - 1.0));
+
+    Duration::from_millis(delay.clamp(0.0, max).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use atlas_common::channel;
+    use atlas_common::node_id::NodeId;
+
+    use crate::config::ReconnectionConfig;
+    use crate::mio_tcp::connections::reconnection::{backoff_delay, ReconnectionManager};
+
+    fn config() -> ReconnectionConfig {
+        ReconnectionConfig {
+            initial_backoff_millis: 100,
+            max_backoff_millis: 5000,
+            backoff_multiplier: 2.0,
+            jitter: 0.5,
+            connect_timeout_millis: 1000,
+            max_attempts: None,
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_until_the_cap() {
+        let config = config();
+
+        // A random value of 0.5 cancels out the jitter
+        let delays = (0..10)
+            .map(|attempt| backoff_delay(&config, attempt, 0.5))
+            .collect::<Vec<_>>();
+
+        assert_eq!(delays[0], Duration::from_millis(100));
+        assert_eq!(delays[1], Duration::from_millis(200));
+        assert_eq!(delays[3], Duration::from_millis(800));
+        assert_eq!(delays[9], Duration::from_millis(5000));
+
+        assert_eq!(backoff_delay(&config, u32::MAX, 0.5), Duration::from_millis(5000));
+    }
+
+    #[test]
+    fn test_backoff_jitter_stays_in_bounds() {
+        let config = config();
+
+        for _ in 0..1000 {
+            let delay = backoff_delay(&config, 2, fastrand::f64());
+
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(600));
+
+            let capped = backoff_delay(&config, 20, fastrand::f64());
+
+            assert!(capped >= Duration::from_millis(2500) && capped <= Duration::from_millis(5000));
+        }
+    }
+
+    #[test]
+    fn test_failed_rounds_are_retried_after_the_backoff() {
+        let manager = ReconnectionManager::new(config());
+
+        let node = NodeId::from(1u32);
+
+        let (tx, rx) = channel::new_oneshot_channel();
+
+        manager.connect_now(node, vec![tx]);
+
+        let now = Instant::now();
+
+        assert_eq!(manager.take_due(now, |_| 2), vec![(node, 2)]);
+
+        // The round is underway, so the peer is not due again
+        assert!(manager.take_due(now, |_| 2).is_empty());
+
+        manager.attempt_succeeded(&node);
+        manager.attempt_failed(&node);
+
+        assert!(rx.recv().unwrap().is_ok());
+        assert!(manager.take_due(Instant::now(), |_| 1).is_empty());
+
+        assert_eq!(manager.take_due(Instant::now() + Duration::from_secs(10), |_| 1), vec![(node, 1)]);
+
+        manager.attempt_succeeded(&node);
+
+        assert!(!manager.is_connecting(&node));
+    }
+
+    #[test]
+    fn test_cancelling_notifies_the_waiters() {
+        let manager = ReconnectionManager::new(config());
+
+        let node = NodeId::from(1u32);
+
+        let (tx, rx) = channel::new_oneshot_channel();
+
+        manager.connect_now(node, vec![tx]);
+        manager.cancel(&node);
+
+        assert!(rx.recv().unwrap().is_err());
+        assert!(manager.take_due(Instant::now(), |_| 1).is_empty());
+        assert!(!manager.is_connecting(&node));
+    }
+}
@@ -0,0 +1,636 @@
+//! Reliable delivery of protocol messages, across the connections to a peer breaking.
+//!
+//! Every protocol message we write to a peer is given a sequence number, and kept until the peer
+//! acknowledges it. When a connection to the peer breaks, whatever the peer has not acknowledged
+//! is written again over its other connections (or once we reconnect to it), along with what was
+//! still waiting in its send queue. The peer drops the messages it has already received, so
+//! retransmitted messages are never delivered twice.
+//!
+//! A sequenced message is marked with [Header::is_sequenced], and its payload is prefixed with the
+//! session of the sender, the sequence number of the message and the floor of the sender (all the
+//! messages up to it have been acknowledged, so the receiver doesn't have to wait for them, which
+//! matters when the receiver has restarted). All three are little endian `u64`s. Sessions are random,
+//! and change whenever we forget a peer, so that the peer knows to start over.
+//!
+//! Acknowledgements are cumulative: a frame marked with [Header::is_ack], carrying the session it
+//! acknowledges and the sequence number up to which everything has been received.
+//!
+//! Messages are only sequenced on connections to peers that speak at least [WireMessage::RELIABLE_VERSION].
+//!
+//! When every connection to a peer breaks, the protocol messages still waiting in its send queue are
+//! sequenced as well (up to the unacknowledged limit), keeping whoever tracks them waiting until
+//! they are written over the next connection.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use bytes::{Buf, Bytes, BytesMut};
+use dashmap::DashMap;
+use thiserror::Error;
+
+use atlas_common::Err;
+use atlas_common::error::*;
+use atlas_common::node_id::NodeId;
+use atlas_metrics::metrics::metric_increment;
+
+use crate::config::{BackpressurePolicy, ReliableDeliveryConfig};
+use crate::message::{Header, MessageClass, WireMessage};
+use crate::metric::{COMM_DUPLICATES_DROPPED_ID, COMM_RETRANSMISSIONS_ID, COMM_SEND_QUEUE_FULL_ID};
+use crate::mio_tcp::connections::send_queue::{DeliveryNotifier, QueuedMessage, SendQueue};
+
+/// The size of the prefix of each sequenced message (session, sequence number and floor)
+const SEQUENCE_PREFIX_LENGTH: usize = 3 * std::mem::size_of::<u64>();
+
+/// The size of the payload of an acknowledgement (session and sequence number)
+const ACK_LENGTH: usize = 2 * std::mem::size_of::<u64>();
+
+/// How far past everything it has received a message can be, which bounds how many out of order
+/// messages we keep track of. Senders don't go further than their unacknowledged limit, so a
+/// message past this is a misbehaving peer
+const RECEIVE_WINDOW: u64 = 1 << 16;
+
+/// The reliable delivery state of all the peers we know of
+pub(crate) struct ReliableDelivery {
+    config: ReliableDeliveryConfig,
+    peers: DashMap<NodeId, Arc<ReliablePeer>>,
+}
+
+/// The reliable delivery state of a single peer, shared by all of its connections.
+/// It outlives them, so that what we had sent over them can be written again once we reconnect
+pub(crate) struct ReliablePeer {
+    peer_id: NodeId,
+    // Do we sequence the messages we send to this peer
+    enabled: bool,
+    max_unacked: usize,
+    sender: Mutex<SenderState>,
+    receiver: Mutex<ReceiverState>,
+}
+
+/// What we have sent to the peer
+struct SenderState {
+    session: u64,
+    next_sequence: u64,
+    // The messages the peer has yet to acknowledge, by sequence number
+    unacked: BTreeMap<u64, (Header, Bytes)>,
+    // The (unacknowledged) messages we have to write again
+    retransmit: VecDeque<u64>,
+    // The trackers of the messages salvaged from the send queue, told once they are first written
+    notifiers: BTreeMap<u64, DeliveryNotifier>,
+}
+
+/// What we have received from the peer
+struct ReceiverState {
+    // The session of the peer the sequence numbers belong to
+    session: Option<u64>,
+    // Every message up to this sequence number has been received
+    received_up_to: u64,
+    // The messages received past a gap (as the connections to the peer don't keep the order between them)
+    received_above: BTreeSet<u64>,
+    // Do we owe the peer an acknowledgement
+    ack_due: bool,
+}
+
+impl ReliableDelivery {
+    pub fn new(config: ReliableDeliveryConfig) -> Self {
+        Self {
+            config,
+            peers: Default::default(),
+        }
+    }
+
+    pub fn peer(&self, node: NodeId) -> Arc<ReliablePeer> {
+        self.peers.entry(node)
+            .or_insert_with(|| Arc::new(ReliablePeer::new(node, &self.config)))
+            .value()
+            .clone()
+    }
+
+    /// Forget what we have sent to (and received from) the peer, as it is gone for good.
+    /// If we ever talk to it again, it will be in a new session
+    pub fn forget(&self, node: &NodeId) {
+        self.peers.remove(node);
+    }
+}
+
+impl ReliablePeer {
+    fn new(peer_id: NodeId, config: &ReliableDeliveryConfig) -> Self {
+        Self {
+            peer_id,
+            enabled: config.enabled,
+            max_unacked: config.max_unacked.max(1),
+            sender: Mutex::new(SenderState {
+                session: fastrand::u64(..),
+                next_sequence: 1,
+                unacked: Default::default(),
+                retransmit: Default::default(),
+                notifiers: Default::default(),
+            }),
+            receiver: Mutex::new(ReceiverState::new(None)),
+        }
+    }
+
+    /// Do we sequence the messages we write over a connection using the given wire version
+    pub fn is_supported(&self, wire_version: u32) -> bool {
+        self.enabled && wire_version >= WireMessage::RELIABLE_VERSION
+    }
+
+    /// Take the next message to write to the peer, along with its sequence number (for protocol messages).
+    /// Retransmissions go first, then the messages returned by `take_queued` (which should take them from
+    /// the send queue), unless too many messages are waiting to be acknowledged
+    pub fn next_message<F>(&self, take_queued: F) -> Result<Option<(QueuedMessage, Option<u64>)>>
+        where F: FnOnce() -> Result<Option<QueuedMessage>> {
+        let mut sender = self.sender.lock().unwrap();
+
+        while let Some(sequence) = sender.retransmit.pop_front() {
+            // Unless it has been acknowledged in the meantime
+            if let Some((header, payload)) = sender.unacked.get(&sequence) {
+                metric_increment(COMM_RETRANSMISSIONS_ID, Some(1));
+
+                let message = WireMessage { header: *header, payload: payload.clone() };
+
+                let notifier = sender.notifiers.remove(&sequence).unwrap_or_else(DeliveryNotifier::untracked);
+
+                return Ok(Some(((message, notifier), Some(sequence))));
+            }
+        }
+
+        if sender.unacked.len() >= self.max_unacked {
+            return Ok(None);
+        }
+
+        let (message, notifier) = match take_queued()? {
+            Some(queued) => queued,
+            None => return Ok(None),
+        };
+
+        if !is_reliable(&message) {
+            return Ok(Some(((message, notifier), None)));
+        }
+
+        let sequence = sender.sequence(&message);
+
+        Ok(Some(((message, notifier), Some(sequence))))
+    }
+
+    /// Mark a message as sequenced, prefixing its payload (see the module docs).
+    /// Done once the message has been authenticated, as the prefix is removed before it is verified
+    pub fn wrap(&self, message: WireMessage, sequence: u64) -> WireMessage {
+        let (session, floor) = {
+            let sender = self.sender.lock().unwrap();
+
+            (sender.session, sender.floor())
+        };
+
+        let (mut header, payload) = message.into_inner();
+
+        let mut prefixed = BytesMut::with_capacity(SEQUENCE_PREFIX_LENGTH + payload.len());
+
+        prefixed.extend_from_slice(&session.to_le_bytes()[..]);
+        prefixed.extend_from_slice(&sequence.to_le_bytes()[..]);
+        prefixed.extend_from_slice(&floor.to_le_bytes()[..]);
+        prefixed.extend_from_slice(&payload[..]);
+
+        header.set_sequenced(true);
+        header.length = prefixed.len() as u64;
+
+        WireMessage { header, payload: prefixed.freeze() }
+    }
+
+    /// The peer has acknowledged what we sent it. Returns whether we can write messages
+    /// to it again, having stopped because too many were waiting to be acknowledged
+    pub fn acknowledge(&self, payload: &[u8]) -> Result<bool> {
+        let (session, received_up_to) = self.read_ack(payload)?;
+
+        let mut sender = self.sender.lock().unwrap();
+
+        // An acknowledgement of a session we have forgotten
+        if session != sender.session {
+            return Ok(false);
+        }
+
+        let was_full = sender.unacked.len() >= self.max_unacked;
+
+        sender.unacked = match received_up_to.checked_add(1) {
+            Some(first_unacked) => sender.unacked.split_off(&first_unacked),
+            None => Default::default(),
+        };
+
+        Ok(was_full && sender.unacked.len() < self.max_unacked)
+    }
+
+    /// A connection to the peer broke, taking with it anything the peer had yet to acknowledge.
+    /// All of it is written again over the next connection to write to the peer
+    pub fn connection_lost(&self) {
+        let mut sender = self.sender.lock().unwrap();
+
+        sender.retransmit = sender.unacked.keys().copied().collect();
+    }
+
+    /// All of the connections to the peer broke, so its send queue is about to be dropped.
+    /// The protocol messages in it are sequenced, so they are written once we reconnect
+    /// (see [Self::connection_lost]), and their trackers are told once that happens.
+    ///
+    /// No more than the unacknowledged limit is kept. Past it, the given backpressure policy picks
+    /// which messages are dropped (there is no sender left to block, so blocking policies drop the newest)
+    pub fn salvage(&self, queue: &SendQueue, policy: BackpressurePolicy) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut salvaged = VecDeque::new();
+
+        while let Ok(Some((message, notifier))) = queue.try_pop() {
+            if is_reliable(&message) {
+                salvaged.push_back((message, notifier));
+            }
+        }
+
+        let dropped = {
+            let mut sender = self.sender.lock().unwrap();
+
+            let room = self.max_unacked.saturating_sub(sender.unacked.len());
+
+            let dropped = if salvaged.len() > room {
+                metric_increment(COMM_SEND_QUEUE_FULL_ID, Some(1));
+
+                match policy {
+                    BackpressurePolicy::DropOldest => salvaged.drain(..salvaged.len() - room).collect(),
+                    BackpressurePolicy::DropNewest | BackpressurePolicy::Fail | BackpressurePolicy::Block { .. } => salvaged.split_off(room),
+                }
+            } else {
+                VecDeque::new()
+            };
+
+            for (message, notifier) in salvaged {
+                let sequence = sender.sequence(&message);
+
+                if notifier.is_tracked() {
+                    sender.notifiers.insert(sequence, notifier);
+                }
+            }
+
+            dropped
+        };
+
+        // The notifiers of the dropped messages report them as not delivered, once we let go of the sender
+        drop(dropped);
+    }
+
+    /// We have received a sequenced message from the peer. Returns the message without its prefix
+    /// (as it was sent), or `None` if we had already received it.
+    /// Errors mean the peer is misbehaving, so the connection should be closed
+    pub fn receive(&self, mut header: Header, mut payload: BytesMut) -> Result<Option<(Header, BytesMut)>> {
+        if payload.len() < SEQUENCE_PREFIX_LENGTH {
+            return Err!(ReliableDeliveryError::MalformedSequence(self.peer_id));
+        }
+
+        let session = payload.get_u64_le();
+        let sequence = payload.get_u64_le();
+        let floor = payload.get_u64_le();
+
+        let fresh = {
+            let mut receiver = self.receiver.lock().unwrap();
+
+            if receiver.session != Some(session) {
+                *receiver = ReceiverState::new(Some(session));
+            }
+
+            receiver.ack_due = true;
+
+            receiver.advance_to(floor);
+
+            if sequence > receiver.received_up_to.saturating_add(RECEIVE_WINDOW) {
+                return Err!(ReliableDeliveryError::OutOfWindow(self.peer_id, sequence, receiver.received_up_to));
+            }
+
+            receiver.receive(sequence)
+        };
+
+        if !fresh {
+            metric_increment(COMM_DUPLICATES_DROPPED_ID, Some(1));
+
+            return Ok(None);
+        }
+
+        header.set_sequenced(false);
+        header.length = payload.len() as u64;
+
+        Ok(Some((header, payload)))
+    }
+
+    /// Do we owe the peer an acknowledgement
+    pub fn has_pending_ack(&self) -> bool {
+        self.receiver.lock().unwrap().ack_due
+    }
+
+    /// Take the acknowledgement we owe the peer, if any, to write over a connection using the given wire version
+    pub fn take_ack(&self, my_id: NodeId, wire_version: u32) -> Option<WireMessage> {
+        if wire_version < WireMessage::RELIABLE_VERSION {
+            return None;
+        }
+
+        let mut receiver = self.receiver.lock().unwrap();
+
+        match receiver.session {
+            Some(session) if receiver.ack_due => {
+                receiver.ack_due = false;
+
+                Some(ack_message(my_id, self.peer_id, session, receiver.received_up_to))
+            }
+            _ => None,
+        }
+    }
+
+    fn read_ack(&self, payload: &[u8]) -> Result<(u64, u64)> {
+        if payload.len() != ACK_LENGTH {
+            return Err!(ReliableDeliveryError::MalformedAck(self.peer_id));
+        }
+
+        let mut payload = payload;
+
+        Ok((payload.get_u64_le(), payload.get_u64_le()))
+    }
+}
+
+impl SenderState {
+    /// Give the message the next sequence number, keeping it until it is acknowledged
+    fn sequence(&mut self, message: &WireMessage) -> u64 {
+        let sequence = self.next_sequence;
+
+        self.next_sequence += 1;
+
+        self.unacked.insert(sequence, (message.header, message.payload.clone()));
+
+        sequence
+    }
+
+    /// Every message up to this sequence number has been acknowledged
+    fn floor(&self) -> u64 {
+        match self.unacked.keys().next() {
+            Some(first_unacked) => first_unacked - 1,
+            None => self.next_sequence - 1,
+        }
+    }
+}
+
+impl ReceiverState {
+    fn new(session: Option<u64>) -> Self {
+        Self {
+            session,
+            received_up_to: 0,
+            received_above: Default::default(),
+            ack_due: false,
+        }
+    }
+
+    /// The sender won't send anything up to the given sequence number again
+    fn advance_to(&mut self, floor: u64) {
+        if floor <= self.received_up_to {
+            return;
+        }
+
+        self.received_up_to = floor;
+        self.received_above = self.received_above.split_off(&floor);
+        self.received_above.remove(&floor);
+
+        self.fill_gaps();
+    }
+
+    /// Receive the given sequence number, returning whether it is the first time
+    fn receive(&mut self, sequence: u64) -> bool {
+        if sequence <= self.received_up_to || !self.received_above.insert(sequence) {
+            return false;
+        }
+
+        self.fill_gaps();
+
+        true
+    }
+
+    fn fill_gaps(&mut self) {
+        while self.received_above.remove(&(self.received_up_to + 1)) {
+            self.received_up_to += 1;
+        }
+    }
+}
+
+/// Are messages like this one delivered reliably (only protocol messages are)
+fn is_reliable(message: &WireMessage) -> bool {
+    matches!(message.header().message_class(), Some(MessageClass::Protocol))
+}
+
+/// Build an acknowledgement of everything up to the given sequence number. Like pings,
+/// acknowledgements are neither signed nor digested, as they only concern our connections
+fn ack_message(my_id: NodeId, peer_id: NodeId, session: u64, received_up_to: u64) -> WireMessage {
+    let mut payload = BytesMut::with_capacity(ACK_LENGTH);
+
+    payload.extend_from_slice(&session.to_le_bytes()[..]);
+    payload.extend_from_slice(&received_up_to.to_le_bytes()[..]);
+
+    let mut message = WireMessage::new(my_id, peer_id, payload.freeze(), fastrand::u64(..), None, None);
+
+    message.header.set_ack(true);
+
+    message
+}
+
+#[derive(Error, Debug)]
+pub enum ReliableDeliveryError {
+    #[error("Received a sequenced message with a malformed prefix from {0:?}")]
+    MalformedSequence(NodeId),
+    #[error("Received a malformed acknowledgement from {0:?}")]
+    MalformedAck(NodeId),
+    #[error("Received sequence number {1} from {0:?}, too far past the {2} messages received from it")]
+    OutOfWindow(NodeId, u64, u64),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use bytes::{Bytes, BytesMut};
+
+    use atlas_common::node_id::NodeId;
+
+    use crate::config::{BackpressurePolicy, PriorityLanesConfig, ReliableDeliveryConfig};
+    use crate::message::{MessageClass, WireMessage};
+    use crate::mio_tcp::connections::reliable::{ReliablePeer, RECEIVE_WINDOW};
+    use crate::mio_tcp::connections::send_queue::{DeliveryNotifier, SendQueue};
+
+    const SENDER: NodeId = NodeId(0);
+    const RECEIVER: NodeId = NodeId(1);
+
+    fn peer(node: NodeId, max_unacked: usize) -> ReliablePeer {
+        ReliablePeer::new(node, &ReliableDeliveryConfig { enabled: true, max_unacked })
+    }
+
+    fn message(nonce: u64) -> WireMessage {
+        WireMessage::new(SENDER, RECEIVER, Bytes::from(nonce.to_le_bytes().to_vec()), nonce, None, None)
+            .with_class(MessageClass::Protocol)
+    }
+
+    /// Write the next message from the sender, as a connection would
+    fn write(sender: &ReliablePeer, queued: Option<WireMessage>) -> Option<WireMessage> {
+        let ((message, _), sequence) = sender
+            .next_message(|| Ok(queued.map(|message| (message, DeliveryNotifier::untracked()))))
+            .unwrap()?;
+
+        Some(sender.wrap(message, sequence.expect("Protocol messages are sequenced")))
+    }
+
+    fn read(receiver: &ReliablePeer, message: WireMessage) -> Option<u64> {
+        let (header, payload) = message.into_inner();
+
+        receiver.receive(header, BytesMut::from(&payload[..])).unwrap()
+            .map(|(header, _)| {
+                assert!(!header.is_sequenced());
+
+                header.nonce()
+            })
+    }
+
+    fn acknowledge(sender: &ReliablePeer, receiver: &ReliablePeer) -> bool {
+        let (header, payload) = receiver.take_ack(RECEIVER, WireMessage::CURRENT_VERSION)
+            .expect("The receiver should owe an acknowledgement")
+            .into_inner();
+
+        assert!(header.is_ack());
+
+        sender.acknowledge(&payload[..]).unwrap()
+    }
+
+    #[test]
+    fn test_unacknowledged_messages_are_retransmitted_once() {
+        let sender = peer(RECEIVER, 16);
+        let receiver = peer(SENDER, 16);
+
+        let written = (0..3).map(|nonce| write(&sender, Some(message(nonce))).unwrap()).collect::<Vec<_>>();
+
+        // The connection breaks after the first message was received (and acknowledged)
+        let mut written = written.into_iter();
+
+        assert_eq!(read(&receiver, written.next().unwrap()), Some(0));
+
+        acknowledge(&sender, &receiver);
+
+        sender.connection_lost();
+
+        let retransmitted = (0..2).map(|_| write(&sender, None).unwrap()).collect::<Vec<_>>();
+
+        assert!(write(&sender, None).is_none());
+
+        // The second message did make it before the connection broke, the third did not
+        assert_eq!(read(&receiver, written.next().unwrap()), Some(1));
+
+        let received = retransmitted.into_iter().map(|message| read(&receiver, message)).collect::<Vec<_>>();
+
+        assert_eq!(received, vec![None, Some(2)]);
+    }
+
+    #[test]
+    fn test_unacknowledged_messages_limit_what_is_written() {
+        let sender = peer(RECEIVER, 2);
+        let receiver = peer(SENDER, 2);
+
+        for nonce in 0..2 {
+            let written = write(&sender, Some(message(nonce))).unwrap();
+
+            assert_eq!(read(&receiver, written), Some(nonce));
+        }
+
+        // Nothing is taken from the queue until the receiver acknowledges something
+        assert!(sender.next_message(|| panic!("Took a message with too many unacknowledged")).unwrap().is_none());
+
+        assert!(acknowledge(&sender, &receiver));
+
+        assert!(write(&sender, Some(message(2))).is_some());
+    }
+
+    #[test]
+    fn test_restarted_receiver_skips_acknowledged_messages() {
+        let sender = peer(RECEIVER, 16);
+        let receiver = peer(SENDER, 16);
+
+        for nonce in 0..3 {
+            read(&receiver, write(&sender, Some(message(nonce))).unwrap());
+        }
+
+        acknowledge(&sender, &receiver);
+
+        // A receiver that lost its state doesn't wait for what was already acknowledged
+        let restarted = peer(SENDER, 16);
+
+        assert_eq!(read(&restarted, write(&sender, Some(message(3))).unwrap()), Some(3));
+        assert_eq!(read(&restarted, write(&sender, Some(message(4))).unwrap()), Some(4));
+
+        // A sender that lost its state starts a new session, which is not mistaken for duplicates
+        let new_sender = peer(RECEIVER, 16);
+
+        assert_eq!(read(&receiver, write(&new_sender, Some(message(5))).unwrap()), Some(5));
+    }
+
+    /// Salvage the given tracked messages from a send queue, after having written one message that
+    /// was not acknowledged, then write everything again. Returns the outcome reported to each tracker
+    fn salvage_and_rewrite(max_unacked: usize, policy: BackpressurePolicy, nonces: &[u64]) -> Vec<(u64, bool)> {
+        let sender = peer(RECEIVER, max_unacked);
+
+        write(&sender, Some(message(0))).unwrap();
+
+        let queue = SendQueue::new(16, &PriorityLanesConfig { control_weight: 1, normal_weight: 1, bulk_weight: 1 });
+
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+        for nonce in nonces.iter().copied() {
+            let outcomes = outcomes.clone();
+
+            let notifier = DeliveryNotifier::new(Some(Box::new(move |delivered| outcomes.lock().unwrap().push((nonce, delivered)))));
+
+            queue.reserve(RECEIVER, policy).unwrap().push(message(nonce), notifier).unwrap();
+        }
+
+        sender.salvage(&queue, policy);
+        sender.connection_lost();
+
+        assert_eq!(queue.len(), 0);
+
+        while let Some(((_, notifier), _)) = sender.next_message(|| Ok(None)).unwrap() {
+            notifier.delivered();
+        }
+
+        let outcomes = outcomes.lock().unwrap().clone();
+
+        outcomes
+    }
+
+    #[test]
+    fn test_salvaged_messages_are_bounded_and_tracked() {
+        // The trackers of salvaged messages are only told once they are written again
+        assert_eq!(salvage_and_rewrite(16, BackpressurePolicy::DropNewest, &[1, 2]),
+                   vec![(1, true), (2, true)]);
+
+        // Only what fits under the unacknowledged limit is salvaged, the rest is dropped according to the policy
+        assert_eq!(salvage_and_rewrite(3, BackpressurePolicy::DropNewest, &[1, 2, 3, 4]),
+                   vec![(3, false), (4, false), (1, true), (2, true)]);
+
+        assert_eq!(salvage_and_rewrite(3, BackpressurePolicy::DropOldest, &[1, 2, 3, 4]),
+                   vec![(1, false), (2, false), (3, true), (4, true)]);
+
+        // There is nobody to block, so the newest are dropped
+        assert_eq!(salvage_and_rewrite(3, BackpressurePolicy::Block { timeout_millis: 0 }, &[1, 2, 3, 4]),
+                   vec![(3, false), (4, false), (1, true), (2, true)]);
+    }
+
+    #[test]
+    fn test_messages_past_the_window_are_rejected() {
+        let sender = peer(RECEIVER, 16);
+        let receiver = peer(SENDER, 16);
+
+        // The furthest a message can be past what has been received is still kept
+        assert_eq!(read(&receiver, sender.wrap(message(0), RECEIVE_WINDOW)), Some(0));
+
+        // Past it, the peer is misbehaving
+        let (header, payload) = sender.wrap(message(1), RECEIVE_WINDOW + 1).into_inner();
+
+        assert!(receiver.receive(header, BytesMut::from(&payload[..])).is_err());
+    }
+}
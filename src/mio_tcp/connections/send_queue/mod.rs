@@ -0,0 +1,441 @@
+//! The queue of messages waiting to be written to a peer, shared by all of our connections to it.
+//!
+//! The queue is bounded. A message reserves its place in the queue (a [QueueSlot]) on the thread
+//! of whoever is sending it, before it is handed to the threadpool to be serialized and pushed.
+//! That is where the [BackpressurePolicy] of the peer is applied when the queue is full, so the
+//! outcome is reported to the caller instead of blocking (or panicking) the threadpool.
+//!
+//! The queue is split in lanes, one for each [MessagePriority], which share its capacity.
+//! Messages are taken from the lanes in weighted round robin (see [PriorityLanesConfig]),
+//! so a burst of bulk transfers doesn't hold up the control messages queued behind it.
+//!
+//! Each queued message carries a [DeliveryNotifier], which travels with it until it has been
+//! written to the connection, for the senders that want to know when that happens.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use log::debug;
+
+use atlas_common::channel;
+use atlas_common::channel::{ChannelSyncRx, ChannelSyncTx, TryRecvError};
+use atlas_common::Err;
+use atlas_common::error::*;
+use atlas_common::node_id::{NodeId, NodeType};
+use atlas_metrics::metrics::metric_increment;
+
+use crate::config::{BackpressureConfig, BackpressurePolicy, PriorityLanesConfig};
+use crate::conn_utils::Callback;
+use crate::message::MessagePriority;
+use crate::metric::COMM_SEND_QUEUE_FULL_ID;
+use crate::mio_tcp::connections::{MioError, NetworkSerializedMessage};
+use crate::NetworkSendError;
+
+/// How many lanes (one per [MessagePriority]) each queue has
+const LANE_COUNT: usize = 3;
+
+pub(crate) struct SendQueue {
+    capacity: usize,
+    // How many messages each lane gets to take in each round
+    weights: [u32; LANE_COUNT],
+    state: Mutex<QueueState>,
+    // Signalled whenever a place in the queue is freed
+    freed: Condvar,
+    // One per priority, from the most to the least urgent.
+    // The capacity is enforced by the place reservations, so the channels themselves are unbounded
+    lanes: [(ChannelSyncTx<QueuedMessage>, ChannelSyncRx<QueuedMessage>); LANE_COUNT],
+}
+
+struct QueueState {
+    // How many messages are in the queue, or have reserved a place in it
+    occupied: usize,
+    // How many messages are waiting in each lane
+    queued: [usize; LANE_COUNT],
+    // How many more messages each lane may take in the current round
+    credits: [u32; LANE_COUNT],
+}
+
+/// A message waiting in a [SendQueue], along with whoever wants to know when it has been written
+pub(crate) type QueuedMessage = (NetworkSerializedMessage, DeliveryNotifier);
+
+/// Tells whoever is tracking a message (with a [Callback]) that it has been written to the connection.
+/// A notifier dropped before that (because the message was dropped from the queue, or the connection
+/// it was being written to broke) reports the message as not delivered
+pub(crate) struct DeliveryNotifier(Callback);
+
+/// A place reserved in a [SendQueue], which is freed if no message is pushed into it
+pub(crate) struct QueueSlot {
+    queue: Arc<SendQueue>,
+    used: bool,
+}
+
+/// The policy to apply to the send queue of a peer of the given type
+pub(crate) fn policy_for(config: &BackpressureConfig, node_type: Option<NodeType>) -> BackpressurePolicy {
+    match node_type {
+        Some(NodeType::Replica) => config.replica_policy,
+        _ => config.client_policy,
+    }
+}
+
+/// The lane of the messages with the given priority
+fn lane(priority: MessagePriority) -> usize {
+    match priority {
+        MessagePriority::Control => 0,
+        MessagePriority::Normal => 1,
+        MessagePriority::Bulk => 2,
+    }
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, lanes: &PriorityLanesConfig) -> Arc<Self> {
+        // Every lane gets at least one turn per round, so none of them is starved
+        let weights = [lanes.control_weight, lanes.normal_weight, lanes.bulk_weight]
+            .map(|weight| weight.max(1));
+
+        Arc::new(Self {
+            capacity,
+            weights,
+            state: Mutex::new(QueueState {
+                occupied: 0,
+                queued: [0; LANE_COUNT],
+                credits: weights,
+            }),
+            freed: Condvar::new(),
+            lanes: [
+                channel::new_unbounded_sync(Some("Network Msg (Control)")),
+                channel::new_unbounded_sync(Some("Network Msg (Normal)")),
+                channel::new_unbounded_sync(Some("Network Msg (Bulk)")),
+            ],
+        })
+    }
+
+    /// Reserve a place in the queue for a message to the given peer, applying
+    /// the given policy if the queue is full
+    pub fn reserve(self: &Arc<Self>, peer: NodeId, policy: BackpressurePolicy) -> std::result::Result<QueueSlot, NetworkSendError> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.occupied < self.capacity {
+            state.occupied += 1;
+
+            return Ok(self.slot());
+        }
+
+        metric_increment(COMM_SEND_QUEUE_FULL_ID, Some(1));
+
+        match policy {
+            BackpressurePolicy::Block { timeout_millis } => {
+                let (mut state, _) = self.freed
+                    .wait_timeout_while(state, Duration::from_millis(timeout_millis), |state| state.occupied >= self.capacity)
+                    .unwrap();
+
+                if state.occupied >= self.capacity {
+                    return Err(NetworkSendError::SendQueueFull(peer));
+                }
+
+                state.occupied += 1;
+
+                Ok(self.slot())
+            }
+            BackpressurePolicy::DropOldest => {
+                // The place of the message we drop goes to the new one. When every place is
+                // reserved by messages that are still being serialized, there is nothing to drop
+                let dropped = (0..LANE_COUNT).rev()
+                    .find(|lane| state.queued[*lane] > 0)
+                    .and_then(|lane| {
+                        let message = self.lanes[lane].1.try_recv().ok()?;
+
+                        state.queued[lane] -= 1;
+
+                        Some(message)
+                    });
+
+                // The notifier of the dropped message is only told after we let go of the queue
+                drop(state);
+
+                match dropped {
+                    Some(_) => {
+                        debug!("Dropped the oldest message of the least urgent lane queued to {:?} to make room for a new one", peer);
+
+                        Ok(self.slot())
+                    }
+                    None => Err(NetworkSendError::SendQueueFull(peer)),
+                }
+            }
+            BackpressurePolicy::DropNewest => Err(NetworkSendError::MessageDropped(peer)),
+            BackpressurePolicy::Fail => Err(NetworkSendError::SendQueueFull(peer)),
+        }
+    }
+
+    fn slot(self: &Arc<Self>) -> QueueSlot {
+        QueueSlot {
+            queue: Arc::clone(self),
+            used: false,
+        }
+    }
+
+    fn free(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.occupied = state.occupied.saturating_sub(1);
+
+        self.freed.notify_one();
+    }
+
+    /// Take the next message from the queue (non blocking), freeing its place.
+    /// That is the oldest message of the lane whose turn it is
+    pub fn try_pop(&self) -> Result<Option<QueuedMessage>> {
+        let mut state = self.state.lock().unwrap();
+
+        let lane = match state.next_lane(&self.weights) {
+            Some(lane) => lane,
+            None => return Ok(None),
+        };
+
+        match self.lanes[lane].1.try_recv() {
+            Ok(message) => {
+                state.queued[lane] -= 1;
+                state.occupied = state.occupied.saturating_sub(1);
+
+                self.freed.notify_one();
+
+                Ok(Some(message))
+            }
+            Err(TryRecvError::ChannelDc) => Err!(MioError::FailedToRetrieveFromSendQueue),
+            Err(TryRecvError::ChannelEmpty) | Err(TryRecvError::Timeout) => Ok(None),
+        }
+    }
+
+    /// How many messages are in the queue, or have reserved a place in it
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().occupied
+    }
+}
+
+impl QueueState {
+    /// Pick the lane to take the next message from, spending one of its credits.
+    /// Lanes take turns from the most to the least urgent, and a new round starts
+    /// once every lane with messages waiting has spent its credits
+    fn next_lane(&mut self, weights: &[u32; LANE_COUNT]) -> Option<usize> {
+        if self.queued.iter().all(|queued| *queued == 0) {
+            return None;
+        }
+
+        loop {
+            if let Some(lane) = (0..LANE_COUNT).find(|lane| self.queued[*lane] > 0 && self.credits[*lane] > 0) {
+                self.credits[lane] -= 1;
+
+                return Some(lane);
+            }
+
+            self.credits = *weights;
+        }
+    }
+}
+
+impl QueueSlot {
+    /// Push the message into the place we reserved, in the lane of its priority
+    pub fn push(mut self, message: NetworkSerializedMessage, notifier: DeliveryNotifier) -> Result<()> {
+        self.used = true;
+
+        let lane = lane(message.header().priority());
+
+        let mut state = self.queue.state.lock().unwrap();
+
+        self.queue.lanes[lane].0.send((message, notifier)).context("Failed to push the message into the send queue")?;
+
+        state.queued[lane] += 1;
+
+        Ok(())
+    }
+}
+
+impl DeliveryNotifier {
+    pub fn new(callback: Callback) -> Self {
+        Self(callback)
+    }
+
+    /// A notifier for a message nobody is tracking
+    pub fn untracked() -> Self {
+        Self(None)
+    }
+
+    pub fn is_tracked(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// The message has been written to the connection
+    pub fn delivered(mut self) {
+        if let Some(callback) = self.0.take() {
+            callback(true);
+        }
+    }
+}
+
+impl Drop for DeliveryNotifier {
+    fn drop(&mut self) {
+        if let Some(callback) = self.0.take() {
+            callback(false);
+        }
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        if !self.used {
+            self.queue.free();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+
+    use atlas_common::node_id::NodeId;
+
+    use crate::config::{BackpressurePolicy, PriorityLanesConfig};
+    use crate::message::{MessagePriority, WireMessage};
+    use crate::mio_tcp::connections::send_queue::{DeliveryNotifier, SendQueue};
+    use crate::NetworkSendError;
+
+    const PEER: NodeId = NodeId(1);
+
+    fn message(nonce: u64) -> WireMessage {
+        WireMessage::new(NodeId(0), PEER, Bytes::new(), nonce, None, None)
+    }
+
+    fn lanes() -> PriorityLanesConfig {
+        PriorityLanesConfig {
+            control_weight: 4,
+            normal_weight: 2,
+            bulk_weight: 1,
+        }
+    }
+
+    #[test]
+    fn test_full_queue_policies() {
+        let queue = SendQueue::new(2, &lanes());
+
+        for nonce in 0..2 {
+            queue.reserve(PEER, BackpressurePolicy::Fail).unwrap().push(message(nonce), DeliveryNotifier::untracked()).unwrap();
+        }
+
+        assert!(matches!(queue.reserve(PEER, BackpressurePolicy::Fail), Err(NetworkSendError::SendQueueFull(_))));
+        assert!(matches!(queue.reserve(PEER, BackpressurePolicy::DropNewest), Err(NetworkSendError::MessageDropped(_))));
+
+        let start = Instant::now();
+
+        assert!(matches!(queue.reserve(PEER, BackpressurePolicy::Block { timeout_millis: 50 }), Err(NetworkSendError::SendQueueFull(_))));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // The oldest message makes room for the new one
+        queue.reserve(PEER, BackpressurePolicy::DropOldest).unwrap().push(message(2), DeliveryNotifier::untracked()).unwrap();
+
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.try_pop().unwrap().unwrap().0.header().nonce(), 1);
+        assert_eq!(queue.try_pop().unwrap().unwrap().0.header().nonce(), 2);
+        assert!(queue.try_pop().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unused_slots_are_freed() {
+        let queue = SendQueue::new(1, &lanes());
+
+        let slot = queue.reserve(PEER, BackpressurePolicy::Fail).unwrap();
+
+        // Reserved places count, even before their message is pushed
+        assert!(queue.reserve(PEER, BackpressurePolicy::Fail).is_err());
+
+        drop(slot);
+
+        assert_eq!(queue.len(), 0);
+
+        queue.reserve(PEER, BackpressurePolicy::Fail).unwrap().push(message(0), DeliveryNotifier::untracked()).unwrap();
+
+        // A blocked sender gets the place once the queue is drained
+        let popper = {
+            let queue = queue.clone();
+
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+
+                queue.try_pop().unwrap().unwrap();
+            })
+        };
+
+        queue.reserve(PEER, BackpressurePolicy::Block { timeout_millis: 5000 }).unwrap();
+
+        popper.join().unwrap();
+    }
+
+    #[test]
+    fn test_dropped_messages_are_reported_as_not_delivered() {
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+        let notifier = |outcomes: &Arc<Mutex<Vec<bool>>>| {
+            let outcomes = outcomes.clone();
+
+            DeliveryNotifier::new(Some(Box::new(move |delivered| outcomes.lock().unwrap().push(delivered))))
+        };
+
+        let queue = SendQueue::new(1, &lanes());
+
+        queue.reserve(PEER, BackpressurePolicy::Fail).unwrap().push(message(0), notifier(&outcomes)).unwrap();
+
+        // Dropping the oldest message to make room for a new one
+        queue.reserve(PEER, BackpressurePolicy::DropOldest).unwrap().push(message(1), notifier(&outcomes)).unwrap();
+
+        assert_eq!(*outcomes.lock().unwrap(), vec![false]);
+
+        let (_, written) = queue.try_pop().unwrap().unwrap();
+
+        written.delivered();
+
+        assert_eq!(*outcomes.lock().unwrap(), vec![false, true]);
+    }
+
+    #[test]
+    fn test_lanes_are_drained_by_weight() {
+        let queue = SendQueue::new(64, &lanes());
+
+        for (nonce, priority) in (0..8).map(|nonce| (nonce, MessagePriority::Bulk))
+            .chain((8..16).map(|nonce| (nonce, MessagePriority::Normal)))
+            .chain((16..24).map(|nonce| (nonce, MessagePriority::Control))) {
+            queue.reserve(PEER, BackpressurePolicy::Fail).unwrap()
+                .push(message(nonce).with_priority(priority), DeliveryNotifier::untracked()).unwrap();
+        }
+
+        let mut drained = Vec::new();
+
+        while let Some((message, _)) = queue.try_pop().unwrap() {
+            drained.push(message.header().nonce());
+        }
+
+        // Each round takes 4 control, 2 normal and 1 bulk message, each lane in the order it was queued
+        assert_eq!(&drained[..7], &[16, 17, 18, 19, 8, 9, 0]);
+        assert_eq!(&drained[7..14], &[20, 21, 22, 23, 10, 11, 1]);
+
+        // Once the control lane is empty, the remaining lanes share the rounds
+        assert_eq!(&drained[14..], &[12, 13, 2, 14, 15, 3, 4, 5, 6, 7]);
+        assert_eq!(queue.len(), 0);
+
+        // Dropping the oldest message takes it from the least urgent lane
+        let queue = SendQueue::new(2, &lanes());
+
+        queue.reserve(PEER, BackpressurePolicy::Fail).unwrap()
+            .push(message(0).with_priority(MessagePriority::Control), DeliveryNotifier::untracked()).unwrap();
+        queue.reserve(PEER, BackpressurePolicy::Fail).unwrap()
+            .push(message(1).with_priority(MessagePriority::Bulk), DeliveryNotifier::untracked()).unwrap();
+        queue.reserve(PEER, BackpressurePolicy::DropOldest).unwrap()
+            .push(message(2), DeliveryNotifier::untracked()).unwrap();
+
+        assert_eq!(queue.try_pop().unwrap().unwrap().0.header().nonce(), 0);
+        assert_eq!(queue.try_pop().unwrap().unwrap().0.header().nonce(), 2);
+        assert!(queue.try_pop().unwrap().is_none());
+    }
+}
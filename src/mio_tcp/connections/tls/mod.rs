@@ -0,0 +1,230 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+
+use mio::{Interest, Registry, Token};
+use mio::event::Source;
+use rustls::{ClientConfig, ClientConnection, Connection, ServerConfig, ServerConnection, ServerName};
+
+use atlas_common::error::*;
+use atlas_common::socket::MioSocket;
+
+use crate::message_signing::SessionKey;
+use crate::mio_tcp::connections::conn_util::{interrupted, would_block};
+
+// The labels of the keying material we export from TLS sessions (RFC 5705)
+const CHANNEL_BINDING_LABEL: &[u8] = b"EXPORTER-atlas-comm-mio-channel-binding";
+const SESSION_KEY_LABEL: &[u8] = b"EXPORTER-atlas-comm-mio-session-key";
+
+/// A value that is unique to a given TLS session and known only to its two ends.
+/// It is included in the authentication handshake, so the signatures exchanged there
+/// can't be relayed to another connection.
+pub(crate) type ChannelBinding = [u8; 32];
+
+/// A non blocking socket that is used by the MIO workers.
+///
+/// When TLS is in use, the rustls session is driven by the same read and write
+/// calls the epoll workers already perform, so the handshake is completed transparently
+/// as the socket becomes readable/writable.
+pub(crate) enum SecureMioSocket {
+    Plain(MioSocket),
+    Tls(Connection, MioSocket),
+}
+
+impl SecureMioSocket {
+    pub fn new_plain(socket: MioSocket) -> Self {
+        SecureMioSocket::Plain(socket)
+    }
+
+    /// Wrap an accepted socket with a server side TLS session
+    pub fn new_tls_server(config: Arc<ServerConfig>, socket: MioSocket) -> Result<Self> {
+        let session = ServerConnection::new(config)?;
+
+        Ok(SecureMioSocket::Tls(Connection::Server(session), socket))
+    }
+
+    /// Wrap a socket with an already initialized client side TLS session
+    pub fn new_tls_client(session: ClientConnection, socket: MioSocket) -> Self {
+        SecureMioSocket::Tls(Connection::Client(session), socket)
+    }
+
+    /// Is this socket protected by TLS?
+    pub fn is_tls(&self) -> bool {
+        matches!(self, SecureMioSocket::Tls(_, _))
+    }
+
+    /// Do we still have TLS records that have not yet been written to the socket?
+    /// If so, we must keep being notified of writability, even if we have no
+    /// plaintext left to write.
+    pub fn has_pending_output(&self) -> bool {
+        match self {
+            SecureMioSocket::Plain(_) => false,
+            SecureMioSocket::Tls(tls, _) => tls.wants_write()
+        }
+    }
+
+    /// The channel binding of this socket's TLS session (empty for plain sockets)
+    pub fn channel_binding(&self) -> Option<ChannelBinding> {
+        match self {
+            SecureMioSocket::Plain(_) => None,
+            SecureMioSocket::Tls(tls, _) => tls.export_keying_material([0; 32], CHANNEL_BINDING_LABEL, None).ok()
+        }
+    }
+
+    /// The session key shared by both ends of this socket's TLS session.
+    /// Only available after the TLS handshake has completed.
+    pub fn session_key(&self) -> Option<SessionKey> {
+        match self {
+            SecureMioSocket::Plain(_) => None,
+            SecureMioSocket::Tls(tls, _) => tls.export_keying_material([0; SessionKey::LENGTH], SESSION_KEY_LABEL, None)
+                .ok()
+                .map(SessionKey::from_bytes)
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            SecureMioSocket::Plain(socket) => socket.peer_addr(),
+            SecureMioSocket::Tls(_, socket) => socket.peer_addr()
+        }
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match self {
+            SecureMioSocket::Plain(socket) => socket.shutdown(how),
+            SecureMioSocket::Tls(tls, socket) => {
+                tls.send_close_notify();
+
+                // Best effort attempt to let the peer know we are closing the session
+                let _ = write_tls_until_block(tls, socket);
+
+                socket.shutdown(how)
+            }
+        }
+    }
+}
+
+/// Create the client side of a TLS session, for the peer with the given host name
+pub(crate) fn new_client_session(config: Arc<ClientConfig>, host_name: &str) -> Result<ClientConnection> {
+    let server_name = ServerName::try_from(host_name)?;
+
+    Ok(ClientConnection::new(config, server_name)?)
+}
+
+/// The channel binding of a client session that has not yet been wrapped in a socket
+pub(crate) fn client_channel_binding(session: &ClientConnection) -> Option<ChannelBinding> {
+    session.export_keying_material([0; 32], CHANNEL_BINDING_LABEL, None).ok()
+}
+
+/// Write all of the TLS records we have buffered until the socket would block
+fn write_tls_until_block(tls: &mut Connection, socket: &mut MioSocket) -> io::Result<()> {
+    while tls.wants_write() {
+        match tls.write_tls(socket) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(_) => {}
+            Err(ref err) if would_block(err) => break,
+            Err(ref err) if interrupted(err) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+impl Read for SecureMioSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SecureMioSocket::Plain(socket) => socket.read(buf),
+            SecureMioSocket::Tls(tls, socket) => {
+                loop {
+                    // Return any plaintext that is already available
+                    match tls.reader().read(buf) {
+                        Ok(n) => return Ok(n),
+                        Err(ref err) if would_block(err) => {}
+                        Err(err) => return Err(err),
+                    }
+
+                    // We need more data from the socket
+                    match tls.read_tls(socket) {
+                        Ok(0) => return Ok(0),
+                        Ok(_) => {
+                            if let Err(err) = tls.process_new_packets() {
+                                // Attempt to deliver the alert to the peer before failing
+                                let _ = write_tls_until_block(tls, socket);
+
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                            }
+
+                            // Handshake messages (or other records) may have been generated
+                            write_tls_until_block(tls, socket)?;
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Write for SecureMioSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SecureMioSocket::Plain(socket) => socket.write(buf),
+            SecureMioSocket::Tls(tls, socket) => {
+                write_tls_until_block(tls, socket)?;
+
+                if tls.wants_write() {
+                    // Don't keep buffering plaintext while the socket can't keep up
+                    return Err(io::Error::from(io::ErrorKind::WouldBlock));
+                }
+
+                let written = tls.writer().write(buf)?;
+
+                write_tls_until_block(tls, socket)?;
+
+                Ok(written)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SecureMioSocket::Plain(socket) => socket.flush(),
+            SecureMioSocket::Tls(tls, socket) => {
+                tls.writer().flush()?;
+
+                write_tls_until_block(tls, socket)?;
+
+                if tls.wants_write() {
+                    return Err(io::Error::from(io::ErrorKind::WouldBlock));
+                }
+
+                socket.flush()
+            }
+        }
+    }
+}
+
+impl Source for SecureMioSocket {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            SecureMioSocket::Plain(socket) => socket.register(registry, token, interests),
+            SecureMioSocket::Tls(_, socket) => socket.register(registry, token, interests)
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            SecureMioSocket::Plain(socket) => socket.reregister(registry, token, interests),
+            SecureMioSocket::Tls(_, socket) => socket.reregister(registry, token, interests)
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            SecureMioSocket::Plain(socket) => socket.deregister(registry),
+            SecureMioSocket::Tls(_, socket) => socket.deregister(registry)
+        }
+    }
+}
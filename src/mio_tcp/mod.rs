@@ -1,15 +1,18 @@
 use std::collections::BTreeMap;
 use std::iter;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use anyhow::Context;
 
 use either::Either;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use smallvec::SmallVec;
 
 use atlas_common::{Err, socket, threadpool};
+use atlas_common::channel;
+use atlas_common::channel::{ChannelSyncRx, OneShotRx};
 use atlas_common::crypto::hash::Digest;
 use atlas_common::crypto::signature::KeyPair;
 use atlas_common::error::*;
@@ -19,16 +22,18 @@ use atlas_common::prng::ThreadSafePrng;
 use atlas_common::socket::SyncListener;
 use atlas_metrics::metrics::metric_duration;
 
-use crate::{FullNetworkNode, NetworkNode, NetworkSendError};
+use crate::{compression, FullNetworkNode, NetworkNode, NetworkSendError};
 use crate::client_pooling::{ConnectedPeer, PeerIncomingRqHandling};
-use crate::config::MioConfig;
-use crate::conn_utils::ConnCounts;
-use crate::message::{NetworkMessageKind, SerializedMessage, StoredMessage, StoredSerializedNetworkMessage, StoredSerializedProtocolMessage, WireMessage};
+use crate::config::{BackpressureConfig, MioConfig};
+use crate::conn_utils::{Callback, ConnCounts, ConnectionEvent};
+use crate::message::{MessageClass, MessagePriority, NetworkMessageKind, SerializedMessage, StoredMessage, StoredSerializedNetworkMessage, StoredSerializedProtocolMessage, WireMessage};
 use crate::message_signing::{DefaultProtocolSignatureVerifier, DefaultReconfigSignatureVerifier};
 use crate::metric::THREADPOOL_PASS_TIME_ID;
-use crate::mio_tcp::connections::{Connections, PeerConnection};
+use crate::mio_tcp::connections::{Connections, ConnectionsConfig, PeerConnection};
 use crate::mio_tcp::connections::conn_establish::pending_conn::{NetworkUpdateHandler, PendingConnHandle};
 use crate::mio_tcp::connections::epoll_group::{init_worker_group_handle, initialize_worker_group};
+use crate::mio_tcp::connections::send_queue;
+use crate::mio_tcp::connections::send_queue::QueueSlot;
 use crate::protocol_node::ProtocolNetworkNode;
 use crate::reconfiguration_node::{NetworkInformationProvider, ReconfigurationMessageHandler, ReconfigurationNode};
 use crate::serialize::{Buf, Serializable};
@@ -57,6 +62,15 @@ pub struct MIOTcpNode<NI, RM, PM>
     //Handles the incoming connections' buffering and request collection
     //This is polled by the proposer for client requests and by the
     client_pooling: Arc<PeerIncomingRqHandling<StoredMessage<PM::Message>>>,
+    // Should signed messages to other replicas be authenticated with a session MAC instead
+    replica_session_macs: bool,
+    // Compress the payloads of messages larger than this threshold
+    compression_threshold: Option<usize>,
+    // What happens to the messages sent to peers whose send queues are full
+    backpressure_config: BackpressureConfig,
+    // The threads handling our connections (the server worker, the epoll workers and the
+    // network update handler), which are joined when we shut down
+    threads: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl<NI, RM, PM> MIOTcpNode<NI, RM, PM>
@@ -68,9 +82,10 @@ impl<NI, RM, PM> MIOTcpNode<NI, RM, PM>
         socket::bind_sync_server(server_addr.clone()).context(format!("Failed to setup connection with socket {:?}", server_addr))
     }
 
-    /// Create the send tos for a given target
+    /// Create the send tos for a given target, reserving a place for the message in the send queue
+    /// of each of them. Returns the targets we can't send the message to, along with the reason
     fn send_tos(&self, shared: Option<&Arc<KeyPair>>, targets: impl Iterator<Item=NodeId>, flush: bool)
-                -> (Option<SendTo<RM, PM>>, Option<SendTos<RM, PM>>, Vec<NodeId>) {
+                -> (Option<SendTo<RM, PM>>, Option<SendTos<RM, PM>>, Vec<(NodeId, NetworkSendError)>) {
         let mut send_to_me = None;
         let mut send_tos: Option<SendTos<RM, PM>> = None;
 
@@ -87,8 +102,12 @@ impl<NI, RM, PM> MIOTcpNode<NI, RM, PM>
                     peer_id: id,
                     shared: shared.cloned(),
                     nonce,
+                    session_mac: false,
                     reconfig_handling: self.reconfig_handling.clone(),
                     peer_cnn: SendToPeer::Me(self.client_pooling.loopback_connection().clone()),
+                    queue_slot: None,
+                    callback: None,
+                    priority: None,
                     flush,
                     rq_send_time: Instant::now(),
                 })
@@ -97,9 +116,21 @@ impl<NI, RM, PM> MIOTcpNode<NI, RM, PM>
                     None => {
                         match self.connections.get_pending_connection(&id) {
                             None => {
-                                failed.push(id)
+                                failed.push((id, NetworkSendError::PeerNotFound(id)))
                             }
                             Some(conn) => {
+                                // We don't know the type of pending nodes yet
+                                let policy = send_queue::policy_for(&self.backpressure_config, None);
+
+                                let queue_slot = match conn.channel().reserve(id, policy) {
+                                    Ok(slot) => slot,
+                                    Err(err) => {
+                                        failed.push((id, err));
+
+                                        continue;
+                                    }
+                                };
+
                                 let send_to = match &mut send_tos {
                                     None => {
                                         send_tos = Some(SmallVec::new());
@@ -116,8 +147,13 @@ impl<NI, RM, PM> MIOTcpNode<NI, RM, PM>
                                     peer_id: id.clone(),
                                     shared: shared.cloned(),
                                     nonce,
+                                    // Pending connections are not yet handled by the epoll workers
+                                    session_mac: false,
                                     reconfig_handling: self.reconfig_handling.clone(),
                                     peer_cnn: SendToPeer::PendingPeer(conn),
+                                    queue_slot: Some(queue_slot),
+                                    callback: None,
+                                    priority: None,
                                     flush,
                                     rq_send_time: Instant::now(),
                                 });
@@ -125,6 +161,19 @@ impl<NI, RM, PM> MIOTcpNode<NI, RM, PM>
                         }
                     }
                     Some(conn) => {
+                        let node_type = self.reconfiguration.get_node_type(&id);
+
+                        let policy = send_queue::policy_for(&self.backpressure_config, node_type);
+
+                        let queue_slot = match conn.send_queue().reserve(id, policy) {
+                            Ok(slot) => slot,
+                            Err(err) => {
+                                failed.push((id, err));
+
+                                continue;
+                            }
+                        };
+
                         let send_to = match &mut send_tos {
                             None => {
                                 send_tos = Some(SmallVec::new());
@@ -136,13 +185,20 @@ impl<NI, RM, PM> MIOTcpNode<NI, RM, PM>
                             }
                         };
 
+                        let session_mac = self.replica_session_macs
+                            && node_type == Some(NodeType::Replica);
+
                         send_to.push(SendTo {
                             my_id,
                             peer_id: id.clone(),
                             shared: shared.cloned(),
                             nonce,
+                            session_mac,
                             reconfig_handling: self.reconfig_handling.clone(),
                             peer_cnn: SendToPeer::Peer(conn),
+                            queue_slot: Some(queue_slot),
+                            callback: None,
+                            priority: None,
                             flush,
                             rq_send_time: Instant::now(),
                         });
@@ -154,8 +210,38 @@ impl<NI, RM, PM> MIOTcpNode<NI, RM, PM>
         (send_to_me, send_tos, failed)
     }
 
+    /// Send the message to all of the given send tos with the given priority
+    fn prioritize(send_tos: &mut Option<SendTos<RM, PM>>, priority: MessagePriority) {
+        if let Some(send_tos) = send_tos {
+            send_tos.iter_mut().for_each(|send_to| send_to.priority = Some(priority));
+        }
+    }
+
+    /// The outcome of sending a message to a single target
+    fn send_result(failed: Vec<(NodeId, NetworkSendError)>) -> Result<()> {
+        match failed.into_iter().next() {
+            Some((_, err)) => Err!(err),
+            None => Ok(()),
+        }
+    }
+
+    /// The outcome of broadcasting a message, with the targets it could not be sent to
+    fn broadcast_result(failed: Vec<(NodeId, NetworkSendError)>) -> std::result::Result<(), Vec<NodeId>> {
+        if failed.is_empty() {
+            return Ok(());
+        }
+
+        Err(failed.into_iter()
+            .map(|(id, err)| {
+                debug!("Failed to broadcast message to {:?}: {:?}", id, err);
+
+                id
+            })
+            .collect())
+    }
+
     fn serialize_send_impl(send_to_me: Option<SendTo<RM, PM>>, send_to_others: Option<SendTos<RM, PM>>,
-                           message: NetworkMessageKind<RM, PM>) {
+                           message: NetworkMessageKind<RM, PM>, compression_threshold: Option<usize>) {
         let start = Instant::now();
 
         threadpool::execute(move || {
@@ -163,7 +249,7 @@ impl<NI, RM, PM> MIOTcpNode<NI, RM, PM>
 
             match crate::cpu_workers::serialize_digest_no_threadpool(&message) {
                 Ok((buffer, digest)) => {
-                    Self::send_impl(send_to_me, send_to_others, message, buffer, digest);
+                    Self::send_impl(send_to_me, send_to_others, message, buffer, digest, compression_threshold);
                 }
                 Err(err) => {
                     error!("Failed to serialize message {:?}", err);
@@ -173,14 +259,22 @@ impl<NI, RM, PM> MIOTcpNode<NI, RM, PM>
     }
 
     fn send_impl(send_to_me: Option<SendTo<RM, PM>>, send_to_others: Option<SendTos<RM, PM>>,
-                 msg: NetworkMessageKind<RM, PM>, buffer: Buf, digest: Digest, ) {
+                 msg: NetworkMessageKind<RM, PM>, buffer: Buf, digest: Digest, compression_threshold: Option<usize>) {
         if let Some(send_to) = send_to_me {
             send_to.value(Either::Left((msg, buffer.clone(), digest.clone())));
         }
 
         if let Some(send_to) = send_to_others {
+            let class = msg.class();
+
+            // The digest has already been computed over the uncompressed payload, so it remains valid
+            let (buffer, compressed) = match compression_threshold {
+                Some(threshold) => compression::compress_above(buffer, threshold),
+                None => (buffer, false),
+            };
+
             for send in send_to {
-                send.value(Either::Right((buffer.clone(), digest.clone())));
+                send.value(Either::Right((class, compressed, buffer.clone(), digest.clone())));
             }
         }
     }
@@ -220,11 +314,54 @@ impl<NI, RM, PM> ProtocolNetworkNode<PM> for MIOTcpNode<NI, RM, PM>
         let (send_to_me, send_to_others, failed) =
             self.send_tos(None, iter::once(target), flush);
 
-        if !failed.is_empty() {
-            return Err!(NetworkSendError::PeerNotFound(target));
+        Self::send_result(failed)?;
+
+        Self::serialize_send_impl(send_to_me, send_to_others, nmk, self.compression_threshold);
+
+        Ok(())
+    }
+
+    fn send_tracked(&self, message: PM::Message, target: NodeId, flush: bool) -> Result<OneShotRx<Result<()>>> {
+        let nmk = NetworkMessageKind::from_system(message);
+
+        let (mut send_to_me, mut send_to_others, failed) =
+            self.send_tos(None, iter::once(target), flush);
+
+        Self::send_result(failed)?;
+
+        let (tx, rx) = channel::new_oneshot_channel();
+
+        let callback: Callback = Some(Box::new(move |delivered| {
+            let result = if delivered {
+                Ok(())
+            } else {
+                Err!(NetworkSendError::NotDelivered(target))
+            };
+
+            let _ = tx.send(result);
+        }));
+
+        // There is a single target, so the message goes either to ourselves or to the peer
+        if let Some(send_to) = send_to_me.as_mut().or(send_to_others.as_mut().and_then(|send_tos| send_tos.first_mut())) {
+            send_to.callback = callback;
         }
 
-        Self::serialize_send_impl(send_to_me, send_to_others, nmk);
+        Self::serialize_send_impl(send_to_me, send_to_others, nmk, self.compression_threshold);
+
+        Ok(rx)
+    }
+
+    fn send_with_priority(&self, message: PM::Message, target: NodeId, priority: MessagePriority, flush: bool) -> Result<()> {
+        let nmk = NetworkMessageKind::from_system(message);
+
+        let (send_to_me, mut send_to_others, failed) =
+            self.send_tos(None, iter::once(target), flush);
+
+        Self::send_result(failed)?;
+
+        Self::prioritize(&mut send_to_others, priority);
+
+        Self::serialize_send_impl(send_to_me, send_to_others, nmk, self.compression_threshold);
 
         Ok(())
     }
@@ -237,11 +374,9 @@ impl<NI, RM, PM> ProtocolNetworkNode<PM> for MIOTcpNode<NI, RM, PM>
         let (send_to_me, send_to_others, failed) =
             self.send_tos(keys, iter::once(target), flush);
 
-        if !failed.is_empty() {
-            return Err!(NetworkSendError::PeerNotFound(target));
-        }
+        Self::send_result(failed)?;
 
-        Self::serialize_send_impl(send_to_me, send_to_others, nmk);
+        Self::serialize_send_impl(send_to_me, send_to_others, nmk, self.compression_threshold);
 
         Ok(())
     }
@@ -252,13 +387,22 @@ impl<NI, RM, PM> ProtocolNetworkNode<PM> for MIOTcpNode<NI, RM, PM>
         let (send_to_me, send_to_others, failed) =
             self.send_tos(None, targets, true);
 
-        Self::serialize_send_impl(send_to_me, send_to_others, nmk);
+        Self::serialize_send_impl(send_to_me, send_to_others, nmk, self.compression_threshold);
 
-        if !failed.is_empty() {
-            Err(failed)
-        } else {
-            Ok(())
-        }
+        Self::broadcast_result(failed)
+    }
+
+    fn broadcast_with_priority(&self, message: PM::Message, targets: impl Iterator<Item=NodeId>, priority: MessagePriority) -> std::result::Result<(), Vec<NodeId>> {
+        let nmk = NetworkMessageKind::from_system(message);
+
+        let (send_to_me, mut send_to_others, failed) =
+            self.send_tos(None, targets, true);
+
+        Self::prioritize(&mut send_to_others, priority);
+
+        Self::serialize_send_impl(send_to_me, send_to_others, nmk, self.compression_threshold);
+
+        Self::broadcast_result(failed)
     }
 
     fn broadcast_signed(&self, message: PM::Message, target: impl Iterator<Item=NodeId>) -> std::result::Result<(), Vec<NodeId>> {
@@ -269,13 +413,9 @@ impl<NI, RM, PM> ProtocolNetworkNode<PM> for MIOTcpNode<NI, RM, PM>
         let (send_to_me, send_to_others, failed) =
             self.send_tos(keys, target, true);
 
-        Self::serialize_send_impl(send_to_me, send_to_others, nmk);
+        Self::serialize_send_impl(send_to_me, send_to_others, nmk, self.compression_threshold);
 
-        if !failed.is_empty() {
-            Err(failed)
-        } else {
-            Ok(())
-        }
+        Self::broadcast_result(failed)
     }
 
     fn serialize_digest_message(&self, message: PM::Message) -> Result<(SerializedMessage<PM::Message>, Digest)> {
@@ -328,11 +468,7 @@ impl<NI, RM, PM> ProtocolNetworkNode<PM> for MIOTcpNode<NI, RM, PM>
             Self::send_serialized_impl(send_to_me, send_to_others, mapped_serialized_messages);
         });
 
-        if !failed.is_empty() {
-            Err(failed)
-        } else {
-            Ok(())
-        }
+        Self::broadcast_result(failed)
     }
 }
 
@@ -351,6 +487,44 @@ impl<NI, RM, PM> NetworkNode for MIOTcpNode<NI, RM, PM> where NI: 'static + Netw
     fn network_info_provider(&self) -> &Arc<Self::NetworkInfoProvider> {
         &self.reconfiguration
     }
+
+    fn subscribe_connection_events(&self) -> ChannelSyncRx<ConnectionEvent> {
+        self.connections.connection_events().subscribe()
+    }
+
+    fn shutdown(&self) {
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+
+        if threads.is_empty() {
+            // We have already been shut down
+            return;
+        }
+
+        info!("{:?} // Shutting down the node", self.id);
+
+        self.connections.shutdown();
+
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("{:?} // A network thread panicked while shutting down", self.id);
+            }
+        }
+
+        self.connections.clear_connections();
+        self.connections.failure_detector().stop();
+        self.client_pooling.shutdown();
+
+        info!("{:?} // The node has been shut down", self.id);
+    }
+}
+
+impl<NI, RM, PM> Drop for MIOTcpNode<NI, RM, PM>
+    where NI: NetworkInformationProvider + 'static,
+          RM: Serializable + 'static,
+          PM: Serializable + 'static {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
 }
 
 impl<NI, RM, PM> ReconfigurationNode<RM> for MIOTcpNode<NI, RM, PM>
@@ -376,11 +550,9 @@ impl<NI, RM, PM> ReconfigurationNode<RM> for MIOTcpNode<NI, RM, PM>
         let (send_to_me, send_to_others, failed) =
             self.send_tos(keys, iter::once(target), true);
 
-        if !failed.is_empty() {
-            return Err!(NetworkSendError::PeerNotFound(target));
-        }
+        Self::send_result(failed)?;
 
-        Self::serialize_send_impl(send_to_me, send_to_others, nmk);
+        Self::serialize_send_impl(send_to_me, send_to_others, nmk, self.compression_threshold);
 
         Ok(())
     }
@@ -393,13 +565,9 @@ impl<NI, RM, PM> ReconfigurationNode<RM> for MIOTcpNode<NI, RM, PM>
         let (send_to_me, send_to_others, failed) =
             self.send_tos(keys, target, true);
 
-        Self::serialize_send_impl(send_to_me, send_to_others, nmk);
+        Self::serialize_send_impl(send_to_me, send_to_others, nmk, self.compression_threshold);
 
-        if !failed.is_empty() {
-            Err(failed)
-        } else {
-            Ok(())
-        }
+        Self::broadcast_result(failed)
     }
 }
 
@@ -416,6 +584,17 @@ impl<NI, RM, PM> FullNetworkNode<NI, RM, PM> for MIOTcpNode<NI, RM, PM>
 
         let tcp_config = cfg.tcp_config;
 
+        let replica_session_macs = tcp_config.replica_session_macs;
+
+        let compression_threshold = match tcp_config.compression_threshold {
+            Some(_) if !compression::is_available() => {
+                warn!("{:?} // Payload compression was configured, but this build does not have the compression feature. Ignoring", id);
+
+                None
+            }
+            threshold => threshold,
+        };
+
         let conn_counts = ConnCounts::from_tcp_config(&tcp_config);
 
         let reconfig_message_handler = Arc::new(ReconfigurationMessageHandler::initialize());
@@ -442,23 +621,41 @@ impl<NI, RM, PM> FullNetworkNode<NI, RM, PM> for MIOTcpNode<NI, RM, PM>
             conn_counts.clone(),
             reconfig_message_handler.clone(),
             peers.clone(),
+            ConnectionsConfig {
+                tls_config: network,
+                max_wire_version: tcp_config.max_wire_version.unwrap_or(WireMessage::CURRENT_VERSION),
+                max_payload_length: tcp_config.max_payload_length,
+                leave_timeout: Duration::from_millis(tcp_config.shutdown_timeout_millis),
+                verification_config: cfg.verification_config,
+                replay_config: cfg.replay_protection_config,
+                fragmentation_config: cfg.fragmentation_config,
+                heartbeat_config: cfg.heartbeat_config,
+                failure_detector_config: cfg.failure_detector_config,
+                reconnection_config: cfg.reconnection_config,
+                reliable_delivery_config: cfg.reliable_delivery_config,
+                priority_lanes_config: cfg.priority_lanes_config,
+                rate_limit_config: tcp_config.rate_limit_config,
+                backpressure_config: cfg.backpressure_config.clone(),
+            },
         )?);
 
-        NetworkUpdateHandler::initialize_update_handler(
+        let addr = network_info_provider.get_own_addr();
+
+        // Bind before launching any thread, so failing to bind doesn't leave them running
+        let listener = Self::setup_connection(&id, addr.socket())?;
+
+        let update_handler = NetworkUpdateHandler::initialize_update_handler(
             connections.registered_servers().clone(),
             connections.pending_server_connections().clone(),
             reconfig_message_handler.clone(),
             connections.clone(),
         );
 
-        initialize_worker_group(connections.clone(), receivers)?;
-
-        let addr = network_info_provider.get_own_addr();
+        let mut threads = initialize_worker_group(connections.clone(), receivers)?;
 
-        let listener = Self::setup_connection(&id, addr.socket())?;
+        threads.push(update_handler);
+        threads.push(connections.setup_tcp_server_worker(listener));
 
-        connections.setup_tcp_server_worker(listener);
-            
         let network_node = Self {
             id,
             rng,
@@ -466,6 +663,10 @@ impl<NI, RM, PM> FullNetworkNode<NI, RM, PM> for MIOTcpNode<NI, RM, PM>
             reconfig_handling: reconfig_message_handler,
             client_pooling: peers,
             reconfiguration: network_info_provider.clone(),
+            replica_session_macs,
+            compression_threshold,
+            backpressure_config: cfg.backpressure_config,
+            threads: Mutex::new(threads),
         };
 
         Ok(network_node)
@@ -480,8 +681,16 @@ struct SendTo<RM, PM>
     peer_id: NodeId,
     shared: Option<Arc<KeyPair>>,
     nonce: u64,
+    // Authenticate the message with the session key of the connection, instead of signing it
+    session_mac: bool,
     reconfig_handling: Arc<ReconfigurationMessageHandler<StoredMessage<RM::Message>>>,
     peer_cnn: SendToPeer<RM, PM>,
+    // The place reserved for the message in the send queue of the peer (not needed to send to ourselves)
+    queue_slot: Option<QueueSlot>,
+    // Told whether the message was delivered, for tracked messages
+    callback: Callback,
+    // The priority to send the message with, when not the default one of its class
+    priority: Option<MessagePriority>,
     flush: bool,
     rq_send_time: Instant,
 }
@@ -497,7 +706,15 @@ enum SendToPeer<RM, PM> where RM: Serializable + 'static, PM: Serializable + 'st
 impl<RM, PM> SendTo<RM, PM>
     where RM: Serializable + 'static,
           PM: Serializable + 'static {
-    fn value(self, msg: Either<(NetworkMessageKind<RM, PM>, Buf, Digest), (Buf, Digest)>) {
+    /// The message with the priority it should be sent with
+    fn prioritized(message: WireMessage, priority: Option<MessagePriority>) -> WireMessage {
+        match priority {
+            Some(priority) => message.with_priority(priority),
+            None => message,
+        }
+    }
+
+    fn value(self, msg: Either<(NetworkMessageKind<RM, PM>, Buf, Digest), (MessageClass, bool, Buf, Digest)>) {
         let key_pair = match &self.shared {
             None => {
                 None
@@ -510,7 +727,8 @@ impl<RM, PM> SendTo<RM, PM>
         match (self.peer_cnn, msg) {
             (SendToPeer::Me(conn), Either::Left((msg, buf, digest))) => {
                 let message = WireMessage::new(self.my_id, self.peer_id,
-                                               buf, self.nonce, Some(digest), key_pair);
+                                               buf, self.nonce, Some(digest), key_pair)
+                    .with_class(msg.class());
 
                 let (header, _) = message.into_inner();
 
@@ -525,18 +743,42 @@ impl<RM, PM> SendTo<RM, PM>
                         unreachable!()
                     }
                 }
+
+                // Messages to ourselves are delivered as soon as they are queued
+                if let Some(callback) = self.callback {
+                    callback(true);
+                }
             }
-            (SendToPeer::Peer(peer), Either::Right((buf, digest))) => {
-                let message = WireMessage::new(self.my_id, self.peer_id,
-                                               buf, self.nonce, Some(digest), key_pair);
+            (SendToPeer::Peer(peer), Either::Right((class, compressed, buf, digest))) => {
+                let message = if self.session_mac && key_pair.is_some() {
+                    WireMessage::new_session_authenticated(self.my_id, self.peer_id,
+                                                           buf, self.nonce, digest)
+                } else {
+                    WireMessage::new(self.my_id, self.peer_id,
+                                     buf, self.nonce, Some(digest), key_pair)
+                };
+
+                let slot = self.queue_slot.expect("Messages to peers always have a place in their send queue");
 
-                peer.peer_message(message, None).unwrap();
+                let message = Self::prioritized(message.with_class(class).with_compressed_payload(compressed), self.priority);
+
+                if let Err(err) = peer.peer_message(slot, message, self.callback) {
+                    error!("Failed to send message to {:?}: {:?}", self.peer_id, err);
+                }
             }
-            (SendToPeer::PendingPeer(peer), Either::Right((buf, digest))) => {
+            (SendToPeer::PendingPeer(peer), Either::Right((class, compressed, buf, digest))) => {
                 let message = WireMessage::new(self.my_id, self.peer_id,
-                                               buf, self.nonce, Some(digest), key_pair);
+                                               buf, self.nonce, Some(digest), key_pair)
+                    .with_class(class)
+                    .with_compressed_payload(compressed);
 
-                peer.peer_message(message).unwrap();
+                let message = Self::prioritized(message, self.priority);
+
+                let slot = self.queue_slot.expect("Messages to peers always have a place in their send queue");
+
+                if let Err(err) = peer.peer_message(slot, message, self.callback) {
+                    error!("Failed to send message to pending node {:?}: {:?}", self.peer_id, err);
+                }
             }
             (_, _) => { unreachable!() }
         }
@@ -560,15 +802,24 @@ impl<RM, PM> SendTo<RM, PM>
                         unreachable!()
                     }
                 }
+
+                if let Some(callback) = self.callback {
+                    callback(true);
+                }
             }
             SendToPeer::Peer(peer_cnn) => {
                 let (header, msg) = msg.into_inner();
 
-                let (_, buf) = msg.into_inner();
+                let (msg, buf) = msg.into_inner();
+
+                let wm = WireMessage::from_parts(header, buf).unwrap()
+                    .with_class(msg.class());
 
-                let wm = WireMessage::from_parts(header, buf).unwrap();
+                let slot = self.queue_slot.expect("Messages to peers always have a place in their send queue");
 
-                peer_cnn.peer_message(wm, None).unwrap();
+                if let Err(err) = peer_cnn.peer_message(slot, wm, self.callback) {
+                    error!("Failed to send message to {:?}: {:?}", self.peer_id, err);
+                }
             }
             SendToPeer::PendingPeer(pending_conn) => {
                 let (header, msg) = msg.into_inner();
@@ -577,9 +828,14 @@ impl<RM, PM> SendTo<RM, PM>
 
                 match msg {
                     NetworkMessageKind::ReconfigurationMessage(reconf) => {
-                        let wm = WireMessage::from_parts(header, buf).unwrap();
+                        let wm = WireMessage::from_parts(header, buf).unwrap()
+                            .with_class(MessageClass::Reconfiguration);
+
+                        let slot = self.queue_slot.expect("Messages to peers always have a place in their send queue");
 
-                        pending_conn.peer_message(wm).unwrap();
+                        if let Err(err) = pending_conn.peer_message(slot, wm, self.callback) {
+                            error!("Failed to send message to pending node {:?}: {:?}", self.peer_id, err);
+                        }
                     }
                     NetworkMessageKind::Ping(_) => {}
                     NetworkMessageKind::System(_) => {
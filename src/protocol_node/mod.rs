@@ -2,11 +2,12 @@ use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
+use atlas_common::channel::OneShotRx;
 use atlas_common::crypto::hash::Digest;
 use atlas_common::node_id::NodeId;
 use atlas_common::error::*;
 use crate::{NetworkNode, NodeConnections};
-use crate::message::{SerializedMessage, StoredMessage, StoredSerializedProtocolMessage};
+use crate::message::{MessagePriority, SerializedMessage, StoredMessage, StoredSerializedProtocolMessage};
 use crate::message_signing::NetworkMessageSignatureVerifier;
 use crate::reconfiguration_node::NetworkInformationProvider;
 use crate::serialize::Serializable;
@@ -51,6 +52,19 @@ pub trait ProtocolNetworkNode<M>: NetworkNode +  Send + Sync where M: Serializab
     /// on the success of the message dispatch
     fn send(&self, message: M::Message, target: NodeId, flush: bool) -> Result<()>;
 
+    /// Sends a message to a given target, tracking its delivery.
+    /// Does not block on the message sent. Fails like [Self::send], otherwise the returned
+    /// receiver resolves to Ok once the message has been fully written to the connection to the target,
+    /// or to an error if it was dropped or the connection broke before that.
+    /// Being written does not mean the target has received (or processed) the message
+    fn send_tracked(&self, message: M::Message, target: NodeId, flush: bool) -> Result<OneShotRx<Result<()>>>;
+
+    /// Sends a message to a given target, in the lane of the given priority instead of the default one
+    /// of protocol messages. Messages in more urgent lanes are written ahead of those queued in
+    /// less urgent ones (see [crate::config::PriorityLanesConfig]).
+    /// Otherwise behaves like [Self::send]
+    fn send_with_priority(&self, message: M::Message, target: NodeId, priority: MessagePriority, flush: bool) -> Result<()>;
+
     /// Sends a signed message to a given target
     /// Does not block on the message sent. Returns a result that is
     /// Ok if there is a current connection to the target or err if not. No other checks are made
@@ -63,6 +77,10 @@ pub trait ProtocolNetworkNode<M>: NetworkNode +  Send + Sync where M: Serializab
     /// on the success of the message dispatch
     fn broadcast(&self, message: M::Message, targets: impl Iterator<Item=NodeId>) -> std::result::Result<(), Vec<NodeId>>;
 
+    /// Broadcast a message to all of the given targets, in the lane of the given priority
+    /// (see [Self::send_with_priority]). Otherwise behaves like [Self::broadcast]
+    fn broadcast_with_priority(&self, message: M::Message, targets: impl Iterator<Item=NodeId>, priority: MessagePriority) -> std::result::Result<(), Vec<NodeId>>;
+
     /// Broadcast a signed message for all of the given targets
     /// Does not block on the message sent. Returns a result that is
     /// Ok if there is a current connection to the targets or err if not. No other checks are made
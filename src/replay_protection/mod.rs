@@ -0,0 +1,170 @@
+//! Protection against replays of messages we have already received.
+//!
+//! Since the nonces of our headers are random (and not sequential), we can't use a
+//! sliding bitmap. Instead, we remember the last nonces each peer has sent us, up to
+//! a configurable amount of nonces per peer. When that amount is reached, the oldest
+//! nonce is forgotten to make room for the new one.
+//!
+//! The windows are kept for the peers we are connected to, keyed by the id they authenticated
+//! with when the connection was established, and are dropped once we lose every connection to
+//! that peer. At most a configurable amount of peers is tracked at once.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+use dashmap::DashMap;
+use thiserror::Error;
+
+use atlas_common::Err;
+use atlas_common::error::*;
+use atlas_common::node_id::NodeId;
+
+use crate::config::ReplayProtectionConfig;
+use crate::message::Header;
+
+/// The nonces we have recently received from a given peer
+struct PeerWindow {
+    // The nonces we currently remember, for fast lookups
+    seen: HashSet<u64>,
+    // The same nonces, in the order they were received, so we know which to forget first
+    order: VecDeque<u64>,
+}
+
+/// The replay window of all the peers we are receiving messages from
+pub struct ReplayWindow {
+    // How many nonces we remember for each peer. 0 means replay protection is disabled
+    nonces_per_peer: usize,
+    // How many peers we keep windows for at once
+    max_peers: usize,
+    peers: DashMap<NodeId, Mutex<PeerWindow>>,
+}
+
+impl PeerWindow {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Register a nonce, returning false if it was already registered
+    fn register(&mut self, nonce: u64, limit: usize) -> bool {
+        if !self.seen.insert(nonce) {
+            return false;
+        }
+
+        self.order.push_back(nonce);
+
+        while self.order.len() > limit {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+impl ReplayWindow {
+    pub fn new(config: &ReplayProtectionConfig) -> Self {
+        Self {
+            nonces_per_peer: config.nonces_per_peer,
+            max_peers: config.max_peers,
+            peers: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.nonces_per_peer > 0
+    }
+
+    /// Register that we are delivering the message with the given header, received from the
+    /// given (authenticated) peer.
+    /// Fails if we have already delivered a message from the same peer with the same nonce
+    /// (within the window we keep for that peer), or if we are already tracking as many peers as we can.
+    pub fn check_and_register(&self, peer: NodeId, header: &Header) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        if !self.peers.contains_key(&peer) && self.peers.len() >= self.max_peers {
+            return Err!(ReplayError::TooManyPeers(peer, self.max_peers));
+        }
+
+        let peer_window = self.peers.entry(peer)
+            .or_insert_with(|| Mutex::new(PeerWindow::with_capacity(self.nonces_per_peer)));
+
+        let fresh = peer_window.lock().unwrap().register(header.nonce(), self.nonces_per_peer);
+
+        if !fresh {
+            return Err!(ReplayError::ReplayedMessage(peer, header.nonce()));
+        }
+
+        Ok(())
+    }
+
+    /// We have lost every connection to the given peer, so stop tracking it
+    pub fn forget(&self, peer: &NodeId) {
+        self.peers.remove(peer);
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Message from {0:?} with nonce {1} has already been received")]
+    ReplayedMessage(NodeId, u64),
+    #[error("Can't track the messages of {0:?}, as we are already tracking {1} peers")]
+    TooManyPeers(NodeId, usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use atlas_common::node_id::NodeId;
+
+    use crate::config::ReplayProtectionConfig;
+    use crate::message::{Header, WireMessage};
+    use crate::replay_protection::ReplayWindow;
+
+    fn header(from: u32, nonce: u64) -> Header {
+        let (header, _) = WireMessage::new(NodeId::from(from), NodeId::from(0u32), Bytes::new(), nonce, None, None).into_inner();
+
+        header
+    }
+
+    #[test]
+    fn test_replays_are_rejected_within_window() {
+        let window = ReplayWindow::new(&ReplayProtectionConfig { nonces_per_peer: 2, max_peers: 16 });
+
+        assert!(window.check_and_register(NodeId::from(1u32), &header(1, 10)).is_ok());
+        assert!(window.check_and_register(NodeId::from(1u32), &header(1, 10)).is_err());
+
+        // The same nonce from another peer is not a replay
+        assert!(window.check_and_register(NodeId::from(2u32), &header(2, 10)).is_ok());
+
+        assert!(window.check_and_register(NodeId::from(1u32), &header(1, 11)).is_ok());
+        assert!(window.check_and_register(NodeId::from(1u32), &header(1, 12)).is_ok());
+
+        // The oldest nonce has been forgotten, to respect the memory bound
+        assert!(window.check_and_register(NodeId::from(1u32), &header(1, 10)).is_ok());
+        assert!(window.check_and_register(NodeId::from(1u32), &header(1, 12)).is_err());
+    }
+
+    #[test]
+    fn test_tracked_peers_are_bounded() {
+        let window = ReplayWindow::new(&ReplayProtectionConfig { nonces_per_peer: 2, max_peers: 2 });
+
+        assert!(window.check_and_register(NodeId::from(1u32), &header(1, 10)).is_ok());
+        assert!(window.check_and_register(NodeId::from(2u32), &header(2, 10)).is_ok());
+
+        // No room is made for a third peer, while the ones we track keep being checked
+        assert!(window.check_and_register(NodeId::from(3u32), &header(3, 10)).is_err());
+        assert!(window.check_and_register(NodeId::from(1u32), &header(1, 11)).is_ok());
+
+        // Until one of them is gone
+        window.forget(&NodeId::from(2u32));
+
+        assert!(window.check_and_register(NodeId::from(3u32), &header(3, 10)).is_ok());
+    }
+}
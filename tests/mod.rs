@@ -16,12 +16,15 @@ mod communication_test {
     use serde::{Deserialize, Serialize};
     use atlas_common::crypto::signature::{KeyPair, PublicKey};
     use atlas_common::error::*;
-    use atlas_common::node_id::NodeId;
+    use atlas_common::node_id::{NodeId, NodeType};
     use atlas_common::{async_runtime as rt, channel};
+    use atlas_common::channel::ChannelSyncRx;
     use atlas_common::threadpool;
-    use atlas_communication::config::{ClientPoolConfig, MioConfig, NodeConfig, PKConfig, TcpConfig, TlsConfig};
-    use atlas_communication::{Node, NodeConnections, NodeIncomingRqHandler};
-    use atlas_communication::message::NetworkMessageKind;
+    use atlas_communication::config::{AdmissionConfig, BackpressureConfig, BackpressurePolicy, BandwidthLimit, BatchCollection, ClientPoolConfig, FailureDetectorConfig, FragmentationConfig, HeartbeatConfig, MessageVerificationConfig, MioConfig, NodeConfig, PeerRateLimits, PKConfig, PriorityLanesConfig, RateLimitConfig, ReconnectionConfig, ReliableDeliveryConfig, ReplayProtectionConfig, TcpConfig, TlsConfig};
+    use atlas_communication::{FullNetworkNode, NetworkNode, Node, NodeConnections, NodeIncomingRqHandler};
+    use atlas_communication::message::{Header, NetworkMessageKind, WireMessage};
+    use atlas_communication::message_signing::NetworkMessageSignatureVerifier;
+    use atlas_communication::reconfiguration_node::NetworkInformationProvider;
     use atlas_communication::mio_tcp::MIOTcpNode;
     use atlas_communication::serialize::Serializable;
     use atlas_communication::tcp_ip_simplex::TCPSimplexNode;
@@ -33,6 +36,14 @@ mod communication_test {
         clients_per_pool: 100,
         batch_timeout_micros: 1000,
         batch_sleep_micros: 1500,
+        admission_config: AdmissionConfig {
+            client_quota: 16384,
+            overloaded_client_quota: 1024,
+            high_watermark: 131072,
+            low_watermark: 65536,
+        },
+        adaptive_batching: None,
+        collection: BatchCollection::Polling,
     };
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -45,6 +56,12 @@ mod communication_test {
     impl Serializable for TestMessage {
         type Message = TestMessage;
 
+        fn verify_message_internal<NI, SV>(_info_provider: &Arc<NI>, _header: &Header, _msg: &Self::Message) -> Result<()>
+            where NI: NetworkInformationProvider + 'static,
+                  SV: NetworkMessageSignatureVerifier<Self, NI> {
+            Ok(())
+        }
+
         #[cfg(feature = "serialize_capnp")]
         fn serialize_capnp(builder: Builder, msg: &Self::Message) -> Result<()> {
             todo!()
@@ -280,6 +297,11 @@ mod communication_test {
                 network_config: gen_tls_config(node_id, name),
                 replica_concurrent_connections: 1,
                 client_concurrent_connections: 1,
+                replica_session_macs: false,
+                max_wire_version: None,
+                compression_threshold: None,
+                max_payload_length: 64 * 1024 * 1024,
+                shutdown_timeout_millis: 1000,
             },
             client_pool_config: CLI_POOL_CFG,
             pk_crypto_config: gen_pk_config(node_id, node_count),
@@ -298,6 +320,11 @@ mod communication_test {
                 network_config: gen_tls_config(node_id, name),
                 replica_concurrent_connections: 1,
                 client_concurrent_connections: 1,
+                replica_session_macs: false,
+                max_wire_version: None,
+                compression_threshold: None,
+                max_payload_length: 64 * 1024 * 1024,
+                shutdown_timeout_millis: 1000,
             },
             client_pool_config: CLI_POOL_CFG,
             pk_crypto_config: gen_pk_config(node_id, node_count),
@@ -316,6 +343,11 @@ mod communication_test {
                 network_config: gen_tls_config(node_id, name),
                 replica_concurrent_connections: 1,
                 client_concurrent_connections: 1,
+                replica_session_macs: false,
+                max_wire_version: None,
+                compression_threshold: None,
+                max_payload_length: 64 * 1024 * 1024,
+                shutdown_timeout_millis: 1000,
             },
             client_pool_config: CLI_POOL_CFG,
             pk_crypto_config: gen_pk_config(node_id, node_count),
@@ -594,6 +626,1190 @@ mod communication_test {
         }
     }
 
+    /// A static view of the network, used to bootstrap the MIO nodes
+    struct MockNetworkInfo {
+        own_id: NodeId,
+        key_pair: Arc<KeyPair>,
+        addrs: IntMap<PeerAddr>,
+        public_keys: IntMap<PublicKey>,
+    }
+
+    impl MockNetworkInfo {
+        fn new(node_id: NodeId, addrs: IntMap<PeerAddr>, node_count: usize) -> Self {
+            let PKConfig { sk, pk } = gen_pk_config(node_id, node_count);
+
+            Self {
+                own_id: node_id,
+                key_pair: Arc::new(sk),
+                addrs,
+                public_keys: pk,
+            }
+        }
+    }
+
+    impl NetworkInformationProvider for MockNetworkInfo {
+        fn get_own_id(&self) -> NodeId {
+            self.own_id
+        }
+
+        fn get_own_addr(&self) -> PeerAddr {
+            self.addrs.get(self.own_id.0 as u64).cloned().unwrap()
+        }
+
+        fn get_key_pair(&self) -> &Arc<KeyPair> {
+            &self.key_pair
+        }
+
+        fn get_own_node_type(&self) -> NodeType {
+            self.get_node_type(&self.own_id).unwrap()
+        }
+
+        fn get_node_type(&self, node: &NodeId) -> Option<NodeType> {
+            if node.0 >= FIRST_CLI.0 {
+                Some(NodeType::Client)
+            } else {
+                Some(NodeType::Replica)
+            }
+        }
+
+        fn get_public_key(&self, node: &NodeId) -> Option<PublicKey> {
+            self.public_keys.get(node.0 as u64).cloned()
+        }
+
+        fn get_addr_for_node(&self, node: &NodeId) -> Option<PeerAddr> {
+            self.addrs.get(node.0 as u64).cloned()
+        }
+    }
+
+    type TestMioNode = MIOTcpNode<MockNetworkInfo, TestMessage, TestMessage>;
+
+    fn gen_tls_mio_node(node_id: NodeId, addrs: IntMap<PeerAddr>, node_count: usize, name: &str) -> Result<Arc<TestMioNode>> {
+        gen_tls_mio_node_with_info(MockNetworkInfo::new(node_id, addrs, node_count), name, |_| {})
+    }
+
+    /// Generate a node with the given network information, allowing the test to
+    /// adjust the default configuration before the node is bootstrapped
+    fn gen_tls_mio_node_with_info(network_info: MockNetworkInfo, name: &str, configure: impl FnOnce(&mut NodeConfig)) -> Result<Arc<TestMioNode>> {
+        let node_id = network_info.own_id;
+        let network_info = Arc::new(network_info);
+
+        let mut cfg = NodeConfig {
+            tcp_config: TcpConfig {
+                network_config: gen_tls_config(node_id, name),
+                replica_concurrent_connections: 1,
+                client_concurrent_connections: 1,
+                replica_session_macs: false,
+                max_wire_version: None,
+                compression_threshold: None,
+                max_payload_length: 64 * 1024 * 1024,
+                shutdown_timeout_millis: 1000,
+                rate_limit_config: RateLimitConfig {
+                    global_send_limit: None,
+                    replica_limits: PeerRateLimits { send_limit: None, receive_limit: None },
+                    client_limits: PeerRateLimits { send_limit: None, receive_limit: None },
+                },
+            },
+            client_pool_config: CLI_POOL_CFG,
+            verification_config: MessageVerificationConfig {
+                verify_reconfiguration_messages: true,
+                verify_protocol_messages: false,
+            },
+            replay_protection_config: ReplayProtectionConfig {
+                nonces_per_peer: 1024,
+                max_peers: 4096,
+            },
+            fragmentation_config: FragmentationConfig {
+                max_frame_size: 64 * 1024,
+                max_reassembly_bytes_per_peer: 16 * 1024 * 1024,
+            },
+            heartbeat_config: HeartbeatConfig {
+                interval_millis: 1000,
+                timeout_millis: 5000,
+            },
+            failure_detector_config: FailureDetectorConfig {
+                phi_threshold: 8.0,
+                max_sample_size: 1000,
+                min_std_deviation_millis: 100,
+                acceptable_pause_millis: 3000,
+                first_interval_estimate_millis: 1000,
+            },
+            reconnection_config: ReconnectionConfig {
+                initial_backoff_millis: 100,
+                max_backoff_millis: 2000,
+                backoff_multiplier: 2.0,
+                jitter: 0.2,
+                connect_timeout_millis: 5000,
+                max_attempts: None,
+            },
+            backpressure_config: BackpressureConfig {
+                replica_policy: BackpressurePolicy::Block { timeout_millis: 1000 },
+                client_policy: BackpressurePolicy::DropOldest,
+            },
+            reliable_delivery_config: ReliableDeliveryConfig {
+                enabled: false,
+                max_unacked: 1024,
+            },
+            priority_lanes_config: PriorityLanesConfig {
+                control_weight: 8,
+                normal_weight: 4,
+                bulk_weight: 1,
+            },
+        };
+
+        configure(&mut cfg);
+
+        let config = MioConfig {
+            node_config: cfg,
+            worker_count: 2,
+        };
+
+        rt::block_on(TestMioNode::bootstrap(node_id, network_info, config)).map(Arc::new)
+    }
+
+    /// Nodes in the MIO cluster should only talk TLS, so a peer that connects and just
+    /// sends an identifying header in plaintext should be disconnected without ever being registered
+    #[test]
+    fn test_mio_tls_rejects_plaintext() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        let addrs = setup_addrs(3, 0);
+
+        let node_1 = NodeId(0u32);
+        let node_2 = NodeId(1u32);
+        let impersonated = NodeId(2u32);
+
+        let node = gen_tls_mio_node(node_1, addrs.clone(), 3, "srv0").unwrap();
+        let node_2_ = gen_tls_mio_node(node_2, addrs.clone(), 3, "srv1").unwrap();
+
+        for rx in node.node_connections().connect_to_node(node_2) {
+            rx.recv().unwrap().unwrap();
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+
+        assert!(node.node_connections().is_connected_to_node(&node_2));
+        assert!(node_2_.node_connections().is_connected_to_node(&node_1));
+
+        let mut plain_stream = TcpStream::connect(addrs.get(node_1.0 as u64).unwrap().socket()).unwrap();
+
+        let (header, _) = WireMessage::new(impersonated, node_1, Default::default(), 0, None, None).into_inner();
+
+        let mut buf = [0; Header::LENGTH];
+        header.serialize_into(&mut buf[..]).unwrap();
+
+        plain_stream.write_all(&buf[..]).unwrap();
+        plain_stream.flush().unwrap();
+
+        plain_stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // The node must close the connection (possibly after sending a TLS alert)
+        let mut response = Vec::new();
+        let closed = plain_stream.read_to_end(&mut response).is_ok();
+
+        assert!(closed, "The node did not close the plaintext connection");
+        assert!(Header::deserialize_from(&response[..]).map(|header| header.from() != node_1).unwrap_or(true));
+
+        std::thread::sleep(Duration::from_secs(1));
+
+        assert!(!node.node_connections().is_connected_to_node(&impersonated));
+        assert_eq!(node.node_connections().connected_nodes_count(), 1);
+    }
+
+    /// A node that holds a valid TLS certificate but not the signing key of the id it
+    /// claims must fail the authentication handshake and never be registered
+    #[test]
+    fn test_mio_rejects_spoofed_node_id() {
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        let addrs = setup_addrs(3, 0);
+
+        let node_1 = NodeId(0u32);
+        let node_2 = NodeId(1u32);
+        let impersonated = NodeId(2u32);
+
+        let node = gen_tls_mio_node(node_1, addrs.clone(), 3, "srv0").unwrap();
+        let node_2_ = gen_tls_mio_node(node_2, addrs.clone(), 3, "srv1").unwrap();
+
+        let mut spoofer_info = MockNetworkInfo::new(impersonated, addrs.clone(), 3);
+
+        // Not the key the rest of the network knows for this id
+        spoofer_info.key_pair = Arc::new(KeyPair::from_bytes(&[1; 32][..]).unwrap());
+
+        let spoofer = gen_tls_mio_node_with_info(spoofer_info, "srv2", |_| {}).unwrap();
+
+        for rx in node.node_connections().connect_to_node(node_2) {
+            rx.recv().unwrap().unwrap();
+        }
+
+        for rx in spoofer.node_connections().connect_to_node(node_1) {
+            let _ = rx.recv().unwrap();
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+
+        // The genuine node was authenticated
+        assert!(node.node_connections().is_connected_to_node(&node_2));
+        assert!(node_2_.node_connections().is_connected_to_node(&node_1));
+
+        // While the spoofer was not
+        assert!(!node.node_connections().is_connected_to_node(&impersonated));
+        assert_eq!(node.node_connections().connected_nodes_count(), 1);
+    }
+
+    /// A node joining the system is not known to the other nodes, so it authenticates with the
+    /// key it presents and is kept pending until the reconfiguration protocol vouches for that key
+    #[test]
+    fn test_mio_joining_node_waits_for_authorization() {
+        use atlas_communication::conn_utils::ConnectionEvent;
+        use atlas_communication::reconfiguration_node::{NetworkUpdateMessage, ReconfigurationNetworkUpdate, ReconfigurationNode};
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        let addrs = setup_addrs(2, 0);
+
+        let node_1 = NodeId(0u32);
+        let joining = NodeId(1u32);
+
+        let mut node_info = MockNetworkInfo::new(node_1, addrs.clone(), 2);
+
+        let joining_key = node_info.public_keys.remove(joining.0 as u64).unwrap();
+
+        let node = gen_tls_mio_node_with_info(node_info, "srv0", |_| {}).unwrap();
+        let joining_ = gen_tls_mio_node(joining, addrs.clone(), 2, "srv1").unwrap();
+
+        let events = node.subscribe_connection_events();
+
+        for rx in joining_.node_connections().connect_to_node(node_1) {
+            rx.recv().unwrap().unwrap();
+        }
+
+        assert_eq!(next_event(&events), ConnectionEvent::PendingAuthorization(joining));
+        assert!(!node.node_connections().is_connected_to_node(&joining));
+
+        // Permitting the node with a key other than the one it authenticated with closes its connection
+        let wrong_key: PublicKey = KeyPair::from_bytes(&[1; 32][..]).unwrap().public_key().into();
+
+        node.reconfiguration_network_update()
+            .send_reconfiguration_update(NetworkUpdateMessage::NodeConnectionPermitted(joining, NodeType::Replica, wrong_key))
+            .unwrap();
+
+        // The joining node reconnects, and is kept pending once again
+        assert_eq!(next_event(&events), ConnectionEvent::PendingAuthorization(joining));
+        assert!(!node.node_connections().is_connected_to_node(&joining));
+
+        node.reconfiguration_network_update()
+            .send_reconfiguration_update(NetworkUpdateMessage::NodeConnectionPermitted(joining, NodeType::Replica, joining_key))
+            .unwrap();
+
+        assert!(matches!(next_event(&events), ConnectionEvent::Connected(peer, NodeType::Replica, _) if peer == joining));
+        assert!(node.node_connections().is_connected_to_node(&joining));
+    }
+
+    /// Connect every pair of the given nodes. Each node connects to the nodes after it
+    fn connect_all(nodes: &[Arc<TestMioNode>]) {
+        for (id, node) in nodes.iter().enumerate() {
+            for peer in (id + 1)..nodes.len() {
+                for rx in node.node_connections().connect_to_node(NodeId(peer as u32)) {
+                    rx.recv().unwrap().unwrap();
+                }
+            }
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+    }
+
+    /// Have every node send a message with `data_len` bytes of data to every other node,
+    /// and check that all of them are delivered intact
+    fn assert_all_to_all_delivery(nodes: &[Arc<TestMioNode>], data_len: usize) {
+        use atlas_communication::protocol_node::{NodeIncomingRqHandler, ProtocolNetworkNode};
+
+        for (id, node) in nodes.iter().enumerate() {
+            for peer in 0..nodes.len() {
+                if peer == id {
+                    continue;
+                }
+
+                let message = TestMessage { req: true, hello: format!("{} -> {}", id, peer), data: vec![id as u8; data_len] };
+
+                node.send(message, NodeId(peer as u32), true).unwrap();
+            }
+        }
+
+        for (id, node) in nodes.iter().enumerate() {
+            let mut received = (0..nodes.len() - 1)
+                .map(|_| node.node_incoming_rq_handling().receive_from_replicas(Some(Duration::from_secs(5)))
+                    .unwrap()
+                    .expect("Timed out waiting for a message"))
+                .map(|message| {
+                    let (header, message) = message.into_inner();
+
+                    assert_eq!(message.hello, format!("{} -> {}", header.from().0, id));
+                    assert_eq!(message.data, vec![header.from().0 as u8; data_len]);
+
+                    header.from().0 as usize
+                })
+                .collect::<Vec<_>>();
+
+            received.sort();
+
+            assert_eq!(received, (0..nodes.len()).filter(|peer| *peer != id).collect::<Vec<_>>());
+        }
+    }
+
+    /// Nodes that only support the base wire version (as an older build would) must be
+    /// able to exchange messages with nodes that support newer versions, in both directions
+    #[test]
+    fn test_mio_mixed_wire_versions() {
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const NODE_COUNT: usize = 3;
+
+        let addrs = setup_addrs(NODE_COUNT as u32, 0);
+
+        // Node 2 behaves like an older build, the others use the newest version
+        let nodes = (0..NODE_COUNT)
+            .map(|id| {
+                let max_wire_version = if id == 2 { Some(WireMessage::BASE_VERSION) } else { None };
+
+                gen_tls_mio_node_with_info(MockNetworkInfo::new(NodeId(id as u32), addrs.clone(), NODE_COUNT),
+                                           &format!("srv{}", id), |cfg| cfg.tcp_config.max_wire_version = max_wire_version).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        connect_all(&nodes);
+
+        assert_all_to_all_delivery(&nodes, 16);
+    }
+
+    /// Large messages are compressed, and must still reach both nodes that can decompress them
+    /// and nodes that only speak the base wire version (which has no way to mark compression)
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_mio_compressed_payloads() {
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const NODE_COUNT: usize = 3;
+
+        let addrs = setup_addrs(NODE_COUNT as u32, 0);
+
+        let nodes = (0..NODE_COUNT)
+            .map(|id| {
+                let max_wire_version = if id == 2 { Some(WireMessage::BASE_VERSION) } else { None };
+
+                gen_tls_mio_node_with_info(MockNetworkInfo::new(NodeId(id as u32), addrs.clone(), NODE_COUNT),
+                                           &format!("srv{}", id), |cfg| {
+                                               cfg.tcp_config.max_wire_version = max_wire_version;
+                                               cfg.tcp_config.compression_threshold = Some(1024);
+                                           }).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        connect_all(&nodes);
+
+        assert_all_to_all_delivery(&nodes, 64 * 1024);
+    }
+
+    /// Messages larger than the maximum frame size are fragmented (except to nodes that only
+    /// speak the base wire version) and must be reassembled intact by the receivers
+    #[test]
+    fn test_mio_fragmented_payloads() {
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const NODE_COUNT: usize = 3;
+
+        let addrs = setup_addrs(NODE_COUNT as u32, 0);
+
+        let nodes = (0..NODE_COUNT)
+            .map(|id| {
+                let max_wire_version = if id == 2 { Some(WireMessage::BASE_VERSION) } else { None };
+
+                gen_tls_mio_node_with_info(MockNetworkInfo::new(NodeId(id as u32), addrs.clone(), NODE_COUNT),
+                                           &format!("srv{}", id), |cfg| {
+                                               cfg.tcp_config.max_wire_version = max_wire_version;
+                                               cfg.fragmentation_config.max_frame_size = 4096;
+                                           }).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        connect_all(&nodes);
+
+        assert_all_to_all_delivery(&nodes, 256 * 1024);
+
+        // Small messages still get through after the large ones
+        assert_all_to_all_delivery(&nodes, 16);
+    }
+
+    /// Sequenced messages must be acknowledged (or nothing else gets written once too many are
+    /// unacknowledged), including large ones that are fragmented. Nodes that only speak older
+    /// wire versions get the messages unsequenced
+    #[test]
+    fn test_mio_reliable_delivery() {
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const NODE_COUNT: usize = 3;
+
+        let addrs = setup_addrs(NODE_COUNT as u32, 0);
+
+        let nodes = (0..NODE_COUNT)
+            .map(|id| {
+                let max_wire_version = if id == 2 { Some(WireMessage::GOODBYE_VERSION) } else { None };
+
+                gen_tls_mio_node_with_info(MockNetworkInfo::new(NodeId(id as u32), addrs.clone(), NODE_COUNT),
+                                           &format!("srv{}", id), |cfg| {
+                                               cfg.tcp_config.max_wire_version = max_wire_version;
+                                               cfg.fragmentation_config.max_frame_size = 4096;
+                                               cfg.reliable_delivery_config.enabled = true;
+                                               cfg.reliable_delivery_config.max_unacked = 2;
+                                           }).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        connect_all(&nodes);
+
+        for _ in 0..16 {
+            assert_all_to_all_delivery(&nodes, 16);
+        }
+
+        assert_all_to_all_delivery(&nodes, 64 * 1024);
+    }
+
+    /// Tracked messages must be reported as delivered once written, including
+    /// the fragmented ones (only once all of their fragments are) and those to ourselves
+    #[test]
+    fn test_mio_send_tracked() {
+        use atlas_communication::protocol_node::{NodeIncomingRqHandler, ProtocolNetworkNode};
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const NODE_COUNT: usize = 2;
+
+        let addrs = setup_addrs(NODE_COUNT as u32, 0);
+
+        let nodes = (0..NODE_COUNT)
+            .map(|id| {
+                gen_tls_mio_node_with_info(MockNetworkInfo::new(NodeId(id as u32), addrs.clone(), NODE_COUNT),
+                                           &format!("srv{}", id), |cfg| {
+                                               cfg.fragmentation_config.max_frame_size = 4096;
+                                           }).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        connect_all(&nodes);
+
+        for (target, data_len) in [(1, 16), (1, 64 * 1024), (0, 16)] {
+            let message = TestMessage { req: true, hello: format!("0 -> {}", target), data: vec![0; data_len] };
+
+            let delivered = nodes[0].send_tracked(message, NodeId(target), true).unwrap();
+
+            delivered.recv().unwrap().expect("The message should have been delivered");
+
+            let received = nodes[target as usize].node_incoming_rq_handling()
+                .receive_from_replicas(Some(Duration::from_secs(5)))
+                .unwrap()
+                .expect("Timed out waiting for the tracked message");
+
+            assert_eq!(received.message().data.len(), data_len);
+        }
+
+        // Nodes we are not connected to fail right away
+        assert!(nodes[0].send_tracked(TestMessage { req: true, hello: String::new(), data: vec![] }, NodeId(42), true).is_err());
+    }
+
+    /// Messages sent with a priority go through the lane of that priority, and must reach
+    /// their target still carrying it. Urgent messages queued behind a large transfer are
+    /// written before it is done, so they overtake it
+    #[test]
+    fn test_mio_send_with_priority() {
+        use atlas_communication::message::MessagePriority;
+        use atlas_communication::protocol_node::{NodeIncomingRqHandler, ProtocolNetworkNode};
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const NODE_COUNT: usize = 3;
+
+        let addrs = setup_addrs(NODE_COUNT as u32, 0);
+
+        let nodes = (0..NODE_COUNT)
+            .map(|id| {
+                gen_tls_mio_node_with_info(MockNetworkInfo::new(NodeId(id as u32), addrs.clone(), NODE_COUNT),
+                                           &format!("srv{}", id), |cfg| {
+                                               cfg.fragmentation_config.max_frame_size = 4096;
+
+                                               // Writing the bulk transfer takes around a second, so the urgent
+                                               // messages are always queued while it is still being written
+                                               if id == 0 {
+                                                   cfg.tcp_config.rate_limit_config.replica_limits.send_limit = Some(BandwidthLimit {
+                                                       bytes_per_second: 256 * 1024,
+                                                       burst_bytes: 16 * 1024,
+                                                   });
+                                               }
+                                           }).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        connect_all(&nodes);
+
+        // A large bulk transfer, with urgent messages queued right behind it
+        let bulk = TestMessage { req: true, hello: String::from("bulk"), data: vec![0; 256 * 1024] };
+
+        nodes[0].send_with_priority(bulk, NodeId(1), MessagePriority::Bulk, true).unwrap();
+
+        for round in 0..4 {
+            let message = TestMessage { req: true, hello: format!("control {}", round), data: vec![0; 16] };
+
+            assert!(nodes[0].broadcast_with_priority(message, (1..NODE_COUNT as u32).map(NodeId), MessagePriority::Control).is_ok());
+        }
+
+        let controls = (0..4).map(|round| format!("control {}", round)).collect::<Vec<_>>();
+
+        for target in 1..NODE_COUNT {
+            let expected = if target == 1 { 5 } else { 4 };
+
+            let mut arrivals = Vec::with_capacity(expected);
+
+            for _ in 0..expected {
+                let received = nodes[target].node_incoming_rq_handling()
+                    .receive_from_replicas(Some(Duration::from_secs(10)))
+                    .unwrap()
+                    .expect("Timed out waiting for the prioritized messages");
+
+                let expected_priority = if received.message().hello == "bulk" {
+                    MessagePriority::Bulk
+                } else {
+                    MessagePriority::Control
+                };
+
+                assert_eq!(received.header().priority(), expected_priority);
+
+                arrivals.push(received.message().hello.clone());
+            }
+
+            // Every control message arrives before the bulk transfer (received messages are deserialized
+            // in parallel, so the order between the control messages themselves is not kept)
+            if target == 1 {
+                assert_eq!(arrivals.pop().as_deref(), Some("bulk"), "The control messages did not overtake the bulk transfer");
+            }
+
+            arrivals.sort();
+
+            assert_eq!(arrivals, controls);
+        }
+
+        // Messages sent without a priority keep the default one of their class
+        assert_all_to_all_delivery(&nodes, 16);
+    }
+
+    /// What we write to a rate limited peer must not go faster than its limit, whose
+    /// debt is paid even by frames larger than its burst, and the limits can be lifted at runtime
+    #[test]
+    fn test_mio_send_rate_limit() {
+        use atlas_communication::protocol_node::{NodeIncomingRqHandler, ProtocolNetworkNode};
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const NODE_COUNT: usize = 2;
+        const MESSAGE_COUNT: usize = 4;
+        const MESSAGE_SIZE: usize = 64 * 1024;
+
+        let limit = BandwidthLimit {
+            bytes_per_second: 128 * 1024,
+            burst_bytes: 16 * 1024,
+        };
+
+        let addrs = setup_addrs(NODE_COUNT as u32, 0);
+
+        let nodes = (0..NODE_COUNT)
+            .map(|id| {
+                gen_tls_mio_node_with_info(MockNetworkInfo::new(NodeId(id as u32), addrs.clone(), NODE_COUNT),
+                                           &format!("srv{}", id), |cfg| {
+                                               if id == 0 {
+                                                   cfg.tcp_config.rate_limit_config.replica_limits.send_limit = Some(limit);
+                                               }
+                                           }).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        connect_all(&nodes);
+
+        let receive_all = |node: &Arc<TestMioNode>| {
+            for _ in 0..MESSAGE_COUNT {
+                node.node_incoming_rq_handling()
+                    .receive_from_replicas(Some(Duration::from_secs(10)))
+                    .unwrap()
+                    .expect("Timed out waiting for the rate limited messages");
+            }
+        };
+
+        let start = Instant::now();
+
+        for round in 0..MESSAGE_COUNT {
+            let message = TestMessage { req: true, hello: format!("0 -> 1 ({})", round), data: vec![0; MESSAGE_SIZE] };
+
+            nodes[0].send(message, NodeId(1), true).unwrap();
+        }
+
+        receive_all(&nodes[1]);
+
+        // Only the burst goes through without waiting
+        let expected = Duration::from_secs_f64((MESSAGE_COUNT * MESSAGE_SIZE - limit.burst_bytes as usize) as f64 / limit.bytes_per_second as f64);
+
+        assert!(start.elapsed() >= expected.mul_f64(0.9), "Took {:?}, expected at least {:?}", start.elapsed(), expected);
+
+        // Lifting the limit applies to the peers we are already connected to
+        nodes[0].node_connections().set_rate_limits(NodeType::Replica, PeerRateLimits { send_limit: None, receive_limit: None });
+
+        for round in 0..MESSAGE_COUNT {
+            let message = TestMessage { req: true, hello: format!("0 -> 1 ({})", round), data: vec![0; MESSAGE_SIZE] };
+
+            nodes[0].send(message, NodeId(1), true).unwrap();
+        }
+
+        receive_all(&nodes[1]);
+    }
+
+    /// Requests of clients that are over their quota are shed, and the clients are told we are busy
+    #[test]
+    fn test_mio_client_admission_control() {
+        use atlas_communication::conn_utils::ConnectionEvent;
+        use atlas_communication::protocol_node::{NodeIncomingRqHandler, ProtocolNetworkNode};
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        let addrs = setup_addrs(1, 1);
+
+        let replica_id = NodeId(0u32);
+
+        let replica = gen_tls_mio_node_with_info(MockNetworkInfo::new(replica_id, addrs.clone(), 1),
+                                                 "srv0", |cfg| {
+                                                     cfg.client_pool_config.admission_config.client_quota = 0;
+                                                     cfg.client_pool_config.admission_config.overloaded_client_quota = 0;
+                                                 }).unwrap();
+
+        let client = gen_tls_mio_node_with_info(MockNetworkInfo::new(FIRST_CLI, addrs.clone(), 1),
+                                                &format!("cli{}", FIRST_CLI.0), |_| {}).unwrap();
+
+        let events = client.subscribe_connection_events();
+
+        for rx in client.node_connections().connect_to_node(replica_id) {
+            rx.recv().unwrap().unwrap();
+        }
+
+        assert!(matches!(next_event(&events), ConnectionEvent::Connected(peer, NodeType::Replica, _) if peer == replica_id));
+
+        let message = TestMessage { req: true, hello: "Flood".to_string(), data: vec![] };
+
+        client.send(message, replica_id, true).unwrap();
+
+        assert!(matches!(next_event(&events), ConnectionEvent::Busy(peer, _) if peer == replica_id));
+
+        // The request never made it to the pools
+        assert_eq!(replica.node_incoming_rq_handling().rqs_len_from_clients(), 0);
+    }
+
+    /// Pools that are notified of the requests of their clients (instead of polling them) must still
+    /// collect every request, whether the client sends them in a burst or trickles them in
+    #[test]
+    fn test_mio_notified_client_pools() {
+        use atlas_communication::protocol_node::{NodeIncomingRqHandler, ProtocolNetworkNode};
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const REQUEST_COUNT: usize = 50;
+
+        let addrs = setup_addrs(1, 1);
+
+        let replica_id = NodeId(0u32);
+
+        let replica = gen_tls_mio_node_with_info(MockNetworkInfo::new(replica_id, addrs.clone(), 1),
+                                                 "srv0", |cfg| {
+                                                     cfg.client_pool_config.collection = BatchCollection::Notified;
+                                                 }).unwrap();
+
+        let client = gen_tls_mio_node_with_info(MockNetworkInfo::new(FIRST_CLI, addrs.clone(), 1),
+                                                &format!("cli{}", FIRST_CLI.0), |_| {}).unwrap();
+
+        for rx in client.node_connections().connect_to_node(replica_id) {
+            rx.recv().unwrap().unwrap();
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+
+        for round in 0..REQUEST_COUNT {
+            let message = TestMessage { req: true, hello: format!("Burst {}", round), data: vec![] };
+
+            client.send(message, replica_id, true).unwrap();
+        }
+
+        for round in 0..REQUEST_COUNT {
+            let message = TestMessage { req: true, hello: format!("Trickle {}", round), data: vec![] };
+
+            client.send(message, replica_id, true).unwrap();
+
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        let mut received = 0;
+
+        while received < 2 * REQUEST_COUNT {
+            assert!(Instant::now() < deadline, "Only {} of the requests were collected", received);
+
+            received += replica.node_incoming_rq_handling()
+                .receive_from_clients(Some(Duration::from_millis(100)))
+                .unwrap()
+                .len();
+        }
+
+        assert_eq!(received, 2 * REQUEST_COUNT);
+    }
+
+    /// Idle connections are pinged, and must survive as long as the peers answer
+    /// (nodes that only speak the base wire version are never pinged, as they wouldn't answer)
+    #[test]
+    fn test_mio_heartbeats_keep_idle_connections() {
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const NODE_COUNT: usize = 3;
+
+        let addrs = setup_addrs(NODE_COUNT as u32, 0);
+
+        let nodes = (0..NODE_COUNT)
+            .map(|id| {
+                let max_wire_version = if id == 2 { Some(WireMessage::BASE_VERSION) } else { None };
+
+                gen_tls_mio_node_with_info(MockNetworkInfo::new(NodeId(id as u32), addrs.clone(), NODE_COUNT),
+                                           &format!("srv{}", id), |cfg| {
+                                               cfg.tcp_config.max_wire_version = max_wire_version;
+                                               cfg.heartbeat_config.interval_millis = 100;
+                                               cfg.heartbeat_config.timeout_millis = 500;
+                                           }).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        connect_all(&nodes);
+
+        // Long enough for several rounds of pings, and for unanswered ones to time out
+        std::thread::sleep(Duration::from_secs(2));
+
+        for node in &nodes {
+            assert_eq!(node.node_connections().connected_nodes_count(), NODE_COUNT - 1);
+        }
+
+        assert_all_to_all_delivery(&nodes, 16);
+    }
+
+    /// A connection we are holding back reading from (because of its receive limit) must not be
+    /// mistaken for a dead one, on either side, even with heartbeats much shorter than the throttling
+    #[test]
+    fn test_mio_heartbeats_while_read_throttled() {
+        use atlas_communication::conn_utils::ConnectionEvent;
+        use atlas_communication::protocol_node::{NodeIncomingRqHandler, ProtocolNetworkNode};
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const NODE_COUNT: usize = 2;
+        const MESSAGE_COUNT: usize = 4;
+        const MESSAGE_SIZE: usize = 64 * 1024;
+
+        let limit = BandwidthLimit {
+            bytes_per_second: 128 * 1024,
+            burst_bytes: 16 * 1024,
+        };
+
+        let addrs = setup_addrs(NODE_COUNT as u32, 0);
+
+        let nodes = (0..NODE_COUNT)
+            .map(|id| {
+                gen_tls_mio_node_with_info(MockNetworkInfo::new(NodeId(id as u32), addrs.clone(), NODE_COUNT),
+                                           &format!("srv{}", id), |cfg| {
+                                               if id == 1 {
+                                                   cfg.tcp_config.rate_limit_config.replica_limits.receive_limit = Some(limit);
+                                               }
+
+                                               cfg.heartbeat_config.interval_millis = 50;
+                                               cfg.heartbeat_config.timeout_millis = 200;
+                                           }).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        connect_all(&nodes);
+
+        let events = nodes.iter().map(|node| node.subscribe_connection_events()).collect::<Vec<_>>();
+
+        // Takes around two seconds to be read, ten times the heartbeat timeout
+        for round in 0..MESSAGE_COUNT {
+            let message = TestMessage { req: true, hello: format!("0 -> 1 ({})", round), data: vec![0; MESSAGE_SIZE] };
+
+            nodes[0].send(message, NodeId(1), true).unwrap();
+        }
+
+        for _ in 0..MESSAGE_COUNT {
+            nodes[1].node_incoming_rq_handling()
+                .receive_from_replicas(Some(Duration::from_secs(10)))
+                .unwrap()
+                .expect("Timed out waiting for the rate limited messages");
+        }
+
+        for events in &events {
+            while let Ok(event) = events.try_recv() {
+                assert!(!matches!(event, ConnectionEvent::Disconnected(..) | ConnectionEvent::AllConnectionsLost(..)),
+                        "A throttled connection was closed");
+            }
+        }
+
+        for node in &nodes {
+            assert_eq!(node.node_connections().connected_nodes_count(), NODE_COUNT - 1);
+        }
+    }
+
+    /// Peers that keep answering our heartbeats must not become suspected,
+    /// while nodes we have never heard from have no suspicion at all
+    #[test]
+    fn test_mio_suspicion_levels() {
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        const NODE_COUNT: usize = 3;
+
+        let addrs = setup_addrs(NODE_COUNT as u32, 0);
+
+        let nodes = (0..NODE_COUNT)
+            .map(|id| {
+                gen_tls_mio_node_with_info(MockNetworkInfo::new(NodeId(id as u32), addrs.clone(), NODE_COUNT),
+                                           &format!("srv{}", id), |cfg| {
+                                               cfg.heartbeat_config.interval_millis = 100;
+                                               cfg.heartbeat_config.timeout_millis = 500;
+                                               cfg.failure_detector_config.acceptable_pause_millis = 300;
+                                               cfg.failure_detector_config.first_interval_estimate_millis = 100;
+                                           }).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let suspicions = nodes.iter()
+            .map(|node| node.node_connections().subscribe_suspicions())
+            .collect::<Vec<_>>();
+
+        connect_all(&nodes);
+
+        std::thread::sleep(Duration::from_secs(2));
+
+        for (id, node) in nodes.iter().enumerate() {
+            for peer in (0..NODE_COUNT).filter(|peer| *peer != id) {
+                let level = node.node_connections().suspicion_level(&NodeId(peer as u32));
+
+                assert!(level < 8.0, "{} suspects {} with level {}", id, peer, level);
+            }
+
+            assert_eq!(node.node_connections().suspicion_level(&NodeId(NODE_COUNT as u32 + 10)), 0.0);
+
+            assert!(suspicions[id].try_recv().is_err());
+        }
+    }
+
+    /// Wait for the next event of a subscription, failing the test if it takes too long
+    fn next_event<T>(events: &ChannelSyncRx<T>) -> T {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        loop {
+            if let Ok(event) = events.try_recv() {
+                return event;
+            }
+
+            assert!(Instant::now() < deadline, "Timed out waiting for an event");
+
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Upper layers are told about connections being established and lost, without polling
+    #[test]
+    fn test_mio_connection_events() {
+        use atlas_communication::conn_utils::{ConnectionEvent, DisconnectReason};
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        let addrs = setup_addrs(2, 0);
+
+        let node_1 = NodeId(0u32);
+        let node_2 = NodeId(1u32);
+
+        let node = gen_tls_mio_node(node_1, addrs.clone(), 2, "srv0").unwrap();
+        let node_2_ = gen_tls_mio_node(node_2, addrs.clone(), 2, "srv1").unwrap();
+
+        let events = node.subscribe_connection_events();
+        let events_2 = node_2_.subscribe_connection_events();
+
+        for rx in node.node_connections().connect_to_node(node_2) {
+            rx.recv().unwrap().unwrap();
+        }
+
+        assert!(matches!(next_event(&events), ConnectionEvent::Connected(peer, NodeType::Replica, _) if peer == node_2));
+        assert!(matches!(next_event(&events_2), ConnectionEvent::Connected(peer, NodeType::Replica, _) if peer == node_1));
+
+        rt::block_on(node.node_connections().disconnect_from_node(&node_2)).unwrap();
+
+        let received = vec![next_event(&events), next_event(&events)];
+
+        assert!(received.contains(&ConnectionEvent::AllConnectionsLost(node_2)));
+        assert!(received.contains(&ConnectionEvent::Disconnected(node_2, DisconnectReason::Requested)));
+
+        // The other end sees its connection break
+        assert!(matches!(next_event(&events_2), ConnectionEvent::Disconnected(peer, _) if peer == node_1));
+        assert_eq!(next_event(&events_2), ConnectionEvent::AllConnectionsLost(node_1));
+    }
+
+    /// Connections lost to failures are re-established, while connections to nodes we
+    /// disconnected from are not
+    #[test]
+    fn test_mio_reconnects_after_failures() {
+        use atlas_communication::conn_utils::ConnectionEvent;
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        let addrs = setup_addrs(3, 0);
+
+        let node_1 = NodeId(0u32);
+        let node_2 = NodeId(1u32);
+        // Never started, so connecting to it keeps failing
+        let offline = NodeId(2u32);
+
+        let node = gen_tls_mio_node(node_1, addrs.clone(), 3, "srv0").unwrap();
+        let node_2_ = gen_tls_mio_node(node_2, addrs.clone(), 3, "srv1").unwrap();
+
+        let events = node.subscribe_connection_events();
+
+        for rx in node.node_connections().connect_to_node(node_2) {
+            rx.recv().unwrap().unwrap();
+        }
+
+        assert!(matches!(next_event(&events), ConnectionEvent::Connected(peer, _, _) if peer == node_2));
+
+        // From the point of view of the first node, its connection just broke
+        rt::block_on(node_2_.node_connections().disconnect_from_node(&node_1)).unwrap();
+
+        assert!(matches!(next_event(&events), ConnectionEvent::Disconnected(peer, _) if peer == node_2));
+        assert_eq!(next_event(&events), ConnectionEvent::AllConnectionsLost(node_2));
+        assert!(matches!(next_event(&events), ConnectionEvent::Connected(peer, _, _) if peer == node_2));
+
+        let pending = node.node_connections().connect_to_node(offline);
+
+        assert!(!pending.is_empty());
+
+        std::thread::sleep(Duration::from_millis(500));
+
+        rt::block_on(node.node_connections().disconnect_from_node(&offline)).unwrap();
+
+        for rx in pending {
+            assert!(rx.recv().unwrap().is_err());
+        }
+    }
+
+    /// Connection attempts to a peer that never answers our handshake time out, instead of
+    /// holding anything up until the OS gives up on them
+    #[test]
+    fn test_mio_connect_timeout() {
+        use std::net::TcpListener;
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        let addrs = setup_addrs(2, 0);
+
+        let node_1 = NodeId(0u32);
+        let unresponsive = NodeId(1u32);
+
+        // Accepts TCP connections (through the backlog), but never completes the TLS handshake
+        let _listener = TcpListener::bind(addrs.get(unresponsive.0 as u64).unwrap().socket()).unwrap();
+
+        let node = gen_tls_mio_node_with_info(MockNetworkInfo::new(node_1, addrs.clone(), 2), "srv0", |cfg| {
+            cfg.reconnection_config.connect_timeout_millis = 500;
+            cfg.reconnection_config.max_attempts = Some(2);
+        }).unwrap();
+
+        let start = Instant::now();
+
+        for rx in node.node_connections().connect_to_node(unresponsive) {
+            assert!(rx.recv().unwrap().is_err());
+        }
+
+        // Two attempts, each timing out, with the backoff in between
+        assert!(start.elapsed() >= Duration::from_millis(1000));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert!(!node.node_connections().is_connected_to_node(&unresponsive));
+    }
+
+    /// A node that shuts down delivers what it had queued and says goodbye, so its peers don't try
+    /// to bring the connections back. It also releases its port, so it can be started again
+    #[test]
+    fn test_mio_shutdown() {
+        use atlas_communication::conn_utils::{ConnectionEvent, DisconnectReason, LeaveReason};
+        use atlas_communication::protocol_node::{NodeIncomingRqHandler, ProtocolNetworkNode};
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        let addrs = setup_addrs(2, 0);
+
+        let node_1 = NodeId(0u32);
+        let node_2 = NodeId(1u32);
+
+        let node_2_ = gen_tls_mio_node(node_2, addrs.clone(), 2, "srv1").unwrap();
+
+        let events_2 = node_2_.subscribe_connection_events();
+
+        for round in 0..3 {
+            let node = gen_tls_mio_node(node_1, addrs.clone(), 2, "srv0").unwrap();
+
+            for rx in node.node_connections().connect_to_node(node_2) {
+                rx.recv().unwrap().unwrap();
+            }
+
+            assert!(matches!(next_event(&events_2), ConnectionEvent::Connected(peer, _, _) if peer == node_1));
+
+            let message = TestMessage { req: true, hello: format!("Round {}", round), data: vec![] };
+
+            node.send(message, node_2, true).unwrap();
+
+            // Let the message be serialized and queued on the connection
+            std::thread::sleep(Duration::from_millis(100));
+
+            node.shutdown();
+
+            assert!(!node.node_connections().is_connected_to_node(&node_2));
+
+            let received = node_2_.node_incoming_rq_handling().receive_from_replicas(Some(Duration::from_secs(5)))
+                .unwrap()
+                .expect("Timed out waiting for the message");
+
+            assert_eq!(received.message().hello, format!("Round {}", round));
+
+            assert_eq!(next_event(&events_2), ConnectionEvent::Disconnected(node_1, DisconnectReason::PeerLeft(LeaveReason::ShuttingDown)));
+            assert_eq!(next_event(&events_2), ConnectionEvent::AllConnectionsLost(node_1));
+
+            // Shutting down again does nothing
+            node.shutdown();
+        }
+
+        // The second node did not try to reconnect to the node that left
+        std::thread::sleep(Duration::from_millis(500));
+
+        assert!(events_2.try_recv().is_err());
+        assert!(!node_2_.node_connections().is_connected_to_node(&node_1));
+    }
+
+    /// Disconnecting from a node delivers what was queued to it and tells it why we left,
+    /// so it doesn't mistake the disconnection for a crash (and try to reconnect)
+    #[test]
+    fn test_mio_disconnect_says_goodbye() {
+        use atlas_communication::conn_utils::{ConnectionEvent, DisconnectReason, LeaveReason};
+        use atlas_communication::protocol_node::{NodeIncomingRqHandler, ProtocolNetworkNode};
+
+        unsafe {
+            rt::init(4).unwrap();
+            threadpool::init(4).unwrap();
+        }
+
+        let addrs = setup_addrs(2, 0);
+
+        let node_1 = NodeId(0u32);
+        let node_2 = NodeId(1u32);
+
+        let node_1_ = gen_tls_mio_node(node_1, addrs.clone(), 2, "srv0").unwrap();
+        let node_2_ = gen_tls_mio_node(node_2, addrs.clone(), 2, "srv1").unwrap();
+
+        let events_2 = node_2_.subscribe_connection_events();
+
+        for rx in node_1_.node_connections().connect_to_node(node_2) {
+            rx.recv().unwrap().unwrap();
+        }
+
+        assert!(matches!(next_event(&events_2), ConnectionEvent::Connected(peer, _, _) if peer == node_1));
+
+        let message = TestMessage { req: true, hello: String::from("Bye"), data: vec![] };
+
+        node_1_.send(message, node_2, true).unwrap();
+
+        // Let the message be serialized and queued on the connection
+        std::thread::sleep(Duration::from_millis(100));
+
+        rt::block_on(node_1_.node_connections().disconnect_from_node(&node_2)).unwrap();
+
+        let received = node_2_.node_incoming_rq_handling().receive_from_replicas(Some(Duration::from_secs(5)))
+            .unwrap()
+            .expect("Timed out waiting for the message");
+
+        assert_eq!(received.message().hello, "Bye");
+
+        assert_eq!(next_event(&events_2), ConnectionEvent::Disconnected(node_1, DisconnectReason::PeerLeft(LeaveReason::Disconnected)));
+        assert_eq!(next_event(&events_2), ConnectionEvent::AllConnectionsLost(node_1));
+
+        // Neither of the nodes tries to bring the connection back
+        std::thread::sleep(Duration::from_millis(500));
+
+        assert!(events_2.try_recv().is_err());
+        assert!(!node_1_.node_connections().is_connected_to_node(&node_2));
+        assert!(!node_2_.node_connections().is_connected_to_node(&node_1));
+    }
+
     #[test]
     fn test_mio_waker() {
 